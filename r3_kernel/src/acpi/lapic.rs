@@ -4,9 +4,12 @@ extern crate log;
 use core::arch::asm;
 
 use crate::acpi::madt;
+use crate::cpu::cpuid::{self, FlagsECX};
+use crate::cpu::tsc::{safe_ticks_from_ns, TSC};
 use crate::mm::{io::MemoryIO, VirtualAddress};
+use crate::system::timer::SYSTEM_TICK_DURATION;
 
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 use madt::PROCESSORS;
 
@@ -14,6 +17,32 @@ pub struct ProcessorID(u8);
 
 const IA32_MSR_APIC_BASE: u32 = 0x1B;
 
+/// LVT timer register bits
+const LVT_MASKED: u32 = 0x10000;
+const LVT_TIMER_PERIODIC: u32 = 0x20000;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0x40000;
+
+/// divide configuration register value for dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+/// the LAPIC timer is counted against the TSC for this long at boot.
+const TIMER_CALIBRATION_NS: u64 = 10 * 1000000;
+
+/// vector used by the scheduler timer in both the modes.
+pub const TIMER_VECTOR: u8 = 0x50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LapicTimerMode {
+    /// an interrupt is armed by writing the TSC deadline MSR before every tick.
+    TSCDeadline,
+    /// the timer reloads itself, used when the CPU has no TSC deadline support.
+    Periodic,
+}
+
+static TSC_DEADLINE_MODE: AtomicBool = AtomicBool::new(true);
+/// initial count of the periodic timer for one system tick, 0 in deadline mode.
+static PERIODIC_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
 pub fn read_msr_base_address() -> u32 {
     let base_addr_eax: u32;
     unsafe {
@@ -105,10 +134,59 @@ impl LAPICUtils {
 
     pub fn setup_timer(vector: u8) {
         // unmasks the timer + configures TSC deadline mode.
-        let timer_flag = (vector as u32) | 0x40000;
+        let timer_flag = (vector as u32) | LVT_TIMER_TSC_DEADLINE;
         LAPICRegistersIO::write_register(LapicNumbers::LvtTimer as u64, timer_flag);
     }
 
+    /// counts how many LAPIC timer ticks (divided by 16) pass during `ns`,
+    /// the TSC must be calibrated before calling this.
+    pub fn calibrate_timer(ns: u64) -> u64 {
+        LAPICRegistersIO::write_register(
+            LapicNumbers::TimerDivideConfig as u64,
+            TIMER_DIVIDE_BY_16,
+        );
+        // one-shot and masked, the count is only read back.
+        LAPICRegistersIO::write_register(LapicNumbers::LvtTimer as u64, LVT_MASKED);
+
+        let wait_ticks = safe_ticks_from_ns(ns).u64();
+        let start = TSC::read_tsc().u64();
+        LAPICRegistersIO::write_register(LapicNumbers::TimerInitialCount as u64, u32::MAX);
+
+        // legacy PIT is already masked here, so the TSC is used as the reference.
+        while TSC::read_tsc().u64() - start < wait_ticks {
+            core::hint::spin_loop();
+        }
+
+        let remaining = LAPICRegistersIO::read_register(LapicNumbers::TimerCurrentCount as u64);
+        LAPICRegistersIO::write_register(LapicNumbers::TimerInitialCount as u64, 0);
+
+        (u32::MAX - remaining) as u64
+    }
+
+    /// configures the timer in periodic mode, it stays stopped until an
+    /// initial count is written with `start_periodic_timer`.
+    pub fn setup_periodic_timer(vector: u8) {
+        LAPICRegistersIO::write_register(
+            LapicNumbers::TimerDivideConfig as u64,
+            TIMER_DIVIDE_BY_16,
+        );
+        LAPICRegistersIO::write_register(LapicNumbers::TimerInitialCount as u64, 0);
+        let timer_flag = (vector as u32) | LVT_TIMER_PERIODIC;
+        LAPICRegistersIO::write_register(LapicNumbers::LvtTimer as u64, timer_flag);
+    }
+
+    /// (re)starts the periodic count down, the next interrupt comes after a full period.
+    #[inline]
+    pub fn start_periodic_timer(initial_count: u32) {
+        LAPICRegistersIO::write_register(LapicNumbers::TimerInitialCount as u64, initial_count);
+    }
+
+    /// writing 0 as the initial count stops the periodic timer.
+    #[inline]
+    pub fn stop_periodic_timer() {
+        LAPICRegistersIO::write_register(LapicNumbers::TimerInitialCount as u64, 0);
+    }
+
     #[inline]
     fn write_lapic_reg(offset: u64, data: u32) {
         let lapic_addr = LAPICRegistersIO::get_base_addr();
@@ -151,13 +229,45 @@ pub fn init_bsp_lapic() {
     // enable LAPIC
     LAPICUtils::enable_lapic();
 
-    // set up LAPIC timer:
-    LAPICUtils::setup_timer(0x50);
+    // set up LAPIC timer, use TSC deadline mode when the CPU has it.
+    if cpuid::has_feature(FlagsECX::TSCD) {
+        LAPICUtils::setup_timer(TIMER_VECTOR);
+        TSC_DEADLINE_MODE.store(true, Ordering::SeqCst);
+    } else {
+        let calibrated = LAPICUtils::calibrate_timer(TIMER_CALIBRATION_NS);
+        let initial_count = (calibrated * SYSTEM_TICK_DURATION) / TIMER_CALIBRATION_NS;
+        let initial_count = core::cmp::max(1, core::cmp::min(initial_count, u32::MAX as u64));
+
+        PERIODIC_INITIAL_COUNT.store(initial_count as u32, Ordering::SeqCst);
+        TSC_DEADLINE_MODE.store(false, Ordering::SeqCst);
+        LAPICUtils::setup_periodic_timer(TIMER_VECTOR);
+
+        log::warn!(
+            "CPU has no TSC deadline support, using periodic LAPIC timer, initial_count={}",
+            initial_count
+        );
+    }
 
-    log::info!("Enabled LAPIC and APIC timer for base processor.");
+    log::info!(
+        "Enabled LAPIC and APIC timer for base processor, mode={:?}",
+        timer_mode()
+    );
     APIC_BSP_ENABLED.store(true, Ordering::SeqCst);
 }
 
 pub fn bsp_apic_enabled() -> bool {
     APIC_BSP_ENABLED.load(Ordering::SeqCst)
 }
+
+pub fn timer_mode() -> LapicTimerMode {
+    if TSC_DEADLINE_MODE.load(Ordering::SeqCst) {
+        LapicTimerMode::TSCDeadline
+    } else {
+        LapicTimerMode::Periodic
+    }
+}
+
+#[inline]
+pub fn periodic_initial_count() -> u32 {
+    PERIODIC_INITIAL_COUNT.load(Ordering::SeqCst)
+}
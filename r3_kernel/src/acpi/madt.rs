@@ -4,6 +4,7 @@ extern crate spin;
 
 use alloc::vec::Vec;
 use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -21,6 +22,24 @@ pub struct PerProcessorLAPIC {
     flags: u32,
 }
 
+/// the processor is ready to use.
+const LAPIC_FLAG_ENABLED: u32 = 1 << 0;
+/// the processor is disabled, but the firmware allows bringing it online later.
+const LAPIC_FLAG_ONLINE_CAPABLE: u32 = 1 << 1;
+
+impl PerProcessorLAPIC {
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.flags & LAPIC_FLAG_ENABLED != 0
+    }
+
+    /// only meaningful when the processor is not enabled.
+    #[inline]
+    pub fn is_online_capable(&self) -> bool {
+        !self.is_enabled() && self.flags & LAPIC_FLAG_ONLINE_CAPABLE != 0
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 pub struct PerProcessorIOAPIC {
@@ -86,7 +105,10 @@ impl MADT {
         let madt_address = madt_entry_opt.unwrap();
 
         let lapic_root: &LAPICRootHeader = unsafe { &*madt_address.get_ptr() };
-        assert_eq!(lapic_root.header.length > 8, true);
+        if lapic_root.header.length as usize <= mem::size_of::<LAPICRootHeader>() {
+            log::error!("APIC MADT is too short to have any entries");
+            return Err(MADTError::InvalidTableData);
+        }
 
         let mut cores: Vec<PerProcessorLAPIC> = Vec::new();
         let mut ioapics: Vec<PerProcessorIOAPIC> = Vec::new();
@@ -157,15 +179,53 @@ lazy_static! {
     pub static ref PROCESSORS: Mutex<MultiProcessorInfo> = Mutex::new(probe_cpus());
 }
 
+// counted once in setup_madt, so the readers don't have to take PROCESSORS.
+static ENABLED_CPUS: AtomicUsize = AtomicUsize::new(1);
+static ONLINE_CAPABLE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// number of processors the firmware reports as enabled, only the boot
+/// processor runs kernel code as of now, but this is what userspace should size for.
+#[inline]
+pub fn cpu_count() -> usize {
+    ENABLED_CPUS.load(Ordering::SeqCst)
+}
+
+/// number of disabled processors that could be brought online later.
+#[inline]
+pub fn online_capable_count() -> usize {
+    ONLINE_CAPABLE_CPUS.load(Ordering::SeqCst)
+}
+
 pub fn setup_madt() {
     let proc_lock = PROCESSORS.lock();
+
+    let enabled = proc_lock.cores.iter().filter(|proc| proc.is_enabled()).count();
+    let online_capable = proc_lock
+        .cores
+        .iter()
+        .filter(|proc| proc.is_online_capable())
+        .count();
+
+    // the boot processor is running this, even if the table says otherwise.
+    ENABLED_CPUS.store(core::cmp::max(enabled, 1), Ordering::SeqCst);
+    ONLINE_CAPABLE_CPUS.store(online_capable, Ordering::SeqCst);
+
     log::info!(
-        "Number of CPU cores: {}, Local APIC Address: 0x{:x}",
+        "Number of CPU cores: {}, enabled: {}, online capable: {}, Local APIC Address: 0x{:x}",
         proc_lock.cores.len(),
+        enabled,
+        online_capable,
         proc_lock.lapic_address.as_u64()
     );
 
     for proc in &proc_lock.cores {
-        log::info!("CPU-{} - {}", proc.id, proc.apic_id);
+        let state = if proc.is_enabled() {
+            "enabled"
+        } else if proc.is_online_capable() {
+            "online capable"
+        } else {
+            "disabled"
+        };
+        log::info!("CPU-{} - {} ({})", proc.id, proc.apic_id, state);
     }
 }
@@ -68,21 +68,74 @@ pub struct SDTHeader {
     pub creator_rev: u32,
 }
 
+/// EBDA segment pointer in the BIOS data area
+const EBDA_SEGMENT_PTR: u64 = 0x40E;
+/// the RSDP is within the first 1KiB of the EBDA
+const EBDA_SEARCH_SIZE: u64 = 1024;
+/// main BIOS area where the RSDP can be found on legacy systems
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
 #[inline]
 pub fn is_extended_rsdp(rsdp: &RSDPDescriptor) -> bool {
-    rsdp.revision == 2
+    rsdp.revision >= 2
+}
+
+/// checks the signature and legacy checksum of the RSDP at given address.
+#[inline]
+fn is_valid_rsdp(rsdp_addr: VirtualAddress) -> bool {
+    let bytes_slice: &[u8; 20] = unsafe { &*rsdp_addr.get_ptr() };
+    if &bytes_slice[0..8] != RSDT_SIG.as_bytes() {
+        return false;
+    }
+
+    let legacy_checksum: usize = bytes_slice.iter().map(|val| *val as usize).sum();
+    legacy_checksum & 0xff == 0
+}
+
+/// scans the memory region on 16-byte boundaries for a valid RSDP.
+fn scan_region(start: u64, end: u64) -> Option<PhysicalAddress> {
+    let mut addr = start;
+    while addr + 20 <= end {
+        if is_valid_rsdp(p_to_v(PhysicalAddress::from_u64(addr))) {
+            return Some(PhysicalAddress::from_u64(addr));
+        }
+        addr += 16;
+    }
+    None
 }
 
 impl AcpiRootTableKind {
-    pub fn parse_from_bootinfo() -> Result<AcpiRootTableKind, AcpiRootTableError> {
+    /// returns the physical address of RSDP, the boot info is the reliable source
+    /// under UEFI, legacy BIOS area scan is used only when it's not provided.
+    fn locate_rsdp() -> Option<PhysicalAddress> {
         let boot_info = BootProtocol::get_boot_proto();
-        let rsdp_opt = boot_info.unwrap().rsdp_addr;
+        if let Some(rsdp_addr) = boot_info.unwrap().rsdp_addr.into_option() {
+            return Some(PhysicalAddress::from_u64(rsdp_addr));
+        }
+
+        log::warn!("Boot info has no RSDP address, scanning the BIOS area.");
+
+        // EBDA segment is stored as a 16-bit real mode segment.
+        let ebda_ptr = p_to_v(PhysicalAddress::from_u64(EBDA_SEGMENT_PTR));
+        let ebda_segment: u16 = unsafe { *ebda_ptr.get_ptr() };
+        let ebda_start = (ebda_segment as u64) << 4;
+        if ebda_start != 0 {
+            if let Some(addr) = scan_region(ebda_start, ebda_start + EBDA_SEARCH_SIZE) {
+                return Some(addr);
+            }
+        }
 
-        if rsdp_opt.into_option().is_none() {
+        scan_region(BIOS_AREA_START, BIOS_AREA_END)
+    }
+
+    pub fn parse_from_bootinfo() -> Result<AcpiRootTableKind, AcpiRootTableError> {
+        let rsdp_opt = AcpiRootTableKind::locate_rsdp();
+        if rsdp_opt.is_none() {
             return Err(AcpiRootTableError::NotFound);
         }
 
-        let rsdp_addr = p_to_v(PhysicalAddress::from_u64(rsdp_opt.into_option().unwrap()));
+        let rsdp_addr = p_to_v(rsdp_opt.unwrap());
 
         // ACPI 1.0 RSDT is 20 bytes
         let bytes_slice: &[u8; 20] = unsafe { &*rsdp_addr.get_ptr() };
@@ -116,7 +169,13 @@ impl AcpiRootTableKind {
                 return Err(AcpiRootTableError::InvalidChecksum2x);
             }
 
-            return Ok(AcpiRootTableKind::XSDT(ext_rsdp_struct.xsdt_address));
+            // some firmwares report 2.0 but leave the XSDT empty, use RSDT then.
+            let xsdt_address = ext_rsdp_struct.xsdt_address;
+            if xsdt_address != 0 {
+                return Ok(AcpiRootTableKind::XSDT(xsdt_address));
+            }
+
+            log::warn!("ACPI 2.0 RSDP without XSDT, falling back to RSDT.");
         }
 
         return Ok(AcpiRootTableKind::RSDT(rsdp_struct.rsdt_address));
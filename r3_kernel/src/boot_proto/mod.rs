@@ -30,6 +30,9 @@ lazy_static! {
 // BootProtocl is an abstract structure that encapsulates all the boot level information.
 // this abstraction helps us to port a multiboot2 based bootloader in the future by only changing
 // this implementation than the whole codebase.
+// there is no multiboot2 parser yet, when one is added, a header without tags (length equal
+// to the basic header size) must give an empty tag list or an error, not a panic. only a bad
+// magic or checksum is fatal.
 pub struct BootProtocol {}
 
 impl BootProtocol {
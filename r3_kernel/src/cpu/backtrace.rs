@@ -0,0 +1,74 @@
+extern crate log;
+
+use crate::mm::paging::KernelVirtualMemoryManager;
+use crate::mm::VirtualAddress;
+use crate::system::abi;
+
+use core::arch::asm;
+
+// frame pointer based unwinder, the kernel is built with frame pointers (see
+// x86_64.json) so every frame starts with the saved rbp of the caller followed
+// by the return address. only raw addresses are printed, resolve them with
+// `tools/backtrace.sh` which runs addr2line over the serial log.
+
+/// frames printed at most, a corrupted chain can't loop forever.
+const MAX_FRAMES: usize = 32;
+
+#[inline(always)]
+pub fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// can the 16 bytes of the frame at `rbp` be read without faulting again?
+fn is_valid_frame(rbp: u64) -> bool {
+    if rbp == 0 || rbp % 8 != 0 || abi::is_in_userspace(rbp) {
+        return false;
+    }
+
+    let (vmm, _) = KernelVirtualMemoryManager::current_vmm();
+    vmm.translate(VirtualAddress::from_u64(rbp)).is_some()
+        && vmm.translate(VirtualAddress::from_u64(rbp + 8)).is_some()
+}
+
+/// walks the chain of frames starting at `rbp`, the first frame is numbered `first`.
+fn walk(mut rbp: u64, first: usize) {
+    for frame_no in first..MAX_FRAMES {
+        if !is_valid_frame(rbp) {
+            return;
+        }
+
+        let (next_rbp, return_addr) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if return_addr == 0 {
+            return;
+        }
+
+        log::error!("bt #{} 0x{:x}", frame_no, return_addr);
+
+        // the callers are above on the stack, anything else is a broken chain.
+        if next_rbp <= rbp {
+            return;
+        }
+        rbp = next_rbp;
+    }
+
+    log::error!("bt stopped after {} frames", MAX_FRAMES);
+}
+
+/// prints the call stack of the function that calls this.
+#[inline(always)]
+pub fn print_backtrace() {
+    log::error!("Backtrace:");
+    walk(current_rbp(), 0);
+}
+
+/// prints the call stack of interrupted kernel code. `rbp` is the one the
+/// faulting code had, saved by the exception entry, so it's frame is walked as is.
+pub fn print_fault_backtrace(rip: u64, rbp: u64) {
+    log::error!("Backtrace:");
+    log::error!("bt #0 0x{:x}", rip);
+    walk(rbp, 1);
+}
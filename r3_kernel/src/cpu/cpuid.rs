@@ -2,6 +2,8 @@ extern crate bitflags;
 
 use core::arch::asm;
 
+use crate::cpu::halt_no_interrupts;
+
 use lazy_static::lazy_static;
 
 use bitflags::bitflags;
@@ -173,20 +175,89 @@ pub fn has_extended_feature(flag: FlagsEDX) -> bool {
     CPU_FEATURES.edx.contains(flag)
 }
 
-pub fn assert_min_levels() {
-    assert!(
-        CPU_FEATURES.max_standard_level >= 3,
-        "Expected CPU standard level >= 3, got {}.",
-        CPU_FEATURES.max_standard_level
-    );
+/// NX bit of edx in the extended leaf 0x8000_0001.
+const EXT_EDX_NO_EXECUTE: u32 = 1 << 20;
 
-    assert!(
-        CPU_FEATURES.max_extended_level >= 0x8000_0007,
-        "Expected CPU extended level >= 0x80000007, got 0x{:x}.",
-        CPU_FEATURES.max_extended_level
-    );
+/// true if page entries can be marked as not executable.
+pub fn has_no_execute() -> bool {
+    if CPU_FEATURES.max_extended_level < 0x8000_0001 {
+        return false;
+    }
+
+    let edx: u32;
+    unsafe {
+        asm!(
+            "xchg {0:r}, rbx",
+            "cpuid",
+            "xchg {0:r}, rbx",
+            out(reg) _,
+            inout("eax") 0x8000_0001u32 => _,
+            out("ecx") _,
+            out("edx") edx,
+            options(nostack, nomem, preserves_flags)
+        );
+    }
+
+    edx & EXT_EDX_NO_EXECUTE != 0
+}
+
+/// standard features the kernel can't run without, checked once at boot.
+/// the kernel and the userland are built without sse, so it is not listed here.
+/// TSC-deadline is optional, the LAPIC timer falls back to periodic mode without it.
+const REQUIRED_EDX: &'static [(FlagsEDX, &'static str)] = &[
+    (FlagsEDX::TSC, "TSC (time stamp counter)"),
+    (FlagsEDX::MSR, "MSR (model specific registers)"),
+    (FlagsEDX::APIC, "APIC (on-chip local APIC)"),
+];
+
+const MIN_STANDARD_LEVEL: u32 = 3;
+const MIN_EXTENDED_LEVEL: u32 = 0x8000_0007;
+
+/// logs every required feature that is missing and returns how many were missing.
+fn count_missing_requirements() -> usize {
+    let mut n_missing = 0;
+
+    if CPU_FEATURES.max_standard_level < MIN_STANDARD_LEVEL {
+        log::error!(
+            "CPU: standard cpuid level 0x{:x} is too low, need >= 0x{:x}.",
+            CPU_FEATURES.max_standard_level,
+            MIN_STANDARD_LEVEL
+        );
+        n_missing += 1;
+    }
+
+    if CPU_FEATURES.max_extended_level < MIN_EXTENDED_LEVEL {
+        log::error!(
+            "CPU: extended cpuid level 0x{:x} is too low, need >= 0x{:x}.",
+            CPU_FEATURES.max_extended_level,
+            MIN_EXTENDED_LEVEL
+        );
+        n_missing += 1;
+    }
+
+    for (flag, name) in REQUIRED_EDX {
+        if !has_extended_feature(*flag) {
+            log::error!("CPU: missing required feature {}.", name);
+            n_missing += 1;
+        }
+    }
+
+    n_missing
+}
+
+/// checks the cpuid levels and all the required features, if something is
+/// missing, each of them is reported on the console and the CPU is halted.
+pub fn check_requirements() {
+    let n_missing = count_missing_requirements();
+    if n_missing > 0 {
+        log::error!(
+            "This CPU can't run r3, {} requirement(s) not met (see above). Halting.",
+            n_missing
+        );
+        halt_no_interrupts();
+    }
 
-    log::info!("CPU level checks passed.");
+    log::info!("CPU level and feature checks passed.");
 }
 
 pub fn assert_feature(flag: FlagsECX) {
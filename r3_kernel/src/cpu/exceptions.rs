@@ -2,12 +2,21 @@ extern crate log;
 extern crate spin;
 
 use crate::cpu;
+use crate::cpu::backtrace;
+use crate::cpu::interrupt_stacks::{DEFAULT_IST_INDEX, DOUBLE_FAULT_IST_INDEX};
 use crate::cpu::rflags::RFlagsStruct;
-use cpu::interrupts::{
-    prepare_default_handle, prepare_error_code_handle, prepare_no_ret_error_code_handle,
-    prepare_page_fault_handler,
-};
+use crate::mm::paging::KernelVirtualMemoryManager;
+use crate::mm::VirtualAddress;
+use crate::system::abi;
+use crate::system::posix::sched;
+use crate::system::tasking::{Sched, SCHEDULER};
+use crate::system::utils::{CodeMapper, ProcessHeapAllocator};
+use cpu::interrupts::{prepare_default_handle, prepare_exception_entry};
 use cpu::interrupts::{InterruptDescriptorTable, InterruptStackFrame};
+use core::arch::asm;
+#[cfg(feature = "debug_checks")]
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::{AtomicBool, Ordering};
 use cpu::mmu::{read_cr2, PageFaultExceptionTypes};
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -22,70 +31,345 @@ const DOUBLE_FAULT_ISR_NO: usize = 8;
 const GPF_ISR_NO: usize = 13;
 const PAFE_FAULT_ISR_NO: usize = 14;
 
-pub extern "x86-interrupt" fn divide_by_zero(stk: InterruptStackFrame) {
-    log::error!("Divide by zero exception\nException info: {:#?}", stk);
+/// exit code used for processes killed by a fault, same as a shell reports SIGSEGV.
+pub const FAULT_EXIT_CODE: i64 = 128 + 11;
+
+/// saves the registers below the error code the CPU pushed, in the order of
+/// `ExceptionFrame`. rsp ends up 8 bytes off a 16 byte boundary.
+macro_rules! save_exception_registers {
+    () => {
+        r#"
+        push r15;
+        push r14;
+        push r13;
+        push r12;
+        push r11;
+        push r10;
+        push r9;
+        push r8;
+        push rdi;
+        push rsi;
+        push rdx;
+        push rcx;
+        push rbx;
+        push rax;
+        push rbp;
+        "#
+    };
+}
+
+/// restores what save_exception_registers saved, drops the error code and returns
+/// to where the exception came from.
+macro_rules! restore_exception_registers {
+    () => {
+        r#"
+        pop rbp;
+        pop rax;
+        pop rbx;
+        pop rcx;
+        pop rdx;
+        pop rsi;
+        pop rdi;
+        pop r8;
+        pop r9;
+        pop r10;
+        pop r11;
+        pop r12;
+        pop r13;
+        pop r14;
+        pop r15;
+        add rsp, 8;
+        iretq;
+        "#
+    };
+}
+
+/// what an exception entry stub leaves on the stack, lowest address first.
+/// the handler gets it by reference, so the changes to `stk` are used by iretq.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub rbp: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub error_code: u64,
+    pub stk: InterruptStackFrame,
+}
+
+/// defines `$entry`, a naked stub that saves the registers and calls the
+/// `#[no_mangle]` function `$handler` with the `ExceptionFrame`. `$push_err`
+/// pushes a 0 for the exceptions the CPU pushes no error code for.
+macro_rules! exception_entry {
+    ($entry:ident, $handler:literal, $push_err:literal) => {
+        #[naked]
+        extern "C" fn $entry() {
+            unsafe {
+                asm!(
+                    $push_err,
+                    save_exception_registers!(),
+                    "mov rdi, rsp",
+                    "sub rsp, 8",
+                    concat!("call ", $handler),
+                    "add rsp, 8",
+                    restore_exception_registers!(),
+                    options(noreturn)
+                )
+            }
+        }
+    };
+}
+
+#[inline]
+fn is_user_fault(stk: &InterruptStackFrame) -> bool {
+    // the requested privilege level of the saved CS tells where the fault happened
+    stk.code_segment & 0x3 == 3
+}
+
+/// decodes the selector error code pushed by GPF and friends.
+#[inline]
+fn decode_selector_error(err: u64) -> (bool, &'static str, u64) {
+    let table = match (err >> 1) & 0x3 {
+        0 => "GDT",
+        2 => "LDT",
+        _ => "IDT",
+    };
+    (err & 0x1 != 0, table, (err >> 3) & 0x1FFF)
+}
+
+/// the registers are the ones of the faulting code, saved by the entry stub.
+fn dump_fault(name: &str, frame: &ExceptionFrame) {
+    let stk = &frame.stk;
+    log::error!(
+        "{} at rip=0x{:x}, ring={}, rsp=0x{:x}, cs=0x{:x}, ss=0x{:x}, rflags={:?}",
+        name,
+        stk.instruction_pointer,
+        stk.code_segment & 0x3,
+        stk.stack_pointer,
+        stk.code_segment,
+        stk.stack_segment,
+        RFlagsStruct::from_bits_truncate(stk.cpu_flags)
+    );
+    log::error!(
+        "rax=0x{:x} rbx=0x{:x} rcx=0x{:x} rdx=0x{:x} rsi=0x{:x} rdi=0x{:x} rbp=0x{:x}",
+        frame.rax,
+        frame.rbx,
+        frame.rcx,
+        frame.rdx,
+        frame.rsi,
+        frame.rdi,
+        frame.rbp
+    );
+    log::error!(
+        "r8=0x{:x} r9=0x{:x} r10=0x{:x} r11=0x{:x} r12=0x{:x} r13=0x{:x} r14=0x{:x} r15=0x{:x}",
+        frame.r8,
+        frame.r9,
+        frame.r10,
+        frame.r11,
+        frame.r12,
+        frame.r13,
+        frame.r14,
+        frame.r15
+    );
+
+    // the user stack can't be trusted, only kernel faults are unwound.
+    if !is_user_fault(stk) {
+        backtrace::print_fault_backtrace(stk.instruction_pointer, frame.rbp);
+    }
+}
+
+/// kills the process that caused the fault and hands over the CPU to the scheduler.
+fn terminate_faulting_process(name: &str) -> ! {
+    let pid = SCHEDULER.lock().current_pid();
+    if pid.is_none() {
+        log::error!("{} in user mode without a running process.", name);
+        cpu::halt_no_interrupts();
+    }
+
+    log::error!(
+        "Terminating process {} due to {}.",
+        pid.unwrap().as_u64(),
+        name
+    );
+    let _ = sched::sys_exit(FAULT_EXIT_CODE);
+
+    // the exited thread is never scheduled again, wait for the timer to switch away.
+    cpu::halt_with_interrupts();
+}
+
+exception_entry!(divide_by_zero_entry, "divide_by_zero", "push 0");
+
+#[no_mangle]
+extern "C" fn divide_by_zero(frame: &mut ExceptionFrame) {
+    dump_fault("Divide by zero exception", frame);
+    if is_user_fault(&frame.stk) {
+        terminate_faulting_process("divide by zero exception");
+    }
+    cpu::halt_no_interrupts();
 }
 
 extern "x86-interrupt" fn breakpoint(stk: InterruptStackFrame) {
     log::error!("Breakpoint exception\nException info: {:#?}", stk);
 }
 
-extern "x86-interrupt" fn invalid_opcode(stk: InterruptStackFrame) {
-    log::error!("Invalid opcode exception\nException info: {:#?}", stk);
+exception_entry!(invalid_opcode_entry, "invalid_opcode", "push 0");
+
+#[no_mangle]
+extern "C" fn invalid_opcode(frame: &mut ExceptionFrame) {
+    dump_fault("Invalid opcode exception", frame);
+    if is_user_fault(&frame.stk) {
+        terminate_faulting_process("invalid opcode exception");
+    }
+    cpu::halt_no_interrupts();
 }
 
 extern "x86-interrupt" fn overflow(stk: InterruptStackFrame) {
     log::error!("Overflow exception.\nException info: {:#?}", stk);
 }
 
-extern "x86-interrupt" fn gpf(stk: InterruptStackFrame, err: u64) {
+exception_entry!(gpf_entry, "gpf", "");
+
+#[no_mangle]
+extern "C" fn gpf(frame: &mut ExceptionFrame) {
+    dump_fault("General protection fault", frame);
+
+    let err = frame.error_code;
+    let (external, table, index) = decode_selector_error(err);
     log::error!(
-        "General protection fault {}\nException info: {:#?}",
+        "GPF error_code=0x{:x}, external={}, table={}, selector_index={}",
         err,
-        stk
-    );
-    log::error!(
-        "GPF rflags: {:?}\n",
-        RFlagsStruct::from_bits_truncate(stk.cpu_flags)
+        external,
+        table,
+        index
     );
+
+    if is_user_fault(&frame.stk) {
+        terminate_faulting_process("general protection fault");
+    }
     cpu::halt_no_interrupts();
 }
 
-extern "x86-interrupt" fn double_fault(stk: InterruptStackFrame, _err: u64) -> ! {
-    log::debug!("double fault interrupt");
-    log::error!(
-        "Double fault rflags: {:?}\n",
-        RFlagsStruct::from_bits_truncate(stk.cpu_flags)
-    );
+/// set once the first double fault is being reported.
+static IN_DOUBLE_FAULT: AtomicBool = AtomicBool::new(false);
+
+exception_entry!(double_fault_entry, "double_fault", "");
+
+#[no_mangle]
+extern "C" fn double_fault(frame: &mut ExceptionFrame) -> ! {
+    // faulting again while reporting, just stop here instead of looping.
+    if IN_DOUBLE_FAULT.swap(true, Ordering::SeqCst) {
+        cpu::halt_no_interrupts();
+    }
+
+    // a double fault is not recoverable, even if it came from user mode.
+    dump_fault("Double fault", frame);
     cpu::halt_no_interrupts();
 }
 
-extern "x86-interrupt" fn page_fault(stk: InterruptStackFrame, err: PageFaultExceptionTypes) -> ! {
+/// where probe_kernel_write resumes if it's write faults, 0 when no probe runs.
+#[cfg(feature = "debug_checks")]
+static PROBE_FIXUP: AtomicU64 = AtomicU64::new(0);
+
+/// writes the byte from kernel mode and tells if the write faulted instead of
+/// taking the kernel down, for the self-tests of the page protections.
+#[cfg(feature = "debug_checks")]
+pub fn probe_kernel_write(addr: VirtualAddress, value: u8) -> bool {
+    let faulted: u64;
+    unsafe {
+        asm!(
+            "lea {tmp}, [rip + 2f]",
+            "mov [{fixup}], {tmp}",
+            "xor {faulted:e}, {faulted:e}",
+            "mov byte ptr [{addr}], {value}",
+            "jmp 3f",
+            "2:",
+            "mov {faulted:e}, 1",
+            "3:",
+            "mov qword ptr [{fixup}], 0",
+            fixup = in(reg) PROBE_FIXUP.as_ptr(),
+            addr = in(reg) addr.as_u64(),
+            value = in(reg_byte) value,
+            tmp = out(reg) _,
+            faulted = out(reg) faulted,
+            options(nostack)
+        );
+    }
+
+    faulted != 0
+}
+
+exception_entry!(page_fault_entry, "page_fault", "");
+
+#[no_mangle]
+extern "C" fn page_fault(frame: &mut ExceptionFrame) {
     let cr2_val = read_cr2();
+    let err = PageFaultExceptionTypes::from_bits_truncate(frame.error_code);
+
+    // the fault of a probe, continue at it's fixup. the frame is the one the
+    // CPU pushed, so the new instruction pointer is used by iretq.
+    #[cfg(feature = "debug_checks")]
+    if !is_user_fault(&frame.stk) && PROBE_FIXUP.load(Ordering::SeqCst) != 0 {
+        frame.stk.instruction_pointer = PROBE_FIXUP.swap(0, Ordering::SeqCst);
+        return;
+    }
+
+    // a page of a demand paged segment touched for the first time, from the user
+    // code or from a syscall accessing user memory, map it and retry the access.
+    // heap pages given back with madvise come back the same way, zeroed.
+    if !err.contains(PageFaultExceptionTypes::PROTECTION_VIOLATION) && abi::is_in_userspace(cr2_val)
+    {
+        let (vmm, _) = KernelVirtualMemoryManager::current_vmm();
+        let fault_addr = VirtualAddress::from_u64(cr2_val);
+        if CodeMapper::map_demand_page(&vmm, &fault_addr)
+            || ProcessHeapAllocator::map_zero_page(&vmm, &fault_addr)
+        {
+            return;
+        }
+    }
+
+    dump_fault("Page fault exception", frame);
+
     // log exception
     log::error!(
-        "Page Fault Exception:\n
-        error_code={:?}, accessed_address=0x{:x},
-        stack_frame={:?}",
+        "Page fault error_code={:?}, accessed_address=0x{:x}, present={}, write={}, user={}, fetch={}",
         err,
         cr2_val,
-        stk
+        err.contains(PageFaultExceptionTypes::PROTECTION_VIOLATION),
+        err.contains(PageFaultExceptionTypes::CAUSED_BY_WRITE),
+        err.contains(PageFaultExceptionTypes::USER_MODE),
+        err.contains(PageFaultExceptionTypes::INSTRUCTION_FETCH)
     );
 
+    if is_user_fault(&frame.stk) {
+        terminate_faulting_process("page fault");
+    }
     cpu::halt_no_interrupts();
 }
 
 pub fn prepare_idt() -> InterruptDescriptorTable {
     let mut idt = InterruptDescriptorTable::empty();
-    idt.interrupts[DIVIDE_BY_ZERO_ISR_NO] = prepare_default_handle(divide_by_zero, 0);
-    idt.interrupts[INVALID_OPCODE_ISR_NO] = prepare_default_handle(invalid_opcode, 0);
-    idt.interrupts[BREAKPOINT_ISR_NO] = prepare_default_handle(breakpoint, 0);
-    idt.interrupts[DOUBLE_FAULT_ISR_NO] = prepare_no_ret_error_code_handle(double_fault);
-    idt.interrupts[PAFE_FAULT_ISR_NO] = prepare_page_fault_handler(page_fault);
-    idt.interrupts[OVERFLOW_ISR_NO] = prepare_default_handle(overflow, 0);
-    idt.interrupts[GPF_ISR_NO] = prepare_error_code_handle(gpf);
-
-    idt.interrupts[DOUBLE_FAULT_ISR_NO].set_stack_index(0);
+    idt.interrupts[DIVIDE_BY_ZERO_ISR_NO] = prepare_exception_entry(divide_by_zero_entry);
+    idt.interrupts[INVALID_OPCODE_ISR_NO] = prepare_exception_entry(invalid_opcode_entry);
+    idt.interrupts[BREAKPOINT_ISR_NO] = prepare_default_handle(breakpoint, DEFAULT_IST_INDEX);
+    idt.interrupts[DOUBLE_FAULT_ISR_NO] = prepare_exception_entry(double_fault_entry);
+    idt.interrupts[PAFE_FAULT_ISR_NO] = prepare_exception_entry(page_fault_entry);
+    idt.interrupts[OVERFLOW_ISR_NO] = prepare_default_handle(overflow, DEFAULT_IST_INDEX);
+    idt.interrupts[GPF_ISR_NO] = prepare_exception_entry(gpf_entry);
+
+    // page faults run on the current stack, so overflowing the kernel stack
+    // ends up here, the dedicated stack keeps that from becoming a triple fault.
+    idt.interrupts[DOUBLE_FAULT_ISR_NO].set_stack_index(DOUBLE_FAULT_IST_INDEX);
 
     log::info!("Prepared basic exceptions.");
     return idt;
@@ -0,0 +1,215 @@
+extern crate log;
+
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::cpu::cpuid::{self, FlagsECX, FlagsEDX};
+use crate::cpu::mmu::{read_cr0, write_cr0};
+
+// x87, SSE and AVX registers of the threads. the kernel is built without sse
+// (see x86_64.json), so these registers only ever hold the state of the user
+// thread that was interrupted. they are switched eagerly, saved with the
+// general purpose registers and restored before the next thread runs.
+
+const CR0_MONITOR_COPROCESSOR: u64 = 1 << 1;
+const CR0_EMULATION: u64 = 1 << 2;
+const CR0_TASK_SWITCHED: u64 = 1 << 3;
+
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const XSAVE_CPUID_LEAF: u32 = 0xD;
+
+/// the legacy area (512 bytes), the XSAVE header and the AVX upper halves fit in this.
+pub const FPU_AREA_SIZE: usize = 1024;
+
+/// control words after `fninit`, the offsets are the same in the FXSAVE and XSAVE layout.
+const DEFAULT_FCW: u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+
+/// false if the CPU has no FXSAVE, then there is no state to switch.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+/// components saved by XSAVE, also written to XCR0.
+static XSAVE_MASK: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+#[repr(C, align(64))]
+/// save area of one thread, XSAVE needs it 64 byte aligned.
+pub struct FPUState {
+    area: [u8; FPU_AREA_SIZE],
+}
+
+impl FPUState {
+    /// the state of a thread that never used the FPU, same as after `fninit`.
+    pub fn new() -> Self {
+        let mut state = FPUState {
+            area: [0; FPU_AREA_SIZE],
+        };
+
+        // the XSAVE header (all zero) marks every component as in it's init state,
+        // only MXCSR is always taken from the legacy area.
+        state.area[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        state.area[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        state
+    }
+
+    /// saves the registers of the running thread into this area.
+    #[inline]
+    pub fn save(&mut self) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                let mask = XSAVE_MASK.load(Ordering::Relaxed);
+                asm!(
+                    "xsave64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// loads this area into the registers, called right before the thread runs.
+    #[inline]
+    pub fn restore(&self) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let area = self.area.as_ptr();
+        unsafe {
+            if USE_XSAVE.load(Ordering::Relaxed) {
+                let mask = XSAVE_MASK.load(Ordering::Relaxed);
+                asm!(
+                    "xrstor64 [{}]",
+                    in(reg) area,
+                    in("eax") mask as u32,
+                    in("edx") (mask >> 32) as u32,
+                    options(nostack, preserves_flags)
+                );
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FPUState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FPUState {{ .. }}")
+    }
+}
+
+#[inline]
+fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+#[inline]
+fn write_cr4(value: u64) {
+    unsafe {
+        asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+
+#[inline]
+fn write_xcr0(value: u64) {
+    unsafe {
+        asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+}
+
+/// size of the XSAVE area for the components enabled in XCR0.
+fn xsave_area_size() -> usize {
+    let mut ebx_scratch: u64;
+    unsafe {
+        asm!(
+            "xchg {0:r}, rbx",
+            "cpuid",
+            "xchg {0:r}, rbx",
+            out(reg) ebx_scratch,
+            inout("eax") XSAVE_CPUID_LEAF => _,
+            inout("ecx") 0 => _,
+            out("edx") _,
+            options(nostack, nomem, preserves_flags)
+        );
+    }
+    (ebx_scratch & 0xFFFF_FFFF) as usize
+}
+
+/// turns on SSE for the user threads and picks XSAVE or FXSAVE for the switches.
+pub fn init() {
+    if !cpuid::has_extended_feature(FlagsEDX::FXSR) || !cpuid::has_extended_feature(FlagsEDX::SSE) {
+        log::warn!("CPU has no FXSAVE or SSE, the FPU state is not saved across switches.");
+        return;
+    }
+
+    // no emulation and no #NM on the first use, the state is switched eagerly.
+    let cr0 = read_cr0();
+    write_cr0((cr0 & !(CR0_EMULATION | CR0_TASK_SWITCHED)) | CR0_MONITOR_COPROCESSOR);
+    write_cr4(read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT);
+
+    if cpuid::has_feature(FlagsECX::XSAVE) {
+        let mut mask = XCR0_X87 | XCR0_SSE;
+        if cpuid::has_feature(FlagsECX::AVX) {
+            mask |= XCR0_AVX;
+        }
+
+        write_cr4(read_cr4() | CR4_OSXSAVE);
+        write_xcr0(mask);
+
+        let area_size = xsave_area_size();
+        if area_size <= FPU_AREA_SIZE {
+            XSAVE_MASK.store(mask, Ordering::SeqCst);
+            USE_XSAVE.store(true, Ordering::SeqCst);
+        } else {
+            log::warn!(
+                "XSAVE area of {} bytes does not fit in {}, using FXSAVE.",
+                area_size,
+                FPU_AREA_SIZE
+            );
+        }
+    }
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+    }
+
+    ENABLED.store(true, Ordering::SeqCst);
+    log::info!(
+        "FPU state is switched with {}, xcr0=0x{:x}",
+        if USE_XSAVE.load(Ordering::SeqCst) {
+            "XSAVE"
+        } else {
+            "FXSAVE"
+        },
+        XSAVE_MASK.load(Ordering::SeqCst)
+    );
+}
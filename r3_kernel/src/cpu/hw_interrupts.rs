@@ -4,6 +4,7 @@ use core::arch::asm;
 
 use crate::acpi::lapic::LAPICUtils;
 use crate::cpu::exceptions;
+use crate::cpu::interrupt_stacks;
 use crate::cpu::interrupts;
 use crate::cpu::pic;
 use crate::cpu::pit;
@@ -40,6 +41,9 @@ const KEYBOARD_INTERRUPT_LINE: usize = 0x01;
 static NETWORK_INTERRUPT_NO: AtomicUsize = AtomicUsize::new(0);
 
 use exceptions::IDT;
+use interrupt_stacks::{
+    DEFAULT_IST_INDEX, KEYBOARD_IST_INDEX, LAPIC_TIMER_IST_INDEX, NETWORK_IST_INDEX,
+};
 use interrupts::{prepare_default_handle, prepare_naked_handler, InterruptStackFrame};
 use pic::CHAINED_PIC;
 use pit::pit_callback;
@@ -49,7 +53,7 @@ macro_rules! prepare_no_irq_handler {
         extern "x86-interrupt" fn wrapper(_: InterruptStackFrame) {
             ($name)($ist);
         }
-        let handler = prepare_default_handle(wrapper, 0);
+        let handler = prepare_default_handle(wrapper, DEFAULT_IST_INDEX);
         handler
     }};
 }
@@ -69,7 +73,6 @@ extern "x86-interrupt" fn pit_irq0_handler_legacy(_stk: InterruptStackFrame) {
     pit_callback();
     // 0th line is PIT
     // ack_hw_interrupt((LEGACY_HARDWARE_INTERRUPTS_BASE + PIT_INTERRUPT_LINE) as u8);
-    log::debug!("PIT interrupt");
     ack_hw_interrupt((LEGACY_HARDWARE_INTERRUPTS_BASE + PIT_INTERRUPT_LINE) as u8);
 }
 
@@ -92,7 +95,7 @@ extern "x86-interrupt" fn net_interrupt_wrapper(_stk: InterruptStackFrame) {
 }
 
 fn no_irq_fn(irq_no: usize) {
-    log::debug!("dev interrupt {:x}", irq_no);
+    log::trace!("dev interrupt {:x}", irq_no);
     LAPICUtils::eoi();
 }
 
@@ -123,7 +126,7 @@ extern "C" fn tsc_deadline_interrupt(_stk: &mut InterruptStackFrame) {
 
 pub fn setup_hw_interrupts() {
     // PIT legacy timer
-    let irq0x00_handle = prepare_default_handle(pit_irq0_handler_legacy, 0);
+    let irq0x00_handle = prepare_default_handle(pit_irq0_handler_legacy, DEFAULT_IST_INDEX);
     IDT.lock().interrupts[LEGACY_HARDWARE_INTERRUPTS_BASE + PIT_INTERRUPT_LINE] = irq0x00_handle;
 
     // fill remaining interrupts with legacy handler
@@ -575,24 +578,24 @@ pub fn setup_hw_interrupts() {
         prepare_no_irq_handler!(no_irq_fn, 0xff);
 
     // ATA 14 primary
-    let irq0x0e_handle = prepare_default_handle(ata_irq14_handler, 0);
+    let irq0x0e_handle = prepare_default_handle(ata_irq14_handler, DEFAULT_IST_INDEX);
     IDT.lock().interrupts[HARDWARE_INTERRUPTS_BASE + ATA_PRIMARY_INTERRIUPT_LINE] = irq0x0e_handle;
 
     // ATA 15 secondary
-    let irq0x0f_handle = prepare_default_handle(ata_irq15_handler, 0);
+    let irq0x0f_handle = prepare_default_handle(ata_irq15_handler, DEFAULT_IST_INDEX);
     IDT.lock().interrupts[HARDWARE_INTERRUPTS_BASE + ATA_SECONDARY_INTERRUPT_LINE] = irq0x0f_handle;
 }
 
 pub fn setup_post_apic_interrupts() {
-    let irq0x50_handle = prepare_naked_handler(tsc_deadline_interrupt, 3);
+    let irq0x50_handle = prepare_naked_handler(tsc_deadline_interrupt, LAPIC_TIMER_IST_INDEX);
     IDT.lock().interrupts[HARDWARE_INTERRUPTS_BASE + TIMESHOT_INTERRUPT_LINE] = irq0x50_handle;
 
-    let irq0x01_handle = prepare_default_handle(kbd_irq1_handler, 2);
+    let irq0x01_handle = prepare_default_handle(kbd_irq1_handler, KEYBOARD_IST_INDEX);
     IDT.lock().interrupts[HARDWARE_INTERRUPTS_BASE + KEYBOARD_INTERRUPT_LINE] = irq0x01_handle;
 }
 
 pub fn register_network_interrupt(int_no: usize) {
     NETWORK_INTERRUPT_NO.store(int_no, Ordering::Relaxed);
-    let irq_handler = prepare_default_handle(net_interrupt_wrapper, 4);
+    let irq_handler = prepare_default_handle(net_interrupt_wrapper, NETWORK_IST_INDEX);
     IDT.lock().interrupts[HARDWARE_INTERRUPTS_BASE + int_no] = irq_handler;
 }
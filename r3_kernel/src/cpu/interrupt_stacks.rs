@@ -4,6 +4,23 @@ use crate::cpu::segments::KERNEL_TSS;
 
 const STACK_SIZE: usize = 4096 * 16;
 
+// interrupt stack table (IST) assignments, these are the TSS slots,
+// the IDT entries refer to them as index + 1 (0 means no stack switch).
+// every slot has its own stack, so a fault on one of them can't clobber another.
+
+/// exceptions and interrupts without a dedicated stack.
+pub const DEFAULT_IST_INDEX: u16 = 0;
+/// int 0x80 system calls, replaced by the per-thread syscall stack on switch.
+pub const SYSCALL_IST_INDEX: u16 = 1;
+/// ps/2 keyboard interrupt.
+pub const KEYBOARD_IST_INDEX: u16 = 2;
+/// LAPIC timer interrupt.
+pub const LAPIC_TIMER_IST_INDEX: u16 = 3;
+/// network device interrupt.
+pub const NETWORK_IST_INDEX: u16 = 4;
+/// double fault, must always be valid so a kernel stack overflow doesn't triple fault.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 5;
+
 /// The default interrupt stack used by general interrupts.
 static mut DEFAULT_INTERRUPT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
@@ -21,30 +38,63 @@ static mut PRIVILEGE_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
 static mut SYSTEM_NETWORK_INTERRUPT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
+/// double fault handler uses this stack, nothing else runs on it.
+static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+/// the stacks grow down, so the TSS must point to the end of the region.
+#[inline]
+fn stack_end(stack: &[u8; STACK_SIZE]) -> u64 {
+    let end = (stack as *const _) as u64 + STACK_SIZE as u64;
+    end & !0xF
+}
+
 pub fn init_system_stacks(tss: &mut TaskStateSegment) {
-    // default interrupt handler is 0th entry
     unsafe {
-        tss.set_interrupt_stack(0, (&DEFAULT_INTERRUPT_STACK as *const _) as u64);
-        // set the privilege stack
-        tss.set_privilege_stack(0, (&PRIVILEGE_STACK as *const _) as u64);
+        tss.set_interrupt_stack(
+            DEFAULT_IST_INDEX as usize,
+            stack_end(&DEFAULT_INTERRUPT_STACK),
+        );
+        // set the privilege stack, kernel threads keep it, user threads replace it on switch.
+        tss.set_privilege_stack(0, stack_end(&PRIVILEGE_STACK));
 
         // set the default system call stack
-        tss.set_syscall_stack((&DEFAULT_SYSCALL_STACK as *const _) as u64);
-
-        // set the ps/2 keyboard stack
-        tss.set_interrupt_stack(2, (&SYSTEM_KEYBOARD_INTERRUPT_STACK as *const _) as u64);
-
-        // set the LAPIC timer stack
-        tss.set_interrupt_stack(3, (&LAPIC_TIMER_INTERRPUT_STACK as *const _) as u64);
+        tss.set_syscall_stack(stack_end(&DEFAULT_SYSCALL_STACK));
 
-        // set Network stack
-        tss.set_interrupt_stack(4, (&SYSTEM_NETWORK_INTERRUPT_STACK as *const _) as u64);
+        tss.set_interrupt_stack(
+            KEYBOARD_IST_INDEX as usize,
+            stack_end(&SYSTEM_KEYBOARD_INTERRUPT_STACK),
+        );
+        tss.set_interrupt_stack(
+            LAPIC_TIMER_IST_INDEX as usize,
+            stack_end(&LAPIC_TIMER_INTERRPUT_STACK),
+        );
+        tss.set_interrupt_stack(
+            NETWORK_IST_INDEX as usize,
+            stack_end(&SYSTEM_NETWORK_INTERRUPT_STACK),
+        );
+        tss.set_interrupt_stack(
+            DOUBLE_FAULT_IST_INDEX as usize,
+            stack_end(&DOUBLE_FAULT_STACK),
+        );
     }
 }
 
 pub fn load_default_syscall_stack() {
     unsafe {
         let mut tss = KERNEL_TSS.lock();
-        tss.set_syscall_stack((&DEFAULT_SYSCALL_STACK as *const _) as u64);
+        tss.set_syscall_stack(stack_end(&DEFAULT_SYSCALL_STACK));
+    }
+}
+
+/// RSP0, the stack the CPU switches to on an interrupt or fault from ring 3
+/// that has no IST stack, set to the stack of the thread that runs next.
+pub fn load_privilege_stack(stack_end_addr: u64) {
+    KERNEL_TSS.lock().set_privilege_stack(0, stack_end_addr);
+}
+
+pub fn load_default_privilege_stack() {
+    unsafe {
+        let mut tss = KERNEL_TSS.lock();
+        tss.set_privilege_stack(0, stack_end(&PRIVILEGE_STACK));
     }
 }
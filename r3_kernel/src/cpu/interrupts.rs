@@ -2,7 +2,6 @@ extern crate bit_field;
 
 use core::arch::asm;
 
-use crate::cpu::mmu::PageFaultExceptionTypes;
 use crate::cpu::segments;
 
 use bit_field::BitField;
@@ -126,8 +125,8 @@ pub type DefaultHandlerFuncNoReturn = extern "x86-interrupt" fn(InterruptStackFr
 // A handler function that handles unrecoverable errors with error code:
 pub type HandlerFuncNoReturnWithErr = extern "x86-interrupt" fn(InterruptStackFrame, u64) -> !;
 
-pub type PageFaultHandlerType =
-    extern "x86-interrupt" fn(InterruptStackFrame, PageFaultExceptionTypes) -> !;
+// A naked entry stub, it saves the registers and calls the handler itself:
+pub type ExceptionEntryType = extern "C" fn();
 
 pub type NakedHandlerType = extern "C" fn(&mut InterruptStackFrame);
 
@@ -193,8 +192,8 @@ pub fn prepare_no_ret_error_code_handle(
     return idt_entry;
 }
 
-pub fn prepare_page_fault_handler(
-    func: PageFaultHandlerType,
+pub fn prepare_exception_entry(
+    func: ExceptionEntryType,
 ) -> InterruptDescriptorEntry {
     let handle_addr = func as u64;
     let mut idt_entry = InterruptDescriptorEntry::empty();
@@ -7,6 +7,13 @@ use bitflags::bitflags;
 
 const CR3_PHY_ADDR_MASK: u64 = 0x000ffffffffff000;
 
+/// with this bit set the kernel can't write to read-only pages either.
+const CR0_WRITE_PROTECT: u64 = 1 << 16;
+
+const IA32_EFER: u32 = 0xC000_0080;
+/// without this bit the NO_EXECUTE bit of the page entries is reserved, setting it faults.
+const EFER_NO_EXECUTE_ENABLE: u64 = 1 << 11;
+
 bitflags! {
     #[repr(transparent)]
     pub struct PageFaultExceptionTypes: u64 {
@@ -77,3 +84,76 @@ pub fn reload_flush() {
     let cr3_val = read_cr3();
     write_cr3(cr3_val);
 }
+
+#[inline]
+pub fn read_cr0() -> u64 {
+    let cr0_val: u64;
+    unsafe {
+        asm!(
+            "mov {}, cr0",
+            out(reg) cr0_val,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+
+    cr0_val
+}
+
+#[inline]
+pub fn write_cr0(value: u64) {
+    unsafe {
+        asm!(
+            "mov cr0, {}",
+            in(reg) value,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+pub fn is_write_protect_enabled() -> bool {
+    read_cr0() & CR0_WRITE_PROTECT != 0
+}
+
+/// makes the kernel writes to read-only pages fault like the user ones do,
+/// otherwise they go through and copy on write could never trigger in ring 0.
+pub fn enable_write_protect() {
+    write_cr0(read_cr0() | CR0_WRITE_PROTECT);
+}
+
+#[inline]
+pub fn read_efer() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!(
+            "rdmsr",
+            in("ecx") IA32_EFER,
+            out("edx") high,
+            out("eax") low,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+
+    (high as u64) << 32 | low as u64
+}
+
+#[inline]
+pub fn write_efer(value: u64) {
+    unsafe {
+        asm!(
+            "wrmsr",
+            in("ecx") IA32_EFER,
+            in("edx") (value >> 32) as u32,
+            in("eax") value as u32,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+
+pub fn is_no_execute_enabled() -> bool {
+    read_efer() & EFER_NO_EXECUTE_ENABLE != 0
+}
+
+/// lets the pages be mapped without execute permission, the CPU must support it.
+pub fn enable_no_execute() {
+    write_efer(read_efer() | EFER_NO_EXECUTE_ENABLE);
+}
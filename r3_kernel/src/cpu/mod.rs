@@ -2,8 +2,10 @@ extern crate log;
 
 use core::arch::asm;
 
+pub mod backtrace;
 pub mod cpuid;
 pub mod exceptions;
+pub mod fpu;
 pub mod hw_interrupts;
 pub mod interrupt_stacks;
 pub mod interrupts;
@@ -33,6 +35,25 @@ pub fn are_enabled() -> bool {
     rflags::RFlags::is_set(rflags::RFlagsStruct::INTERRUPT_FLAG)
 }
 
+/// runs the closure with interrupts disabled, restores the previous state after.
+pub fn without_interrupts<F, R>(func: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let were_enabled = are_enabled();
+    if were_enabled {
+        disable_interrupts();
+    }
+
+    let result = func();
+
+    if were_enabled {
+        enable_interrupts();
+    }
+
+    result
+}
+
 pub fn create_breakpoint() {
     unsafe {
         asm!("int3", options(nomem, nostack));
@@ -71,14 +92,32 @@ pub fn init_base_processor_tables() {
 pub fn init_features_detection() {
     // this will call the lazy static to initialize
     cpuid::display_features();
-    cpuid::assert_min_levels();
+    cpuid::check_requirements();
+    fpu::init();
 }
 
+#[cfg(feature = "debug_checks")]
 pub fn run_test_breakpoint_recovery() {
     create_breakpoint();
     log::info!("Recovered from breakpoint, interrupts properly working.");
 }
 
+/// overflows the kernel stack on purpose, the double fault handler must
+/// report it and halt instead of the machine rebooting with a triple fault.
+#[cfg(feature = "double_fault_test")]
+pub fn run_test_stack_overflow() {
+    #[allow(unconditional_recursion)]
+    fn recurse(depth: u64) -> u64 {
+        let mut frame: [u64; 64] = [depth; 64];
+        // volatile, so the frame is not optimized away.
+        unsafe { core::ptr::write_volatile(&mut frame[0], depth) };
+        recurse(depth + 1) + unsafe { core::ptr::read_volatile(&frame[0]) }
+    }
+
+    log::info!("Overflowing the kernel stack, expecting a double fault.");
+    recurse(0);
+}
+
 pub fn init_core_legacy_hardware() {
     pic::setup_pics();
     hw_interrupts::setup_hw_interrupts();
@@ -8,7 +8,7 @@ use core::mem;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-use crate::cpu::interrupt_stacks::init_system_stacks;
+use crate::cpu::interrupt_stacks::{init_system_stacks, SYSCALL_IST_INDEX};
 
 #[derive(Debug, Clone, PartialEq, Copy)]
 #[repr(u8)]
@@ -159,13 +159,16 @@ impl SegmentRegister {
         return value;
     }
 
+    #[cfg(feature = "debug_checks")]
     pub fn assert_reg(&self, value: u16) {
         let read_value = self.get();
         assert_eq!(read_value, value);
     }
 }
 
-const MAX_GDT_ENTRIES: usize = 8;
+/// size of every GDT, the null entry, 4 code/data segments and one
+/// 2-entry TSS descriptor take 7 of them.
+pub const MAX_GDT_ENTRIES: usize = 8;
 const RING_3_DPL_FLAG: u64 = 3 << 45;
 const SEGMENT_PRESENT: u64 = 1 << 47;
 
@@ -207,8 +210,7 @@ impl TaskStateSegment {
     }
 
     pub fn set_syscall_stack(&mut self, stack_end_addr: u64) {
-        // syscalls use stack index 1
-        self.interrupt_stack_table[1] = stack_end_addr;
+        self.interrupt_stack_table[SYSCALL_IST_INDEX as usize] = stack_end_addr;
     }
 }
 
@@ -264,7 +266,7 @@ pub struct GlobalDescritorTable {
 impl GlobalDescritorTable {
     pub fn empty() -> GlobalDescritorTable {
         GlobalDescritorTable {
-            entries: [0; 8],
+            entries: [0; MAX_GDT_ENTRIES],
             filled: 1,
         }
     }
@@ -308,8 +310,14 @@ impl GlobalDescritorTable {
         PrivilegeLevel::Ring0
     }
 
+    /// number of entries that can still be added.
+    #[inline]
+    pub fn free_entries(&self) -> usize {
+        MAX_GDT_ENTRIES - self.filled
+    }
+
     pub fn set_user_segment(&mut self, entry: u64) -> Result<SegmentSelector, &'static str> {
-        if self.filled >= MAX_GDT_ENTRIES {
+        if self.free_entries() < 1 {
             return Err("GDT is already full, can't add new entry.");
         }
 
@@ -327,8 +335,9 @@ impl GlobalDescritorTable {
         high: u64,
         low: u64,
     ) -> Result<SegmentSelector, &'static str> {
-        if self.filled >= MAX_GDT_ENTRIES {
-            return Err("GDT is already full, can't add new entry.");
+        // both halves must fit, otherwise the high half would be written past the table.
+        if self.free_entries() < 2 {
+            return Err("GDT does not have space for a system segment.");
         }
 
         // add a low and high entries:
@@ -371,47 +380,91 @@ lazy_static! {
     pub static ref KERNEL_TSS: Mutex<TaskStateSegment> = Mutex::new(create_tss_for_bp());
 }
 
-// create GDT for the base processor:
-pub fn create_gdt_for_bp() -> GDTContainer {
+/// builds a GDT with the kernel and user segments and the descriptor of the given TSS.
+/// every processor needs one of these, since the TSS holds the per-CPU stacks.
+pub fn create_gdt_for_cpu(
+    tss: &'static Mutex<TaskStateSegment>,
+) -> Result<GDTContainer, &'static str> {
     // create a GDT with empty segment
     let mut gdt = GlobalDescritorTable::empty();
-    let k_code_segment_res = gdt.set_user_segment(LinuxKernelSegments::KernelCode as u64);
-    if k_code_segment_res.is_err() {
-        panic!("{}", k_code_segment_res.unwrap_err());
+    let kernel_code_selector = gdt.set_user_segment(LinuxKernelSegments::KernelCode as u64);
+    if kernel_code_selector.is_err() {
+        return Err(kernel_code_selector.unwrap_err());
     }
 
-    let tss_descriptor = TaskStateDescriptor::new(&KERNEL_TSS);
-
-    let k_tss_segment_result = gdt.set_system_segment(tss_descriptor.high, tss_descriptor.low);
-    if k_tss_segment_result.is_err() {
-        panic!("{}", k_tss_segment_result.unwrap_err());
+    let tss_descriptor = TaskStateDescriptor::new(tss);
+    let kernel_tss_selector = gdt.set_system_segment(tss_descriptor.high, tss_descriptor.low);
+    if kernel_tss_selector.is_err() {
+        return Err(kernel_tss_selector.unwrap_err());
     }
 
     // set user mode selector:
-    let user_code_segment_res = gdt.set_user_segment(LinuxKernelSegments::UserCode as u64);
-    if user_code_segment_res.is_err() {
-        panic!("Failed to set user code segment.");
+    let user_code_selector = gdt.set_user_segment(LinuxKernelSegments::UserCode as u64);
+    if user_code_selector.is_err() {
+        return Err(user_code_selector.unwrap_err());
     }
 
     // set kernel and user data:
-    let kernel_data_selector = gdt
-        .set_user_segment(LinuxKernelSegments::KernelData as u64)
-        .unwrap();
+    let kernel_data_selector = gdt.set_user_segment(LinuxKernelSegments::KernelData as u64);
+    if kernel_data_selector.is_err() {
+        return Err(kernel_data_selector.unwrap_err());
+    }
 
-    let user_data_selector = gdt
-        .set_user_segment(LinuxKernelSegments::UserData as u64)
-        .unwrap();
+    let user_data_selector = gdt.set_user_segment(LinuxKernelSegments::UserData as u64);
+    if user_data_selector.is_err() {
+        return Err(user_data_selector.unwrap_err());
+    }
 
-    GDTContainer {
+    Ok(GDTContainer {
         gdt_table: gdt,
-        kernel_code_selector: k_code_segment_res.unwrap(),
-        kernel_tss_selector: k_tss_segment_result.unwrap(),
-        user_code_selector: user_code_segment_res.unwrap(),
-        kernel_data_selector,
-        user_data_selector,
+        kernel_code_selector: kernel_code_selector.unwrap(),
+        kernel_tss_selector: kernel_tss_selector.unwrap(),
+        user_code_selector: user_code_selector.unwrap(),
+        kernel_data_selector: kernel_data_selector.unwrap(),
+        user_data_selector: user_data_selector.unwrap(),
+    })
+}
+
+// create GDT for the base processor:
+pub fn create_gdt_for_bp() -> GDTContainer {
+    match create_gdt_for_cpu(&KERNEL_TSS) {
+        Ok(gdt) => gdt,
+        Err(err) => panic!("Failed to create the GDT: {}", err),
     }
 }
 
+/// fills a scratch GDT and checks that the inserts past the end fail
+/// and leave the existing entries alone.
+#[cfg(feature = "debug_checks")]
+pub fn run_gdt_bounds_test() {
+    let mut gdt = GlobalDescritorTable::empty();
+    while gdt.free_entries() > 0 {
+        assert!(gdt
+            .set_user_segment(LinuxKernelSegments::KernelData as u64)
+            .is_ok());
+    }
+
+    let entries = gdt.entries;
+    assert!(gdt
+        .set_user_segment(LinuxKernelSegments::UserData as u64)
+        .is_err());
+    assert!(gdt.set_system_segment(u64::MAX, u64::MAX).is_err());
+    assert_eq!(gdt.filled, MAX_GDT_ENTRIES);
+    assert_eq!(gdt.entries, entries);
+
+    // one slot left is not enough for a system segment.
+    let mut gdt = GlobalDescritorTable::empty();
+    while gdt.free_entries() > 1 {
+        gdt.set_user_segment(LinuxKernelSegments::KernelData as u64)
+            .unwrap();
+    }
+
+    assert!(gdt.set_system_segment(u64::MAX, u64::MAX).is_err());
+    assert_eq!(gdt.entries[MAX_GDT_ENTRIES - 1], 0);
+
+    log::info!("Passed GDT bounds test.");
+}
+
 lazy_static! {
     static ref KERNEL_BASE_GDT: GDTContainer = create_gdt_for_bp();
 }
@@ -434,8 +487,11 @@ pub fn init_gdt() {
     log::info!("Kernel code selector: {}", kernel_cs.0);
 
     // assert the register value:
-    SegmentRegister::CS.assert_reg(kernel_cs.0);
-    log::debug!("Verified Code Segment Register value: 0x{:x}", kernel_cs.0);
+    #[cfg(feature = "debug_checks")]
+    {
+        SegmentRegister::CS.assert_reg(kernel_cs.0);
+        log::debug!("Verified Code Segment Register value: 0x{:x}", kernel_cs.0);
+    }
 
     // set kernel data selector:
     let kernel_ds = &KERNEL_BASE_GDT.kernel_data_selector;
@@ -446,6 +502,9 @@ pub fn init_gdt() {
     let tss_sel = &KERNEL_BASE_GDT.kernel_tss_selector;
     load_tss(tss_sel.0);
     log::info!("Initialized TSS.");
+
+    #[cfg(feature = "debug_checks")]
+    run_gdt_bounds_test();
 }
 
 pub fn get_kernel_cs() -> &'static SegmentSelector {
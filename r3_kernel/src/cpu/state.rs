@@ -3,9 +3,7 @@ extern crate log;
 use core::arch::asm;
 // Provide state management functions
 
-// TODO: Add SSE and AVX registers
-
-// TODO: Move to hadware based schemes like XSAVE etc
+// the x87, SSE and AVX registers are saved separately, see cpu/fpu.rs
 
 // TODO: is there a neat way?
 
@@ -1,7 +1,7 @@
 use core::arch::asm;
 
 use crate::cpu::exceptions::IDT;
-use crate::cpu::interrupt_stacks::load_default_syscall_stack;
+use crate::cpu::interrupt_stacks::{load_default_syscall_stack, SYSCALL_IST_INDEX};
 use crate::cpu::interrupts::{prepare_syscall_interrupt, InterruptStackFrame};
 use crate::cpu::segments::KERNEL_TSS;
 
@@ -60,7 +60,7 @@ pub extern "sysv64" fn x80_handle(_stk: &mut InterruptStackFrame) {
 }
 
 pub fn setup_syscall_interrupt() {
-    let irq0x80_handle = prepare_syscall_interrupt(x80_handle, 1);
+    let irq0x80_handle = prepare_syscall_interrupt(x80_handle, SYSCALL_IST_INDEX);
     IDT.lock().interrupts[0x80] = irq0x80_handle;
 }
 
@@ -2,6 +2,7 @@ use core::arch::asm;
 
 use core::sync::atomic::{AtomicU64, Ordering};
 
+use crate::cpu::cpuid::{self, FlagsECX};
 use crate::cpu::pit;
 
 /// Represents the TSC ticks
@@ -147,9 +148,13 @@ impl TSCTimerShot {
     }
 
     pub fn wait_for_shot_at(ticks: TSCTicks) {
-        // reset any shot if pending
-        Self::reset_current_shot();
-        Self::set_shot_at(ticks.clone());
+        // without TSC deadline the MSR faults, the periodic timer
+        // interrupts wake the loop below instead.
+        if cpuid::has_feature(FlagsECX::TSCD) {
+            // reset any shot if pending
+            Self::reset_current_shot();
+            Self::set_shot_at(ticks.clone());
+        }
 
         unsafe {
             while TSC::read_tsc().0 < ticks.0 {
@@ -0,0 +1,88 @@
+extern crate alloc;
+
+use crate::acpi::madt;
+use crate::system::filesystem::devfs::{DevFSDescriptor, DevOps};
+use crate::system::filesystem::{FSError, SeekType};
+
+use alloc::string::String;
+use core::fmt::Write;
+
+/// builds the text served by /dev/cpuinfo, one block per processor found in the MADT.
+fn cpuinfo_text() -> String {
+    let mut text = String::new();
+
+    {
+        let proc_lock = madt::PROCESSORS.lock();
+        for proc in &proc_lock.cores {
+            let state = if proc.is_enabled() {
+                "enabled"
+            } else if proc.is_online_capable() {
+                "online capable"
+            } else {
+                "disabled"
+            };
+
+            let _ = write!(
+                text,
+                "processor\t: {}\napic id\t\t: {}\nstate\t\t: {}\n\n",
+                proc.id, proc.apic_id, state
+            );
+        }
+    }
+
+    let _ = write!(
+        text,
+        "enabled\t\t: {}\nonline capable\t: {}\n",
+        madt::cpu_count(),
+        madt::online_capable_count()
+    );
+
+    text
+}
+
+pub struct CpuInfoDriver;
+
+impl CpuInfoDriver {
+    pub fn empty() -> Self {
+        CpuInfoDriver {}
+    }
+}
+
+impl DevOps for CpuInfoDriver {
+    fn read(&self, fd: &mut DevFSDescriptor, buffer: &mut [u8]) -> Result<usize, FSError> {
+        let text = cpuinfo_text();
+        let bytes = text.as_bytes();
+
+        let start = fd.offset as usize;
+        if start >= bytes.len() {
+            return Ok(0);
+        }
+
+        let copied = core::cmp::min(buffer.len(), bytes.len() - start);
+        buffer[0..copied].copy_from_slice(&bytes[start..start + copied]);
+        fd.offset += copied as u32;
+        Ok(copied)
+    }
+
+    fn write(&self, _fd: &mut DevFSDescriptor, _buffer: &[u8]) -> Result<usize, FSError> {
+        Err(FSError::ReadOnly)
+    }
+
+    fn ioctl(&self, _command: usize, _arg: usize) -> Result<usize, FSError> {
+        Ok(0)
+    }
+
+    fn seek(&self, fd: &mut DevFSDescriptor, offset: u32, st: SeekType) -> Result<u32, FSError> {
+        match st {
+            SeekType::SEEK_SET => fd.offset = offset,
+            SeekType::SEEK_CUR => fd.offset = fd.offset.saturating_add(offset),
+            SeekType::SEEK_END => fd.offset = (cpuinfo_text().len() as u32).saturating_add(offset),
+        }
+
+        Ok(fd.offset)
+    }
+}
+
+// TODO: Find a best way to mitigate this
+unsafe impl Sync for CpuInfoDriver {}
+unsafe impl Send for CpuInfoDriver {}
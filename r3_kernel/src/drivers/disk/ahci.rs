@@ -0,0 +1,618 @@
+extern crate alloc;
+extern crate bit_field;
+extern crate log;
+extern crate spin;
+
+use crate::cpu;
+use crate::drivers::pci::PCIDevice;
+use crate::mm;
+use crate::mm::io::{self, MemoryIO};
+use crate::mm::phy::{DMABuffer, DMAError, DMAPool};
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use bit_field::BitField;
+use core::ops::Range;
+use core::ptr;
+use spin::Mutex;
+
+use lazy_static::lazy_static;
+
+// a polling AHCI driver for the SATA drives of every controller. each port
+// uses one command slot and a bounce buffer, so there is a single command in
+// flight per drive at any time and the interrupts stay masked.
+
+pub const AHCI_BLOCK_SIZE: usize = 512;
+
+/// largest transfer of a single command, the size of the bounce buffer.
+const AHCI_MAX_SECTORS: usize = 16;
+pub const AHCI_BUFFER_SIZE: usize = AHCI_MAX_SECTORS * AHCI_BLOCK_SIZE;
+
+/// size of the ABAR with all the 32 ports implemented.
+const AHCI_ABAR_SIZE: u64 = 0x1100;
+const AHCI_MAX_PORTS: usize = 32;
+
+// generic host control registers
+const HBA_REG_GHC: u64 = 0x04;
+const HBA_REG_IS: u64 = 0x08;
+const HBA_REG_PI: u64 = 0x0C;
+const HBA_REG_VS: u64 = 0x10;
+
+const HBA_GHC_AHCI_ENABLE: usize = 31;
+const HBA_GHC_INTERRUPT_ENABLE: usize = 1;
+
+// port registers, relative to the start of the port
+const HBA_PORTS_START: u64 = 0x100;
+const HBA_PORT_SIZE: u64 = 0x80;
+
+const PORT_REG_CLB: u64 = 0x00;
+const PORT_REG_CLBU: u64 = 0x04;
+const PORT_REG_FB: u64 = 0x08;
+const PORT_REG_FBU: u64 = 0x0C;
+const PORT_REG_IS: u64 = 0x10;
+const PORT_REG_IE: u64 = 0x14;
+const PORT_REG_CMD: u64 = 0x18;
+const PORT_REG_TFD: u64 = 0x20;
+const PORT_REG_SIG: u64 = 0x24;
+const PORT_REG_SSTS: u64 = 0x28;
+const PORT_REG_SERR: u64 = 0x30;
+const PORT_REG_CI: u64 = 0x38;
+
+const PORT_CMD_START: usize = 0;
+const PORT_CMD_FIS_RECV_ENABLE: usize = 4;
+const PORT_CMD_FIS_RECV_RUNNING: usize = 14;
+const PORT_CMD_LIST_RUNNING: usize = 15;
+
+/// task file error bit of PxIS
+const PORT_IS_TFES: usize = 30;
+
+const ATA_STATUS_ERR: u32 = 1 << 0;
+const ATA_STATUS_DRQ: u32 = 1 << 3;
+const ATA_STATUS_BSY: u32 = 1 << 7;
+
+/// device detected and phy communication established
+const SSTS_DET_PRESENT: u32 = 0x3;
+/// interface in the active state
+const SSTS_IPM_ACTIVE: u32 = 0x1;
+/// signature of a plain SATA drive, ATAPI and port multipliers are skipped.
+const SATA_SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// the FIS carries a command, not a device control update.
+const FIS_H2D_COMMAND: u8 = 1 << 7;
+/// LBA mode bit of the device register
+const FIS_DEVICE_LBA: u8 = 1 << 6;
+
+const COMMAND_HEADER_SIZE: usize = 32;
+const COMMAND_LIST_SIZE: usize = 32 * COMMAND_HEADER_SIZE;
+const FIS_RECV_SIZE: usize = 256;
+/// command FIS, ATAPI command and reserved area before the PRDT.
+const COMMAND_TABLE_HEADER_SIZE: usize = 0x80;
+const PRDT_ENTRY_SIZE: usize = 16;
+const COMMAND_TABLE_SIZE: usize = COMMAND_TABLE_HEADER_SIZE + PRDT_ENTRY_SIZE;
+
+/// length of the H2D register FIS in dwords
+const FIS_H2D_LENGTH: u32 = 5;
+const COMMAND_HEADER_WRITE: usize = 6;
+const PRDT_INTERRUPT: usize = 31;
+
+/// number of register reads before giving up on the port or a command.
+const AHCI_MAX_POLLS: usize = 1000000;
+
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+pub enum AHCICommand {
+    IDENTIFY = 0xEC,
+    READ_DMA_EXT = 0x25,
+    WRITE_DMA_EXT = 0x35,
+}
+
+#[derive(Debug, Clone)]
+pub enum AHCIError {
+    /// the ABAR is not a memory BAR or could not be mapped
+    InvalidABAR(u64),
+    /// no port has a usable SATA drive attached
+    NoDrive,
+    DMAError(DMAError),
+    /// the command engine of the port did not start or stop
+    PortHung(usize),
+    /// the drive did not finish the command in time
+    Timeout,
+    /// the drive reported an error, the task file data is attached
+    TaskFileError(u32),
+    /// the transfer is outside the drive or larger than the bounce buffer
+    InvalidTransfer,
+}
+
+/// registers of one port inside the ABAR.
+#[derive(Debug, Clone)]
+struct AHCIPort {
+    base: u64,
+    index: usize,
+}
+
+impl AHCIPort {
+    #[inline]
+    fn new(abar: mm::VirtualAddress, index: usize) -> Self {
+        AHCIPort {
+            base: abar.as_u64() + HBA_PORTS_START + index as u64 * HBA_PORT_SIZE,
+            index,
+        }
+    }
+
+    #[inline]
+    fn read(&self, register: u64) -> u32 {
+        MemoryIO::new(mm::VirtualAddress::from_u64(self.base + register), false).read_u32()
+    }
+
+    #[inline]
+    fn write(&self, register: u64, value: u32) {
+        MemoryIO::new(mm::VirtualAddress::from_u64(self.base + register), false).write_u32(value);
+    }
+
+    #[inline]
+    fn has_sata_drive(&self) -> bool {
+        let ssts = self.read(PORT_REG_SSTS);
+        ssts.get_bits(0..4) == SSTS_DET_PRESENT
+            && ssts.get_bits(8..12) == SSTS_IPM_ACTIVE
+            && self.read(PORT_REG_SIG) == SATA_SIG_ATA
+    }
+
+    /// waits until the bit of the register is `value`.
+    fn wait_bit(&self, register: u64, bit: usize, value: bool) -> bool {
+        for _ in 0..AHCI_MAX_POLLS {
+            if self.read(register).get_bit(bit) == value {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn stop(&self) -> Result<(), AHCIError> {
+        let mut cmd = self.read(PORT_REG_CMD);
+        cmd.set_bit(PORT_CMD_START, false);
+        self.write(PORT_REG_CMD, cmd);
+        if !self.wait_bit(PORT_REG_CMD, PORT_CMD_LIST_RUNNING, false) {
+            return Err(AHCIError::PortHung(self.index));
+        }
+
+        let mut cmd = self.read(PORT_REG_CMD);
+        cmd.set_bit(PORT_CMD_FIS_RECV_ENABLE, false);
+        self.write(PORT_REG_CMD, cmd);
+        if !self.wait_bit(PORT_REG_CMD, PORT_CMD_FIS_RECV_RUNNING, false) {
+            return Err(AHCIError::PortHung(self.index));
+        }
+
+        Ok(())
+    }
+
+    fn start(&self) -> Result<(), AHCIError> {
+        if !self.wait_bit(PORT_REG_CMD, PORT_CMD_LIST_RUNNING, false) {
+            return Err(AHCIError::PortHung(self.index));
+        }
+
+        let mut cmd = self.read(PORT_REG_CMD);
+        cmd.set_bit(PORT_CMD_FIS_RECV_ENABLE, true);
+        cmd.set_bit(PORT_CMD_START, true);
+        self.write(PORT_REG_CMD, cmd);
+        Ok(())
+    }
+
+    fn wait_idle(&self) -> bool {
+        for _ in 0..AHCI_MAX_POLLS {
+            if self.read(PORT_REG_TFD) & (ATA_STATUS_BSY | ATA_STATUS_DRQ) == 0 {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// DMA memory of the port, only command slot 0 is used.
+struct PortMemory {
+    command_list: DMABuffer,
+    fis_recv: DMABuffer,
+    command_table: DMABuffer,
+    buffer: DMABuffer,
+    /// owns the buffers above
+    _pool: DMAPool,
+}
+
+impl PortMemory {
+    fn new() -> Result<Self, DMAError> {
+        let mut pool = DMAPool::new("ahci");
+
+        // the alignments are the ones required by the spec.
+        let command_list = pool.alloc_aligned(COMMAND_LIST_SIZE, 1024);
+        if command_list.is_err() {
+            return Err(command_list.unwrap_err());
+        }
+
+        let fis_recv = pool.alloc_aligned(FIS_RECV_SIZE, 256);
+        if fis_recv.is_err() {
+            return Err(fis_recv.unwrap_err());
+        }
+
+        let command_table = pool.alloc_aligned(COMMAND_TABLE_SIZE, 128);
+        if command_table.is_err() {
+            return Err(command_table.unwrap_err());
+        }
+
+        let buffer = pool.alloc(AHCI_BUFFER_SIZE);
+        if buffer.is_err() {
+            return Err(buffer.unwrap_err());
+        }
+
+        let memory = PortMemory {
+            command_list: command_list.unwrap(),
+            fis_recv: fis_recv.unwrap(),
+            command_table: command_table.unwrap(),
+            buffer: buffer.unwrap(),
+            _pool: pool,
+        };
+
+        memory.command_list.get_mut_slice::<u8>().fill(0);
+        memory.fis_recv.get_mut_slice::<u8>().fill(0);
+        memory.command_table.get_mut_slice::<u8>().fill(0);
+        Ok(memory)
+    }
+
+    #[inline]
+    fn write_u32(buffer: &DMABuffer, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(buffer.get_mut_ptr::<u8>().add(offset) as *mut u32, value) }
+    }
+
+    /// fills the header of slot 0, the command FIS and the single PRDT entry.
+    fn prepare(&self, command: AHCICommand, lba: u64, n_sectors: u16, bytes: usize, write: bool) {
+        let mut flags: u32 = FIS_H2D_LENGTH;
+        flags.set_bit(COMMAND_HEADER_WRITE, write);
+        // one PRDT entry
+        flags.set_bits(16..32, 1);
+
+        let table_addr = self.command_table.phy_addr.as_u64();
+        Self::write_u32(&self.command_list, 0, flags);
+        // bytes transferred, updated by the HBA
+        Self::write_u32(&self.command_list, 4, 0);
+        Self::write_u32(&self.command_list, 8, table_addr as u32);
+        Self::write_u32(&self.command_list, 12, (table_addr >> 32) as u32);
+
+        let table = self.command_table.get_mut_slice::<u8>();
+        table.fill(0);
+
+        // register host to device FIS
+        table[0] = FIS_TYPE_REG_H2D;
+        table[1] = FIS_H2D_COMMAND;
+        table[2] = command as u8;
+        table[4] = lba.get_bits(0..8) as u8;
+        table[5] = lba.get_bits(8..16) as u8;
+        table[6] = lba.get_bits(16..24) as u8;
+        table[7] = FIS_DEVICE_LBA;
+        table[8] = lba.get_bits(24..32) as u8;
+        table[9] = lba.get_bits(32..40) as u8;
+        table[10] = lba.get_bits(40..48) as u8;
+        table[12] = n_sectors as u8;
+        table[13] = (n_sectors >> 8) as u8;
+
+        let buffer_addr = self.buffer.phy_addr.as_u64();
+        let mut byte_count: u32 = (bytes - 1) as u32;
+        byte_count.set_bit(PRDT_INTERRUPT, true);
+
+        let prdt = COMMAND_TABLE_HEADER_SIZE;
+        Self::write_u32(&self.command_table, prdt, buffer_addr as u32);
+        Self::write_u32(&self.command_table, prdt + 4, (buffer_addr >> 32) as u32);
+        Self::write_u32(&self.command_table, prdt + 12, byte_count);
+    }
+}
+
+pub struct AHCIDrive {
+    pub port_no: usize,
+    pub n_blocks: u64,
+    pub model_name: String,
+    pub serial_no: String,
+    port: AHCIPort,
+    /// held across the whole command, the slot and the bounce buffer are shared.
+    memory: Mutex<PortMemory>,
+}
+
+impl AHCIDrive {
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.n_blocks as usize * AHCI_BLOCK_SIZE
+    }
+
+    #[inline]
+    pub fn dump(&self) {
+        log::info!(
+            "AHCI:port{} model={}, serial={}, size={}bytes",
+            self.port_no,
+            self.model_name,
+            self.serial_no,
+            self.size()
+        )
+    }
+
+    /// issues the command on slot 0 and polls until it completes.
+    fn issue(
+        &self,
+        memory: &PortMemory,
+        command: AHCICommand,
+        lba: u64,
+        n_sectors: usize,
+        write: bool,
+    ) -> Result<(), AHCIError> {
+        let bytes = n_sectors * AHCI_BLOCK_SIZE;
+        if n_sectors == 0 || bytes > AHCI_BUFFER_SIZE {
+            return Err(AHCIError::InvalidTransfer);
+        }
+
+        if !self.port.wait_idle() {
+            return Err(AHCIError::Timeout);
+        }
+
+        memory.prepare(command, lba, n_sectors as u16, bytes, write);
+
+        self.port.write(PORT_REG_IS, u32::MAX);
+        self.port.write(PORT_REG_CI, 1);
+
+        for _ in 0..AHCI_MAX_POLLS {
+            if self.port.read(PORT_REG_IS).get_bit(PORT_IS_TFES) {
+                return Err(AHCIError::TaskFileError(self.port.read(PORT_REG_TFD)));
+            }
+
+            if !self.port.read(PORT_REG_CI).get_bit(0) {
+                let tfd = self.port.read(PORT_REG_TFD);
+                if tfd & ATA_STATUS_ERR != 0 {
+                    return Err(AHCIError::TaskFileError(tfd));
+                }
+                return Ok(());
+            }
+        }
+
+        Err(AHCIError::Timeout)
+    }
+
+    /// reads whole blocks starting at `block_no`, buffer is cut to the blocks it covers.
+    pub fn read_blocks(&self, buffer: &mut [u8], block_no: u64) -> Result<(), AHCIError> {
+        let n_sectors = (buffer.len() + AHCI_BLOCK_SIZE - 1) / AHCI_BLOCK_SIZE;
+        if block_no + n_sectors as u64 > self.n_blocks {
+            return Err(AHCIError::InvalidTransfer);
+        }
+
+        // the lock holder can't be preempted, so the others never spin on it for long.
+        cpu::without_interrupts(|| {
+            let memory = self.memory.lock();
+            let result = self.issue(
+                &memory,
+                AHCICommand::READ_DMA_EXT,
+                block_no,
+                n_sectors,
+                false,
+            );
+            if result.is_err() {
+                return result;
+            }
+
+            buffer.copy_from_slice(&memory.buffer.get_slice::<u8>()[0..buffer.len()]);
+            Ok(())
+        })
+    }
+
+    /// writes whole blocks starting at `block_no`, the tail of a partial block is zeroed.
+    pub fn write_blocks(&self, buffer: &[u8], block_no: u64) -> Result<(), AHCIError> {
+        let n_sectors = (buffer.len() + AHCI_BLOCK_SIZE - 1) / AHCI_BLOCK_SIZE;
+        if block_no + n_sectors as u64 > self.n_blocks {
+            return Err(AHCIError::InvalidTransfer);
+        }
+
+        cpu::without_interrupts(|| {
+            let memory = self.memory.lock();
+            let bounce = memory.buffer.get_mut_slice::<u8>();
+            bounce[0..buffer.len()].copy_from_slice(buffer);
+            bounce[buffer.len()..n_sectors * AHCI_BLOCK_SIZE].fill(0);
+
+            self.issue(
+                &memory,
+                AHCICommand::WRITE_DMA_EXT,
+                block_no,
+                n_sectors,
+                true,
+            )
+        })
+    }
+}
+
+/// strings of the identify data are stored as big endian words.
+fn identify_string(words: &[u16]) -> String {
+    words
+        .iter()
+        .map(|word| word.to_be_bytes().map(|byte| byte as char))
+        .flatten()
+        .collect::<String>()
+        .trim()
+        .into()
+}
+
+fn identify(port: AHCIPort, memory: PortMemory) -> Result<AHCIDrive, AHCIError> {
+    let mut drive = AHCIDrive {
+        port_no: port.index,
+        n_blocks: 1,
+        model_name: String::new(),
+        serial_no: String::new(),
+        port,
+        memory: Mutex::new(memory),
+    };
+
+    let mut data: [u8; AHCI_BLOCK_SIZE] = [0; AHCI_BLOCK_SIZE];
+    {
+        let memory = drive.memory.lock();
+        let result = drive.issue(&memory, AHCICommand::IDENTIFY, 0, 1, false);
+        if result.is_err() {
+            return Err(result.unwrap_err());
+        }
+        data.copy_from_slice(&memory.buffer.get_slice::<u8>()[0..AHCI_BLOCK_SIZE]);
+    }
+
+    let mut words: [u16; 256] = [0; 256];
+    for (index, word) in words.iter_mut().enumerate() {
+        *word = u16::from_le_bytes([data[2 * index], data[2 * index + 1]]);
+    }
+
+    // 48-bit sector count if the drive supports it, the 28-bit one otherwise.
+    let lba48 = (words[103] as u64) << 48
+        | (words[102] as u64) << 32
+        | (words[101] as u64) << 16
+        | (words[100] as u64);
+    let lba28 = (words[61] as u64) << 16 | (words[60] as u64);
+
+    drive.n_blocks = if words[83].get_bit(10) && lba48 != 0 {
+        lba48
+    } else {
+        lba28
+    };
+    drive.serial_no = identify_string(&words[10..20]);
+    drive.model_name = identify_string(&words[27..47]);
+    Ok(drive)
+}
+
+/// maps the ABAR uncached, it is above the RAM and might not be covered by
+/// the physical memory mapping of the bootloader.
+fn map_abar(phy_addr: u64) -> Result<mm::VirtualAddress, AHCIError> {
+    let result = io::map_device_memory(phy_addr, AHCI_ABAR_SIZE);
+    if result.is_err() {
+        log::error!("ahci: failed to map the ABAR: {:?}", result.unwrap_err());
+        return Err(AHCIError::InvalidABAR(phy_addr));
+    }
+
+    Ok(result.unwrap())
+}
+
+fn setup_controller(pci_dev: &PCIDevice) -> Result<Vec<AHCIDrive>, AHCIError> {
+    // BAR5 is the ABAR, it has to be a memory BAR.
+    let bar = pci_dev.bars[5];
+    if bar == 0 || bar & 0x1 == 0x1 {
+        return Err(AHCIError::InvalidABAR(bar as u64));
+    }
+
+    // memory space and bus mastering, the HBA fetches the commands by DMA.
+    let mut command = pci_dev.read_config_u16(0x04);
+    command.set_bit(1, true);
+    command.set_bit(2, true);
+    pci_dev.write_config_u16(0x04, command);
+
+    let abar_res = map_abar((bar & 0xFFFF_FFF0) as u64);
+    if abar_res.is_err() {
+        return Err(abar_res.unwrap_err());
+    }
+    let abar = abar_res.unwrap();
+    let hba = |register: u64| {
+        MemoryIO::new(
+            mm::VirtualAddress::from_u64(abar.as_u64() + register),
+            false,
+        )
+    };
+
+    let mut ghc = hba(HBA_REG_GHC).read_u32();
+    ghc.set_bit(HBA_GHC_AHCI_ENABLE, true);
+    ghc.set_bit(HBA_GHC_INTERRUPT_ENABLE, false);
+    hba(HBA_REG_GHC).write_u32(ghc);
+
+    let version = hba(HBA_REG_VS).read_u32();
+    log::debug!(
+        "ahci: controller version {}.{}, ABAR at 0x{:x}",
+        version >> 16,
+        version & 0xFFFF,
+        bar & 0xFFFF_FFF0
+    );
+
+    let implemented = hba(HBA_REG_PI).read_u32();
+    let mut drives: Vec<AHCIDrive> = Vec::new();
+    for index in 0..AHCI_MAX_PORTS {
+        if !implemented.get_bit(index) {
+            continue;
+        }
+
+        let port = AHCIPort::new(abar, index);
+        if !port.has_sata_drive() {
+            continue;
+        }
+
+        match setup_port(port) {
+            Ok(drive) => drives.push(drive),
+            Err(err) => log::warn!("ahci: port {} not usable: {:?}", index, err),
+        }
+    }
+
+    hba(HBA_REG_IS).write_u32(u32::MAX);
+
+    if drives.is_empty() {
+        return Err(AHCIError::NoDrive);
+    }
+    Ok(drives)
+}
+
+fn setup_port(port: AHCIPort) -> Result<AHCIDrive, AHCIError> {
+    let stop_res = port.stop();
+    if stop_res.is_err() {
+        return Err(stop_res.unwrap_err());
+    }
+
+    let memory = match PortMemory::new() {
+        Ok(memory) => memory,
+        Err(err) => return Err(AHCIError::DMAError(err)),
+    };
+
+    let command_list = memory.command_list.phy_addr.as_u64();
+    let fis_recv = memory.fis_recv.phy_addr.as_u64();
+    port.write(PORT_REG_CLB, command_list as u32);
+    port.write(PORT_REG_CLBU, (command_list >> 32) as u32);
+    port.write(PORT_REG_FB, fis_recv as u32);
+    port.write(PORT_REG_FBU, (fis_recv >> 32) as u32);
+
+    // polled, clear whatever is pending and keep the interrupts masked.
+    port.write(PORT_REG_SERR, u32::MAX);
+    port.write(PORT_REG_IS, u32::MAX);
+    port.write(PORT_REG_IE, 0);
+
+    let start_res = port.start();
+    if start_res.is_err() {
+        return Err(start_res.unwrap_err());
+    }
+
+    identify(port, memory)
+}
+
+lazy_static! {
+    pub static ref AHCI_DRIVES: Mutex<Vec<Arc<AHCIDrive>>> = Mutex::new(Vec::new());
+}
+
+/// sets up the drives of the controller, returns the indices they got in AHCI_DRIVES.
+pub fn init(pci_dev: &PCIDevice) -> Range<usize> {
+    let setup_result = setup_controller(pci_dev);
+    let mut drives_lock = AHCI_DRIVES.lock();
+    let first = drives_lock.len();
+
+    match setup_result {
+        Ok(drives) => {
+            for drive in drives {
+                drive.dump();
+                drives_lock.push(Arc::new(drive));
+            }
+        }
+        Err(err) => {
+            log::warn!(
+                "ahci: controller {:x}:{:x} not usable: {:?}",
+                pci_dev.device_id,
+                pci_dev.vendor_id,
+                err
+            );
+        }
+    }
+
+    first..drives_lock.len()
+}
+
+pub fn get_drive(index: usize) -> Option<Arc<AHCIDrive>> {
+    AHCI_DRIVES.lock().get(index).cloned()
+}
+
+unsafe impl Send for AHCIDrive {}
+unsafe impl Sync for AHCIDrive {}
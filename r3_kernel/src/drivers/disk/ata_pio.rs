@@ -10,7 +10,7 @@ use bit_field::BitField;
 
 use core::iter::Iterator;
 
-use alloc::{string::String, vec::Vec};
+use alloc::{string::String, sync::Arc, vec::Vec};
 use spin::Mutex;
 
 use lazy_static::lazy_static;
@@ -21,6 +21,13 @@ const ATA_SOFT_RESET: u8 = 0x04;
 /// ATA block size - made public because others (ex: fs) may use it.
 pub const ATA_BLOCK_SIZE: usize = 512;
 
+/// primary and secondary channels, see `register_devices`.
+const ATA_MAX_CHANNELS: usize = 2;
+
+/// held across the whole command + data transfer sequence, both the drives
+/// of a channel share the same registers, so there is one lock per channel.
+static ATA_CHANNEL_LOCKS: [Mutex<()>; ATA_MAX_CHANNELS] = [Mutex::new(()), Mutex::new(())];
+
 #[derive(Debug, Clone)]
 #[repr(u8)]
 pub enum ATADriveType {
@@ -35,6 +42,8 @@ pub struct ATADrive {
     pub n_blocks: u32,
     pub model_name: String,
     pub serial_no: String,
+    /// registers of the channel the drive is attached to
+    channel: ATADevice,
 }
 
 impl ATADrive {
@@ -59,61 +68,52 @@ impl ATADrive {
     }
 
     #[inline]
-    pub fn read_block(&self, buffer: &mut [u8], block_no: u32) {
-        // get bus device:
-
-        cpu::disable_interrupts();
-
-        let bus_lock = ATA_DEVICES.lock();
-        let bus_device = bus_lock.get(self.bus_no as usize).unwrap();
+    fn select(&self) {
         match self.drive_type {
             ATADriveType::PRIMARY => {
-                bus_device.sel_primary();
+                self.channel.sel_primary();
             }
             ATADriveType::SECONDARY => {
-                bus_device.sel_secondary();
+                self.channel.sel_secondary();
             }
         }
+    }
 
-        // setup block for reading
-        bus_device.set_block(self.drive_type.clone(), block_no);
-        // set command:
-        bus_device.send_command(ATACommand::READ);
-        bus_device.wait_while_busy();
-
-        // read
-        bus_device.read_current_block_u8(buffer);
-        cpu::enable_interrupts();
+    #[inline]
+    pub fn read_block(&self, buffer: &mut [u8], block_no: u32) {
+        // the lock holder can't be preempted, so the others never spin on it for long.
+        cpu::without_interrupts(|| {
+            let _channel_lock = ATA_CHANNEL_LOCKS[self.bus_no as usize].lock();
+            self.select();
+
+            // setup block for reading
+            self.channel.set_block(self.drive_type.clone(), block_no);
+            // set command:
+            self.channel.send_command(ATACommand::READ);
+            self.channel.wait_while_busy();
+
+            // read
+            self.channel.read_current_block_u8(buffer);
+        });
     }
 
     #[inline]
     pub fn write_block(&self, buffer: &[u8], block_no: u32) {
-        // get bus device:
+        cpu::without_interrupts(|| {
+            let _channel_lock = ATA_CHANNEL_LOCKS[self.bus_no as usize].lock();
+            self.select();
 
-        cpu::disable_interrupts();
+            // setup block for writing
+            self.channel.set_block(self.drive_type.clone(), block_no);
+            // set command:
+            self.channel.send_command(ATACommand::WRITE);
+            self.channel.wait_while_busy();
 
-        let bus_lock = ATA_DEVICES.lock();
-        let bus_device = bus_lock.get(self.bus_no as usize).unwrap();
-        match self.drive_type {
-            ATADriveType::PRIMARY => {
-                bus_device.sel_primary();
-            }
-            ATADriveType::SECONDARY => {
-                bus_device.sel_secondary();
-            }
-        }
-
-        // setup block for reading
-        bus_device.set_block(self.drive_type.clone(), block_no);
-        // set command:
-        bus_device.send_command(ATACommand::WRITE);
-        bus_device.wait_while_busy();
+            // write
+            self.channel.write_current_block_u8(buffer);
 
-        // write
-        bus_device.write_current_block_u8(buffer);
-
-        bus_device.wait_while_busy();
-        cpu::enable_interrupts();
+            self.channel.wait_while_busy();
+        });
     }
 }
 
@@ -300,6 +300,7 @@ impl ATADevice {
             model_name,
             serial_no,
             n_blocks,
+            channel: self.clone(),
         })
     }
 
@@ -400,7 +401,8 @@ pub fn register_devices() {
 }
 
 lazy_static! {
-    pub static ref ATA_DRIVES: Mutex<Vec<Option<ATADrive>>> = Mutex::new(Vec::new());
+    /// the drives are shared, so the block transfers don't have to hold this lock.
+    pub static ref ATA_DRIVES: Mutex<Vec<Option<Arc<ATADrive>>>> = Mutex::new(Vec::new());
 }
 
 pub fn probe_drives() {
@@ -411,13 +413,22 @@ pub fn probe_drives() {
         let primary_drive = device.identify_primary();
         let secondary_drive = device.identify_secondary();
 
-        drives_lock.push(primary_drive);
-        drives_lock.push(secondary_drive);
+        drives_lock.push(primary_drive.map(Arc::new));
+        drives_lock.push(secondary_drive.map(Arc::new));
     }
 
     log::info!("Probed ATA PCI drives.");
 }
 
+/// returns the drive at the given index, if one was found there while probing.
+pub fn get_drive(index: usize) -> Option<Arc<ATADrive>> {
+    let drives_lock = ATA_DRIVES.lock();
+    if let Some(Some(drive)) = drives_lock.get(index) {
+        return Some(drive.clone());
+    }
+    None
+}
+
 pub fn list_drives() {
     let drives_lock = ATA_DRIVES.lock();
     for drive_opt in drives_lock.iter() {
@@ -5,14 +5,23 @@ use crate::system::filesystem::devfs::{register_device, DevFSDescriptor, DevOps}
 use crate::system::filesystem::{FSError, SeekType};
 
 use alloc::{boxed::Box, format};
+use core::ops::Range;
 
+pub mod ahci;
 pub mod ata_pio;
 
+/// SATA drives are registered after the ATA ones with the same major,
+/// their minor numbers start here.
+pub const AHCI_MINOR_BASE: usize = 16;
+
 pub fn init() {
     // register devices
     ata_pio::register_devices();
     ata_pio::probe_drives();
     ata_pio::list_drives();
+
+    #[cfg(feature = "debug_checks")]
+    test_missing_drive();
 }
 
 pub struct ATAIODriver {
@@ -25,61 +34,90 @@ impl ATAIODriver {
     }
 }
 
+impl ATAIODriver {
+    /// number of bytes of a transfer at `offset` that fit on the drive,
+    /// None if the offset is past the end of the drive.
+    #[inline]
+    fn clamp_to_drive(drive_size: usize, offset: u32, length: usize) -> Option<usize> {
+        let start = offset as usize;
+        if start > drive_size {
+            return None;
+        }
+
+        Some(core::cmp::min(length, drive_size - start))
+    }
+
+    /// the drives move whole blocks, a transfer in the middle of one would
+    /// start at the beginning of the block instead.
+    #[inline]
+    fn is_block_aligned(offset: u32) -> bool {
+        offset as usize % ata_pio::ATA_BLOCK_SIZE == 0
+    }
+}
+
 impl DevOps for ATAIODriver {
+    /// a write that does not fit is cut at the end of the drive,
+    /// one that starts at the end fails with NoSpace. the offset must be
+    /// at the start of a block.
     fn write(&self, fd: &mut DevFSDescriptor, buffer: &[u8]) -> Result<usize, FSError> {
-        let device_lock = ata_pio::ATA_DRIVES.lock();
-        let device = device_lock.get(self.index).unwrap().as_ref().unwrap();
-        let block_start = fd.offset / ata_pio::ATA_BLOCK_SIZE as u32;
+        let device_opt = ata_pio::get_drive(self.index);
+        if device_opt.is_none() {
+            return Err(FSError::DeviceNotFound);
+        }
 
-        let is_short_block: bool;
+        if !ATAIODriver::is_block_aligned(fd.offset) {
+            return Err(FSError::AlignmentError);
+        }
 
-        let n_blocks = if buffer.len() > ata_pio::ATA_BLOCK_SIZE {
-            is_short_block = false;
-            buffer.len() / ata_pio::ATA_BLOCK_SIZE
-        } else {
-            is_short_block = true;
-            1
+        let device = device_opt.unwrap();
+        let block_start = fd.offset / ata_pio::ATA_BLOCK_SIZE as u32;
+
+        let length = match Self::clamp_to_drive(device.size(), fd.offset, buffer.len()) {
+            Some(0) if !buffer.is_empty() => return Err(FSError::NoSpace),
+            Some(length) => length,
+            None => return Err(FSError::NoSpace),
         };
 
-        for block_offset in 0..n_blocks {
-            let start = block_offset * ata_pio::ATA_BLOCK_SIZE;
-            let end = if is_short_block {
-                buffer.len()
-            } else {
-                start + ata_pio::ATA_BLOCK_SIZE
-            };
-            device.write_block(&buffer[start..end], block_start + block_offset as u32);
+        let mut done = 0;
+        while done < length {
+            let end = core::cmp::min(done + ata_pio::ATA_BLOCK_SIZE, length);
+            let block_no = block_start + (done / ata_pio::ATA_BLOCK_SIZE) as u32;
+            device.write_block(&buffer[done..end], block_no);
+            done = end;
         }
 
-        Ok(buffer.len())
+        Ok(length)
     }
 
+    /// a read is cut at the end of the drive, it returns 0 at the end
+    /// and fails with InvalidSeek if the offset is past it.
     fn read(&self, fd: &mut DevFSDescriptor, buffer: &mut [u8]) -> Result<usize, FSError> {
-        let device_lock = ata_pio::ATA_DRIVES.lock();
-        let device = device_lock.get(self.index).unwrap().as_ref().unwrap();
-        let block_start = fd.offset / ata_pio::ATA_BLOCK_SIZE as u32;
+        let device_opt = ata_pio::get_drive(self.index);
+        if device_opt.is_none() {
+            return Err(FSError::DeviceNotFound);
+        }
 
-        let is_short_block: bool;
+        if !ATAIODriver::is_block_aligned(fd.offset) {
+            return Err(FSError::AlignmentError);
+        }
 
-        let n_blocks = if buffer.len() > ata_pio::ATA_BLOCK_SIZE {
-            is_short_block = false;
-            buffer.len() / ata_pio::ATA_BLOCK_SIZE
-        } else {
-            is_short_block = true;
-            1
+        let device = device_opt.unwrap();
+        let block_start = fd.offset / ata_pio::ATA_BLOCK_SIZE as u32;
+
+        let length = match Self::clamp_to_drive(device.size(), fd.offset, buffer.len()) {
+            Some(length) => length,
+            None => return Err(FSError::InvalidSeek),
         };
 
-        for block_offset in 0..n_blocks {
-            let start = block_offset * ata_pio::ATA_BLOCK_SIZE;
-            let end = if is_short_block {
-                buffer.len()
-            } else {
-                start + ata_pio::ATA_BLOCK_SIZE
-            };
-            device.read_block(&mut buffer[start..end], block_start + block_offset as u32);
+        let mut done = 0;
+        while done < length {
+            let end = core::cmp::min(done + ata_pio::ATA_BLOCK_SIZE, length);
+            let block_no = block_start + (done / ata_pio::ATA_BLOCK_SIZE) as u32;
+            device.read_block(&mut buffer[done..end], block_no);
+            done = end;
         }
 
-        Ok(buffer.len())
+        Ok(length)
     }
 
     fn ioctl(&self, _command: usize, _arg: usize) -> Result<usize, FSError> {
@@ -87,22 +125,137 @@ impl DevOps for ATAIODriver {
     }
 
     fn seek(&self, fd: &mut DevFSDescriptor, offset: u32, st: SeekType) -> Result<u32, FSError> {
-        match st {
-            SeekType::SEEK_SET => {
-                fd.offset = offset;
-            }
-            SeekType::SEEK_CUR => {
-                fd.offset = fd.offset + offset;
+        seek_drive(fd, offset, st, drive_size(self.index))
+    }
+}
+
+/// the same for every drive, the offset can be anywhere up to the end of the drive.
+/// offsets are unsigned, so SEEK_END only works with 0, which returns the size.
+fn seek_drive(
+    fd: &mut DevFSDescriptor,
+    offset: u32,
+    st: SeekType,
+    size_opt: Option<usize>,
+) -> Result<u32, FSError> {
+    if size_opt.is_none() {
+        return Err(FSError::DeviceNotFound);
+    }
+
+    let size = size_opt.unwrap() as u64;
+    let new_offset = match st {
+        SeekType::SEEK_SET => offset as u64,
+        SeekType::SEEK_CUR => fd.offset as u64 + offset as u64,
+        SeekType::SEEK_END => size + offset as u64,
+    };
+
+    // a drive bigger than 4GiB has no 32-bit offset for it's end.
+    if new_offset > size || new_offset > u32::MAX as u64 {
+        return Err(FSError::InvalidSeek);
+    }
+
+    fd.offset = new_offset as u32;
+    Ok(fd.offset)
+}
+
+pub struct AHCIIODriver {
+    pub index: usize,
+}
+
+impl AHCIIODriver {
+    pub fn empty(index: usize) -> Self {
+        AHCIIODriver { index }
+    }
+}
+
+impl DevOps for AHCIIODriver {
+    /// same semantics as the ATA driver, but up to a bounce buffer is moved per command.
+    fn write(&self, fd: &mut DevFSDescriptor, buffer: &[u8]) -> Result<usize, FSError> {
+        let device_opt = ahci::get_drive(self.index);
+        if device_opt.is_none() {
+            return Err(FSError::DeviceNotFound);
+        }
+
+        if !ATAIODriver::is_block_aligned(fd.offset) {
+            return Err(FSError::AlignmentError);
+        }
+
+        let device = device_opt.unwrap();
+        let block_start = (fd.offset as usize / ahci::AHCI_BLOCK_SIZE) as u64;
+
+        let length = match ATAIODriver::clamp_to_drive(device.size(), fd.offset, buffer.len()) {
+            Some(0) if !buffer.is_empty() => return Err(FSError::NoSpace),
+            Some(length) => length,
+            None => return Err(FSError::NoSpace),
+        };
+
+        let mut done = 0;
+        while done < length {
+            let end = core::cmp::min(done + ahci::AHCI_BUFFER_SIZE, length);
+            let block_no = block_start + (done / ahci::AHCI_BLOCK_SIZE) as u64;
+            if let Err(err) = device.write_blocks(&buffer[done..end], block_no) {
+                log::error!("ahci: write of block {} failed: {:?}", block_no, err);
+                return Err(FSError::IOError);
             }
-            SeekType::SEEK_END => {
-                return Err(FSError::InvalidSeek);
+            done = end;
+        }
+
+        Ok(length)
+    }
+
+    fn read(&self, fd: &mut DevFSDescriptor, buffer: &mut [u8]) -> Result<usize, FSError> {
+        let device_opt = ahci::get_drive(self.index);
+        if device_opt.is_none() {
+            return Err(FSError::DeviceNotFound);
+        }
+
+        if !ATAIODriver::is_block_aligned(fd.offset) {
+            return Err(FSError::AlignmentError);
+        }
+
+        let device = device_opt.unwrap();
+        let block_start = (fd.offset as usize / ahci::AHCI_BLOCK_SIZE) as u64;
+
+        let length = match ATAIODriver::clamp_to_drive(device.size(), fd.offset, buffer.len()) {
+            Some(length) => length,
+            None => return Err(FSError::InvalidSeek),
+        };
+
+        let mut done = 0;
+        while done < length {
+            let end = core::cmp::min(done + ahci::AHCI_BUFFER_SIZE, length);
+            let block_no = block_start + (done / ahci::AHCI_BLOCK_SIZE) as u64;
+            if let Err(err) = device.read_blocks(&mut buffer[done..end], block_no) {
+                log::error!("ahci: read of block {} failed: {:?}", block_no, err);
+                return Err(FSError::IOError);
             }
+            done = end;
         }
 
-        Ok(fd.offset as u32)
+        Ok(length)
+    }
+
+    fn ioctl(&self, _command: usize, _arg: usize) -> Result<usize, FSError> {
+        Err(FSError::NotYetImplemented)
+    }
+
+    fn seek(&self, fd: &mut DevFSDescriptor, offset: u32, st: SeekType) -> Result<u32, FSError> {
+        seek_drive(fd, offset, st, drive_size(AHCI_MINOR_BASE + self.index))
     }
 }
 
+/// returns the size of the drive in bytes, if it exists.
+pub fn drive_size(index: usize) -> Option<usize> {
+    if index >= AHCI_MINOR_BASE {
+        return ahci::get_drive(index - AHCI_MINOR_BASE).map(|drive| drive.size());
+    }
+
+    let locked_drives = ata_pio::ATA_DRIVES.lock();
+    if let Some(Some(drive)) = locked_drives.get(index) {
+        return Some(drive.size());
+    }
+    None
+}
+
 pub fn register_hdd_devices() {
     let locked_drives = ata_pio::ATA_DRIVES.lock();
     for (index, drive_opt) in locked_drives.iter().enumerate() {
@@ -121,5 +274,79 @@ pub fn register_hdd_devices() {
     }
 }
 
+/// SATA drives show up as sda, sdb and so on, the drives of a controller
+/// are registered once it is set up.
+pub fn register_sata_devices(indices: Range<usize>) {
+    for index in indices {
+        let char_suffix = (97 + index) as u8 as char;
+        let drive_name = format!("sd{}", char_suffix);
+        let driver = AHCIIODriver::empty(index);
+
+        register_device(
+            &drive_name,
+            2,
+            (AHCI_MINOR_BASE + index) as u32,
+            Box::new(driver),
+        )
+        .expect("Failed to register SATA disk device to devfs");
+
+        log::info!("Registered devfs device {}", drive_name);
+    }
+}
+
+/// the transfers on a slot without a drive must fail, not panic.
+#[cfg(feature = "debug_checks")]
+fn test_missing_drive() {
+    let mut fd = DevFSDescriptor {
+        flags: 0,
+        major: 2,
+        minor: 0,
+        offset: 0,
+    };
+    let mut buffer: [u8; ata_pio::ATA_BLOCK_SIZE] = [0; ata_pio::ATA_BLOCK_SIZE];
+
+    // probing pushes a None for every channel without a drive, past the end
+    // there are no slots at all.
+    let mut missing: alloc::vec::Vec<usize> = ata_pio::ATA_DRIVES
+        .lock()
+        .iter()
+        .enumerate()
+        .filter(|(_, drive_opt)| drive_opt.is_none())
+        .map(|(index, _)| index)
+        .collect();
+    missing.push(ata_pio::ATA_DRIVES.lock().len());
+
+    for index in missing {
+        let driver = ATAIODriver::empty(index);
+        assert!(matches!(
+            driver.read(&mut fd, &mut buffer),
+            Err(FSError::DeviceNotFound)
+        ));
+        assert!(matches!(
+            driver.write(&mut fd, &buffer),
+            Err(FSError::DeviceNotFound)
+        ));
+        assert!(matches!(
+            driver.seek(&mut fd, 0, SeekType::SEEK_END),
+            Err(FSError::DeviceNotFound)
+        ));
+    }
+
+    let driver = AHCIIODriver::empty(ahci::AHCI_DRIVES.lock().len());
+    assert!(matches!(
+        driver.read(&mut fd, &mut buffer),
+        Err(FSError::DeviceNotFound)
+    ));
+    assert!(matches!(
+        driver.write(&mut fd, &buffer),
+        Err(FSError::DeviceNotFound)
+    ));
+
+    log::info!("Passed missing drive test.");
+}
+
 unsafe impl Send for ATAIODriver {}
 unsafe impl Sync for ATAIODriver {}
+
+unsafe impl Send for AHCIIODriver {}
+unsafe impl Sync for AHCIIODriver {}
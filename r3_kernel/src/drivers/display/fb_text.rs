@@ -190,19 +190,13 @@ impl FramebufferLogger {
     }
 
     pub fn write(&mut self, string: &str) {
-        let locked_buffer_opt = framebuffer::Framebuffer::get_buffer_lock();
-        if locked_buffer_opt.is_none() {
-            return;
-        }
-
-        let mut locked_buffer = locked_buffer_opt.as_ref().unwrap().lock();
+        let new_lines = framebuffer::Framebuffer::with_buffer(|fb| {
+            FramebufferText::print_string(fb, string, self.color, &self.current_lines)
+        });
 
-        self.current_lines = FramebufferText::print_string(
-            &mut locked_buffer,
-            string,
-            self.color,
-            &self.current_lines,
-        );
+        if let Some(lines) = new_lines {
+            self.current_lines = lines;
+        }
     }
 }
 
@@ -2,6 +2,7 @@ extern crate log;
 extern crate spin;
 
 use crate::boot_proto::BootProtocol;
+use crate::cpu;
 use lazy_static::lazy_static;
 use spin::{Mutex, MutexGuard};
 
@@ -101,12 +102,34 @@ lazy_static! {
 }
 
 /// Set of control functions used for writing pixels to frame buffer
+///
+/// the TTY, the kernel logger and the panic handler all draw on the framebuffer.
+/// the lock is the innermost one: it is held with interrupts off and nothing else
+/// is locked while holding it, so an interrupt never spins on a lock held by the code
+/// it interrupted. the only way to find it locked is a fault while drawing, which
+/// ends in the panic handler, that one takes it over with `force_unlock`.
 pub struct Framebuffer;
 
 impl Framebuffer {
+    /// runs the closure with the framebuffer locked, None if there is no framebuffer.
     #[inline]
-    pub fn get_buffer_lock() -> &'static Option<LockedFramebuffer> {
-        &FRAMEBUFFER
+    pub fn with_buffer<F, R>(func: F) -> Option<R>
+    where
+        F: FnOnce(&mut MutexGuard<FramebufferMemory>) -> R,
+    {
+        let locked_buffer = FRAMEBUFFER.as_ref()?;
+        Some(cpu::without_interrupts(|| {
+            let mut fb = locked_buffer.lock();
+            func(&mut fb)
+        }))
+    }
+
+    /// releases the lock whoever holds it, only for the panic handler: the holder
+    /// never runs again, what it was drawing is left half done.
+    pub unsafe fn force_unlock() {
+        if let Some(locked_buffer) = FRAMEBUFFER.as_ref() {
+            locked_buffer.force_unlock();
+        }
     }
 
     #[inline]
@@ -174,14 +197,17 @@ impl Framebuffer {
 pub fn setup_framebuffer() {
     if FRAMEBUFFER.is_none() {
         log::error!("Fraebuffer set-up failed, system display will not work.");
+        return;
     }
 
-    let fb_ref = FRAMEBUFFER.as_ref().unwrap().lock();
+    // the logger draws on the framebuffer too, so it is called with the lock released.
+    let (address, width, height) =
+        Framebuffer::with_buffer(|fb| (fb.buffer.as_ptr() as u64, fb.width, fb.height)).unwrap();
 
     log::info!(
-        "Framebuffer initialized, address={:p}, width={}, height={}.",
-        &fb_ref.buffer[0],
-        fb_ref.width,
-        fb_ref.height
+        "Framebuffer initialized, address=0x{:x}, width={}, height={}.",
+        address,
+        width,
+        height
     );
 }
@@ -0,0 +1,231 @@
+extern crate log;
+
+use crate::cpu::io::Port;
+use crate::drivers::pci::PCIDevice;
+use crate::system::net::iface;
+
+// this is only the scaffolding of the Intel 8254x (e1000) driver, the device
+// is detected, reset and it's MAC address is read, but the TX and RX rings are
+// not set up yet, so frames can't be sent or received.
+
+pub const E1000_VENDOR_ID: u16 = 0x8086;
+/// 82540EM, the NIC emulated by QEMU with `-device e1000`
+pub const E1000_DEVICE_ID: u16 = 0x100E;
+
+const E1000_PHY_MTU_SIZE: usize = 1500;
+
+// registers, accessed through the IOADDR/IODATA window of the I/O BAR,
+// this way the MMIO BAR does not have to be mapped.
+const E1000_REG_CTRL: u32 = 0x0000;
+const E1000_REG_EERD: u32 = 0x0014;
+const E1000_REG_IMC: u32 = 0x00D8;
+const E1000_REG_RAL0: u32 = 0x5400;
+const E1000_REG_RAH0: u32 = 0x5404;
+
+const E1000_CTRL_RESET: u32 = 1 << 26;
+/// receive address valid bit of RAH
+const E1000_RAH_VALID: u32 = 1 << 31;
+
+const E1000_EERD_START: u32 = 1 << 0;
+const E1000_EERD_DONE: u32 = 1 << 4;
+/// number of reads before giving up on the reset or an EEPROM word.
+const E1000_MAX_POLLS: usize = 100000;
+
+struct DeviceIO {
+    addr: Port,
+    data: Port,
+}
+
+impl DeviceIO {
+    #[inline]
+    pub fn new(io_base: usize) -> Self {
+        DeviceIO {
+            addr: Port::new(io_base, false),
+            data: Port::new(io_base + 4, false),
+        }
+    }
+
+    #[inline]
+    pub fn read_register(&self, register: u32) -> u32 {
+        self.addr.write_u32(register);
+        self.data.read_u32()
+    }
+
+    #[inline]
+    pub fn write_register(&self, register: u32, value: u32) {
+        self.addr.write_u32(register);
+        self.data.write_u32(value);
+    }
+}
+
+pub struct E1000Device {
+    io: DeviceIO,
+    mac: [u8; 6],
+    interrupt_line: usize,
+    is_polling: bool,
+}
+
+/// the first BAR with bit 0 set is the I/O BAR (BAR1 on the 82540EM), the upper
+/// half of a 64-bit memory BAR is skipped so it's bits are not taken for a flag.
+fn find_io_base(bars: &[u32; 6]) -> Option<usize> {
+    let mut index = 0;
+    while index < bars.len() {
+        let bar = bars[index];
+        if bar & 0x1 == 0x1 {
+            let io_base = (bar & 0xFFFC) as usize;
+            if io_base == 0 {
+                return None;
+            }
+            return Some(io_base);
+        }
+
+        // memory BAR, type 0b10 in bits 2:1 is 64-bit wide.
+        index += if (bar >> 1) & 0x3 == 0x2 { 2 } else { 1 };
+    }
+
+    None
+}
+
+impl E1000Device {
+    /// returns None if the device has no usable I/O BAR, the registers are accessed through it.
+    pub fn new(pci_dev: &PCIDevice) -> Option<E1000Device> {
+        let io_base_opt = find_io_base(&pci_dev.bars);
+        if io_base_opt.is_none() {
+            log::warn!("e1000: no I/O BAR found, the device can't be accessed.");
+            return None;
+        }
+
+        pci_dev.set_bus_mastering();
+
+        Some(E1000Device {
+            io: DeviceIO::new(io_base_opt.unwrap()),
+            mac: [0; 6],
+            interrupt_line: pci_dev.interrupt_info().interrupt_line as usize,
+            is_polling: false,
+        })
+    }
+
+    fn reset(&self) -> bool {
+        let ctrl = self.io.read_register(E1000_REG_CTRL);
+        self.io
+            .write_register(E1000_REG_CTRL, ctrl | E1000_CTRL_RESET);
+
+        for _ in 0..E1000_MAX_POLLS {
+            if self.io.read_register(E1000_REG_CTRL) & E1000_CTRL_RESET == 0 {
+                // keep all the interrupts masked until the rings exist.
+                self.io.write_register(E1000_REG_IMC, 0xFFFFFFFF);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn read_eeprom_word(&self, word: u32) -> Option<u16> {
+        self.io
+            .write_register(E1000_REG_EERD, E1000_EERD_START | (word << 8));
+
+        for _ in 0..E1000_MAX_POLLS {
+            let value = self.io.read_register(E1000_REG_EERD);
+            if value & E1000_EERD_DONE == E1000_EERD_DONE {
+                return Some((value >> 16) as u16);
+            }
+        }
+
+        None
+    }
+
+    /// the MAC is loaded to RAL0/RAH0 from the EEPROM after reset,
+    /// the EEPROM is read directly if that did not happen.
+    fn read_mac(&self) -> [u8; 6] {
+        let ral = self.io.read_register(E1000_REG_RAL0);
+        let rah = self.io.read_register(E1000_REG_RAH0);
+
+        if rah & E1000_RAH_VALID == E1000_RAH_VALID {
+            return [
+                ral as u8,
+                (ral >> 8) as u8,
+                (ral >> 16) as u8,
+                (ral >> 24) as u8,
+                rah as u8,
+                (rah >> 8) as u8,
+            ];
+        }
+
+        let mut mac: [u8; 6] = [0; 6];
+        for word in 0..3 {
+            let value = self.read_eeprom_word(word as u32).unwrap_or(0);
+            mac[word * 2] = value as u8;
+            mac[word * 2 + 1] = (value >> 8) as u8;
+        }
+
+        mac
+    }
+
+    pub fn prepare_interface(&mut self) {
+        if !self.reset() {
+            log::warn!("e1000: device did not come out of reset.");
+        }
+
+        self.mac = self.read_mac();
+        log::debug!(
+            "Initialized e1000 device driver, MAC address: {:?}",
+            self.mac
+        );
+        log::warn!("e1000: TX/RX are not implemented yet, the interface will not pass frames.");
+    }
+}
+
+impl iface::PhysicalNetworkDevice for E1000Device {
+    fn get_current_tx_buffer(&mut self) -> Result<&'static mut [u8], iface::PhyNetdevError> {
+        // TODO: set up the TX descriptor ring
+        Err(iface::PhyNetdevError::NoTxBuffer)
+    }
+
+    fn transmit_and_wait(
+        &mut self,
+        _buffer: &mut [u8],
+        _length: usize,
+    ) -> Result<(), iface::PhyNetdevError> {
+        Err(iface::PhyNetdevError::NoTxBuffer)
+    }
+
+    fn handle_interrupt(&mut self) -> Result<(), iface::PhyNetdevError> {
+        // all the interrupts are masked, nothing to handle yet.
+        Ok(())
+    }
+
+    fn get_interrupt_no(&self) -> Result<usize, iface::PhyNetdevError> {
+        Ok(self.interrupt_line)
+    }
+
+    fn get_mtu_size(&self) -> Result<usize, iface::PhyNetdevError> {
+        Ok(E1000_PHY_MTU_SIZE)
+    }
+
+    fn get_mac_address(&self) -> Result<[u8; 6], iface::PhyNetdevError> {
+        Ok(self.mac)
+    }
+
+    fn set_polling_mode(&mut self, enable: bool) -> Result<(), iface::PhyNetdevError> {
+        self.is_polling = enable;
+        Ok(())
+    }
+
+    fn is_polling_enabled(&self) -> Result<bool, iface::PhyNetdevError> {
+        Ok(self.is_polling)
+    }
+
+    fn poll_for_frame(
+        &mut self,
+        _max_polls: usize,
+    ) -> Result<&'static [u8], iface::PhyNetdevError> {
+        // TODO: set up the RX descriptor ring
+        Err(iface::PhyNetdevError::EmptyInterruptRecvBuffer)
+    }
+
+    fn set_rx_interrupts(&mut self, _enable: bool) -> Result<(), iface::PhyNetdevError> {
+        // all the interrupts are masked already.
+        Ok(())
+    }
+}
@@ -3,12 +3,14 @@ extern crate pc_keyboard;
 extern crate spin;
 
 use crate::cpu::io::Port;
+#[cfg(feature = "kmonitor")]
+use crate::system::kmonitor;
 
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts::Us104Key, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 use spin::Mutex;
 
-type OnKeyCallback = fn(char) -> ();
+pub type OnKeyCallback = fn(char) -> ();
 
 const KEYBOARD_DATA_PORT: usize = 0x60;
 
@@ -72,6 +74,11 @@ impl PCKeyboardController {
                         DecodedKey::RawKey(KeyCode::ArrowDown) => self.send_control('B'),
                         DecodedKey::RawKey(KeyCode::AltLeft) => self.send_control('D'),
                         DecodedKey::RawKey(KeyCode::AltRight) => self.send_control('C'),
+                        // the handler is swapped here, the keyboard is already locked.
+                        #[cfg(feature = "kmonitor")]
+                        DecodedKey::RawKey(KeyCode::F12) => {
+                            self.on_key = Some(kmonitor::toggle())
+                        }
                         _ => {}
                     }
                 }
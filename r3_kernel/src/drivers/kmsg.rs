@@ -0,0 +1,188 @@
+extern crate spin;
+
+use crate::cpu;
+use crate::system::filesystem::devfs::{DevFSDescriptor, DevOps};
+use crate::system::filesystem::{FSError, SeekType};
+
+use core::fmt;
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// size of the kernel message ring buffer in bytes.
+const KMSG_BUFFER_SIZE: usize = 16 * 1024;
+
+const LINE_END: u8 = b'\n';
+
+/// a ring buffer of log lines, positions are absolute and wrap around u32,
+/// so a reader can keep its position in the 32-bit devfs offset.
+pub struct KernelMessageBuffer {
+    buffer: [u8; KMSG_BUFFER_SIZE],
+    /// position of the oldest byte still in the buffer, always at a line start.
+    head: u32,
+    /// position where the next byte will be written.
+    tail: u32,
+}
+
+impl KernelMessageBuffer {
+    fn new() -> Self {
+        KernelMessageBuffer {
+            buffer: [0; KMSG_BUFFER_SIZE],
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.tail.wrapping_sub(self.head) as usize
+    }
+
+    #[inline]
+    fn byte_at(&self, position: u32) -> u8 {
+        self.buffer[position as usize % KMSG_BUFFER_SIZE]
+    }
+
+    /// drops the oldest line to make space.
+    fn drop_oldest_line(&mut self) {
+        while self.head != self.tail {
+            let byte = self.byte_at(self.head);
+            self.head = self.head.wrapping_add(1);
+            if byte == LINE_END {
+                break;
+            }
+        }
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        if self.len() == KMSG_BUFFER_SIZE {
+            self.drop_oldest_line();
+        }
+
+        self.buffer[self.tail as usize % KMSG_BUFFER_SIZE] = byte;
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    /// if the reader fell behind the head, its lines were overwritten,
+    /// continue from the oldest line available.
+    #[inline]
+    fn clamp_position(&self, position: u32) -> u32 {
+        if self.tail.wrapping_sub(position) as usize > self.len() {
+            return self.head;
+        }
+        position
+    }
+
+    /// copies the line at the position into the buffer, returns (bytes copied, next position).
+    /// the line is truncated if the buffer is too small, the rest of it is skipped.
+    pub fn read_line(&self, position: u32, buffer: &mut [u8]) -> (usize, u32) {
+        let mut current = self.clamp_position(position);
+        let mut copied = 0;
+
+        while current != self.tail {
+            let byte = self.byte_at(current);
+            current = current.wrapping_add(1);
+
+            if copied < buffer.len() {
+                buffer[copied] = byte;
+                copied += 1;
+            }
+
+            if byte == LINE_END {
+                break;
+            }
+        }
+
+        (copied, current)
+    }
+}
+
+impl fmt::Write for KernelMessageBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.push_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref KERNEL_MESSAGES: Mutex<KernelMessageBuffer> =
+        Mutex::new(KernelMessageBuffer::new());
+}
+
+/// appends a log line to the buffer, interrupts are kept off while the lock
+/// is held so an interrupt handler that logs can never spin on it. returns false
+/// if the buffer was busy anyway (an exception taken while writing it), the line is lost then.
+pub fn write_record(args: fmt::Arguments) -> bool {
+    cpu::without_interrupts(|| {
+        let kmsg_lock_opt = KERNEL_MESSAGES.try_lock();
+        if kmsg_lock_opt.is_none() {
+            return false;
+        }
+
+        let mut kmsg_lock = kmsg_lock_opt.unwrap();
+        let _ = kmsg_lock.write_fmt(args);
+        kmsg_lock.push_byte(LINE_END);
+        true
+    })
+}
+
+pub struct KernelMessageDriver;
+
+impl KernelMessageDriver {
+    pub fn empty() -> Self {
+        KernelMessageDriver {}
+    }
+}
+
+impl DevOps for KernelMessageDriver {
+    /// every read returns the next buffered line of the reader, 0 if there are no new lines.
+    fn read(&self, fd: &mut DevFSDescriptor, buffer: &mut [u8]) -> Result<usize, FSError> {
+        let (copied, next_position) =
+            cpu::without_interrupts(|| KERNEL_MESSAGES.lock().read_line(fd.offset, buffer));
+
+        fd.offset = next_position;
+        Ok(copied)
+    }
+
+    fn write(&self, _fd: &mut DevFSDescriptor, buffer: &[u8]) -> Result<usize, FSError> {
+        // userspace can add messages to the kernel log as well.
+        let message = core::str::from_utf8(buffer);
+        if message.is_err() {
+            return Err(FSError::InvalidOperation);
+        }
+
+        write_record(format_args!(
+            "{:20} {:5} {}",
+            "user",
+            "INFO",
+            message.unwrap().trim_end()
+        ));
+        Ok(buffer.len())
+    }
+
+    fn ioctl(&self, _command: usize, _arg: usize) -> Result<usize, FSError> {
+        Ok(0)
+    }
+
+    fn seek(&self, fd: &mut DevFSDescriptor, offset: u32, st: SeekType) -> Result<u32, FSError> {
+        // only rewinding to the oldest line or skipping to the end make sense here.
+        let (head, tail) = cpu::without_interrupts(|| {
+            let kmsg_lock = KERNEL_MESSAGES.lock();
+            (kmsg_lock.head, kmsg_lock.tail)
+        });
+
+        match (st, offset) {
+            (SeekType::SEEK_SET, 0) => fd.offset = head,
+            (SeekType::SEEK_END, 0) => fd.offset = tail,
+            _ => return Err(FSError::InvalidSeek),
+        }
+
+        Ok(0)
+    }
+}
+
+// TODO: Find a best way to mitigate this
+unsafe impl Sync for KernelMessageDriver {}
+unsafe impl Send for KernelMessageDriver {}
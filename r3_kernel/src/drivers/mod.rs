@@ -3,17 +3,22 @@ extern crate log;
 
 use crate::system::filesystem::devfs::register_device;
 use alloc::{boxed::Box, vec::Vec};
+use pci::{PCIDevice, PCIDeviceMatch};
 
 use crate::system::net::iface::PhyNetDevType;
 
+pub mod cpuinfo;
 pub mod disk;
 pub mod display;
+pub mod e1000;
 pub mod keyboard;
+pub mod kmsg;
 pub mod pci;
 pub mod random;
 pub mod rtl8139;
 pub mod tty;
 pub mod uart;
+pub mod watchdog;
 
 /// registers all the devices to DevFS
 pub fn register_buultin_devices() {
@@ -27,57 +32,215 @@ pub fn register_buultin_devices() {
     register_device("rand", 1, 2, Box::new(random::RandomIODriver::empty()))
         .expect("Failed to register Random generator to devfs");
 
+    register_device("kmsg", 1, 3, Box::new(kmsg::KernelMessageDriver::empty()))
+        .expect("Failed to register kernel message buffer to devfs");
+
+    register_device("cpuinfo", 1, 4, Box::new(cpuinfo::CpuInfoDriver::empty()))
+        .expect("Failed to register cpuinfo to devfs");
+
     log::info!("Registered devfs devices - uart");
 }
 
 /// The ATA controller device
 const ATA_CONTROLLER: (u16, u16) = (0x7010, 0x8086);
 const RTL_NETWORK_INTERFACE: (u16, u16) = (0x8139, 0x10EC);
+const E1000_NETWORK_INTERFACE: (u16, u16) = (e1000::E1000_DEVICE_ID, e1000::E1000_VENDOR_ID);
+
+/// a PCI driver along with the devices it can serve.
+struct PCIDriverEntry {
+    name: &'static str,
+    matches: &'static [PCIDeviceMatch],
+    /// called once, for the first device that matched.
+    init: fn(&PCIDevice),
+}
+
+fn init_ata_driver(_device: &PCIDevice) {
+    disk::init();
+    disk::register_hdd_devices();
+}
+
+fn init_ahci_driver(device: &PCIDevice) {
+    let indices = disk::ahci::init(device);
+    disk::register_sata_devices(indices);
+}
+
+fn init_net_driver(_device: &PCIDevice) {
+    // the network stack brings up the interface, see get_network_device()
+}
+
+/// drivers known to the kernel, exact id matches take priority over class matches.
+const PCI_DRIVERS: &[PCIDriverEntry] = &[
+    PCIDriverEntry {
+        name: "ata",
+        matches: &[
+            PCIDeviceMatch::Exact(ATA_CONTROLLER.1, ATA_CONTROLLER.0),
+            // the PIO driver works with any IDE compatible controller.
+            PCIDeviceMatch::Class(pci::CLASS_MASS_STORAGE, pci::SUBCLASS_IDE, None),
+        ],
+        init: init_ata_driver,
+    },
+    PCIDriverEntry {
+        name: "ahci",
+        matches: &[PCIDeviceMatch::Class(
+            pci::CLASS_MASS_STORAGE,
+            pci::SUBCLASS_SATA,
+            Some(pci::PROG_IF_AHCI),
+        )],
+        init: init_ahci_driver,
+    },
+    PCIDriverEntry {
+        name: "rtl8139",
+        matches: &[PCIDeviceMatch::Exact(
+            RTL_NETWORK_INTERFACE.1,
+            RTL_NETWORK_INTERFACE.0,
+        )],
+        init: init_net_driver,
+    },
+    PCIDriverEntry {
+        name: "e1000",
+        matches: &[PCIDeviceMatch::Exact(
+            E1000_NETWORK_INTERFACE.1,
+            E1000_NETWORK_INTERFACE.0,
+        )],
+        init: init_net_driver,
+    },
+];
+
+/// finds the driver for the device, exact id matches are checked first so they
+/// can override a driver that matches the device by class.
+fn find_pci_driver(device: &PCIDevice) -> Option<usize> {
+    let exact = PCI_DRIVERS.iter().position(|driver| {
+        driver
+            .matches
+            .iter()
+            .any(|m| m.is_exact() && m.matches(device))
+    });
+
+    if exact.is_some() {
+        return exact;
+    }
+
+    PCI_DRIVERS.iter().position(|driver| {
+        driver
+            .matches
+            .iter()
+            .any(|m| !m.is_exact() && m.matches(device))
+    })
+}
 
 /// this method iterates over the available PCI devices,
-/// uses vendor_id and device_id to determine which driver can
-/// serve this device.
+/// uses vendor_id and device_id or the device class to determine
+/// which driver can serve this device.
 pub fn load_pci_drivers() {
-    let mut devices: Vec<(u16, u16)> = Vec::new();
+    let mut devices: Vec<PCIDevice> = Vec::new();
     for &device in pci::PCI_DEVICES.lock().iter() {
-        devices.push((device.device_id, device.vendor_id));
+        devices.push(device);
     }
 
-    for (device_id, vendor_id) in devices.iter() {
-        match (*device_id, *vendor_id) {
-            ATA_CONTROLLER => {
-                // load the ATA controller driver
-                log::info!("Found driver for device {:x}:{:x}.", device_id, vendor_id);
-                disk::init();
-                disk::register_hdd_devices();
-            }
-            RTL_NETWORK_INTERFACE => {
-                log::info!("Found driver for device {:x}:{:x}", device_id, vendor_id);
-                // rtl8139::init();
+    let mut loaded = [false; PCI_DRIVERS.len()];
+
+    for device in devices.iter() {
+        match find_pci_driver(device) {
+            Some(index) => {
+                let driver = &PCI_DRIVERS[index];
+                log::info!(
+                    "Found driver {} for device {:x}:{:x}.",
+                    driver.name,
+                    device.device_id,
+                    device.vendor_id
+                );
+
+                if !loaded[index] {
+                    (driver.init)(device);
+                    loaded[index] = true;
+                }
             }
-            _ => {
+            None => {
                 log::warn!(
-                    "No driver found to handle the device {:x}:{:x}",
-                    device_id,
-                    vendor_id
+                    "No driver found to handle the device {:x}:{:x}, class={:x} ({}), subclass={:x}, prog_if={:x}",
+                    device.device_id,
+                    device.vendor_id,
+                    device.class_code,
+                    pci::class_name(device.class_code),
+                    device.subclass,
+                    device.prog_if
                 );
             }
         }
     }
 }
 
-pub fn get_network_device() -> Option<Box<PhyNetDevType>> {
-    // TODO: Extend this to more network
-    // check if there is a RTL driver:
-    let (device_id, vendor_id) = RTL_NETWORK_INTERFACE;
-    let rtl_driver_opt = pci::search_device(vendor_id, device_id);
-    if rtl_driver_opt.is_none() {
+/// a network card driver, creates the device used by the network stack.
+struct NetDriverEntry {
+    name: &'static str,
+    /// (device_id, vendor_id)
+    device: (u16, u16),
+    /// None if the driver could not set the device up.
+    create: fn(&PCIDevice) -> Option<Box<PhyNetDevType>>,
+}
+
+fn create_rtl_device(_device: &PCIDevice) -> Option<Box<PhyNetDevType>> {
+    let mut device = match rtl8139::Realtek8139Device::new() {
+        Ok(device) => device,
+        Err(err) => {
+            log::error!("rtl8139: failed to allocate DMA buffers: {:?}", err);
+            return None;
+        }
+    };
+    device.prepare_interface();
+    Some(Box::new(device))
+}
+
+fn create_e1000_device(pci_dev: &PCIDevice) -> Option<Box<PhyNetDevType>> {
+    let device_opt = e1000::E1000Device::new(pci_dev);
+    if device_opt.is_none() {
         return None;
     }
 
-    // we found the device: create the instance
-    let mut device = rtl8139::Realtek8139Device::new();
+    let mut device = device_opt.unwrap();
     device.prepare_interface();
-
     Some(Box::new(device))
 }
+
+/// network cards in the order of preference, only one of them is used.
+const NET_DRIVERS: &[NetDriverEntry] = &[
+    NetDriverEntry {
+        name: "rtl8139",
+        device: RTL_NETWORK_INTERFACE,
+        create: create_rtl_device,
+    },
+    NetDriverEntry {
+        name: "e1000",
+        device: E1000_NETWORK_INTERFACE,
+        create: create_e1000_device,
+    },
+];
+
+pub fn get_network_device() -> Option<Box<PhyNetDevType>> {
+    for driver in NET_DRIVERS {
+        let (device_id, vendor_id) = driver.device;
+        if let Some(pci_dev) = pci::search_device(vendor_id, device_id) {
+            log::info!(
+                "Detected NIC {:x}:{:x}, using driver {}.",
+                device_id,
+                vendor_id,
+                driver.name
+            );
+            let device = (driver.create)(&pci_dev);
+            if device.is_some() {
+                return device;
+            }
+        }
+    }
+
+    // an ethernet controller we don't have a driver for.
+    if let Some(pci_dev) = pci::search_device_by_class(pci::CLASS_NETWORK, pci::SUBCLASS_ETHERNET) {
+        log::warn!(
+            "Detected NIC {:x}:{:x}, but no driver supports it.",
+            pci_dev.device_id,
+            pci_dev.vendor_id
+        );
+    }
+
+    None
+}
@@ -31,6 +31,20 @@ const MAX_FUNCTIONS_PER_DEVICE: usize = 8;
 /// if this flag is set, then the device is a multi-function device
 const FLAG_MULTIFUNCTION_DEVICE: usize = 0x80;
 
+/// PCI class codes used by drivers to match devices.
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_NETWORK: u8 = 0x02;
+
+/// mass storage subclasses
+pub const SUBCLASS_IDE: u8 = 0x01;
+pub const SUBCLASS_SATA: u8 = 0x06;
+
+/// network subclasses
+pub const SUBCLASS_ETHERNET: u8 = 0x00;
+
+/// SATA prog-if of a controller in AHCI mode
+pub const PROG_IF_AHCI: u8 = 0x01;
+
 /// This function will be called upon every successfull device/function detection
 /// on the system.
 type OnEntryCallback = fn(bus: u8, dev: u8, func: u8);
@@ -40,6 +54,7 @@ pub struct PCIConfigRegister {
     pub address_line: Port,
     pub data_line: Port,
     pub dev_addr: u32,
+    pub offset: u8,
 }
 
 impl PCIConfigRegister {
@@ -58,9 +73,17 @@ impl PCIConfigRegister {
             address_line: Port::new(PCI_ADDRESS_PORT, false),
             data_line: Port::new(PCI_DATA_PORT, false),
             dev_addr: Self::get_address(bus, dev, func, offset),
+            offset,
         }
     }
 
+    /// the address port always selects a dword, sub-dword fields are accessed
+    /// by moving the data port within that dword.
+    #[inline]
+    fn data_line_at(&self, mask: u8) -> Port {
+        Port::new(PCI_DATA_PORT + (self.offset & mask) as usize, false)
+    }
+
     pub fn read_config(&self) -> u32 {
         self.address_line.write_u32(self.dev_addr);
         self.data_line.read_u32()
@@ -70,6 +93,26 @@ impl PCIConfigRegister {
         self.address_line.write_u32(self.dev_addr);
         self.data_line.write_u32(data);
     }
+
+    pub fn read_config_u16(&self) -> u16 {
+        self.address_line.write_u32(self.dev_addr);
+        self.data_line_at(0x02).read_u16()
+    }
+
+    pub fn write_config_u16(&self, data: u16) {
+        self.address_line.write_u32(self.dev_addr);
+        self.data_line_at(0x02).write_u16(data);
+    }
+
+    pub fn read_config_u8(&self) -> u8 {
+        self.address_line.write_u32(self.dev_addr);
+        self.data_line_at(0x03).read_u8()
+    }
+
+    pub fn write_config_u8(&self, data: u8) {
+        self.address_line.write_u32(self.dev_addr);
+        self.data_line_at(0x03).write_u8(data);
+    }
 }
 
 /// Handles different types of queries on PCI devices.
@@ -77,20 +120,22 @@ pub enum PCIDeviceQuery {
     DeviceID,
     VendorID,
     HeaderType,
+    ClassCode,
+    SubClass,
+    ProgIF,
 }
 
 impl PCIDeviceQuery {
     pub fn query(&self, bus: u8, dev: u8, func: u8) -> u16 {
         match self {
-            Self::VendorID => PCIConfigRegister::new(bus, dev, func, 0x00)
-                .read_config()
-                .get_bits(0..16) as u16,
-            Self::DeviceID => PCIConfigRegister::new(bus, dev, func, 0x00)
-                .read_config()
-                .get_bits(16..32) as u16,
-            Self::HeaderType => PCIConfigRegister::new(bus, dev, func, 0x0C)
-                .read_config()
-                .get_bits(16..24) as u16,
+            Self::VendorID => PCIConfigRegister::new(bus, dev, func, 0x00).read_config_u16(),
+            Self::DeviceID => PCIConfigRegister::new(bus, dev, func, 0x02).read_config_u16(),
+            Self::HeaderType => {
+                PCIConfigRegister::new(bus, dev, func, 0x0E).read_config_u8() as u16
+            }
+            Self::ClassCode => PCIConfigRegister::new(bus, dev, func, 0x0B).read_config_u8() as u16,
+            Self::SubClass => PCIConfigRegister::new(bus, dev, func, 0x0A).read_config_u8() as u16,
+            Self::ProgIF => PCIConfigRegister::new(bus, dev, func, 0x09).read_config_u8() as u16,
         }
     }
 }
@@ -166,6 +211,9 @@ pub struct PCIDevice {
     pub func: u8,
     pub vendor_id: u16,
     pub device_id: u16,
+    pub class_code: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
     pub bars: [u32; 6],
 }
 
@@ -173,6 +221,9 @@ impl PCIDevice {
     pub fn new(bus: u8, dev: u8, func: u8) -> PCIDevice {
         let vendor_id = PCIDeviceQuery::VendorID.query(bus, dev, func);
         let device_id = PCIDeviceQuery::DeviceID.query(bus, dev, func);
+        let class_code = PCIDeviceQuery::ClassCode.query(bus, dev, func) as u8;
+        let subclass = PCIDeviceQuery::SubClass.query(bus, dev, func) as u8;
+        let prog_if = PCIDeviceQuery::ProgIF.query(bus, dev, func) as u8;
 
         let mut bars: [u32; 6] = [0; 6];
 
@@ -188,10 +239,24 @@ impl PCIDevice {
             func,
             vendor_id,
             device_id,
+            class_code,
+            subclass,
+            prog_if,
             bars,
         }
     }
 
+    /// is this device still present at its bus location?
+    #[inline]
+    pub fn is_present(&self) -> bool {
+        PCIDeviceQuery::VendorID.query(self.bus, self.dev, self.func) == self.vendor_id
+    }
+
+    #[inline]
+    pub fn is_same_location(&self, other: &PCIDevice) -> bool {
+        self.bus == other.bus && self.dev == other.dev && self.func == other.func
+    }
+
     pub fn control_registers(&self) -> PCIDeviceControlRegs {
         let config_reg = PCIConfigRegister::new(self.bus, self.dev, self.func, 0x04);
         let data = config_reg.read_config();
@@ -215,11 +280,78 @@ impl PCIDevice {
         config_reg.write_config(value);
     }
 
+    pub fn read_config_u16(&self, offset: u8) -> u16 {
+        PCIConfigRegister::new(self.bus, self.dev, self.func, offset).read_config_u16()
+    }
+
+    pub fn write_config_u16(&self, offset: u8, value: u16) {
+        PCIConfigRegister::new(self.bus, self.dev, self.func, offset).write_config_u16(value);
+    }
+
+    pub fn read_config_u8(&self, offset: u8) -> u8 {
+        PCIConfigRegister::new(self.bus, self.dev, self.func, offset).read_config_u8()
+    }
+
+    pub fn write_config_u8(&self, offset: u8, value: u8) {
+        PCIConfigRegister::new(self.bus, self.dev, self.func, offset).write_config_u8(value);
+    }
+
     pub fn set_bus_mastering(&self) {
-        let config_reg = PCIConfigRegister::new(self.bus, self.dev, self.func, 0x04);
-        let mut current_data = config_reg.read_config();
-        current_data.set_bit(2, true);
-        config_reg.write_config(current_data);
+        // only touch the command register, writing back the status word
+        // would clear its write-1-to-clear bits.
+        let mut command = self.read_config_u16(0x04);
+        command.set_bit(2, true);
+        self.write_config_u16(0x04, command);
+    }
+}
+
+/// describes which devices a driver is interested in.
+#[derive(Debug, Clone, Copy)]
+pub enum PCIDeviceMatch {
+    /// matches the exact (vendor_id, device_id) pair.
+    Exact(u16, u16),
+    /// matches (class_code, subclass, prog_if), any prog_if if None.
+    Class(u8, u8, Option<u8>),
+}
+
+impl PCIDeviceMatch {
+    pub fn matches(&self, pci_dev: &PCIDevice) -> bool {
+        match *self {
+            Self::Exact(vendor_id, device_id) => {
+                pci_dev.vendor_id == vendor_id && pci_dev.device_id == device_id
+            }
+            Self::Class(class_code, subclass, prog_if) => {
+                pci_dev.class_code == class_code
+                    && pci_dev.subclass == subclass
+                    && prog_if.map_or(true, |pi| pci_dev.prog_if == pi)
+            }
+        }
+    }
+
+    #[inline]
+    pub fn is_exact(&self) -> bool {
+        match self {
+            Self::Exact(_, _) => true,
+            _ => false,
+        }
+    }
+}
+
+/// human readable name of the PCI class code, used for logging.
+pub fn class_name(class_code: u8) -> &'static str {
+    match class_code {
+        0x00 => "unclassified",
+        0x01 => "mass storage controller",
+        0x02 => "network controller",
+        0x03 => "display controller",
+        0x04 => "multimedia controller",
+        0x05 => "memory controller",
+        0x06 => "bridge",
+        0x07 => "communication controller",
+        0x08 => "system peripheral",
+        0x09 => "input device controller",
+        0x0C => "serial bus controller",
+        _ => "unknown",
     }
 }
 
@@ -230,23 +362,63 @@ lazy_static! {
 fn on_device_callback(bus: u8, dev: u8, func: u8) {
     let pci_device = PCIDevice::new(bus, dev, func);
 
+    let mut devices = PCI_DEVICES.lock();
+    // already known from an earlier scan, refresh it in place.
+    for known_device in devices.iter_mut() {
+        if known_device.is_same_location(&pci_device) {
+            *known_device = pci_device;
+            return;
+        }
+    }
+
     log::info!(
         "New PCI device added. bus={:x}, dev={:x}, func={:x}
-         vendor_id={:x}, device_id={:x}",
+         vendor_id={:x}, device_id={:x}, class={:x}, subclass={:x}",
         pci_device.bus,
         pci_device.dev,
         pci_device.func,
         pci_device.vendor_id,
-        pci_device.device_id
+        pci_device.device_id,
+        pci_device.class_code,
+        pci_device.subclass
     );
 
-    PCI_DEVICES.lock().push(pci_device);
+    devices.push(pci_device);
 }
 
 pub fn detect_devices() {
     PCIDeviceProber::probe(on_device_callback);
 }
 
+/// re-probes the bus, drops devices that went away and adds the new ones,
+/// devices that are already known are not duplicated.
+pub fn rescan() {
+    PCI_DEVICES.lock().retain(|pci_dev| {
+        let present = pci_dev.is_present();
+        if !present {
+            log::info!(
+                "PCI device removed. bus={:x}, dev={:x}, func={:x}",
+                pci_dev.bus,
+                pci_dev.dev,
+                pci_dev.func
+            );
+        }
+        present
+    });
+
+    PCIDeviceProber::probe(on_device_callback);
+}
+
+pub fn search_device_by_class(class_code: u8, subclass: u8) -> Option<PCIDevice> {
+    for &pci_dev in PCI_DEVICES.lock().iter() {
+        if (pci_dev.class_code == class_code) && (pci_dev.subclass == subclass) {
+            return Some(pci_dev);
+        }
+    }
+
+    None
+}
+
 pub fn search_device(vendor_id: u16, device_id: u16) -> Option<PCIDevice> {
     for &pci_dev in PCI_DEVICES.lock().iter() {
         if (pci_dev.vendor_id == vendor_id) && (pci_dev.device_id == device_id) {
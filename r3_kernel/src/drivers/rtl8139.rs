@@ -36,7 +36,9 @@ const RTL_TX_DMA2: usize = 1 << 10;
 // RTL basic device flags
 const RTL_RECV_OK: usize = 0x01;
 const RTL_TX_OK: usize = 1 << 15;
+/// the OWN bit, set by the device once the frame has been moved out of the buffer.
 const RTL_DMA_COMPLETE: usize = 1 << 13;
+const RTL_TX_ABORTED: usize = 1 << 30;
 
 const RTL_WRAP_BUFFER: usize = 1 << 7;
 const RTL_INTERFRAME_TIME_GAP: usize = 1 << 24;
@@ -45,7 +47,11 @@ const RTL_TX_BUFFER_SIZE: usize = 4096;
 
 const RTL_RX_BUFFER_SIZE: usize = ((8 * 1024) << RTL_RX_SIZE_FACTOR) + RTL_RX_BUFFER_PAD;
 const RTL_PHY_MTU_SIZE: usize = 1500;
+/// MTU plus the ethernet header, the largest frame we hand to the device.
+const RTL_MAX_FRAME_SIZE: usize = RTL_PHY_MTU_SIZE + 14;
 const RTL_N_TX_BUFFERS: usize = 4;
+/// number of status reads before giving up on a busy TX descriptor.
+const RTL_TX_MAX_POLLS: usize = 100000;
 const RTL_RC_EMPTY_BUFFER: usize = 1 << 0;
 
 /// First 13 bits will be used for representing length
@@ -68,6 +74,8 @@ struct DeviceTx {
     addr: [Port; 4],
     config: Port,
     tx_id: usize,
+    /// descriptors handed to the device whose completion was not yet seen.
+    in_flight: [bool; RTL_N_TX_BUFFERS],
 }
 
 impl DeviceTx {
@@ -88,8 +96,49 @@ impl DeviceTx {
             ],
             config: Port::new(io_base + 0x040, false),
             tx_id: RTL_N_TX_BUFFERS - 1,
+            in_flight: [false; RTL_N_TX_BUFFERS],
         }
     }
+
+    #[inline]
+    fn next_id(&self) -> usize {
+        (self.tx_id + 1) % RTL_N_TX_BUFFERS
+    }
+
+    /// a descriptor can be reused once the device owns no part of it,
+    /// i.e the DMA is complete and the transmit either finished or aborted.
+    fn is_free(&mut self, tx_id: usize) -> bool {
+        if !self.in_flight[tx_id] {
+            return true;
+        }
+
+        let status = self.cmds[tx_id].read_u32() as usize;
+        if status & RTL_DMA_COMPLETE != RTL_DMA_COMPLETE {
+            return false;
+        }
+
+        if status & (RTL_TX_OK | RTL_TX_ABORTED) == 0 {
+            return false;
+        }
+
+        if status & RTL_TX_ABORTED == RTL_TX_ABORTED {
+            log::warn!("RTL8139: transmit aborted on descriptor {}", tx_id);
+        }
+
+        self.in_flight[tx_id] = false;
+        true
+    }
+
+    /// waits a bounded number of polls for the descriptor to be free.
+    fn wait_free(&mut self, tx_id: usize) -> bool {
+        for _ in 0..RTL_TX_MAX_POLLS {
+            if self.is_free(tx_id) {
+                return true;
+            }
+        }
+
+        false
+    }
 }
 
 struct DeviceConfig {
@@ -134,23 +183,35 @@ struct DeviceBuffers {
     tx_dma: [phy::DMABuffer; RTL_N_TX_BUFFERS],
     rx_dma: phy::DMABuffer,
     read_offset: usize,
+    /// owns the buffers above, they go back to the DMA region when the device is dropped.
+    _pool: phy::DMAPool,
 }
 
 impl DeviceBuffers {
     #[inline]
-    pub fn new() -> Self {
-        let rx_dma = phy::DMAMemoryManager::alloc(RTL_RX_BUFFER_SIZE + RTL_PHY_MTU_SIZE)
-            .expect("Failed to allocate DMA buffer");
+    pub fn new() -> Result<Self, phy::DMAError> {
+        let mut pool = phy::DMAPool::new("rtl8139");
+
+        let rx_dma = pool.alloc(RTL_RX_BUFFER_SIZE + RTL_PHY_MTU_SIZE);
+        if rx_dma.is_err() {
+            return Err(rx_dma.unwrap_err());
+        }
 
-        let tx_dma: [phy::DMABuffer; RTL_N_TX_BUFFERS] = [(); RTL_N_TX_BUFFERS].map(|_| {
-            phy::DMAMemoryManager::alloc(RTL_TX_BUFFER_SIZE).expect("Failed to allocate DMA buffer")
-        });
+        let mut tx_dma: [phy::DMABuffer; RTL_N_TX_BUFFERS] = [rx_dma.unwrap(); RTL_N_TX_BUFFERS];
+        for idx in 0..RTL_N_TX_BUFFERS {
+            let buffer = pool.alloc(RTL_TX_BUFFER_SIZE);
+            if buffer.is_err() {
+                return Err(buffer.unwrap_err());
+            }
+            tx_dma[idx] = buffer.unwrap();
+        }
 
-        DeviceBuffers {
-            rx_dma,
+        Ok(DeviceBuffers {
+            rx_dma: rx_dma.unwrap(),
             tx_dma,
             read_offset: 0,
-        }
+            _pool: pool,
+        })
     }
 }
 
@@ -215,23 +276,26 @@ impl Realtek8139Device {
         }
     }
 
-    pub fn new() -> Realtek8139Device {
+    pub fn new() -> Result<Realtek8139Device, phy::DMAError> {
         let pci_dev = pci::search_device(RTL_VENDOR_ID, RTL_DEVICE_ID).unwrap();
+
+        let buffers = DeviceBuffers::new()?;
+
         // enable bus mastering
         pci_dev.set_bus_mastering();
 
         // get io base register offset
         let io_base = (pci_dev.bars[0] & 0xFFF0) as usize;
 
-        Realtek8139Device {
+        Ok(Realtek8139Device {
             tx_line: DeviceTx::new(io_base),
             rx_line: DeviceRx::new(io_base),
-            buffers: DeviceBuffers::new(),
+            buffers,
             mac: DeviceMAC::new(io_base),
             config: DeviceConfig::new(io_base),
             interrupt_line: pci_dev.interrupt_info().interrupt_line as usize,
             is_polling: false,
-        }
+        })
     }
 
     #[inline]
@@ -248,7 +312,6 @@ impl Realtek8139Device {
         }
     }
 
-
     #[inline]
     fn ack_probable_isr(&self) {
         self.config.isr.write_u16(0x05);
@@ -339,12 +402,14 @@ impl Realtek8139Device {
 
 impl iface::PhysicalNetworkDevice for Realtek8139Device {
     fn get_current_tx_buffer(&mut self) -> Result<&'static mut [u8], iface::PhyNetdevError> {
-        let tx_id = (self.tx_line.tx_id + 1) % RTL_N_TX_BUFFERS;
-        if tx_id >= RTL_N_TX_BUFFERS {
+        let tx_id = self.tx_line.next_id();
+        // the frame might still be in the buffer, don't overwrite it.
+        if !self.tx_line.wait_free(tx_id) {
             return Err(iface::PhyNetdevError::NoTxBuffer);
         }
 
-        Ok(self.buffers.tx_dma[tx_id].get_mut_slice::<u8>())
+        let buffer = self.buffers.tx_dma[tx_id].get_mut_slice::<u8>();
+        Ok(&mut buffer[0..RTL_MAX_FRAME_SIZE])
     }
 
     fn transmit_and_wait(
@@ -352,9 +417,16 @@ impl iface::PhysicalNetworkDevice for Realtek8139Device {
         _buffer: &mut [u8],
         length: usize,
     ) -> Result<(), iface::PhyNetdevError> {
+        if length > RTL_MAX_FRAME_SIZE || length > RTL_TX_BUFFER_SIZE {
+            return Err(iface::PhyNetdevError::FrameTooLarge);
+        }
+
+        let tx_id = self.tx_line.next_id();
+        if !self.tx_line.is_free(tx_id) {
+            return Err(iface::PhyNetdevError::NoTxBuffer);
+        }
 
-        self.tx_line.tx_id = (self.tx_line.tx_id + 1) % RTL_N_TX_BUFFERS;
-        
+        self.tx_line.tx_id = tx_id;
         self.ack_probable_isr();
 
         let current_imr = self.config.imr.read_u16();
@@ -364,19 +436,22 @@ impl iface::PhysicalNetworkDevice for Realtek8139Device {
 
         // get current command port
         let tx_cmd_port = self.tx_line.cmds[self.tx_line.tx_id];
-        // write the length:
+        // write the length, this clears OWN and hands the buffer to the device.
         tx_cmd_port.write_u32((RTL_LENGTH_BITS & length) as u32);
+        self.tx_line.in_flight[tx_id] = true;
 
-        // wait for packet to be moved from DMA to FIFO queue
-        while (tx_cmd_port.read_u32() as usize & RTL_DMA_COMPLETE) != RTL_DMA_COMPLETE {}
-        // wait for Tx to complete
-        while (tx_cmd_port.read_u32() as usize & RTL_TX_OK) != RTL_TX_OK {}
+        // wait for the frame to leave the buffer, the descriptor stays tracked
+        // as in-flight until its completion is seen, so a slow transmit is not
+        // overwritten by the next frame.
+        let done = self.tx_line.wait_free(tx_id);
 
-        // Tx is not complete
         // write back the current imr
         self.config.imr.write_u16(current_imr);
 
         self.ack_probable_isr();
+        if !done {
+            log::debug!("RTL8139: descriptor {} still in flight", tx_id);
+        }
         Ok(())
     }
 
@@ -421,7 +496,6 @@ impl iface::PhysicalNetworkDevice for Realtek8139Device {
     }
 
     fn set_polling_mode(&mut self, enable: bool) -> Result<(), iface::PhyNetdevError> {
-
         if enable {
             self.config.imr.write_u16(0x0000);
             self.is_polling = true;
@@ -451,6 +525,21 @@ impl iface::PhysicalNetworkDevice for Realtek8139Device {
 
         Err(iface::PhyNetdevError::PollingModeError)
     }
+
+    fn set_rx_interrupts(&mut self, enable: bool) -> Result<(), iface::PhyNetdevError> {
+        if self.is_polling {
+            return Err(iface::PhyNetdevError::PollingModeError);
+        }
+
+        let imr = self.config.imr.read_u16() as usize;
+        let imr = if enable {
+            imr | RTL_INTERRUPT_RECVOK
+        } else {
+            imr & !RTL_INTERRUPT_RECVOK
+        };
+        self.config.imr.write_u16(imr as u16);
+        Ok(())
+    }
 }
 
 unsafe impl Sync for RTLDeviceCommand {}
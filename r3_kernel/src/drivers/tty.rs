@@ -8,11 +8,20 @@ use crate::drivers::display::font::{FONT_HEIGHT, FONT_WIDTH};
 use crate::drivers::display::framebuffer::{Framebuffer, Pixel};
 use crate::drivers::keyboard::PC_KEYBOARD;
 
+use crate::logging;
+use crate::mm::VirtualAddress;
+use crate::system;
+use crate::system::abi;
 use crate::system::filesystem::devfs::{DevFSDescriptor, DevOps};
 use crate::system::filesystem::{FSError, SeekType};
-use crate::system::tasking::wait_until_return;
+use crate::system::process::{self, PID};
+use crate::system::tasking::{
+    self, schedule_yield, wait_until_return, Sched, ThreadSuspendType, SCHEDULER,
+};
+use crate::system::thread::{self, ThreadID};
 
 use alloc::{format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -20,6 +29,72 @@ const END_OF_TEXT: char = '\x03';
 const END_OF_TRANSFER: char = '\x04';
 const BACKSPACE: char = '\x08';
 const ESCAPE: char = '\x1B';
+const SUSPEND: char = '\x1A';
+
+/// ioctl commands to get and set the foreground process, same as linux.
+const TIOCGPGRP: usize = 0x540F;
+const TIOCSPGRP: usize = 0x5410;
+/// ioctl command that feeds a byte to the terminal as if it was typed.
+const TIOCSTI: usize = 0x5412;
+
+/// ioctl commands of termios, TCSETSF also drops the pending input.
+const TCGETS: usize = 0x5401;
+const TCSETS: usize = 0x5402;
+const TCSETSW: usize = 0x5403;
+const TCSETSF: usize = 0x5404;
+
+/// the c_lflag bits used by the terminal, the other flags are only kept.
+const ISIG: u32 = 0o000001;
+const ICANON: u32 = 0o000002;
+const ECHO: u32 = 0o000010;
+
+/// exit code of processes killed by ^C, same as a shell reports SIGINT.
+pub const INTERRUPTED_EXIT_CODE: i64 = 128 + 2;
+
+const NO_INTERRUPT_THREAD: u64 = u64::MAX;
+const NO_PENDING_INTERRUPT: u64 = u64::MAX;
+
+/// the thread that kills the foreground process on ^C, the keyboard interrupt
+/// can't take the process and scheduler locks itself.
+static INTERRUPT_THREAD: AtomicU64 = AtomicU64::new(NO_INTERRUPT_THREAD);
+/// pid of the foreground process at the last ^C, until the thread kills it.
+static PENDING_INTERRUPT: AtomicU64 = AtomicU64::new(NO_PENDING_INTERRUPT);
+
+const NCCS: usize = 19;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+/// struct termios of the TCGETS and TCSETS ioctls, same layout as linux.
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; NCCS],
+}
+
+impl Default for Termios {
+    /// canonical mode with echo, ^C kills the foreground process.
+    fn default() -> Self {
+        Termios {
+            c_iflag: 0,
+            c_oflag: 0,
+            c_cflag: 0,
+            c_lflag: ISIG | ICANON | ECHO,
+            c_line: 0,
+            c_cc: [0; NCCS],
+        }
+    }
+}
+
+/// the characters that complete a line in canonical mode.
+#[inline]
+fn is_line_end(ch: char) -> bool {
+    // ^C without a foreground process or ISIG, and ^Z, complete the read right away.
+    // TODO: deliver ^Z as a signal once signals are supported
+    ch == '\n' || ch == END_OF_TEXT || ch == SUSPEND
+}
 
 lazy_static! {
     pub static ref STDIN_QUEUE: Mutex<InputQueue> = Mutex::new(InputQueue::empty());
@@ -55,6 +130,34 @@ impl InputQueue {
         self.keybuf.pop().map(|byte| byte as char)
     }
 
+    /// pops the last character of the line being typed, a complete line
+    /// waiting to be read can't be edited anymore.
+    #[inline]
+    pub fn pop_last_unfinished(&mut self) -> Option<char> {
+        match self.keybuf.last() {
+            Some(last) if !is_line_end(*last as char) => self.pop_last(),
+            _ => None,
+        }
+    }
+
+    /// length of the first complete line, with the character that ended it.
+    #[inline]
+    pub fn line_length(&self) -> Option<usize> {
+        self.keybuf
+            .iter()
+            .position(|byte| is_line_end(*byte as char))
+            .map(|index| index + 1)
+    }
+
+    /// moves up to length bytes into the buffer, returns how many were moved.
+    #[inline]
+    pub fn take(&mut self, buffer: &mut [u8], length: usize) -> usize {
+        let length = core::cmp::min(length, buffer.len());
+        buffer[0..length].copy_from_slice(&self.keybuf[0..length]);
+        self.keybuf.drain(0..length);
+        length
+    }
+
     #[inline]
     pub fn drain(&mut self) {
         self.keybuf.clear();
@@ -79,32 +182,37 @@ pub struct BlockingSystemTerminal {
     pub lines: FramebufferLines,
     pub color: Pixel,
     pub echo_input: bool,
+    /// canonical mode, backspace edits the input and reads return whole lines.
     pub parse: bool,
     pub max_rows: usize,
     pub max_cols: usize,
+    /// process that owns the keyboard input, anyone can read if not set.
+    pub foreground: Option<PID>,
+    /// the flags set with TCSETS, ICANON and ECHO are kept in parse and echo_input.
+    pub termios: Termios,
 }
 
 impl BlockingSystemTerminal {
     pub fn clear(&mut self) {
-        let mut fb = Framebuffer::get_buffer_lock().as_ref().unwrap().lock();
         // clear off the framebuffer
-        Framebuffer::fill(
-            &mut fb,
-            Pixel {
-                r: 0,
-                g: 0,
-                b: 0,
-                channel: 0,
-            },
-        );
+        Framebuffer::with_buffer(|fb| {
+            Framebuffer::fill(
+                fb,
+                Pixel {
+                    r: 0,
+                    g: 0,
+                    b: 0,
+                    channel: 0,
+                },
+            )
+        });
 
         self.lines.row_line = 0;
         self.lines.col_line = 0;
     }
 
     pub fn new() -> Self {
-        let fb = Framebuffer::get_buffer_lock().as_ref().unwrap().lock();
-        // clear off the framebuffer
+        let (width, height) = Framebuffer::with_buffer(|fb| (fb.width, fb.height)).unwrap();
         BlockingSystemTerminal {
             lines: FramebufferLines {
                 row_line: 0,
@@ -118,8 +226,10 @@ impl BlockingSystemTerminal {
             },
             echo_input: true,
             parse: true,
-            max_cols: fb.width / FONT_WIDTH,
-            max_rows: fb.height / FONT_HEIGHT,
+            max_cols: width / FONT_WIDTH,
+            max_rows: height / FONT_HEIGHT,
+            foreground: None,
+            termios: Termios::default(),
         }
     }
 
@@ -143,52 +253,74 @@ impl BlockingSystemTerminal {
         self.echo_input = true;
     }
 
+    /// true if ^C should kill the foreground process instead of being read.
+    #[inline]
+    fn interrupts_foreground(&self, key: char) -> bool {
+        key == END_OF_TEXT
+            && self.termios.c_lflag & ISIG != 0
+            && self.foreground.is_some()
+            && INTERRUPT_THREAD.load(Ordering::SeqCst) != NO_INTERRUPT_THREAD
+    }
+
     #[inline]
     pub fn process_key(&mut self, key: char) {
         let mut input_queue = STDIN_QUEUE.lock();
 
         // match special tokens
-        // 1. backspace
+        // 1. ^C, the input typed for the killed process is dropped with it.
+        if self.interrupts_foreground(key) {
+            input_queue.drain();
+            let pid = self.foreground.as_ref().unwrap().as_u64();
+            PENDING_INTERRUPT.store(pid, Ordering::SeqCst);
+            tasking::wake_thread(ThreadID::new(INTERRUPT_THREAD.load(Ordering::SeqCst)));
+
+            if self.echo_input {
+                let (lines, color) = (&mut self.lines, self.color);
+                Framebuffer::with_buffer(|fb| {
+                    *lines = FramebufferText::print_string(fb, &format!("^C"), color, lines);
+                    FramebufferText::print_string(fb, &format!("_"), color, lines);
+                });
+            }
+            return;
+        }
+
+        // 2. backspace
         if key == BACKSPACE && self.parse {
             // this is a backspace
-            if let Some(last_char) = input_queue.pop_last() {
+            if let Some(last_char) = input_queue.pop_last_unfinished() {
                 // how many times do we pop?
                 if self.echo_input {
                     let n_times = match last_char {
-                        END_OF_TEXT | END_OF_TRANSFER | ESCAPE => 2,
+                        END_OF_TEXT | END_OF_TRANSFER | ESCAPE | SUSPEND => 2,
                         _ => 1,
                     };
-                    let mut fb = Framebuffer::get_buffer_lock().as_ref().unwrap().lock();
-                    for _ in 0..n_times {
-                        FramebufferText::print_backspace(
-                            &mut fb,
-                            &mut self.lines,
-                            self.max_cols,
-                            self.color,
-                        );
-                    }
-
-                    FramebufferText::print_string(&mut fb, &format!("_"), self.color, &self.lines);
+                    let (lines, max_cols, color) = (&mut self.lines, self.max_cols, self.color);
+                    Framebuffer::with_buffer(|fb| {
+                        for _ in 0..n_times {
+                            FramebufferText::print_backspace(fb, lines, max_cols, color);
+                        }
+
+                        FramebufferText::print_string(fb, &format!("_"), color, lines);
+                    });
                 }
             }
         } else {
             // write the char to the buffer:
             input_queue.push(key);
             if self.echo_input {
-                let mut fb = Framebuffer::get_buffer_lock().as_ref().unwrap().lock();
                 let to_write = match key {
                     END_OF_TEXT => format!("^C"),
                     END_OF_TRANSFER => format!("^D"),
                     ESCAPE => format!("^["),
+                    SUSPEND => format!("^Z"),
                     _ => format!("{}", key),
                 };
 
-                let new_lines =
-                    FramebufferText::print_string(&mut fb, &to_write, self.color, &self.lines);
-
-                self.lines = new_lines;
-
-                FramebufferText::print_string(&mut fb, &format!("_"), self.color, &self.lines);
+                let (lines, color) = (&mut self.lines, self.color);
+                Framebuffer::with_buffer(|fb| {
+                    *lines = FramebufferText::print_string(fb, &to_write, color, lines);
+                    FramebufferText::print_string(fb, &format!("_"), color, lines);
+                });
             }
         }
     }
@@ -198,10 +330,19 @@ impl BlockingSystemTerminal {
         let string = String::from_utf8_lossy(buffer);
 
         // write this string:
-        let mut fb_lock = Framebuffer::get_buffer_lock().as_ref().unwrap().lock();
-        let new_lines =
-            FramebufferText::print_string(&mut fb_lock, &string, self.color, &self.lines);
-        self.lines = new_lines;
+        let new_lines = Framebuffer::with_buffer(|fb| {
+            FramebufferText::print_string(fb, &string, self.color, &self.lines)
+        });
+
+        if let Some(lines) = new_lines {
+            self.lines = lines;
+        }
+    }
+
+    /// rubs out the last character on the screen, the input queue is not touched.
+    pub fn erase_char(&mut self) {
+        let (lines, max_cols, color) = (&mut self.lines, self.max_cols, self.color);
+        Framebuffer::with_buffer(|fb| FramebufferText::print_backspace(fb, lines, max_cols, color));
     }
 
     #[inline]
@@ -223,6 +364,31 @@ impl BlockingSystemTerminal {
         Ok(())
     }
 
+    /// sets the foreground process, pending input was typed for the old one so it is dropped.
+    #[inline]
+    pub fn set_foreground(&mut self, pid: Option<PID>) {
+        self.foreground = pid;
+        STDIN_QUEUE.lock().drain();
+    }
+
+    pub fn get_termios(&self) -> Termios {
+        let mut termios = self.termios;
+        termios.c_lflag &= !(ICANON | ECHO);
+        if self.parse {
+            termios.c_lflag |= ICANON;
+        }
+        if self.echo_input {
+            termios.c_lflag |= ECHO;
+        }
+        termios
+    }
+
+    pub fn set_termios(&mut self, termios: Termios) {
+        self.termios = termios;
+        self.parse = termios.c_lflag & ICANON != 0;
+        self.echo_input = termios.c_lflag & ECHO != 0;
+    }
+
     #[inline]
     pub fn end(&mut self) -> usize {
         self.lines.col_line = self.max_cols;
@@ -231,70 +397,141 @@ impl BlockingSystemTerminal {
     }
 }
 
+/// is the current process allowed to read the keyboard input?
 #[inline]
-pub fn polling_pop() -> char {
-    cpu::enable_interrupts();
+fn is_foreground_reader() -> bool {
+    let foreground = SYSTEM_TTY.lock().foreground.clone();
+    if foreground.is_none() {
+        return true;
+    }
 
-    loop {
-        cpu::halt();
-        cpu::disable_interrupts();
+    match system::current_pid() {
+        Some(pid) => pid.as_u64() == foreground.unwrap().as_u64(),
+        // kernel context
+        None => true,
+    }
+}
 
-        let read_char = STDIN_QUEUE.lock().pop_first();
+/// releases the foreground ownership if the given process holds it, called on exit.
+pub fn release_foreground(pid: &PID) {
+    let mut tty_lock = SYSTEM_TTY.lock();
+    let is_owner = match &tty_lock.foreground {
+        Some(fg_pid) => fg_pid.as_u64() == pid.as_u64(),
+        None => false,
+    };
+
+    if is_owner {
+        tty_lock.set_foreground(None);
+    }
 
-        cpu::enable_interrupts();
+    // the pid can be handed out again, the ^C was not for the new process.
+    let _ = PENDING_INTERRUPT.compare_exchange(
+        pid.as_u64(),
+        NO_PENDING_INTERRUPT,
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+    );
+}
 
-        if read_char.is_some() {
-            return read_char.unwrap();
+/// sleeps until a ^C wakes the thread. the keyboard interrupt can't come in
+/// between the check and the suspend, so the wakeup is not missed.
+fn park_interrupt_thread() {
+    cpu::without_interrupts(|| {
+        if PENDING_INTERRUPT.load(Ordering::SeqCst) != NO_PENDING_INTERRUPT {
+            return;
+        }
+
+        SCHEDULER
+            .lock()
+            .suspend_thread(ThreadSuspendType::SuspendUntilWoken);
+        schedule_yield();
+    });
+}
+
+fn interrupt_loop() -> ! {
+    loop {
+        let pid = PENDING_INTERRUPT.swap(NO_PENDING_INTERRUPT, Ordering::SeqCst);
+        if pid == NO_PENDING_INTERRUPT {
+            park_interrupt_thread();
+            continue;
+        }
+
+        // init and kernel processes can be in the foreground, they stay.
+        if let Err(err) = system::kill_process(&PID::new(pid), INTERRUPTED_EXIT_CODE) {
+            log::debug!("tty: ^C did not kill process {}: {}", pid, err);
         }
     }
 }
 
-pub fn polling_read_till(till: char, buffer: &mut [u8]) -> usize {
-    // if disabled, enable interrupts to make sure we don't halt infinitely!
-    cpu::enable_interrupts();
+fn interrupt_thread() {
+    interrupt_loop();
+}
 
-    loop {
-        cpu::halt();
+/// starts the kernel thread that kills the foreground process on ^C, needs the
+/// scheduler. without it ^C is read like any other character.
+pub fn start_interrupt_thread() {
+    let process_res = process::new(format!("kernel_tty"), false, "");
+    if process_res.is_err() {
+        log::error!(
+            "tty: failed to create the interrupt process: {:?}",
+            process_res.unwrap_err()
+        );
+        return;
+    }
 
-        let mut stdin = STDIN_QUEUE.lock();
-        let read_size = if !stdin.keybuf.is_empty() && stdin.has_end(till) {
-            stdin.keybuf.truncate(buffer.len() - 1);
-            let keybuf_length = stdin.keybuf.len();
-            buffer[0..keybuf_length].copy_from_slice(&stdin.keybuf);
-            stdin.drain();
-            keybuf_length
-        } else {
-            0
-        };
+    let thread_res = thread::new_from_function(
+        &process_res.unwrap(),
+        format!("tty_interrupt"),
+        VirtualAddress::from_u64(interrupt_thread as fn() as u64),
+    );
 
-        if read_size != 0 {
-            return read_size;
-        }
+    if thread_res.is_err() {
+        log::error!(
+            "tty: failed to start the interrupt thread: {:?}",
+            thread_res.unwrap_err()
+        );
+        return;
     }
+
+    INTERRUPT_THREAD.store(thread_res.unwrap().as_u64(), Ordering::SeqCst);
+    log::info!("Started the terminal interrupt thread.");
 }
 
-pub fn blocked_read_till(till: char, buffer: &mut [u8]) -> Result<usize, FSError> {
+/// the line discipline, in canonical mode the read waits for a complete line and
+/// returns as much of it as fits in the buffer, the rest is left for the next read.
+/// in raw mode it waits for the first byte and returns whatever was typed.
+pub fn blocked_read(buffer: &mut [u8]) -> Result<usize, FSError> {
+    if buffer.is_empty() {
+        return Ok(0);
+    }
+
     cpu::enable_interrupts();
-    let ret_val = wait_until_return(|| {
+    wait_until_return(|| {
+        // the foreground might have changed while we were waiting.
+        if !is_foreground_reader() {
+            return Err(FSError::InvalidOperation);
+        }
+
+        Ok(take_input(buffer))
+    })
+}
+
+/// moves what a read can return now into the buffer, None if it has to wait.
+fn take_input(buffer: &mut [u8]) -> Option<usize> {
+    // the keyboard interrupt takes the same locks.
+    cpu::without_interrupts(|| {
+        let canonical = SYSTEM_TTY.lock().parse;
         let mut stdin = STDIN_QUEUE.lock();
-        let read_size = if !stdin.keybuf.is_empty() && stdin.has_end(till) {
-            stdin.keybuf.truncate(buffer.len() - 1);
-            let keybuf_length = stdin.keybuf.len();
-            buffer[0..keybuf_length].copy_from_slice(&stdin.keybuf);
-            stdin.drain();
-            keybuf_length
+        let length = if canonical {
+            stdin.line_length()
+        } else if !stdin.keybuf.is_empty() {
+            Some(stdin.keybuf.len())
         } else {
-            0
+            None
         };
 
-        if read_size != 0 {
-            return Ok(Some(read_size));
-        }
-
-        Ok(None)
-    });
-
-    ret_val
+        length.map(|length| stdin.take(buffer, length))
+    })
 }
 
 pub fn on_kbd_data(c: char) {
@@ -328,18 +565,12 @@ impl DevOps for TTYDriver {
     }
 
     fn read(&self, _fd: &mut DevFSDescriptor, buffer: &mut [u8]) -> Result<usize, FSError> {
-        let read_result = if buffer.len() <= 4 {
-            // read a single character
-            let ch = polling_pop();
-            buffer[0] = ch as u8;
-            Ok(1)
-        } else {
-            // read until the end
-            let blocked_read_result = blocked_read_till('\n', buffer);
-            blocked_read_result
-        };
+        // background processes can't consume the input of foreground process.
+        if !is_foreground_reader() {
+            return Err(FSError::InvalidOperation);
+        }
 
-        read_result
+        blocked_read(buffer)
     }
 
     fn seek(&self, fd: &mut DevFSDescriptor, offset: u32, st: SeekType) -> Result<u32, FSError> {
@@ -371,8 +602,70 @@ impl DevOps for TTYDriver {
         Ok(fd.offset)
     }
 
-    fn ioctl(&self, _command: usize, _arg: usize) -> Result<usize, FSError> {
-        Ok(0)
+    fn ioctl(&self, command: usize, arg: usize) -> Result<usize, FSError> {
+        match command {
+            TIOCGPGRP => {
+                if !abi::is_in_userspace(arg as u64) {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                let foreground = SYSTEM_TTY.lock().foreground.clone();
+                let pid_ref: &mut abi::CInt = unsafe { &mut *(arg as *mut abi::CInt) };
+                *pid_ref = foreground.map(|pid| pid.as_u64() as abi::CInt).unwrap_or(0);
+                Ok(0)
+            }
+            TIOCSPGRP => {
+                if !abi::is_in_userspace(arg as u64) {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                let pid: abi::CInt = unsafe { *(arg as *const abi::CInt) };
+                if pid <= 0 {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                // a pid that is not alive would keep the terminal from everyone.
+                if !process::pid_exists(&PID::new(pid as u64)) {
+                    return Err(FSError::NoSuchProcess);
+                }
+
+                SYSTEM_TTY.lock().set_foreground(Some(PID::new(pid as u64)));
+                Ok(0)
+            }
+            TIOCSTI => {
+                if !abi::is_in_userspace(arg as u64) {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                let byte: u8 = unsafe { *(arg as *const u8) };
+                // the keyboard interrupt takes the terminal too.
+                cpu::without_interrupts(|| SYSTEM_TTY.lock().process_key(byte as char));
+                Ok(0)
+            }
+            TCGETS => {
+                if !abi::is_in_userspace(arg as u64) {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                let termios_ref: &mut Termios = unsafe { &mut *(arg as *mut Termios) };
+                *termios_ref = SYSTEM_TTY.lock().get_termios();
+                Ok(0)
+            }
+            TCSETS | TCSETSW | TCSETSF => {
+                if !abi::is_in_userspace(arg as u64) {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                // the output is never queued, so TCSETSW has nothing to wait for.
+                let termios: Termios = unsafe { *(arg as *const Termios) };
+                SYSTEM_TTY.lock().set_termios(termios);
+                if command == TCSETSF {
+                    STDIN_QUEUE.lock().drain();
+                }
+                Ok(0)
+            }
+            _ => Ok(0),
+        }
     }
 }
 
@@ -383,9 +676,73 @@ unsafe impl Sync for TTYDriver {}
 pub fn initialize() {
     // touch the tty device:
     SYSTEM_TTY.lock().clear();
+    // the log would be drawn over the terminal, it stays on the serial port and in kmsg.
+    logging::disable_framebuffer_output();
     STDIN_QUEUE.lock().drain();
+
+    #[cfg(feature = "debug_checks")]
+    test_line_discipline();
+
     // set this as default keyboard consumer:
     register_consumer();
 
     log::debug!("Initialized system terminal.");
 }
+
+/// types into the queue the way the keyboard does, and reads it back like a process.
+#[cfg(feature = "debug_checks")]
+fn test_line_discipline() {
+    fn type_input(input: &[u8]) {
+        for byte in input {
+            SYSTEM_TTY.lock().process_key(*byte as char);
+        }
+    }
+
+    fn expect_read(buffer: &mut [u8], expected: &[u8]) {
+        assert_eq!(take_input(buffer), Some(expected.len()));
+        assert_eq!(&buffer[0..expected.len()], expected);
+    }
+
+    let termios = SYSTEM_TTY.lock().get_termios();
+    let mut mode = termios;
+
+    // echo is off, so nothing is drawn.
+    mode.c_lflag = ICANON;
+    SYSTEM_TTY.lock().set_termios(mode);
+    type_input(b"hello\nworld\nab\x08c\n");
+
+    // a large buffer gets one line, not everything that was typed.
+    let mut large: [u8; 256] = [0; 256];
+    expect_read(&mut large, b"hello\n");
+
+    // a small one gets the line in pieces.
+    let mut small: [u8; 3] = [0; 3];
+    expect_read(&mut small, b"wor");
+    expect_read(&mut small, b"ld\n");
+
+    // backspace edited the line before it was complete.
+    expect_read(&mut large, b"ac\n");
+
+    // an unfinished line is not returned, and the line before can't be edited.
+    type_input(b"x\n\x08\x08y");
+    expect_read(&mut large, b"x\n");
+    assert_eq!(take_input(&mut large), None);
+    STDIN_QUEUE.lock().drain();
+
+    mode.c_lflag = 0;
+    SYSTEM_TTY.lock().set_termios(mode);
+    assert_eq!(take_input(&mut large), None);
+    type_input(b"xy\x08");
+
+    // no newline is needed, and a single byte can be read.
+    let mut single: [u8; 1] = [0; 1];
+    expect_read(&mut single, b"x");
+
+    // a large buffer gets what is there, backspace is just a byte.
+    expect_read(&mut large, b"y\x08");
+    assert_eq!(take_input(&mut large), None);
+
+    SYSTEM_TTY.lock().set_termios(termios);
+    STDIN_QUEUE.lock().drain();
+    log::info!("Passed line discipline test.");
+}
@@ -0,0 +1,570 @@
+extern crate alloc;
+extern crate log;
+extern crate spin;
+
+use crate::acpi::rsdt::{SDTHeader, ACPI};
+use crate::cpu::io::Port;
+use crate::drivers::pci::{self, PCIDevice};
+use crate::mm::io::{self, MemoryIO};
+use crate::mm::VirtualAddress;
+use crate::system::process;
+use crate::system::tasking::{idle, schedule_yield, Sched, ThreadSuspendType, SCHEDULER};
+use crate::system::thread;
+
+use alloc::{boxed::Box, format, vec::Vec};
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// hardware watchdog for headless machines: once armed, a kernel thread pets it
+// every tick it gets, if the kernel hangs and the thread stops running the hardware
+// resets the machine. the ACPI WDAT table is used if the firmware has one,
+// otherwise an Intel 6300ESB (`-device i6300esb` on QEMU) is looked for.
+
+/// timeout in seconds, build with `R3_WATCHDOG=<seconds>` to arm the watchdog.
+/// unset, `0` or `off` leave it disabled, there is no kernel command line.
+pub const WATCHDOG_CONFIG: Option<&'static str> = option_env!("R3_WATCHDOG");
+
+/// ticks between two pets.
+const PET_TICKS: usize = 1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum WatchdogError {
+    /// no WDAT table and no supported chipset watchdog
+    NotFound,
+    /// the firmware marked the WDAT watchdog as disabled
+    DisabledByFirmware,
+    /// the timeout is out of the range of the hardware
+    InvalidTimeout(u64),
+    /// the WDAT table has no entry for this action
+    MissingAction(u8),
+    /// register in an address space or with a width that is not supported
+    UnsupportedRegister(u8),
+    MapError(u64),
+}
+
+trait HardwareWatchdog {
+    fn name(&self) -> &'static str;
+
+    /// programs the timeout and starts the countdown.
+    fn start(&mut self, timeout_secs: u64) -> Result<(), WatchdogError>;
+
+    /// restarts the countdown.
+    fn ping(&self);
+
+    fn stop(&mut self);
+}
+
+// ACPI WDAT, the table is a list of register accesses for each action.
+const WDAT_SIGNATURE: &str = "WDAT";
+const WDAT_FLAG_ENABLED: u8 = 1 << 0;
+
+const WDAT_ACTION_RESET: u8 = 0x1;
+const WDAT_ACTION_SET_COUNTDOWN: u8 = 0x6;
+const WDAT_ACTION_SET_RUNNING_STATE: u8 = 0x9;
+const WDAT_ACTION_SET_STOPPED_STATE: u8 = 0xB;
+const WDAT_ACTION_SET_REBOOT: u8 = 0x11;
+const WDAT_ACTION_QUERY_STATUS: u8 = 0x20;
+const WDAT_ACTION_SET_STATUS: u8 = 0x21;
+
+const WDAT_READ_VALUE: u8 = 0x0;
+const WDAT_READ_COUNTDOWN: u8 = 0x1;
+const WDAT_WRITE_VALUE: u8 = 0x2;
+const WDAT_WRITE_COUNTDOWN: u8 = 0x3;
+const WDAT_PRESERVE_REGISTER: u8 = 0x80;
+
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct WDATHeader {
+    sdt: SDTHeader,
+    header_length: u32,
+    pci_segment: u16,
+    pci_bus: u8,
+    pci_dev: u8,
+    pci_func: u8,
+    reserved: [u8; 3],
+    /// milliseconds per count
+    timer_period: u32,
+    max_count: u32,
+    min_count: u32,
+    flags: u8,
+    reserved_2: [u8; 3],
+    n_entries: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct GenericAddress {
+    space_id: u8,
+    bit_width: u8,
+    bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct WDATEntry {
+    action: u8,
+    instruction: u8,
+    reserved: u16,
+    register: GenericAddress,
+    value: u32,
+    mask: u32,
+}
+
+#[derive(Clone, Copy)]
+enum WDATRegister {
+    Memory(VirtualAddress),
+    IO(usize),
+}
+
+#[derive(Clone, Copy)]
+struct WDATInstruction {
+    entry: WDATEntry,
+    register: WDATRegister,
+}
+
+impl WDATInstruction {
+    fn new(entry: WDATEntry) -> Result<Self, WatchdogError> {
+        let gas = entry.register;
+        if gas.access_size == 0 || gas.access_size > 3 {
+            return Err(WatchdogError::UnsupportedRegister(gas.access_size));
+        }
+
+        let register = match gas.space_id {
+            GAS_SYSTEM_MEMORY => {
+                let map_res = io::map_device_memory(gas.address, 4);
+                if map_res.is_err() {
+                    return Err(WatchdogError::MapError(gas.address));
+                }
+                WDATRegister::Memory(map_res.unwrap())
+            }
+            GAS_SYSTEM_IO => WDATRegister::IO(gas.address as usize),
+            _ => return Err(WatchdogError::UnsupportedRegister(gas.space_id)),
+        };
+
+        Ok(WDATInstruction { entry, register })
+    }
+
+    fn read(&self) -> u32 {
+        match (self.register, self.entry.register.access_size) {
+            (WDATRegister::Memory(addr), 1) => MemoryIO::new(addr, true).read_u8() as u32,
+            (WDATRegister::Memory(addr), 2) => MemoryIO::new(addr, true).read_u16() as u32,
+            (WDATRegister::Memory(addr), _) => MemoryIO::new(addr, true).read_u32(),
+            (WDATRegister::IO(port), 1) => Port::new(port, true).read_u8() as u32,
+            (WDATRegister::IO(port), 2) => Port::new(port, true).read_u16() as u32,
+            (WDATRegister::IO(port), _) => Port::new(port, true).read_u32(),
+        }
+    }
+
+    fn write(&self, value: u32) {
+        match (self.register, self.entry.register.access_size) {
+            (WDATRegister::Memory(addr), 1) => MemoryIO::new(addr, false).write_u8(value as u8),
+            (WDATRegister::Memory(addr), 2) => MemoryIO::new(addr, false).write_u16(value as u16),
+            (WDATRegister::Memory(addr), _) => MemoryIO::new(addr, false).write_u32(value),
+            (WDATRegister::IO(port), 1) => Port::new(port, false).write_u8(value as u8),
+            (WDATRegister::IO(port), 2) => Port::new(port, false).write_u16(value as u16),
+            (WDATRegister::IO(port), _) => Port::new(port, false).write_u32(value),
+        }
+    }
+
+    /// runs the register access, a read returns what it found.
+    fn run(&self, param: u32) -> u32 {
+        let bit_offset = self.entry.register.bit_offset as u32;
+        let mask = self.entry.mask;
+        let value = self.entry.value;
+
+        let operation = self.entry.instruction & !WDAT_PRESERVE_REGISTER;
+        let preserve = self.entry.instruction & WDAT_PRESERVE_REGISTER != 0;
+
+        match operation {
+            WDAT_READ_VALUE => {
+                let current = (self.read() >> bit_offset) & mask;
+                (current == value) as u32
+            }
+            WDAT_READ_COUNTDOWN => (self.read() >> bit_offset) & mask,
+            WDAT_WRITE_VALUE | WDAT_WRITE_COUNTDOWN => {
+                let data = if operation == WDAT_WRITE_VALUE {
+                    value
+                } else {
+                    param
+                };
+
+                let mut new_value = (data & mask) << bit_offset;
+                if preserve {
+                    new_value |= self.read() & !(mask << bit_offset);
+                }
+                self.write(new_value);
+                0
+            }
+            _ => {
+                log::debug!("watchdog: unknown WDAT instruction 0x{:x}", operation);
+                0
+            }
+        }
+    }
+}
+
+struct WDATWatchdog {
+    timer_period: u32,
+    min_count: u32,
+    max_count: u32,
+    instructions: Vec<WDATInstruction>,
+}
+
+impl WDATWatchdog {
+    fn from_table(table: VirtualAddress) -> Result<Self, WatchdogError> {
+        let header: WDATHeader = unsafe { *table.get_ptr() };
+        if header.flags & WDAT_FLAG_ENABLED == 0 {
+            return Err(WatchdogError::DisabledByFirmware);
+        }
+
+        // the entries follow the header, but never past the end of the table.
+        let entries_start = table.as_u64() + mem::size_of::<WDATHeader>() as u64;
+        let max_entries = (header.sdt.length as usize).saturating_sub(mem::size_of::<WDATHeader>())
+            / mem::size_of::<WDATEntry>();
+        let n_entries = core::cmp::min(header.n_entries as usize, max_entries);
+
+        let mut instructions = Vec::with_capacity(n_entries);
+        for index in 0..n_entries {
+            let entry_addr = entries_start + (index * mem::size_of::<WDATEntry>()) as u64;
+            let entry: WDATEntry = unsafe { *(entry_addr as *const WDATEntry) };
+            instructions.push(WDATInstruction::new(entry)?);
+        }
+
+        Ok(WDATWatchdog {
+            timer_period: header.timer_period,
+            min_count: header.min_count,
+            max_count: header.max_count,
+            instructions,
+        })
+    }
+
+    /// an action is a sequence of instructions, the result is the one of the last.
+    fn run_action(&self, action: u8, param: u32) -> Result<u32, WatchdogError> {
+        let mut found = false;
+        let mut result = 0;
+        for instruction in self.instructions.iter() {
+            if instruction.entry.action == action {
+                found = true;
+                result = instruction.run(param);
+            }
+        }
+
+        if !found {
+            return Err(WatchdogError::MissingAction(action));
+        }
+        Ok(result)
+    }
+}
+
+impl HardwareWatchdog for WDATWatchdog {
+    fn name(&self) -> &'static str {
+        "ACPI WDAT"
+    }
+
+    fn start(&mut self, timeout_secs: u64) -> Result<(), WatchdogError> {
+        let count = (timeout_secs * 1000) / core::cmp::max(self.timer_period, 1) as u64;
+        if count < self.min_count as u64 || count > self.max_count as u64 {
+            return Err(WatchdogError::InvalidTimeout(timeout_secs));
+        }
+
+        if let Ok(1) = self.run_action(WDAT_ACTION_QUERY_STATUS, 0) {
+            log::warn!("watchdog: the last boot ended with a watchdog reset.");
+            let _ = self.run_action(WDAT_ACTION_SET_STATUS, 0);
+        }
+
+        // some firmware only knows how to reboot, the action is optional.
+        let _ = self.run_action(WDAT_ACTION_SET_REBOOT, 0);
+
+        let countdown_res = self.run_action(WDAT_ACTION_SET_COUNTDOWN, count as u32);
+        if countdown_res.is_err() {
+            return Err(countdown_res.unwrap_err());
+        }
+
+        let running_res = self.run_action(WDAT_ACTION_SET_RUNNING_STATE, 0);
+        if running_res.is_err() {
+            return Err(running_res.unwrap_err());
+        }
+
+        self.ping();
+        Ok(())
+    }
+
+    fn ping(&self) {
+        let _ = self.run_action(WDAT_ACTION_RESET, 0);
+    }
+
+    fn stop(&mut self) {
+        let _ = self.run_action(WDAT_ACTION_SET_STOPPED_STATE, 0);
+    }
+}
+
+// Intel 6300ESB, two stages count down from the preload value. the first one
+// would raise an interrupt (turned off here), the second one resets the machine.
+const ESB_WATCHDOG: (u16, u16) = (0x25AB, 0x8086);
+
+const ESB_CONFIG_REG: u8 = 0x60;
+const ESB_LOCK_REG: u8 = 0x68;
+
+const ESB_TIMER1_REG: u64 = 0x00;
+const ESB_TIMER2_REG: u64 = 0x04;
+const ESB_RELOAD_REG: u64 = 0x0C;
+const ESB_BAR_SIZE: u64 = 0x10;
+
+/// no interrupt after the first stage, reboot after the second, ~1KHz clock.
+const ESB_CONFIG_NO_INTERRUPT: u16 = 0x3;
+const ESB_LOCK_ENABLE: u8 = 1 << 1;
+const ESB_LOCK_LOCKED: u8 = 1 << 0;
+
+const ESB_RELOAD: u16 = 1 << 8;
+const ESB_TIMEOUT_FLAG: u16 = 1 << 9;
+/// the preload and reload registers are writable only after this sequence.
+const ESB_UNLOCK: (u16, u16) = (0x80, 0x86);
+
+/// the preload is 20 bits, each stage counts half of the timeout.
+const ESB_MAX_TIMEOUT_SECS: u64 = 2046;
+const ESB_PRELOAD_SHIFT: u64 = 9;
+
+struct ESBWatchdog {
+    device: PCIDevice,
+    registers: VirtualAddress,
+}
+
+impl ESBWatchdog {
+    fn probe() -> Result<Self, WatchdogError> {
+        let device = pci::search_device(ESB_WATCHDOG.1, ESB_WATCHDOG.0);
+        if device.is_none() {
+            return Err(WatchdogError::NotFound);
+        }
+
+        let device = device.unwrap();
+        let bar = device.bars[0];
+        if bar == 0 || bar & 0x1 == 0x1 {
+            return Err(WatchdogError::UnsupportedRegister(GAS_SYSTEM_IO));
+        }
+
+        let map_res = io::map_device_memory((bar & 0xFFFF_FFF0) as u64, ESB_BAR_SIZE);
+        if map_res.is_err() {
+            return Err(WatchdogError::MapError(bar as u64));
+        }
+
+        // memory space
+        let command = device.read_config_u16(0x04);
+        device.write_config_u16(0x04, command | (1 << 1));
+
+        Ok(ESBWatchdog {
+            device,
+            registers: map_res.unwrap(),
+        })
+    }
+
+    #[inline]
+    fn register(&self, offset: u64) -> MemoryIO {
+        MemoryIO::new(
+            VirtualAddress::from_u64(self.registers.as_u64() + offset),
+            false,
+        )
+    }
+
+    #[inline]
+    fn unlock(&self) {
+        self.register(ESB_RELOAD_REG).write_u16(ESB_UNLOCK.0);
+        self.register(ESB_RELOAD_REG).write_u16(ESB_UNLOCK.1);
+    }
+}
+
+impl HardwareWatchdog for ESBWatchdog {
+    fn name(&self) -> &'static str {
+        "Intel 6300ESB"
+    }
+
+    fn start(&mut self, timeout_secs: u64) -> Result<(), WatchdogError> {
+        if timeout_secs == 0 || timeout_secs > ESB_MAX_TIMEOUT_SECS {
+            return Err(WatchdogError::InvalidTimeout(timeout_secs));
+        }
+
+        if self.device.read_config_u8(ESB_LOCK_REG) & ESB_LOCK_LOCKED != 0 {
+            log::warn!("watchdog: 6300ESB is locked by the firmware, the timeout may not apply.");
+        }
+
+        self.device
+            .write_config_u16(ESB_CONFIG_REG, ESB_CONFIG_NO_INTERRUPT);
+
+        // clear a timeout left by the last boot.
+        self.unlock();
+        self.register(ESB_RELOAD_REG)
+            .write_u16(ESB_TIMEOUT_FLAG | ESB_RELOAD);
+
+        let preload = (timeout_secs << ESB_PRELOAD_SHIFT) as u32;
+        self.unlock();
+        self.register(ESB_TIMER1_REG).write_u32(preload);
+        self.unlock();
+        self.register(ESB_TIMER2_REG).write_u32(preload);
+
+        self.ping();
+        self.device.write_config_u8(ESB_LOCK_REG, ESB_LOCK_ENABLE);
+        Ok(())
+    }
+
+    fn ping(&self) {
+        self.unlock();
+        self.register(ESB_RELOAD_REG).write_u16(ESB_RELOAD);
+    }
+
+    fn stop(&mut self) {
+        self.ping();
+        self.device.write_config_u8(ESB_LOCK_REG, 0);
+    }
+}
+
+lazy_static! {
+    static ref WATCHDOG: Mutex<Option<Box<dyn HardwareWatchdog + Send>>> = Mutex::new(None);
+}
+
+/// false once the watchdog is disabled, the pet thread then stops.
+static ARMED: AtomicBool = AtomicBool::new(false);
+
+fn find_watchdog() -> Result<Box<dyn HardwareWatchdog + Send>, WatchdogError> {
+    let wdat_table = ACPI
+        .lock()
+        .as_ref()
+        .and_then(|acpi| acpi.get_table(WDAT_SIGNATURE));
+
+    if let Some(table) = wdat_table {
+        match WDATWatchdog::from_table(table) {
+            Ok(wdat) => return Ok(Box::new(wdat)),
+            Err(err) => log::warn!("watchdog: WDAT table can't be used: {:?}", err),
+        }
+    }
+
+    match ESBWatchdog::probe() {
+        Ok(esb) => Ok(Box::new(esb)),
+        Err(err) => Err(err),
+    }
+}
+
+/// the timeout given at build time, None if the watchdog is off.
+fn configured_timeout() -> Option<u64> {
+    let config = WATCHDOG_CONFIG?;
+    if config.is_empty() || config == "off" || config == "0" {
+        return None;
+    }
+
+    match config.parse::<u64>() {
+        Ok(timeout_secs) => Some(timeout_secs),
+        Err(_) => {
+            log::error!(
+                "watchdog: R3_WATCHDOG={} is not a number of seconds.",
+                config
+            );
+            None
+        }
+    }
+}
+
+/// arms the watchdog with the given timeout.
+pub fn enable(timeout_secs: u64) -> Result<(), WatchdogError> {
+    let mut watchdog_lock = WATCHDOG.lock();
+    if watchdog_lock.is_none() {
+        *watchdog_lock = Some(find_watchdog()?);
+    }
+
+    let watchdog = watchdog_lock.as_mut().unwrap();
+    let start_res = watchdog.start(timeout_secs);
+    if start_res.is_err() {
+        return Err(start_res.unwrap_err());
+    }
+
+    ARMED.store(true, Ordering::SeqCst);
+    log::info!(
+        "watchdog: {} armed, the machine resets after {}s without a pet.",
+        watchdog.name(),
+        timeout_secs
+    );
+    Ok(())
+}
+
+/// stops the countdown, the machine is not reset anymore.
+pub fn disable() {
+    ARMED.store(false, Ordering::SeqCst);
+    if let Some(watchdog) = WATCHDOG.lock().as_mut() {
+        watchdog.stop();
+        log::info!("watchdog: {} disabled.", watchdog.name());
+    }
+}
+
+#[inline]
+pub fn is_armed() -> bool {
+    ARMED.load(Ordering::SeqCst)
+}
+
+fn pet_loop() -> ! {
+    // the pet thread is low priority, it runs only when no other thread but
+    // idle wants the CPU. a thread that spins for longer than the timeout
+    // starves it and resets the machine, the same as a hang would.
+    loop {
+        if is_armed() {
+            if let Some(watchdog) = WATCHDOG.lock().as_ref() {
+                watchdog.ping();
+            }
+        }
+
+        SCHEDULER
+            .lock()
+            .suspend_thread(ThreadSuspendType::SuspendSleep(PET_TICKS));
+        schedule_yield();
+    }
+}
+
+fn pet_thread() {
+    pet_loop();
+}
+
+/// arms the watchdog if one was configured and starts the thread that pets it,
+/// needs the scheduler.
+pub fn start_watchdog() {
+    let timeout_opt = configured_timeout();
+    if timeout_opt.is_none() {
+        return;
+    }
+
+    let enable_res = enable(timeout_opt.unwrap());
+    if enable_res.is_err() {
+        log::error!("watchdog: not armed: {:?}", enable_res.unwrap_err());
+        return;
+    }
+
+    let process_res = process::new(format!("kernel_watchdog"), false, "");
+    if process_res.is_err() {
+        log::error!(
+            "watchdog: failed to create the watchdog process: {:?}",
+            process_res.unwrap_err()
+        );
+        disable();
+        return;
+    }
+
+    let thread_res = thread::new_from_function(
+        &process_res.unwrap(),
+        format!("watchdog_pet"),
+        VirtualAddress::from_u64(pet_thread as fn() as u64),
+    );
+
+    // nothing would pet it, the machine would reset after the timeout.
+    if thread_res.is_err() {
+        log::error!(
+            "watchdog: failed to start the pet thread: {:?}",
+            thread_res.unwrap_err()
+        );
+        disable();
+        return;
+    }
+
+    idle::set_low_priority_thread(thread_res.unwrap());
+}
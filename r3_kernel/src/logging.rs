@@ -3,15 +3,32 @@ extern crate log;
 
 use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use log::{Level, LevelFilter, Metadata, Record};
 
-use crate::drivers::{display::fb_text::FRAMEBUFFER_LOGGER, display::framebuffer::Pixel, uart};
+use crate::cpu;
+use crate::drivers::{
+    display::fb_text::FRAMEBUFFER_LOGGER,
+    display::framebuffer::{Framebuffer, Pixel},
+    kmsg, uart,
+};
 use uart::UART_DRIVER;
 
 // a logger that implements kernel logging functionalities
 pub struct KernelLogger;
 
+/// the logger can run in an interrupt handler that preempted the holder of an
+/// output lock, so it never waits for one. lines that could not be written
+/// because an output was busy are counted here and reported with the next line.
+static DROPPED_LINES: AtomicU64 = AtomicU64::new(0);
+
+/// the screen belongs to the TTY once it is up, the boot log then goes to the
+/// serial port and kmsg only. the panic handler takes the screen back.
+static FRAMEBUFFER_OUTPUT: AtomicBool = AtomicBool::new(true);
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 fn get_color(level: Level) -> Pixel {
     match level {
         Level::Error => Pixel {
@@ -44,6 +61,8 @@ macro_rules! print_uart {
                 uart.write_fmt(
                     format_args!(concat!($fmt, "\n"), $($arg)*)
                 ).unwrap();
+            } else {
+                DROPPED_LINES.fetch_add(1, Ordering::SeqCst);
             }
         }
     );
@@ -51,16 +70,18 @@ macro_rules! print_uart {
 
 macro_rules! print_framebuffer {
     ($level:expr, $fmt:expr, $($arg:tt)*) => {
-        let mut fb_lgr_lock = FRAMEBUFFER_LOGGER.lock();
-        fb_lgr_lock.set_color(get_color($level));
-        let _ = fb_lgr_lock.write_fmt(
-            format_args!(concat!($fmt, "\n"), $($arg)*)
-        );
+        if let Some(mut fb_lgr_lock) = FRAMEBUFFER_LOGGER.try_lock() {
+            fb_lgr_lock.set_color(get_color($level));
+            let _ = fb_lgr_lock.write_fmt(
+                format_args!(concat!($fmt, "\n"), $($arg)*)
+            );
+        } else {
+            DROPPED_LINES.fetch_add(1, Ordering::SeqCst);
+        }
     };
 }
 
 impl log::Log for KernelLogger {
-
     #[inline]
     fn enabled(&self, _meta: &Metadata) -> bool {
         // TOOD: Add level based filtering
@@ -79,7 +100,17 @@ impl log::Log for KernelLogger {
             );
         }
 
-        if level <= LevelFilter::Info {
+        let written = kmsg::write_record(format_args!(
+            "{:20} {:5} {}",
+            record.target(),
+            record.level(),
+            record.args()
+        ));
+        if !written {
+            DROPPED_LINES.fetch_add(1, Ordering::SeqCst);
+        }
+
+        if level <= LevelFilter::Info && FRAMEBUFFER_OUTPUT.load(Ordering::SeqCst) {
             print_framebuffer!(
                 level,
                 "{:20} {:5} {}",
@@ -88,6 +119,16 @@ impl log::Log for KernelLogger {
                 record.args()
             );
         }
+
+        let dropped = DROPPED_LINES.swap(0, Ordering::SeqCst);
+        if dropped > 0 {
+            let _ = kmsg::write_record(format_args!(
+                "{:20} {:5} {} log line(s) were dropped, the outputs were busy",
+                module_path!(),
+                Level::Warn,
+                dropped
+            ));
+        }
     }
 
     fn flush(&self) {
@@ -103,9 +144,33 @@ pub fn init() {
     log::set_max_level(LevelFilter::Debug);
 }
 
+/// stops writing the log to the screen, called when the TTY takes it over.
+pub fn disable_framebuffer_output() {
+    FRAMEBUFFER_OUTPUT.store(false, Ordering::SeqCst);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    // write the panic info and loop infinitely:
+    cpu::disable_interrupts();
+
+    // a panic while printing the last one, the outputs can't be trusted anymore.
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        cpu::halt_no_interrupts();
+    }
+
+    // the panic may have hit a holder of an output lock, it never runs again,
+    // so the locks are taken over instead of waiting for them.
+    unsafe {
+        FRAMEBUFFER_LOGGER.force_unlock();
+        Framebuffer::force_unlock();
+        if let Some(uart) = UART_DRIVER.as_ref() {
+            uart.force_unlock();
+        }
+    }
+    FRAMEBUFFER_OUTPUT.store(true, Ordering::SeqCst);
+
+    // write the panic info and the call stack, then loop infinitely:
     log::error!("{}", info);
+    crate::cpu::backtrace::print_backtrace();
     loop {}
 }
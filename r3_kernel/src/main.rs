@@ -38,8 +38,11 @@ fn init_basic_setup(boot_info: &'static BootInfo) {
 
     cpu::init_core_legacy_hardware();
     cpu::init_features_detection();
+    #[cfg(feature = "debug_checks")]
     cpu::run_test_breakpoint_recovery();
     mm::init();
+    #[cfg(feature = "double_fault_test")]
+    cpu::run_test_stack_overflow();
 
     // init PCI device list.
     drivers::pci::detect_devices();
@@ -50,13 +53,15 @@ fn init_basic_setup(boot_info: &'static BootInfo) {
 }
 
 fn ideal_k_thread () {
-    cpu::halt_with_interrupts();
+    // halts between the ticks, the time spent here is the idle time of the cpu.
+    system::tasking::idle::idle_loop();
 }
 
 fn start_idle_kthread() {
     // this will always run in the background and keep atleast
     // one task running in the kernel with CPU interrupts enabled.
-    let process = system::process::new(format!("kernel_background"), false, "");
+    let process = system::process::new(format!("kernel_background"), false, "")
+        .expect("Failed to create the kernel background process");
 
     // start a thread for this process
     let k_thread_result = system::thread::new_from_function(
@@ -70,18 +75,27 @@ fn start_idle_kthread() {
         return;
     }
 
+    // the scheduler doesn't count it as something to yield to.
+    system::tasking::idle::set_idle_thread(k_thread_result.unwrap());
+
     // run this thread
     log::info!("Started system idle thread in background.");
+}
 
-    // start the echo client process
-    let pid = system::process::new(format!("test"), true, "/sbin/sys_shell");
-    let thread_result = system::thread::new_main_thread(&pid, format!("main"));
-    if thread_result.is_err() {
-        log::error!("Failed to run /sbin/write thread, threading not working!!!");
-        return;
+#[cfg(not(feature = "selftest"))]
+fn start_init() {
+    log::info!("Starting init program {}", system::INIT_PATH);
+    if system::start_init_program().is_none() {
+        log::error!("No init program is running, build with R3_INIT set to pick another one.");
     }
 }
 
+#[cfg(feature = "selftest")]
+fn start_init() {
+    // the test programs take the place of init.
+    system::selftest::run();
+}
+
 fn init_functionalities() {
     acpi::setup_smp_prerequisites();
     cpu::hw_interrupts::setup_post_apic_interrupts();
@@ -108,6 +122,26 @@ fn init_functionalities() {
     // start the idle thread that just keeps the scheduler filled.
     start_idle_kthread();
 
+    // the timer has to take the cpu away from threads that never yield.
+    #[cfg(feature = "debug_checks")]
+    system::tasking::start_preemption_test();
+
+    // drains the network device when the frames come in too fast for interrupts.
+    system::start_network_thread();
+
+    // kills the foreground process on ^C.
+    drivers::tty::start_interrupt_thread();
+
+    // resets the machine if the kernel stops petting it, off unless R3_WATCHDOG is set.
+    drivers::watchdog::start_watchdog();
+
+    // the bring-up monitor, F12 or the serial port.
+    #[cfg(feature = "kmonitor")]
+    system::kmonitor::start_monitor_thread();
+
+    // the first user programs
+    start_init();
+
     // initialize the terminal
     drivers::tty::initialize();
 
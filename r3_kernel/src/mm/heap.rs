@@ -6,6 +6,7 @@ extern crate log;
 
 // we are using LinkedListAllocator from osdev-rust comminity.
 // Future plan is to use our own allocator.
+#[cfg(feature = "debug_checks")]
 use alloc::vec::Vec;
 use linked_list_allocator::LockedHeap;
 
@@ -64,10 +65,18 @@ pub fn init_heap() {
             .init(HEAP_START_ADDRESS as usize, HEAP_SIZE as usize);
     }
 
+    #[cfg(feature = "debug_checks")]
     test_heap_alloc();
     log::info!("Setting up Kernel heap as Rust Global allocator is successful.");
 }
 
+/// (size, used) bytes of the kernel heap.
+pub fn heap_stats() -> (usize, usize) {
+    let heap = KERNEL_HEAP_ALLOCATOR.lock();
+    (heap.size(), heap.used())
+}
+
+#[cfg(feature = "debug_checks")]
 fn test_heap_alloc() {
     log::debug!("Testing heap by allocating a vector: ");
     let mut test_vec: Vec<u64> = Vec::new();
@@ -1,4 +1,5 @@
-use crate::mm::VirtualAddress;
+use crate::mm::paging::{KernelVirtualMemoryManager, PageEntryFlags, PageSize, PagingError};
+use crate::mm::{self, PhysicalAddress, VirtualAddress};
 
 use core::ptr;
 
@@ -47,3 +48,34 @@ impl MemoryIO {
         unsafe { ptr::write_volatile(self.address.as_u64() as *mut u64, value) }
     }
 }
+
+/// maps the registers of a device uncached at their place in the physical memory
+/// mapping. they are usually above the RAM, where the bootloader did not map anything.
+pub fn map_device_memory(phy_addr: u64, size: u64) -> Result<VirtualAddress, PagingError> {
+    let page_size = PageSize::Page4KiB.size();
+    let start = mm::Alignment::align_down(phy_addr, page_size);
+    let end = mm::Alignment::align_up(phy_addr + size, page_size);
+    let flags = PageEntryFlags::PRESENT | PageEntryFlags::READ_WRITE | PageEntryFlags::NO_CACHE;
+
+    let mut page = start;
+    while page < end {
+        let virt_addr = mm::p_to_v(PhysicalAddress::from_u64(page));
+        if KernelVirtualMemoryManager::pt()
+            .translate(virt_addr)
+            .is_none()
+        {
+            let result = KernelVirtualMemoryManager::pt().map_from_address(
+                virt_addr,
+                PhysicalAddress::from_u64(page),
+                flags,
+                false,
+            );
+            if result.is_err() {
+                return Err(result.unwrap_err());
+            }
+        }
+        page += page_size;
+    }
+
+    Ok(mm::p_to_v(PhysicalAddress::from_u64(phy_addr)))
+}
@@ -114,7 +114,7 @@ impl VirtualAddress {
 
     #[inline]
     pub fn get_page_offset(&self) -> u16 {
-        self.0 as u16 % (1 << 12)
+        (self.0 & 0xFFF) as u16
     }
 
     #[inline]
@@ -160,13 +160,45 @@ impl PhysicalAddress {
     }
 }
 
-pub fn p_to_v(addr: PhysicalAddress) -> VirtualAddress {
+/// the bootloader maps all of the physical memory at this offset, panics
+/// if it did not, nothing that reads physical memory could work without it.
+#[inline]
+fn phy_offset() -> u64 {
     let phy_offset = BootProtocol::get_phy_offset();
     if phy_offset.is_none() {
-        panic!("Unable to convert physical address to virtual address.");
+        panic!("Boot protocol did not provide physical memory offset.");
+    }
+
+    phy_offset.unwrap()
+}
+
+/// virtual address of a physical address in the physical memory mapping,
+/// this is how the kernel reaches page tables, frames and DMA buffers.
+pub fn p_to_v(addr: PhysicalAddress) -> VirtualAddress {
+    let phy_offset = phy_offset();
+    let virt_addr = addr.as_u64().checked_add(phy_offset);
+    if virt_addr.is_none() {
+        panic!(
+            "Physical address 0x{:x} is outside the physical memory mapping.",
+            addr.as_u64()
+        );
+    }
+
+    VirtualAddress::from_u64(virt_addr.unwrap())
+}
+
+/// the opposite of p_to_v, only for addresses in the physical memory mapping,
+/// the others (heap, stacks, kernel image) have to be looked up with `translate`.
+pub fn v_to_p(addr: VirtualAddress) -> PhysicalAddress {
+    let phy_offset = phy_offset();
+    if addr.as_u64() < phy_offset {
+        panic!(
+            "Virtual address 0x{:x} is not in the physical memory mapping.",
+            addr.as_u64()
+        );
     }
 
-    return VirtualAddress::from_u64(addr.as_u64() + phy_offset.unwrap());
+    PhysicalAddress::from_u64(addr.as_u64() - phy_offset)
 }
 
 pub fn init() {
@@ -175,15 +207,26 @@ pub fn init() {
     log::info!("Enabling kernel paging...");
     paging::setup_paging();
 
+    #[cfg(feature = "debug_checks")]
     run_initial_paging_test();
     // init kenel heap
     log::info!("Enabling kernel heap...");
     heap::init_heap();
 
+    #[cfg(feature = "debug_checks")]
+    run_translate_offset_test();
+
+    #[cfg(feature = "debug_checks")]
+    run_phy_offset_conversion_test();
+
+    #[cfg(feature = "debug_checks")]
+    run_write_protect_test();
+
     stack::setup_stack_allocator();
 }
 
 #[inline]
+#[cfg(feature = "debug_checks")]
 pub fn run_initial_paging_test() {
     log::info!("Running simple paging test....");
 
@@ -205,10 +248,9 @@ pub fn run_initial_paging_test() {
         );
     }
 
-    // check if the difference between physical address and virtual address == phy_offset
-    let phy_offset = BootProtocol::get_phy_offset();
-    let v_result_addr = phy_offset.unwrap() + phy_addr.unwrap().as_u64();
-    let value: &u64 = unsafe { &*(v_result_addr as *const u64) };
+    // the value must be readable through the physical memory mapping as well
+    let v_result_addr = p_to_v(phy_addr.unwrap());
+    let value: &u64 = unsafe { &*v_result_addr.get_ptr() };
 
     assert_eq!(expected_value, *value);
 
@@ -218,3 +260,145 @@ pub fn run_initial_paging_test() {
         value
     );
 }
+
+/// the offset is taken from the low 12 bits only, whatever the high bits are,
+/// and translate must land on the very byte, for 4KiB pages (stack) and 2MiB
+/// pages (heap) alike.
+#[cfg(feature = "debug_checks")]
+fn run_translate_offset_test() {
+    extern crate alloc;
+
+    let offsets: [(u64, u16); 6] = [
+        (0x0, 0x0),
+        (0xFFF, 0xFFF),
+        (0x1000, 0x0),
+        (0x0000_D000_0000_0123, 0x123),
+        (0xFFFF_8000_1234_5ABC, 0xABC),
+        (0xFFFF_FFFF_FFFF_FFFF, 0xFFF),
+    ];
+    for (address, offset) in offsets.iter() {
+        assert_eq!(
+            VirtualAddress::from_u64(*address).get_page_offset(),
+            *offset
+        );
+    }
+
+    let k_table = paging::get_kernel_table();
+
+    let mut stack_buffer: [u8; 2 * 4096] = [0; 2 * 4096];
+    let mut heap_buffer = alloc::vec![0u8; 3 * 4096];
+
+    for buffer in [&mut stack_buffer[..], &mut heap_buffer[..]] {
+        let last = buffer.len() - 1;
+        for (index, position) in [0, 1, 0xFFF, 0x1000, last].iter().enumerate() {
+            let position = *position;
+            let byte = 0xA0 + index as u8;
+            buffer[position] = byte;
+
+            let virt_addr = VirtualAddress::from_ptr(&buffer[position]);
+            let phy_addr = k_table.translate(virt_addr);
+            assert!(phy_addr.is_some());
+
+            let phy_addr = phy_addr.unwrap();
+            assert_eq!(phy_addr.as_u64() & 0xFFF, virt_addr.as_u64() & 0xFFF);
+
+            let value = unsafe { core::ptr::read_volatile(p_to_v(phy_addr).get_ptr::<u8>()) };
+            assert_eq!(value, byte);
+        }
+    }
+
+    log::info!("Passed page offset and translate test.");
+}
+
+/// p_to_v and v_to_p must undo each other, and agree with the kernel page table
+/// on where the tables and the heap sit in the physical memory.
+#[cfg(feature = "debug_checks")]
+fn run_phy_offset_conversion_test() {
+    extern crate alloc;
+
+    let phy_offset = BootProtocol::get_phy_offset().unwrap();
+
+    for address in [0x0, 0xFFF, 0x1000, 0x20_0000, 0x1234_5678].iter() {
+        let virt_addr = p_to_v(PhysicalAddress::from_u64(*address));
+        assert_eq!(virt_addr.as_u64(), phy_offset + *address);
+        assert_eq!(v_to_p(virt_addr).as_u64(), *address);
+    }
+
+    let k_table = paging::get_kernel_table();
+    assert_eq!(
+        p_to_v(k_table.l4_phy_addr).as_u64(),
+        k_table.l4_virtual_address.as_u64()
+    );
+    assert_eq!(
+        v_to_p(k_table.l4_virtual_address).as_u64(),
+        k_table.l4_phy_addr.as_u64()
+    );
+
+    // the heap is not in the physical memory mapping, but it's frames are.
+    let value = alloc::boxed::Box::new(0x5A5A_1234_u64);
+    let phy_addr = k_table.translate(VirtualAddress::from_ptr(&*value));
+    assert!(phy_addr.is_some());
+
+    let phy_addr = phy_addr.unwrap();
+    let mapped_addr = p_to_v(phy_addr);
+    assert_eq!(v_to_p(mapped_addr).as_u64(), phy_addr.as_u64());
+    assert_eq!(unsafe { *mapped_addr.get_ptr::<u64>() }, *value);
+
+    log::info!("Passed physical offset conversion test.");
+}
+
+/// a free spot in the kernel half, below the heap, for the read-only test page.
+#[cfg(feature = "debug_checks")]
+const WRITE_PROTECT_TEST_ADDRESS: u64 = 0xffff_9000_0000_0000;
+
+/// a kernel write to a read-only page must fault now that CR0.WP is set,
+/// while the same frame stays writable through the physical memory mapping.
+#[cfg(feature = "debug_checks")]
+fn run_write_protect_test() {
+    use crate::cpu::exceptions::probe_kernel_write;
+    use crate::cpu::mmu;
+    use paging::{KernelVirtualMemoryManager, Page, PageEntryFlags};
+
+    assert!(mmu::is_write_protect_enabled());
+
+    let frame = phy::PhysicalMemoryManager::alloc();
+    assert!(frame.is_some());
+    let frame = frame.unwrap();
+
+    let writable_addr = p_to_v(frame.addr());
+    unsafe { core::ptr::write_volatile(writable_addr.get_mut_ptr::<u8>(), 0xA5) };
+
+    let read_only_addr = VirtualAddress::from_u64(WRITE_PROTECT_TEST_ADDRESS);
+    let result = KernelVirtualMemoryManager::pt().map_page(
+        Page::from_address(read_only_addr),
+        frame,
+        PageEntryFlags::PRESENT,
+    );
+    if result.is_err() {
+        panic!("Write protect test failed to map the page: {:?}", result);
+    }
+
+    // the page can be read, but not written.
+    let value = unsafe { core::ptr::read_volatile(read_only_addr.get_ptr::<u8>()) };
+    assert_eq!(value, 0xA5);
+    assert!(probe_kernel_write(read_only_addr, 0x5A));
+    assert_eq!(
+        unsafe { core::ptr::read_volatile(writable_addr.get_ptr::<u8>()) },
+        0xA5
+    );
+
+    // the probe itself must not report writes that went through.
+    assert!(!probe_kernel_write(writable_addr, 0x5A));
+    assert_eq!(
+        unsafe { core::ptr::read_volatile(read_only_addr.get_ptr::<u8>()) },
+        0x5A
+    );
+
+    let result = KernelVirtualMemoryManager::pt().unmap_page(Page::from_address(read_only_addr));
+    if result.is_err() {
+        panic!("Write protect test failed to unmap the page: {:?}", result);
+    }
+    phy::PhysicalMemoryManager::free(frame);
+
+    log::info!("Passed kernel write protect test.");
+}
@@ -2,9 +2,8 @@ extern crate bit_field;
 extern crate bitflags;
 extern crate log;
 
-use crate::cpu::mmu;
+use crate::cpu::{cpuid, mmu};
 
-use crate::boot_proto::BootProtocol;
 use crate::mm;
 use crate::mm::phy::{Frame, PhysicalMemoryManager};
 use lazy_static::lazy_static;
@@ -145,7 +144,8 @@ bitflags! {
         const DIRTY = 1 << 6;
         const HUGE_PAGE = 1 << 7;
         const GLOBAL = 1 << 8;
-        const RW_ONLY = 1 << 63;
+        /// honoured only with EFER.NXE set, see mmu::enable_no_execute.
+        const NO_EXECUTE = 1 << 63;
     }
 }
 
@@ -278,7 +278,6 @@ pub struct VirtualMemoryManager {
     pub n_tables: usize,
     pub l4_virtual_address: mm::VirtualAddress,
     pub l4_phy_addr: mm::PhysicalAddress,
-    pub phy_offset: u64,
     pub offset_base_addr: mm::PhysicalAddress,
     pub l4_offset_addr: mm::VirtualAddress,
 }
@@ -289,12 +288,13 @@ impl VirtualMemoryManager {
         addr + index * PAGE_TABLE_SIZE
     }
 
-    pub fn from_cr3(phy_offset: u64) -> VirtualMemoryManager {
+    pub fn from_cr3() -> VirtualMemoryManager {
         let current_pt_addr = mmu::get_page_table_address();
+        #[cfg(feature = "debug_checks")]
         assert_eq!(current_pt_addr.is_aligned_at(PAGE_TABLE_SIZE), true);
 
-        // add the physical offset to that address:
-        let mapped_vmm_addr = mm::VirtualAddress::from_u64(current_pt_addr.as_u64() + phy_offset);
+        // the table is reached through the physical memory mapping:
+        let mapped_vmm_addr = mm::p_to_v(current_pt_addr);
 
         log::info!(
             "Page table at Virtual address: 0x{:x}",
@@ -307,7 +307,6 @@ impl VirtualMemoryManager {
             n_tables: 4,
             l4_virtual_address: mapped_vmm_addr,
             l4_phy_addr: current_pt_addr,
-            phy_offset,
             offset_base_addr: current_pt_addr,
             l4_offset_addr: mapped_vmm_addr,
         }
@@ -336,7 +335,9 @@ impl VirtualMemoryManager {
         if create {
             let frame_for_pt_opt = PhysicalMemoryManager::alloc();
             if frame_for_pt_opt.is_none() {
-                panic!("Failed to create new page table because of OOM.");
+                // callers see this as a mapping error, the system can try to free memory.
+                log::error!("Failed to create new page table because of OOM.");
+                return None;
             }
 
             let frame_addr = frame_for_pt_opt.unwrap().addr();
@@ -348,10 +349,7 @@ impl VirtualMemoryManager {
             }
 
             // create the PageTable from frame and reset it:
-            let new_pt: &mut PageTable = unsafe {
-                &mut *mm::VirtualAddress::from_u64(frame_addr.as_u64() + self.phy_offset)
-                    .get_mut_ptr()
-            };
+            let new_pt: &mut PageTable = unsafe { &mut *mm::p_to_v(frame_addr).get_mut_ptr() };
 
             log::debug!(
                 "Created new page table at phy=0x{:x} virt={:p}",
@@ -403,8 +401,9 @@ impl VirtualMemoryManager {
         let l2_table = l2_table_opt.unwrap();
 
         let l2_entry: &mut PageEntry = &mut l2_table.entries[l2_index.as_usize()];
-        if assert_huge_page {
-            assert_eq!(l2_entry.has_flag(PageEntryFlags::HUGE_PAGE), true);
+        if assert_huge_page && !l2_entry.has_flag(PageEntryFlags::HUGE_PAGE) {
+            log::debug!("Expected a huge page at 0x{:x}", address.as_u64());
+            return None;
         }
 
         Some(l2_entry)
@@ -440,6 +439,47 @@ impl VirtualMemoryManager {
         Frame::from_aligned_address(l1_entry.addr()).ok()
     }
 
+    /// returns the flags of the final level entry that maps the given address
+    pub fn get_page_flags(&self, address: &mm::VirtualAddress) -> Option<PageEntryFlags> {
+        let resolved_opt = self.walk_hierarchy(address, false, false, false);
+        if resolved_opt.is_none() {
+            return None;
+        }
+
+        let l2_entry = resolved_opt.unwrap();
+        if !l2_entry.is_mapped() {
+            return None;
+        }
+
+        if l2_entry.has_flag(PageEntryFlags::HUGE_PAGE) {
+            return Some(PageEntryFlags::from_bits_truncate(l2_entry.0));
+        }
+
+        let l1_index = address.get_level_index(mm::PageTableLevel::Level1);
+        let l1_table_opt = self.get_or_create_table(l2_entry, false);
+        if l1_table_opt.is_none() {
+            return None;
+        }
+
+        let l1_entry: &PageEntry = &l1_table_opt.unwrap().entries[l1_index.as_usize()];
+        if !l1_entry.is_mapped() {
+            return None;
+        }
+
+        Some(PageEntryFlags::from_bits_truncate(l1_entry.0))
+    }
+
+    /// returns true if the address is mapped using a 2MiB page
+    pub fn is_huge_page(&self, address: &mm::VirtualAddress) -> bool {
+        let resolved_opt = self.walk_hierarchy(address, false, false, false);
+        if resolved_opt.is_none() {
+            return false;
+        }
+
+        let l2_entry = resolved_opt.unwrap();
+        l2_entry.is_mapped() && l2_entry.has_flag(PageEntryFlags::HUGE_PAGE)
+    }
+
     pub fn translate(&self, addr: mm::VirtualAddress) -> Option<mm::PhysicalAddress> {
         let translated_frame = self.translate_to_frame(&addr);
         if translated_frame.is_none() {
@@ -447,7 +487,12 @@ impl VirtualMemoryManager {
         }
 
         let phy_u64_frame_addr = translated_frame.unwrap().as_u64();
-        let phy_offset = addr.get_page_offset() as u64;
+        // huge pages cover 2MiB, so the offset has more bits than a 4KiB page offset
+        let phy_offset = if self.is_huge_page(&addr) {
+            addr.as_u64() % PageSize::Page2MiB.size()
+        } else {
+            addr.get_page_offset() as u64
+        };
 
         Some(mm::PhysicalAddress::from_u64(
             phy_u64_frame_addr + phy_offset,
@@ -662,12 +707,7 @@ impl Iterator for PageRangeIterator {
 }
 
 pub fn init_kernel_vmm() -> VirtualMemoryManager {
-    let phy_offset = BootProtocol::get_phy_offset();
-    if phy_offset.is_none() {
-        panic!("Boot protocol did not provide physical memory offset.");
-    }
-
-    VirtualMemoryManager::from_cr3(phy_offset.unwrap())
+    VirtualMemoryManager::from_cr3()
 }
 
 lazy_static! {
@@ -680,6 +720,22 @@ pub fn setup_paging() {
         "Kernel paging is initialized, address at: 0x{:x}",
         KERNEL_PAGING.l4_virtual_address.as_u64()
     );
+
+    // the read-only mappings must hold for the kernel as well.
+    if mmu::is_write_protect_enabled() {
+        log::info!("CR0.WP is already set, kernel writes to read-only pages fault.");
+    } else {
+        mmu::enable_write_protect();
+        log::info!("CR0.WP was not set, enabled write protection for the kernel.");
+    }
+
+    // user data is mapped without execute permission where the CPU allows it.
+    if !cpuid::has_no_execute() {
+        log::warn!("CPU has no NX bit, all the user pages stay executable.");
+    } else if !mmu::is_no_execute_enabled() {
+        mmu::enable_no_execute();
+        log::info!("EFER.NXE was not set, enabled no-execute pages.");
+    }
 }
 
 pub fn get_kernel_table() -> &'static VirtualMemoryManager {
@@ -810,7 +866,7 @@ impl KernelVirtualMemoryManager {
         let frame = frame_opt.unwrap();
 
         // get it's address:
-        let new_pt_vaddr = mm::VirtualAddress::from_u64(k_vmm.phy_offset + frame.as_u64());
+        let new_pt_vaddr = mm::p_to_v(frame.addr());
 
         // clone the page table
         let page_table: &mut PageTable = unsafe { &mut *new_pt_vaddr.get_mut_ptr() };
@@ -828,7 +884,6 @@ impl KernelVirtualMemoryManager {
                 n_tables: 1,
                 l4_virtual_address: new_pt_vaddr,
                 l4_phy_addr: frame.addr(),
-                phy_offset: k_vmm.phy_offset,
                 offset_base_addr: k_vmm.l4_phy_addr,
                 l4_offset_addr: k_vmm.l4_virtual_address,
             },
@@ -839,14 +894,13 @@ impl KernelVirtualMemoryManager {
     pub fn current_vmm() -> (VirtualMemoryManager, mm::PhysicalAddress) {
         let k_vmm = Self::pt();
         let phy_addr = mmu::get_page_table_address();
-        let pt_vaddr = mm::VirtualAddress::from_u64(k_vmm.phy_offset + phy_addr.as_u64());
+        let pt_vaddr = mm::p_to_v(phy_addr);
 
         (
             VirtualMemoryManager {
                 n_tables: 1,
                 l4_virtual_address: pt_vaddr,
                 l4_phy_addr: phy_addr,
-                phy_offset: k_vmm.phy_offset,
                 offset_base_addr: k_vmm.l4_phy_addr,
                 l4_offset_addr: k_vmm.l4_virtual_address,
             },
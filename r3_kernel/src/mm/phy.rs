@@ -1,11 +1,13 @@
+extern crate alloc;
 extern crate bootloader;
 extern crate log;
 extern crate spin;
 
 use crate::boot_proto::BootProtocol;
 use crate::mm;
-use crate::mm::paging::{PageSize, PagingError};
+use crate::mm::paging::{self, PageSize, PagingError};
 use crate::mm::MemorySizes;
+use alloc::vec::Vec;
 use bootloader::boot_info::{MemoryRegionKind, MemoryRegions};
 
 use lazy_static::lazy_static;
@@ -17,9 +19,20 @@ pub struct Frame(mm::PhysicalAddress);
 
 const MAX_FREE_REGIONS: usize = 64;
 
-/// Following X bytes are allocated for DMA memory.
-const DMA_REGION_SIZE: usize = 2 * MemorySizes::OneMib as usize;
-const DMA_FRAME_SIZE: usize = MemorySizes::OneKiB as usize * 4;
+/// max number of entries taken from the bootloader memory map.
+const MAX_BOOT_REGIONS: usize = 128;
+
+/// frames below this are never handed out.
+const LOW_MEMORY_LIMIT: u64 = 4096;
+
+/// Following X bytes are allocated for DMA memory, the region is carved out
+/// of the first free region below 16MiB, raise this if the drivers need more.
+pub const DMA_REGION_SIZE: usize = 2 * MemorySizes::OneMib as usize;
+pub const DMA_FRAME_SIZE: usize = MemorySizes::OneKiB as usize * 4;
+const DMA_MAX_FRAMES: usize = DMA_REGION_SIZE / DMA_FRAME_SIZE;
+
+/// size of the chunks a DMAPool takes from the DMA region for small buffers.
+const DMA_POOL_CHUNK_SIZE: usize = DMA_FRAME_SIZE;
 
 impl Frame {
     pub fn from_aligned_address(addr: mm::PhysicalAddress) -> Result<Self, PagingError> {
@@ -56,7 +69,7 @@ pub trait PhyFrameAllocator {
     fn frame_alloc(&mut self) -> Option<Frame>;
 
     /// deallocate a frame
-    fn frame_dealloc(&mut self, index: usize);
+    fn frame_dealloc(&mut self, frame: Frame);
 
     /// allocate 2MiB amount of Frames i.e 2MiB / 4KiB Frames
     fn frame_alloc_n(&mut self, n: usize, align_huge_page: bool) -> Option<Frame>;
@@ -108,6 +121,11 @@ impl MemoryRegion {
         self.n_frames - self.current
     }
 
+    #[inline]
+    pub fn total_frames(&self) -> usize {
+        self.n_frames
+    }
+
     /// check whether N frames can be allocated here or not.
     #[inline]
     pub fn can_allocate(&self, n: usize) -> bool {
@@ -157,53 +175,233 @@ impl MemoryRegion {
     }
 }
 
+/// [start, end) range of physical memory, used while building the regions.
+#[derive(Debug, Clone, Copy)]
+struct PhysicalRange {
+    start: u64,
+    end: u64,
+}
+
+impl PhysicalRange {
+    #[inline]
+    fn new(start: u64, end: u64) -> Self {
+        PhysicalRange { start, end }
+    }
+
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    #[inline]
+    fn overlaps(&self, other: &PhysicalRange) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// fixed size list of ranges, the frame allocator is set-up before the heap.
+struct RangeList {
+    ranges: [PhysicalRange; MAX_BOOT_REGIONS],
+    len: usize,
+}
+
+impl RangeList {
+    #[inline]
+    fn new() -> Self {
+        RangeList {
+            ranges: [PhysicalRange::new(0, 0); MAX_BOOT_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// empty ranges are ignored.
+    fn push(&mut self, range: PhysicalRange) {
+        if range.is_empty() {
+            return;
+        }
+
+        if self.len == MAX_BOOT_REGIONS {
+            log::warn!(
+                "Memory map is too large, ignoring 0x{:x}-0x{:x}",
+                range.start,
+                range.end
+            );
+            return;
+        }
+
+        self.ranges[self.len] = range;
+        self.len += 1;
+    }
+
+    #[inline]
+    fn as_slice(&self) -> &[PhysicalRange] {
+        &self.ranges[0..self.len]
+    }
+
+    /// insertion sort by start address, the memory map is small.
+    fn sort(&mut self) {
+        for idx in 1..self.len {
+            let mut pos = idx;
+            while pos > 0 && self.ranges[pos - 1].start > self.ranges[pos].start {
+                self.ranges.swap(pos - 1, pos);
+                pos -= 1;
+            }
+        }
+    }
+}
+
 pub struct LinearFrameAllocator {
     pub memory_regions: [MemoryRegion; MAX_FREE_REGIONS],
     pub regions: usize,
+    /// physical address of the first freed frame, 0 if none. each freed frame
+    /// holds the address of the next one, so the list needs no memory of it's own.
+    pub free_list: u64,
+    pub n_freed: usize,
 }
 
 impl LinearFrameAllocator {
-    #[inline]
+    /// physical range of the memory map entry holding the kernel code.
+    fn kernel_image_range(boot_regions: &MemoryRegions) -> Option<PhysicalRange> {
+        let code_addr = mm::VirtualAddress::from_u64(Self::init as fn() -> Self as u64);
+        let phy_addr_opt = paging::get_kernel_table().translate(code_addr);
+        if phy_addr_opt.is_none() {
+            return None;
+        }
+
+        let phy_addr = phy_addr_opt.unwrap().as_u64();
+        for region in boot_regions.iter() {
+            if region.start <= phy_addr && phy_addr < region.end {
+                return Some(PhysicalRange::new(region.start, region.end));
+            }
+        }
+
+        None
+    }
+
+    /// physical range of the framebuffer, it is contiguous in physical memory.
+    fn framebuffer_range() -> Option<PhysicalRange> {
+        let fb_slice_opt = BootProtocol::get_framebuffer_slice();
+        if fb_slice_opt.is_none() {
+            return None;
+        }
+
+        let fb_slice = fb_slice_opt.unwrap();
+        let fb_addr = mm::VirtualAddress::from_u64(fb_slice.as_ptr() as u64);
+        let phy_addr_opt = paging::get_kernel_table().translate(fb_addr);
+        if phy_addr_opt.is_none() {
+            return None;
+        }
+
+        let start = phy_addr_opt.unwrap().as_u64();
+        Some(PhysicalRange::new(start, start + fb_slice.len() as u64))
+    }
+
+    /// builds the usable regions from the bootloader memory map, the map is not
+    /// trusted to be sorted or non-overlapping, so usable entries are sorted and
+    /// merged, then clipped against every reserved entry, the kernel image and
+    /// the framebuffer.
     fn create_combined_regions(
         boot_regions: &MemoryRegions,
         os_regions: &mut [MemoryRegion],
     ) -> usize {
-        let mut current_start: u64 = 0;
-        let mut current_end: u64 = 0;
-        let mut n_regions = 0;
+        let mut usable = RangeList::new();
+        let mut reserved = RangeList::new();
 
-        for idx in 0..boot_regions.len() {
-            let region = &boot_regions[idx];
-            // ignore the region below 4K
-            if region.end <= 4096 {
-                continue;
+        for region in boot_regions.iter() {
+            let range = PhysicalRange::new(region.start, region.end);
+            if region.kind == MemoryRegionKind::Usable {
+                usable.push(range);
+            } else {
+                reserved.push(range);
             }
+        }
 
-            if region.kind == MemoryRegionKind::Usable {
-                if current_start == 0 && current_end == 0 {
-                    current_start = region.start;
-                    current_end = region.end;
-                }
-                if current_end == region.start {
-                    // linear
-                    current_end = region.end;
-                } else {
-                    // non-linear
-                    let memory_region = MemoryRegion::new(current_start, current_end);
-                    log::info!(
-                        "Found memory region of size: {} bytes. start=0x{:x}, end=0x{:x}",
-                        current_end - current_start,
-                        current_start,
-                        current_end
+        let kernel_range = Self::kernel_image_range(boot_regions);
+        if kernel_range.is_some() {
+            reserved.push(kernel_range.unwrap());
+        } else {
+            log::warn!("Could not locate the kernel image in the memory map.");
+        }
+
+        if let Some(fb_range) = Self::framebuffer_range() {
+            reserved.push(fb_range);
+        }
+
+        // sort and merge the usable entries:
+        usable.sort();
+        let mut merged = RangeList::new();
+        for &range in usable.as_slice() {
+            if merged.len > 0 {
+                let last = &mut merged.ranges[merged.len - 1];
+                if range.start < last.end {
+                    log::warn!(
+                        "Overlapping usable memory regions: 0x{:x}-0x{:x} and 0x{:x}-0x{:x}",
+                        last.start,
+                        last.end,
+                        range.start,
+                        range.end
                     );
-                    os_regions[n_regions] = memory_region;
-                    n_regions += 1;
+                }
 
-                    // re-init start and end
-                    current_start = region.start;
-                    current_end = region.end;
+                // overlapping or adjacent, both are usable so they are combined.
+                if range.start <= last.end {
+                    last.end = core::cmp::max(last.end, range.end);
+                    continue;
                 }
             }
+            merged.push(range);
+        }
+
+        // clip against the reserved entries, this keeps the list sorted.
+        for &reserved_range in reserved.as_slice() {
+            let mut clipped = RangeList::new();
+            for &range in merged.as_slice() {
+                if !range.overlaps(&reserved_range) {
+                    clipped.push(range);
+                    continue;
+                }
+
+                log::warn!(
+                    "Usable memory 0x{:x}-0x{:x} overlaps reserved 0x{:x}-0x{:x}, clipping.",
+                    range.start,
+                    range.end,
+                    reserved_range.start,
+                    reserved_range.end
+                );
+
+                let end = core::cmp::min(range.end, reserved_range.start);
+                clipped.push(PhysicalRange::new(range.start, end));
+                let start = core::cmp::max(range.start, reserved_range.end);
+                clipped.push(PhysicalRange::new(start, range.end));
+            }
+            merged = clipped;
+        }
+
+        let mut n_regions = 0;
+        for &range in merged.as_slice() {
+            // ignore the region below 4K
+            let start = core::cmp::max(range.start, LOW_MEMORY_LIMIT);
+            if start >= range.end {
+                continue;
+            }
+
+            if n_regions == os_regions.len() {
+                log::warn!(
+                    "Too many memory regions, ignoring 0x{:x}-0x{:x}",
+                    start,
+                    range.end
+                );
+                continue;
+            }
+
+            log::info!(
+                "Found memory region of size: {} bytes. start=0x{:x}, end=0x{:x}",
+                range.end - start,
+                start,
+                range.end
+            );
+            os_regions[n_regions] = MemoryRegion::new(start, range.end);
+            n_regions += 1;
         }
 
         n_regions
@@ -225,12 +423,23 @@ impl LinearFrameAllocator {
         LinearFrameAllocator {
             memory_regions,
             regions: n_regions,
+            free_list: 0,
+            n_freed: 0,
         }
     }
 }
 
 impl PhyFrameAllocator for LinearFrameAllocator {
     fn frame_alloc(&mut self) -> Option<Frame> {
+        // freed frames are used first.
+        if self.free_list != 0 {
+            let frame = Frame::from_address(mm::PhysicalAddress::from_u64(self.free_list));
+            let next_ptr = mm::p_to_v(frame.addr()).get_ptr::<u64>();
+            self.free_list = unsafe { *next_ptr };
+            self.n_freed -= 1;
+            return Some(frame);
+        }
+
         for region_idx in 0..self.regions {
             if self.memory_regions[region_idx].can_allocate(1) {
                 let frame_opt = self.memory_regions[region_idx].allocate_n(1, false);
@@ -240,8 +449,20 @@ impl PhyFrameAllocator for LinearFrameAllocator {
         None
     }
 
-    fn frame_dealloc(&mut self, index: usize) {
-        log::warn!("Got index={}, Frame deallocation not implemented", index);
+    fn frame_dealloc(&mut self, frame: Frame) {
+        if frame.as_u64() < LOW_MEMORY_LIMIT {
+            log::warn!("Ignoring free of low memory frame 0x{:x}", frame.as_u64());
+            return;
+        }
+
+        // only single frames come from the list, the huge pages and
+        // the contiguous runs are still taken from the regions.
+        let next_ptr = mm::p_to_v(frame.addr()).get_mut_ptr::<u64>();
+        unsafe {
+            *next_ptr = self.free_list;
+        }
+        self.free_list = frame.as_u64();
+        self.n_freed += 1;
     }
 
     fn frame_alloc_n(&mut self, n: usize, align_huge_page: bool) -> Option<Frame> {
@@ -278,9 +499,32 @@ impl PhysicalMemoryManager {
         LINEAR_ALLOCATOR.lock().frame_alloc_n(n_frames, true)
     }
 
-    pub fn free(_frame: Frame) {
-        // Not implemented yet
-        LINEAR_ALLOCATOR.lock().frame_dealloc(0);
+    /// the frame must not be mapped anywhere anymore.
+    pub fn free(frame: Frame) {
+        LINEAR_ALLOCATOR.lock().frame_dealloc(frame);
+    }
+
+    /// frees the 4KiB frames of a huge page one by one.
+    pub fn free_huge_page(frame: Frame) {
+        let n_frames = (2 * mm::MemorySizes::OneMib as usize) / PageSize::Page4KiB.size() as usize;
+        let mut allocator = LINEAR_ALLOCATOR.lock();
+        for idx in 0..n_frames {
+            allocator.frame_dealloc(Frame::from_address(mm::PhysicalAddress::from_u64(
+                frame.as_u64() + (idx as u64 * PageSize::Page4KiB.size()),
+            )));
+        }
+    }
+
+    /// (total, free) number of 4KiB frames across all usable regions.
+    pub fn frame_stats() -> (usize, usize) {
+        let allocator = LINEAR_ALLOCATOR.lock();
+        let mut total = 0;
+        let mut free = 0;
+        for region in &allocator.memory_regions[0..allocator.regions] {
+            total += region.total_frames();
+            free += region.free_frames();
+        }
+        (total, free + allocator.n_freed)
     }
 }
 
@@ -324,23 +568,36 @@ impl DMABuffer {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DMAError {
+    /// no free run of frames in the DMA region can hold the request (size)
+    OutOfMemory(usize),
+    /// the alignment is zero or not a power of two
+    InvalidAlignment(usize),
+    /// the buffer was not allocated from the DMA region (physical address)
+    InvalidBuffer(u64),
+}
+
 /// Manages memory allocated for DMA purposes.
-/// The granularity of memory allocation is 8KiB frames.
-/// This memory cannot be freed once allocated
-/// The devices need to lock this memory area during init time
-/// by stating it's requirements.
+/// The granularity of memory allocation is 4KiB frames, so every buffer is
+/// at least page aligned, larger alignments can be requested with `alloc_aligned`.
+/// Frames are returned with `free`, drivers that own many buffers should use
+/// a DMAPool and reset it on teardown.
 pub struct DMAAllocator {
     pub max_frames: usize,
-    pub current_index: usize,
+    pub used_frames: usize,
     pub start_addr: mm::PhysicalAddress,
+    used: [bool; DMA_MAX_FRAMES],
 }
 
 impl DMAAllocator {
     pub fn empty() -> Self {
-        // is there a free region below 16MiB?
+        // is there a free region below 16MiB that can hold the DMA region?
         let mut alloc_lock: MutexGuard<LinearFrameAllocator> = LINEAR_ALLOCATOR.lock();
         for region in alloc_lock.memory_regions.iter_mut() {
-            if region.start.as_u64() < 16 * MemorySizes::OneMib as u64 {
+            if region.start.as_u64() < 16 * MemorySizes::OneMib as u64
+                && region.size >= DMA_REGION_SIZE
+            {
                 let dma_start = region.start;
                 let dma_end =
                     mm::PhysicalAddress::from_u64(dma_start.as_u64() + DMA_REGION_SIZE as u64);
@@ -363,8 +620,9 @@ impl DMAAllocator {
 
                 return DMAAllocator {
                     max_frames: max_frames as usize,
-                    current_index: 0,
+                    used_frames: 0,
                     start_addr: mm::PhysicalAddress::from_u64(aligned_start),
+                    used: [false; DMA_MAX_FRAMES],
                 };
             }
         }
@@ -373,20 +631,77 @@ impl DMAAllocator {
     }
 
     #[inline]
-    pub fn alloc(&mut self, size: usize) -> Option<DMABuffer> {
-        let aligned_size = mm::Alignment::align_up(size as u64, DMA_FRAME_SIZE as u64) as usize;
-        let n_frames = aligned_size / DMA_FRAME_SIZE;
+    fn frame_addr(&self, index: usize) -> u64 {
+        self.start_addr.as_u64() + (index * DMA_FRAME_SIZE) as u64
+    }
 
-        if self.current_index + n_frames > self.max_frames {
-            return None;
+    #[inline]
+    fn n_frames(size: usize) -> usize {
+        let size = if size == 0 { 1 } else { size };
+        mm::Alignment::align_up(size as u64, DMA_FRAME_SIZE as u64) as usize / DMA_FRAME_SIZE
+    }
+
+    #[inline]
+    pub fn alloc(&mut self, size: usize) -> Result<DMABuffer, DMAError> {
+        self.alloc_aligned(size, DMA_FRAME_SIZE)
+    }
+
+    /// first fit search for a free run of frames whose start is aligned at `align`,
+    /// alignments below the frame size are always satisfied.
+    pub fn alloc_aligned(&mut self, size: usize, align: usize) -> Result<DMABuffer, DMAError> {
+        if align == 0 || !align.is_power_of_two() {
+            return Err(DMAError::InvalidAlignment(align));
         }
 
-        let start_addr = mm::PhysicalAddress::from_u64(
-            self.start_addr.as_u64() + (self.current_index * DMA_FRAME_SIZE) as u64,
-        );
+        let n_frames = Self::n_frames(size);
+        let align = core::cmp::max(align, DMA_FRAME_SIZE) as u64;
+        let step = align as usize / DMA_FRAME_SIZE;
 
-        self.current_index += n_frames;
-        Some(DMABuffer::new(start_addr, size))
+        // first frame whose address is aligned
+        let first_addr = mm::Alignment::align_up(self.start_addr.as_u64(), align);
+        let mut index = ((first_addr - self.start_addr.as_u64()) as usize) / DMA_FRAME_SIZE;
+
+        while index + n_frames <= self.max_frames {
+            if self.used[index..index + n_frames].iter().all(|used| !used) {
+                for used in self.used[index..index + n_frames].iter_mut() {
+                    *used = true;
+                }
+
+                self.used_frames += n_frames;
+                let start_addr = mm::PhysicalAddress::from_u64(self.frame_addr(index));
+                return Ok(DMABuffer::new(start_addr, size));
+            }
+
+            index += step;
+        }
+
+        Err(DMAError::OutOfMemory(size))
+    }
+
+    /// returns the frames of a buffer given out by `alloc` or `alloc_aligned`.
+    pub fn free(&mut self, buffer: &DMABuffer) -> Result<(), DMAError> {
+        let addr = buffer.phy_addr.as_u64();
+        let start = self.start_addr.as_u64();
+        let end = self.frame_addr(self.max_frames);
+
+        if addr < start || addr >= end || (addr - start) % DMA_FRAME_SIZE as u64 != 0 {
+            return Err(DMAError::InvalidBuffer(addr));
+        }
+
+        let index = (addr - start) as usize / DMA_FRAME_SIZE;
+        let n_frames = Self::n_frames(buffer.size);
+        if index + n_frames > self.max_frames {
+            return Err(DMAError::InvalidBuffer(addr));
+        }
+
+        for used in self.used[index..index + n_frames].iter_mut() {
+            if *used {
+                *used = false;
+                self.used_frames -= 1;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -397,9 +712,111 @@ lazy_static! {
 pub struct DMAMemoryManager;
 
 impl DMAMemoryManager {
-    pub fn alloc(size: usize) -> Option<DMABuffer> {
+    pub fn alloc(size: usize) -> Result<DMABuffer, DMAError> {
         DMA_ALLOCATOR.lock().alloc(size)
     }
+
+    pub fn alloc_aligned(size: usize, align: usize) -> Result<DMABuffer, DMAError> {
+        DMA_ALLOCATOR.lock().alloc_aligned(size, align)
+    }
+
+    pub fn free(buffer: &DMABuffer) -> Result<(), DMAError> {
+        DMA_ALLOCATOR.lock().free(buffer)
+    }
+
+    /// (used, total) frames of the DMA region
+    pub fn usage() -> (usize, usize) {
+        let dma_lock = DMA_ALLOCATOR.lock();
+        (dma_lock.used_frames, dma_lock.max_frames)
+    }
+}
+
+/// DMA buffers owned by one driver. small buffers are bump allocated out of
+/// chunks taken from the DMA region, large ones get their own frames.
+/// everything is given back at once by `reset`, which is also done on drop,
+/// so a driver that fails half way through init leaks nothing.
+pub struct DMAPool {
+    pub name: &'static str,
+    /// everything taken from the DMA region, freed by reset
+    buffers: Vec<DMABuffer>,
+    /// chunk the small buffers are bump allocated from and the offset into it
+    chunk: Option<DMABuffer>,
+    offset: usize,
+}
+
+impl DMAPool {
+    pub fn new(name: &'static str) -> Self {
+        DMAPool {
+            name,
+            buffers: Vec::new(),
+            chunk: None,
+            offset: 0,
+        }
+    }
+
+    #[inline]
+    pub fn alloc(&mut self, size: usize) -> Result<DMABuffer, DMAError> {
+        self.alloc_aligned(size, DMA_FRAME_SIZE)
+    }
+
+    /// `align` is usually 64 bytes for descriptors or a page for buffers.
+    pub fn alloc_aligned(&mut self, size: usize, align: usize) -> Result<DMABuffer, DMAError> {
+        if align == 0 || !align.is_power_of_two() {
+            return Err(DMAError::InvalidAlignment(align));
+        }
+
+        // too large to share a chunk, give it it's own frames.
+        if size > DMA_POOL_CHUNK_SIZE || align > DMA_POOL_CHUNK_SIZE {
+            let buffer = DMAMemoryManager::alloc_aligned(size, align);
+            if buffer.is_err() {
+                return Err(buffer.unwrap_err());
+            }
+
+            let buffer = buffer.unwrap();
+            self.buffers.push(buffer);
+            return Ok(buffer);
+        }
+
+        let mut offset = mm::Alignment::align_up(self.offset as u64, align as u64) as usize;
+        if self.chunk.is_none() || offset + size > DMA_POOL_CHUNK_SIZE {
+            let chunk = DMAMemoryManager::alloc(DMA_POOL_CHUNK_SIZE);
+            if chunk.is_err() {
+                return Err(chunk.unwrap_err());
+            }
+
+            let chunk = chunk.unwrap();
+            self.buffers.push(chunk);
+            self.chunk = Some(chunk);
+            offset = 0;
+        }
+
+        let chunk_addr = self.chunk.unwrap().phy_addr.as_u64();
+        self.offset = offset + size;
+        Ok(DMABuffer::new(
+            mm::PhysicalAddress::from_u64(chunk_addr + offset as u64),
+            size,
+        ))
+    }
+
+    /// frees every buffer of the pool, the device must not be using them anymore.
+    pub fn reset(&mut self) {
+        for buffer in self.buffers.iter() {
+            let result = DMAMemoryManager::free(buffer);
+            if result.is_err() {
+                log::error!("dma pool {}: {:?}", self.name, result.unwrap_err());
+            }
+        }
+
+        self.buffers.clear();
+        self.chunk = None;
+        self.offset = 0;
+    }
+}
+
+impl Drop for DMAPool {
+    fn drop(&mut self) {
+        self.reset();
+    }
 }
 
 /// a function that lazy initializes LIEAR_ALLOCATOR
@@ -412,8 +829,9 @@ pub fn setup_physical_memory() {
     let dma_lock = DMA_ALLOCATOR.lock();
 
     log::info!(
-        "Set-up DMA Allocator for DMA memory manager successfull, start=0x{:x}, max_frames={}",
+        "Set-up DMA Allocator for DMA memory manager successfull, start=0x{:x}, max_frames={}, region_size={}",
         dma_lock.start_addr.as_u64(),
-        dma_lock.max_frames
+        dma_lock.max_frames,
+        DMA_REGION_SIZE
     );
 }
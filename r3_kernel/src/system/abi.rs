@@ -33,17 +33,34 @@ pub type CClockID = CInt;
 #[derive(Debug, Clone)]
 #[repr(i32)]
 pub enum Errno {
+    EPERM = 1,
     ENOENT = 2,
+    ESRCH = 3,
     EIO = 5,
+    ENOEXEC = 8,
     EBADF = 9,
+    EAGAIN = 11,
     ENOMEM = 12,
     EFAULT = 14,
     EEXIST = 17,
-    ENOSYS = 32,
+    ENODEV = 19,
     EINVAL = 22,
     EMFILE = 24,
     ENOTTY = 25,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    EPIPE = 32,
+    ENOSYS = 38,
     ENAMETOOLONG = 63,
+    ENOTSOCK = 88,
+    ENOPROTOOPT = 92,
+    EAFNOSUPPORT = 97,
+    EADDRINUSE = 98,
+    ENETUNREACH = 101,
+    EISCONN = 106,
+    ENOTCONN = 107,
+    ETIMEDOUT = 110,
 }
 
 pub type UserAddress = VirtualAddress;
@@ -71,7 +88,7 @@ pub fn copy_cstring(source: VirtualAddress, max_len: usize) -> Result<String, Er
         while *c_ptr != b'\0' {
             buffer[iter] = *c_ptr;
             iter = iter + 1;
-            if iter >= 512 {
+            if iter >= max_len {
                 return Err(Errno::ENAMETOOLONG);
             }
             c_ptr = c_ptr.add(1);
@@ -8,7 +8,7 @@ use crate::system::filesystem::ustar::mount_tarfs;
 use alloc::boxed::Box;
 
 /// Devices capable of storage have major number 2
-const STORAGE_DEVICE_MAJOR: usize = 2;
+pub const STORAGE_DEVICE_MAJOR: usize = 2;
 
 pub fn check_tarfs(major: u32, minor: u32, device: &Box<dyn DevOps + Sync + Send>) -> bool {
     // 1. create a file-descriptor
@@ -81,7 +81,7 @@ impl FSOps for DevFSDriver {
         Err(FSError::NotFound)
     }
 
-    fn close(&self, fd: &FileDescriptor) -> Result<(), FSError> {
+    fn close(&mut self, fd: &FileDescriptor) -> Result<(), FSError> {
         match fd {
             FileDescriptor::DevFSNode(devfd) => {
                 let mut devfs_lock = DEV_FS.lock();
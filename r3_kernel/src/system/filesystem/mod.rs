@@ -4,9 +4,12 @@ extern crate bitflags;
 pub mod detect;
 pub mod devfs;
 pub mod paths;
+pub mod pipe;
 pub mod ustar;
 pub mod vfs;
 
+use crate::system::net::udp;
+
 use bitflags::bitflags;
 use core::fmt;
 
@@ -73,6 +76,8 @@ pub enum FileDescriptor {
     DevFSNode(devfs::DevFSDescriptor),
     Ext2Node,
     TarFSNode(ustar::TarFileDescriptor),
+    PipeNode(pipe::PipeDescriptor),
+    SocketNode(udp::UDPSocketDescriptor),
     Empty,
 }
 
@@ -92,6 +97,15 @@ pub enum FSError {
     InvalidSeek,
     AlignmentError,
     IOError,
+    ReadOnly,
+    /// no space left on the device
+    NoSpace,
+    /// the operation would have to wait on a non-blocking descriptor
+    WouldBlock,
+    /// write to a pipe without readers
+    BrokenPipe,
+    /// the process the operation refers to does not exist
+    NoSuchProcess,
 }
 
 /// Represents the operations performed on File-System
@@ -100,7 +114,7 @@ pub trait FSOps {
         Err(FSError::NotYetImplemented)
     }
 
-    fn close(&self, _fd: &FileDescriptor) -> Result<(), FSError> {
+    fn close(&mut self, _fd: &FileDescriptor) -> Result<(), FSError> {
         Err(FSError::NotYetImplemented)
     }
 }
@@ -128,4 +142,9 @@ pub trait FDOps {
     fn fstat(&self, _fd: &mut FileDescriptor) -> Result<FStatInfo, FSError> {
         Ok(FStatInfo::default())
     }
+
+    /// sets the size of the file, filesystems that can't do this are read-only.
+    fn truncate(&self, _fd: &mut FileDescriptor, _length: usize) -> Result<(), FSError> {
+        Err(FSError::ReadOnly)
+    }
 }
@@ -0,0 +1,178 @@
+extern crate alloc;
+extern crate spin;
+
+use crate::cpu;
+use crate::system::filesystem::{FSError, FStatInfo, POSIXOpenFlags};
+use crate::system::tasking::wait_until_return;
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::fmt;
+use spin::Mutex;
+
+/// bytes a pipe holds before the writers have to wait, same as PIPE_BUF of linux.
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// S_IFIFO, the file type reported by fstat.
+const FIFO_MODE: usize = 0o010000;
+
+/// the buffer shared by the two ends, pipes are not part of a mountpoint.
+pub struct Pipe {
+    buffer: VecDeque<u8>,
+    /// open file descriptions of each end, the descriptors duplicated by dup or
+    /// fork share one, so an end is gone when it's last descriptor is closed.
+    readers: usize,
+    writers: usize,
+}
+
+impl Pipe {
+    fn new() -> Self {
+        Pipe {
+            buffer: VecDeque::with_capacity(PIPE_CAPACITY),
+            readers: 1,
+            writers: 1,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PipeDescriptor {
+    pub pipe: Arc<Mutex<Pipe>>,
+    pub write_end: bool,
+    /// O_NONBLOCK is the only one used.
+    pub flags: u32,
+}
+
+impl fmt::Debug for PipeDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PipeDescriptor")
+            .field("write_end", &self.write_end)
+            .field("flags", &self.flags)
+            .finish()
+    }
+}
+
+impl PipeDescriptor {
+    #[inline]
+    pub fn is_nonblocking(&self) -> bool {
+        POSIXOpenFlags::from_bits_truncate(self.flags).contains(POSIXOpenFlags::O_NONBLOCK)
+    }
+
+    /// reads what is in the pipe, fails with WouldBlock if it is empty and
+    /// could still be written to, 0 means all the writers are gone.
+    pub fn try_read(&self, buffer: &mut [u8]) -> Result<usize, FSError> {
+        if self.write_end {
+            return Err(FSError::InvalidOperation);
+        }
+
+        let mut pipe = self.pipe.lock();
+        if pipe.buffer.is_empty() {
+            if pipe.writers == 0 || buffer.is_empty() {
+                return Ok(0);
+            }
+            return Err(FSError::WouldBlock);
+        }
+
+        let length = core::cmp::min(buffer.len(), pipe.buffer.len());
+        for (dst, src) in buffer.iter_mut().zip(pipe.buffer.drain(..length)) {
+            *dst = src;
+        }
+
+        Ok(length)
+    }
+
+    /// writes as much as fits, fails with WouldBlock if nothing does and
+    /// with BrokenPipe if nobody can read it anymore.
+    pub fn try_write(&self, buffer: &[u8]) -> Result<usize, FSError> {
+        if !self.write_end {
+            return Err(FSError::InvalidOperation);
+        }
+
+        let mut pipe = self.pipe.lock();
+        if pipe.readers == 0 {
+            return Err(FSError::BrokenPipe);
+        }
+
+        let length = core::cmp::min(buffer.len(), PIPE_CAPACITY - pipe.buffer.len());
+        if length == 0 && !buffer.is_empty() {
+            return Err(FSError::WouldBlock);
+        }
+
+        pipe.buffer.extend(buffer[0..length].iter());
+        Ok(length)
+    }
+
+    /// waits for data unless the end is non-blocking, must be called without
+    /// the process or filesystem locks, the writer needs them to get here.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FSError> {
+        if self.is_nonblocking() {
+            return self.try_read(buffer);
+        }
+
+        cpu::enable_interrupts();
+        wait_until_return(|| match self.try_read(buffer) {
+            Err(FSError::WouldBlock) => Ok(None),
+            Err(err) => Err(err),
+            Ok(length) => Ok(Some(length)),
+        })
+    }
+
+    /// waits until all of the buffer is written unless the end is non-blocking,
+    /// the same locking rules as read apply.
+    pub fn write(&self, buffer: &[u8]) -> Result<usize, FSError> {
+        if self.is_nonblocking() {
+            return self.try_write(buffer);
+        }
+
+        cpu::enable_interrupts();
+        let mut done = 0;
+        wait_until_return(|| match self.try_write(&buffer[done..]) {
+            Err(FSError::WouldBlock) => Ok(None),
+            // what was written before the readers left is still reported.
+            Err(FSError::BrokenPipe) if done > 0 => Ok(Some(done)),
+            Err(err) => Err(err),
+            Ok(length) => {
+                done += length;
+                if done == buffer.len() {
+                    return Ok(Some(done));
+                }
+                Ok(None)
+            }
+        })
+    }
+
+    /// the end is closed, the waiting peer sees the end of file or a broken pipe.
+    pub fn close(&self) {
+        let mut pipe = self.pipe.lock();
+        if self.write_end {
+            pipe.writers = pipe.writers.saturating_sub(1);
+        } else {
+            pipe.readers = pipe.readers.saturating_sub(1);
+        }
+    }
+
+    pub fn fstat(&self) -> FStatInfo {
+        FStatInfo {
+            mode: FIFO_MODE,
+            file_size: self.pipe.lock().buffer.len(),
+            block_size: PIPE_CAPACITY,
+            ..Default::default()
+        }
+    }
+}
+
+/// creates a pipe, returns the read end and the write end.
+pub fn new_pipe(flags: u32) -> (PipeDescriptor, PipeDescriptor) {
+    let pipe = Arc::new(Mutex::new(Pipe::new()));
+    let read_end = PipeDescriptor {
+        pipe: pipe.clone(),
+        write_end: false,
+        flags,
+    };
+    let write_end = PipeDescriptor {
+        pipe,
+        write_end: true,
+        flags,
+    };
+
+    (read_end, write_end)
+}
@@ -1,16 +1,17 @@
 extern crate alloc;
 extern crate log;
 
+use crate::drivers::disk;
+use crate::system::filesystem::detect::STORAGE_DEVICE_MAJOR;
 use crate::system::filesystem::devfs::DevFSDriver;
 use crate::system::filesystem::vfs::FILESYSTEM;
 use crate::system::filesystem::MountInfo;
 use crate::system::filesystem::{FDOps, FSOps};
-use crate::system::filesystem::{FSError, FileDescriptor, SeekType, FStatInfo};
+use crate::system::filesystem::{FSError, FileDescriptor, POSIXOpenFlags, SeekType, FStatInfo};
 use crate::mm::Alignment;
 
 use alloc::{format, string::String};
 use core::mem;
-use core::str;
 
 #[derive(Debug, Clone)]
 #[repr(C, packed)]
@@ -36,10 +37,25 @@ struct TarHeader {
 
 const HEADER_SIZE: usize = mem::size_of::<TarHeader>();
 
+/// position and length of the checksum field within the header.
+const CHECKSUM_OFFSET: usize = 148;
+const CHECKSUM_SIZE: usize = 8;
+
+/// result of walking the archive for a path.
+#[derive(Debug)]
+enum TarLookup {
+    /// (header offset, data offset, size) of the matching entry
+    Found(usize, usize, usize),
+    /// offset of the end-of-archive marker
+    End(usize),
+}
+
 pub struct TarFS;
 
 #[derive(Debug, Clone)]
 pub struct TarFileDescriptor {
+    /// offset of the header block of this file on that device
+    pub header_offset: usize,
     /// offset of the file on that device
     pub offset: usize,
     /// size of the file
@@ -52,29 +68,276 @@ pub struct TarFileDescriptor {
     pub driver_name: String,
 }
 
+/// set in the first byte of a numeric field stored in the GNU base-256 form.
+const BASE256_FLAG: u8 = 0x80;
+/// sign bit of the base-256 value, negative values are never valid here.
+const BASE256_SIGN: u8 = 0x40;
+
+/// parses a numeric header field. the usual form is octal digits, optionally
+/// preceded by spaces, ended by a space, a NUL or the end of the field. GNU tar
+/// stores values that don't fit the digits as a big endian base-256 number with
+/// the high bit of the first byte set. returns None for anything else, so a
+/// damaged field is never read as some other number.
+fn parse_numeric(field: &[u8]) -> Option<usize> {
+    if field.is_empty() {
+        return None;
+    }
+
+    if field[0] & BASE256_FLAG == BASE256_FLAG {
+        if field[0] & BASE256_SIGN == BASE256_SIGN {
+            return None;
+        }
+
+        let mut number = (field[0] & !BASE256_FLAG) as usize;
+        for byte in &field[1..] {
+            if number > (usize::MAX >> 8) {
+                return None;
+            }
+            number = (number << 8) | *byte as usize;
+        }
+        return Some(number);
+    }
+
+    let mut idx = 0;
+    while idx < field.len() && field[idx] == b' ' {
+        idx += 1;
+    }
+
+    let mut number: usize = 0;
+    let mut n_digits = 0;
+    while idx < field.len() && field[idx] >= b'0' && field[idx] <= b'7' {
+        let digit = (field[idx] - b'0') as usize;
+        let shifted = number.checked_mul(8).and_then(|n| n.checked_add(digit));
+        if shifted.is_none() {
+            return None;
+        }
+        number = shifted.unwrap();
+        n_digits += 1;
+        idx += 1;
+    }
+
+    if n_digits == 0 {
+        return None;
+    }
+
+    // only padding can follow the digits.
+    if field[idx..].iter().any(|byte| *byte != b' ' && *byte != 0) {
+        return None;
+    }
+
+    Some(number)
+}
+
+/// writes `value` as zero padded octal digits followed by a NUL into `field`,
+/// returns false if the value doesn't fit.
 #[inline]
-fn oct_to_usize(buffer: &[u8]) -> usize {
-    let mut multiplier = 1;
-    let mut number = 0;
-    let last_index = buffer.len() - 1;
+fn usize_to_oct(value: usize, field: &mut [u8]) -> bool {
+    let n_digits = field.len() - 1;
+    let mut number = value;
+
+    for idx in 0..n_digits {
+        field[n_digits - 1 - idx] = b'0' + (number % 8) as u8;
+        number /= 8;
+    }
+
+    field[n_digits] = 0;
+    number == 0
+}
+
+/// writes `value` in the GNU base-256 form, for values too large for octal digits.
+#[inline]
+fn usize_to_base256(value: usize, field: &mut [u8]) -> bool {
+    let mut number = value;
+    for idx in (1..field.len()).rev() {
+        field[idx] = number as u8;
+        number >>= 8;
+    }
+
+    // the first byte holds the flag, it's sign bit must stay clear.
+    if number > (!(BASE256_FLAG | BASE256_SIGN)) as usize {
+        return false;
+    }
+    field[0] = BASE256_FLAG | number as u8;
+    true
+}
+
+/// sum of all the header bytes, with the checksum field counted as spaces.
+#[inline]
+fn header_checksum(block: &[u8]) -> usize {
+    let mut sum = 0;
+    for (idx, byte) in block.iter().enumerate() {
+        if idx >= CHECKSUM_OFFSET && idx < CHECKSUM_OFFSET + CHECKSUM_SIZE {
+            sum += b' ' as usize;
+        } else {
+            sum += *byte as usize;
+        }
+    }
+    sum
+}
+
+/// recomputes the checksum and stores it in the header as six octal digits, NUL and space.
+#[inline]
+fn update_checksum(block: &mut [u8]) {
+    let checksum = header_checksum(block);
+    let field = &mut block[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_SIZE];
+    usize_to_oct(checksum, &mut field[0..CHECKSUM_SIZE - 1]);
+    field[CHECKSUM_SIZE - 1] = b' ';
+}
+
+#[inline]
+fn read_block_at(
+    devfs_driver: &DevFSDriver,
+    devfd: &mut FileDescriptor,
+    offset: usize,
+    buffer: &mut [u8],
+) -> Result<(), FSError> {
+    if devfs_driver
+        .seek(devfd, offset as u32, SeekType::SEEK_SET)
+        .is_err()
+    {
+        return Err(FSError::InvalidSeek);
+    }
+
+    if devfs_driver.read(devfd, buffer).is_err() {
+        return Err(FSError::IOError);
+    }
+    Ok(())
+}
+
+#[inline]
+fn write_block_at(
+    devfs_driver: &DevFSDriver,
+    devfd: &mut FileDescriptor,
+    offset: usize,
+    buffer: &[u8],
+) -> Result<(), FSError> {
+    if devfs_driver
+        .seek(devfd, offset as u32, SeekType::SEEK_SET)
+        .is_err()
+    {
+        return Err(FSError::InvalidSeek);
+    }
+
+    if devfs_driver.write(devfd, buffer).is_err() {
+        return Err(FSError::IOError);
+    }
+    Ok(())
+}
+
+/// checks the header read from `block_no` against the device, returns None at
+/// the end of the archive and the (data offset, size) of the entry otherwise.
+fn check_header(
+    buffer: &[u8; HEADER_SIZE],
+    block_no: usize,
+    device_size: usize,
+) -> Result<Option<(usize, usize)>, FSError> {
+    let (head, body, _tail) = unsafe { buffer.align_to::<TarHeader>() };
+    if !head.is_empty() {
+        return Err(FSError::AlignmentError);
+    }
+    let tar_header = &body[0];
+
+    if !tar_header.signature.starts_with(b"ustar") {
+        return Ok(None);
+    }
+
+    if parse_numeric(&tar_header.checksum) != Some(header_checksum(buffer)) {
+        log::debug!("Tarfs header checksum mismatch at block {}", block_no);
+        return Err(FSError::IOError);
+    }
 
-    for idx in 0..(last_index + 1) {
-        let byte = buffer[last_index - idx];
-        if byte as char >= '0' && byte as char <= '7' {
-            number += ((byte - 48) as usize) * multiplier;
-            multiplier *= 8;
+    let size_opt = parse_numeric(&tar_header.size);
+    if size_opt.is_none() {
+        log::debug!("Tarfs entry at block {} has an invalid size field", block_no);
+        return Err(FSError::IOError);
+    }
+
+    // the entry data starts right after the header, and must fit in the device.
+    let file_size = size_opt.unwrap();
+    let data_offset = block_no
+        .checked_add(1)
+        .and_then(|blocks| blocks.checked_mul(HEADER_SIZE));
+    let data_end = data_offset.and_then(|offset| offset.checked_add(file_size));
+
+    if data_end.is_none() || data_end.unwrap() > device_size {
+        log::debug!(
+            "Tarfs entry at block {} has implausible size {}",
+            block_no,
+            file_size
+        );
+        return Err(FSError::IOError);
+    }
+
+    Ok(Some((data_offset.unwrap(), file_size)))
+}
+
+/// the name is NUL padded and a directory has a trailing slash, the whole name
+/// must be the path, "foo" is not "foobar" or "foo/bar".
+fn name_matches(name: &[u8], path: &str) -> bool {
+    let name_len = name.iter().position(|byte| *byte == 0).unwrap_or(name.len());
+    let name = &name[..name_len];
+    let path = path.as_bytes();
+    if !name.starts_with(path) {
+        return false;
+    }
+
+    let rest = &name[path.len()..];
+    rest.is_empty() || rest == b"/"
+}
+
+/// fills `buffer` with the header of an empty regular file at `path`.
+fn fill_header(buffer: &mut [u8; HEADER_SIZE], path: &str) -> Result<(), FSError> {
+    unsafe {
+        let (head, body, _tail) = buffer.align_to_mut::<TarHeader>();
+        if !head.is_empty() {
+            return Err(FSError::AlignmentError);
         }
+        let tar_header = &mut body[0];
+
+        let path_bytes = path.as_bytes();
+        if path_bytes.len() >= tar_header.name.len() {
+            return Err(FSError::IllegalPath);
+        }
+        tar_header.name[0..path_bytes.len()].copy_from_slice(path_bytes);
+
+        usize_to_oct(0o644, &mut tar_header.mode);
+        usize_to_oct(0, &mut tar_header.uid);
+        usize_to_oct(0, &mut tar_header.gid);
+        usize_to_oct(0, &mut tar_header.size);
+        usize_to_oct(0, &mut tar_header.mtime);
+        tar_header.f_type = b'0';
+        tar_header.signature.copy_from_slice(b"ustar\0");
+        tar_header.version.copy_from_slice(b"00");
     }
 
-    number
+    update_checksum(buffer);
+    Ok(())
 }
 
 impl TarFS {
-    pub fn find_offset(devfd: &mut FileDescriptor, path: &str) -> Option<(usize, usize)> {
+    /// size of the underlying device in bytes, anything beyond this is not a valid offset.
+    #[inline]
+    fn device_limit(devfd: &FileDescriptor) -> usize {
+        let max_seekable = u32::MAX as usize;
+        match devfd {
+            FileDescriptor::DevFSNode(dev) if dev.major == STORAGE_DEVICE_MAJOR as u32 => {
+                if let Some(size) = disk::drive_size(dev.minor as usize) {
+                    if size < max_seekable {
+                        return size;
+                    }
+                }
+                max_seekable
+            }
+            _ => max_seekable,
+        }
+    }
+
+    fn lookup(devfd: &mut FileDescriptor, path: &str) -> Result<TarLookup, FSError> {
         // iterate over the structure:
 
         let mut buffer: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
-        let mut block_no = 0;
+        let mut block_no: usize = 0;
+        let device_size = TarFS::device_limit(devfd);
 
         unsafe {
             let devfs_driver = DevFSDriver::new();
@@ -86,40 +349,313 @@ impl TarFS {
                         "Disk IO error when reading from Tarfs, err={:?}",
                         read_result.unwrap_err()
                     );
-                    return None;
+                    return Err(FSError::IOError);
                 }
 
-                let (head, body, _tail) = buffer.align_to::<TarHeader>();
-                assert_eq!(head.is_empty(), true);
-                let tar_header = &body[0];
-
-                let signature = str::from_utf8_unchecked(&tar_header.signature);
+                let entry_result = check_header(&buffer, block_no, device_size);
+                if entry_result.is_err() {
+                    return Err(entry_result.unwrap_err());
+                }
 
-                if !signature.starts_with("ustar") {
+                let entry_opt = entry_result.unwrap();
+                if entry_opt.is_none() {
                     break;
                 }
 
-                let read_path = str::from_utf8_unchecked(&tar_header.name);
-                // check path, are they equal?
-                if read_path.starts_with(path) {
-                    let file_entry = (block_no + 1) * mem::size_of::<TarHeader>();
-                    return Some((file_entry, oct_to_usize(&tar_header.size)));
+                let (data_offset, file_size) = entry_opt.unwrap();
+                let tar_header = &buffer.align_to::<TarHeader>().1[0];
+
+                if name_matches(&tar_header.name, path) {
+                    return Ok(TarLookup::Found(
+                        block_no * HEADER_SIZE,
+                        data_offset,
+                        file_size,
+                    ));
+                }
+
+                // skip the data blocks and the header:
+                let next_block = (file_size / HEADER_SIZE)
+                    .checked_add((file_size % HEADER_SIZE != 0) as usize)
+                    .and_then(|blocks| blocks.checked_add(1))
+                    .and_then(|blocks| block_no.checked_add(blocks));
+                let next_offset = next_block.and_then(|blocks| blocks.checked_mul(HEADER_SIZE));
+
+                if next_offset.is_none() || next_offset.unwrap() >= device_size {
+                    // reached the end of device without finding the end-of-archive marker.
+                    return Ok(TarLookup::End(device_size));
                 }
 
-                let to_skip_bytes = oct_to_usize(&tar_header.size);
-                block_no += (to_skip_bytes + HEADER_SIZE - 1) / HEADER_SIZE;
-                block_no += 1;
+                block_no = next_block.unwrap();
 
                 let seek_result =
-                    devfs_driver.seek(devfd, (block_no * HEADER_SIZE) as u32, SeekType::SEEK_SET);
+                    devfs_driver.seek(devfd, next_offset.unwrap() as u32, SeekType::SEEK_SET);
                 if seek_result.is_err() {
                     log::debug!("IO error on disk seek");
-                    break;
+                    return Err(FSError::IOError);
                 }
             }
         }
 
-        None
+        Ok(TarLookup::End(block_no * HEADER_SIZE))
+    }
+
+    /// returns the (data offset, size) of the entry at path if it exists.
+    pub fn find_offset(
+        devfd: &mut FileDescriptor,
+        path: &str,
+    ) -> Result<Option<(usize, usize)>, FSError> {
+        let lookup_result = TarFS::lookup(devfd, path);
+        if lookup_result.is_err() {
+            return Err(lookup_result.unwrap_err());
+        }
+
+        match lookup_result.unwrap() {
+            TarLookup::Found(_, offset, size) => Ok(Some((offset, size))),
+            TarLookup::End(_) => Ok(None),
+        }
+    }
+
+    /// creates an empty regular file entry at the end of the archive, followed by
+    /// the two zero blocks marking the end. returns the header offset.
+    fn create_entry(
+        devfd: &mut FileDescriptor,
+        end_offset: usize,
+        path: &str,
+    ) -> Result<usize, FSError> {
+        let mut buffer: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+        // header + two terminator blocks must fit in the device
+        if end_offset + 3 * HEADER_SIZE > TarFS::device_limit(devfd) {
+            log::debug!("No space left on tarfs device to create {}", path);
+            return Err(FSError::IOError);
+        }
+
+        let fill_result = fill_header(&mut buffer, path);
+        if fill_result.is_err() {
+            return Err(fill_result.unwrap_err());
+        }
+
+        let devfs_driver = DevFSDriver::new();
+        let zero_block: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+        // write the terminator first, so the archive stays valid if we fail in between.
+        for idx in 1..3 {
+            let write_result = write_block_at(
+                &devfs_driver,
+                devfd,
+                end_offset + idx * HEADER_SIZE,
+                &zero_block,
+            );
+            if write_result.is_err() {
+                return Err(write_result.unwrap_err());
+            }
+        }
+
+        let write_result = write_block_at(&devfs_driver, devfd, end_offset, &buffer);
+        if write_result.is_err() {
+            return Err(write_result.unwrap_err());
+        }
+
+        log::debug!("Created tarfs entry {} at offset {}", path, end_offset);
+        Ok(end_offset)
+    }
+
+    /// appends data to the entry, the entry must be the last one in the archive.
+    fn append(
+        devfd: &mut FileDescriptor,
+        tarfd: &mut TarFileDescriptor,
+        data: &[u8],
+    ) -> Result<usize, FSError> {
+        let devfs_driver = DevFSDriver::new();
+        let mut block: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        let zero_block: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+        let aligned_size = Alignment::align_up(tarfd.size as u64, HEADER_SIZE as u64) as usize;
+        let read_result =
+            read_block_at(&devfs_driver, devfd, tarfd.offset + aligned_size, &mut block);
+        if read_result.is_err() {
+            return Err(read_result.unwrap_err());
+        }
+
+        // overwriting entries in the middle of the archive is not supported.
+        if block[257..262] == *b"ustar" {
+            return Err(FSError::InvalidOperation);
+        }
+
+        let new_size = tarfd.size + data.len();
+        let new_aligned = Alignment::align_up(new_size as u64, HEADER_SIZE as u64) as usize;
+        if tarfd.offset + new_aligned + 2 * HEADER_SIZE > TarFS::device_limit(devfd) {
+            log::debug!("No space left on tarfs device");
+            return Err(FSError::IOError);
+        }
+
+        // write the data block by block, the last block of the file may be partially filled.
+        let mut position = tarfd.size;
+        let mut written = 0;
+        while written < data.len() {
+            let block_offset = tarfd.offset + (position / HEADER_SIZE) * HEADER_SIZE;
+            let in_block = position % HEADER_SIZE;
+
+            if in_block != 0 {
+                let read_result = read_block_at(&devfs_driver, devfd, block_offset, &mut block);
+                if read_result.is_err() {
+                    return Err(read_result.unwrap_err());
+                }
+            } else {
+                block = [0; HEADER_SIZE];
+            }
+
+            let n_bytes = core::cmp::min(HEADER_SIZE - in_block, data.len() - written);
+            block[in_block..in_block + n_bytes].copy_from_slice(&data[written..written + n_bytes]);
+
+            let write_result = write_block_at(&devfs_driver, devfd, block_offset, &block);
+            if write_result.is_err() {
+                return Err(write_result.unwrap_err());
+            }
+
+            written += n_bytes;
+            position += n_bytes;
+        }
+
+        // move the terminator blocks past the new data:
+        for idx in 0..2 {
+            let write_result = write_block_at(
+                &devfs_driver,
+                devfd,
+                tarfd.offset + new_aligned + idx * HEADER_SIZE,
+                &zero_block,
+            );
+            if write_result.is_err() {
+                return Err(write_result.unwrap_err());
+            }
+        }
+
+        // update the size in the header:
+        let size_result = TarFS::write_entry_size(devfd, tarfd, new_size);
+        if size_result.is_err() {
+            return Err(size_result.unwrap_err());
+        }
+
+        Ok(data.len())
+    }
+
+    /// rewrites the size field of the entry header and its checksum.
+    fn write_entry_size(
+        devfd: &mut FileDescriptor,
+        tarfd: &mut TarFileDescriptor,
+        new_size: usize,
+    ) -> Result<(), FSError> {
+        let devfs_driver = DevFSDriver::new();
+        let mut block: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+        let read_result = read_block_at(&devfs_driver, devfd, tarfd.header_offset, &mut block);
+        if read_result.is_err() {
+            return Err(read_result.unwrap_err());
+        }
+
+        if parse_numeric(&block[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_SIZE])
+            != Some(header_checksum(&block))
+        {
+            log::debug!("Tarfs header checksum mismatch at {}", tarfd.header_offset);
+            return Err(FSError::IOError);
+        }
+
+        if !usize_to_oct(new_size, &mut block[124..136])
+            && !usize_to_base256(new_size, &mut block[124..136])
+        {
+            return Err(FSError::IOError);
+        }
+        update_checksum(&mut block);
+
+        let write_result = write_block_at(&devfs_driver, devfd, tarfd.header_offset, &block);
+        if write_result.is_err() {
+            return Err(write_result.unwrap_err());
+        }
+
+        tarfd.size = new_size;
+        Ok(())
+    }
+
+    /// sets the size of the entry, the entry must be the last one in the archive.
+    /// growing fills the new region with zeroes.
+    fn truncate(
+        devfd: &mut FileDescriptor,
+        tarfd: &mut TarFileDescriptor,
+        length: usize,
+    ) -> Result<(), FSError> {
+        if length == tarfd.size {
+            return Ok(());
+        }
+
+        let devfs_driver = DevFSDriver::new();
+        let mut block: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+        let zero_block: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+        let aligned_size = Alignment::align_up(tarfd.size as u64, HEADER_SIZE as u64) as usize;
+        let read_result =
+            read_block_at(&devfs_driver, devfd, tarfd.offset + aligned_size, &mut block);
+        if read_result.is_err() {
+            return Err(read_result.unwrap_err());
+        }
+
+        // entries in the middle of the archive can't change their size.
+        if block[257..262] == *b"ustar" {
+            return Err(FSError::ReadOnly);
+        }
+
+        if length > tarfd.size {
+            // append writes the zeroes block by block.
+            while tarfd.size < length {
+                let n_bytes = core::cmp::min(HEADER_SIZE, length - tarfd.size);
+                let append_result = TarFS::append(devfd, tarfd, &zero_block[0..n_bytes]);
+                if append_result.is_err() {
+                    return Err(append_result.unwrap_err());
+                }
+            }
+            return Ok(());
+        }
+
+        // shrink: clear the tail of the last partial block, so the stale bytes
+        // don't show up again if the file grows later.
+        let new_aligned = Alignment::align_up(length as u64, HEADER_SIZE as u64) as usize;
+        let in_block = length % HEADER_SIZE;
+        if in_block != 0 {
+            let block_offset = tarfd.offset + new_aligned - HEADER_SIZE;
+            let read_result = read_block_at(&devfs_driver, devfd, block_offset, &mut block);
+            if read_result.is_err() {
+                return Err(read_result.unwrap_err());
+            }
+
+            block[in_block..].copy_from_slice(&zero_block[in_block..]);
+            let write_result = write_block_at(&devfs_driver, devfd, block_offset, &block);
+            if write_result.is_err() {
+                return Err(write_result.unwrap_err());
+            }
+        }
+
+        // move the terminator blocks right after the remaining data:
+        for idx in 0..2 {
+            let write_result = write_block_at(
+                &devfs_driver,
+                devfd,
+                tarfd.offset + new_aligned + idx * HEADER_SIZE,
+                &zero_block,
+            );
+            if write_result.is_err() {
+                return Err(write_result.unwrap_err());
+            }
+        }
+
+        let size_result = TarFS::write_entry_size(devfd, tarfd, length);
+        if size_result.is_err() {
+            return Err(size_result.unwrap_err());
+        }
+
+        if tarfd.seeked_offset > length {
+            tarfd.seeked_offset = length;
+        }
+
+        Ok(())
     }
 }
 
@@ -149,31 +685,81 @@ impl FSOps for TarFSDriver {
         }
 
         let mut devfd = devfd_result.unwrap();
-        if let Some((offset, size)) = TarFS::find_offset(&mut devfd, &path) {
+        let lookup_result = TarFS::lookup(&mut devfd, &path);
+        if lookup_result.is_err() {
             let _ = devfs_driver.close(&devfd);
-            return Ok(FileDescriptor::TarFSNode(TarFileDescriptor {
-                offset,
-                size,
-                flags,
-                seeked_offset: 0,
-                driver_name: self.device.clone(),
-            }));
+            return Err(lookup_result.unwrap_err());
         }
 
+        let open_flags = POSIXOpenFlags::from_bits_truncate(flags);
+        let entry = match lookup_result.unwrap() {
+            TarLookup::Found(header_offset, offset, size) => {
+                if open_flags.contains(POSIXOpenFlags::O_CREAT | POSIXOpenFlags::O_EXCL) {
+                    // exclusive create, the caller expects the file to not exist.
+                    Err(FSError::AlreadyExist)
+                } else {
+                    Ok((header_offset, offset, size))
+                }
+            }
+            TarLookup::End(end_offset) => {
+                // O_TRUNC alone doesn't create the file.
+                if open_flags.contains(POSIXOpenFlags::O_CREAT) {
+                    // new entries are created in append mode at the end of the archive
+                    let create_result = TarFS::create_entry(&mut devfd, end_offset, &path);
+                    create_result
+                        .map(|header_offset| (header_offset, header_offset + HEADER_SIZE, 0))
+                } else {
+                    Err(FSError::NotFound)
+                }
+            }
+        };
+
         let _ = devfs_driver.close(&devfd);
-        Err(FSError::NotFound)
+        if entry.is_err() {
+            return Err(entry.unwrap_err());
+        }
+
+        let (header_offset, offset, size) = entry.unwrap();
+        Ok(FileDescriptor::TarFSNode(TarFileDescriptor {
+            header_offset,
+            offset,
+            size,
+            flags,
+            seeked_offset: 0,
+            driver_name: self.device.clone(),
+        }))
     }
 
-    fn close(&self, _fd: &FileDescriptor) -> Result<(), FSError> {
+    fn close(&mut self, _fd: &FileDescriptor) -> Result<(), FSError> {
         // a stub
         Ok(())
     }
 }
 
 impl FDOps for TarFSDriver {
-    fn write(&self, _fd: &mut FileDescriptor, _buffer: &[u8]) -> Result<usize, FSError> {
-        // tarfs doens't support writes, it is a readonly file-system.
-        Err(FSError::InvalidOperation)
+    fn write(&self, fd: &mut FileDescriptor, buffer: &[u8]) -> Result<usize, FSError> {
+        // tarfs supports only appending to the last entry of the archive.
+        match fd {
+            FileDescriptor::TarFSNode(tarfd) => {
+                let open_flags = POSIXOpenFlags::from_bits_truncate(tarfd.flags);
+                if !open_flags.intersects(POSIXOpenFlags::O_WRONLY | POSIXOpenFlags::O_RDWR) {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                let mut dev_driver = DevFSDriver::new();
+                let dev_result = dev_driver.open(&self.device, 0);
+                if dev_result.is_err() {
+                    return Err(FSError::DeviceNotFound);
+                }
+
+                let mut dev_handle = dev_result.unwrap();
+                let write_result = TarFS::append(&mut dev_handle, tarfd, buffer);
+                let _ = dev_driver.close(&dev_handle);
+                return write_result;
+            }
+            _ => {}
+        }
+        Err(FSError::NotFound)
     }
 
     fn read(&self, fd: &mut FileDescriptor, buffer: &mut [u8]) -> Result<usize, FSError> {
@@ -192,6 +778,11 @@ impl FDOps for TarFSDriver {
                     return Err(FSError::InvalidOperation);
                 }
 
+                // a seek or pread can go past the end, there is nothing to read there.
+                if tarfd.seeked_offset >= tarfd.size {
+                    return Ok(0);
+                }
+
                 let block_offset = tarfd.offset + tarfd.seeked_offset;
 
                 // seek to this offset
@@ -283,9 +874,40 @@ impl FDOps for TarFSDriver {
     ) -> Result<usize, FSError> {
         Ok(0)
     }
+
+    fn truncate(&self, fd: &mut FileDescriptor, length: usize) -> Result<(), FSError> {
+        match fd {
+            FileDescriptor::TarFSNode(tarfd) => {
+                let open_flags = POSIXOpenFlags::from_bits_truncate(tarfd.flags);
+                if !open_flags.intersects(POSIXOpenFlags::O_WRONLY | POSIXOpenFlags::O_RDWR) {
+                    return Err(FSError::InvalidOperation);
+                }
+
+                let mut dev_driver = DevFSDriver::new();
+                let dev_result = dev_driver.open(&self.device, 0);
+                if dev_result.is_err() {
+                    return Err(FSError::DeviceNotFound);
+                }
+
+                let mut dev_handle = dev_result.unwrap();
+                let truncate_result = TarFS::truncate(&mut dev_handle, tarfd, length);
+                let _ = dev_driver.close(&dev_handle);
+                return truncate_result;
+            }
+            _ => {}
+        }
+        Err(FSError::NotFound)
+    }
 }
 
 pub fn mount_tarfs(device: &str, path: &str) {
+    #[cfg(feature = "debug_checks")]
+    test_numeric_fields();
+    #[cfg(feature = "debug_checks")]
+    test_header_sizes();
+    #[cfg(feature = "debug_checks")]
+    test_entry_names();
+
     let mut fs_lock = FILESYSTEM.lock();
     let tarfs = TarFSDriver::new_from_drive(device);
     let mount_info = MountInfo::TarFS(tarfs);
@@ -295,3 +917,136 @@ pub fn mount_tarfs(device: &str, path: &str) {
         .expect("Failed to mount tarfs");
     log::info!("Mounted tarfs at {}", path);
 }
+
+#[cfg(feature = "debug_checks")]
+fn test_numeric_fields() {
+    // mode fields as written by different tar implementations
+    assert_eq!(parse_numeric(b"0000755\0"), Some(0o755));
+    assert_eq!(parse_numeric(b" 0000644 "), Some(0o644));
+    assert_eq!(parse_numeric(b"000644 \0"), Some(0o644));
+    assert_eq!(parse_numeric(b"00000001750\0"), Some(1000));
+    // checksum, six digits, NUL and space
+    assert_eq!(parse_numeric(b"011145\0 "), Some(0o11145));
+
+    // 8GiB does not fit in 11 octal digits, GNU tar writes it in base-256.
+    let large_size: [u8; 12] = [0x80, 0, 0, 0, 0, 0, 0, 0x02, 0, 0, 0, 0];
+    assert_eq!(parse_numeric(&large_size), Some(8 * 1024 * 1024 * 1024));
+
+    let mut field: [u8; 12] = [0; 12];
+    assert!(!usize_to_oct(8 * 1024 * 1024 * 1024, &mut field));
+    assert!(usize_to_base256(8 * 1024 * 1024 * 1024, &mut field));
+    assert_eq!(field, large_size);
+    assert!(usize_to_oct(1000, &mut field));
+    assert_eq!(parse_numeric(&field), Some(1000));
+
+    // damaged fields must not parse as some other number
+    assert_eq!(parse_numeric(b"\0\0\0\0\0\0\0\0"), None);
+    assert_eq!(parse_numeric(b"        "), None);
+    assert_eq!(parse_numeric(b"0007x5\0 "), None);
+    assert_eq!(parse_numeric(b"00 0755\0"), None);
+    assert_eq!(parse_numeric(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]), None);
+    assert_eq!(
+        parse_numeric(&[0x80, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        None
+    );
+
+    log::info!("Passed tarfs numeric field test.");
+}
+
+/// the size field follows the name, mode, uid and gid.
+#[cfg(feature = "debug_checks")]
+const SIZE_OFFSET: usize = 124;
+
+/// the header with another size field, the checksum is recomputed.
+#[cfg(feature = "debug_checks")]
+fn with_size(header: &[u8; HEADER_SIZE], size: &[u8; 12]) -> [u8; HEADER_SIZE] {
+    let mut resized = *header;
+    resized[SIZE_OFFSET..SIZE_OFFSET + size.len()].copy_from_slice(size);
+    update_checksum(&mut resized);
+    resized
+}
+
+/// lookup gives up on these headers instead of seeking past the device.
+#[cfg(feature = "debug_checks")]
+fn test_header_sizes() {
+    const DEVICE_SIZE: usize = 1024 * 1024;
+    let last_block = DEVICE_SIZE / HEADER_SIZE - 1;
+
+    let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+    assert!(fill_header(&mut header, "tarfs/sbin/size.dat").is_ok());
+
+    let small = with_size(&header, b"00000000144\0");
+    assert!(matches!(
+        check_header(&small, 0, DEVICE_SIZE),
+        Ok(Some((HEADER_SIZE, 100)))
+    ));
+
+    // 8GiB - 1, far more than the device holds.
+    let absurd = with_size(&header, b"77777777777\0");
+    assert!(matches!(
+        check_header(&absurd, 0, DEVICE_SIZE),
+        Err(FSError::IOError)
+    ));
+
+    // the largest base-256 size, the end of the data overflows.
+    let overflowing = with_size(
+        &header,
+        &[0x80, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+    );
+    assert!(matches!(
+        check_header(&overflowing, 0, DEVICE_SIZE),
+        Err(FSError::IOError)
+    ));
+
+    // a small entry whose data would run past the end of the device.
+    assert!(matches!(
+        check_header(&small, last_block, DEVICE_SIZE),
+        Err(FSError::IOError)
+    ));
+    assert!(matches!(
+        check_header(&small, usize::MAX / HEADER_SIZE, DEVICE_SIZE),
+        Err(FSError::IOError)
+    ));
+
+    // the size was changed without the checksum.
+    let mut damaged = small;
+    damaged[SIZE_OFFSET] = b'7';
+    assert!(matches!(
+        check_header(&damaged, 0, DEVICE_SIZE),
+        Err(FSError::IOError)
+    ));
+
+    let end: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+    assert!(matches!(check_header(&end, 0, DEVICE_SIZE), Ok(None)));
+
+    log::info!("Passed tarfs header size test.");
+}
+
+#[cfg(feature = "debug_checks")]
+fn test_entry_names() {
+    let mut name: [u8; 100] = [0; 100];
+    name[0..15].copy_from_slice(b"tarfs/sbin/foo\0");
+    assert!(name_matches(&name, "tarfs/sbin/foo"));
+    assert!(!name_matches(&name, "tarfs/sbin/fo"));
+    assert!(!name_matches(&name, "tarfs/sbin/foobar"));
+    assert!(!name_matches(&name, "tarfs/sbin"));
+
+    name[0..18].copy_from_slice(b"tarfs/sbin/foobar\0");
+    assert!(!name_matches(&name, "tarfs/sbin/foo"));
+    assert!(name_matches(&name, "tarfs/sbin/foobar"));
+
+    // a directory, and a file in it.
+    name[0..16].copy_from_slice(b"tarfs/sbin/foo/\0");
+    assert!(name_matches(&name, "tarfs/sbin/foo"));
+    name[0..19].copy_from_slice(b"tarfs/sbin/foo/bar\0");
+    assert!(!name_matches(&name, "tarfs/sbin/foo"));
+    assert!(name_matches(&name, "tarfs/sbin/foo/bar"));
+
+    // the name can fill the whole field without a NUL.
+    let full: [u8; 100] = [b'a'; 100];
+    let full_path = core::str::from_utf8(&full).unwrap();
+    assert!(name_matches(&full, full_path));
+    assert!(!name_matches(&full, &full_path[..99]));
+
+    log::info!("Passed tarfs entry name test.");
+}
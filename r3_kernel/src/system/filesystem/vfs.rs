@@ -4,13 +4,36 @@ extern crate spin;
 use crate::system::filesystem::devfs::DevFSDriver;
 use crate::system::filesystem::paths;
 use crate::system::filesystem::ustar::TarFSDriver;
-use crate::system::filesystem::{FDOps, FSError, FSOps, FileDescriptor, MountInfo, SeekType, FStatInfo};
+use crate::system::filesystem::{
+    FDOps, FSError, FSOps, FStatInfo, FileDescriptor, MountInfo, SeekType,
+};
 
-use alloc::{boxed::Box, string::String, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
 use spin::Mutex;
 
 use lazy_static::lazy_static;
 
+/// max number of directories remembered by the resolver cache.
+const RESOLVE_CACHE_SIZE: usize = 32;
+
+/// mount paths are compared without the trailing slash, so `/dev/` and `/dev` are the same.
+#[inline]
+fn mount_key(path: &str) -> &str {
+    if path.len() > 1 && path.ends_with('/') {
+        return &path[0..path.len() - 1];
+    }
+    path
+}
+
+/// returns the directory component of the path, including the trailing slash.
+#[inline]
+fn dir_component(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(pos) => &path[0..pos + 1],
+        None => path,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VFSMountPoint {
     pub path: String,
@@ -35,13 +58,29 @@ impl VFSMountPoint {
 #[derive(Debug, Clone)]
 pub struct VFS {
     pub mountpoints: Vec<VFSMountPoint>,
+    /// mount path (without trailing slash) to index in mountpoints.
+    mount_table: BTreeMap<String, usize>,
+    /// directory to resolved (mount index, prefix length), cleared on mount and umount.
+    resolve_cache: BTreeMap<String, (usize, usize)>,
 }
 
 impl VFS {
     pub fn empty() -> Self {
         VFS {
             mountpoints: Vec::new(),
+            mount_table: BTreeMap::new(),
+            resolve_cache: BTreeMap::new(),
+        }
+    }
+
+    /// indices change when a mountpoint is removed, so the table is built again.
+    fn rebuild_mount_table(&mut self) {
+        self.mount_table.clear();
+        for (idx, mount) in self.mountpoints.iter().enumerate() {
+            self.mount_table
+                .insert(String::from(mount_key(&mount.path)), idx);
         }
+        self.resolve_cache.clear();
     }
 
     pub fn mount_at(&mut self, path: &str, mountinfo: MountInfo) -> Result<(), FSError> {
@@ -58,18 +97,14 @@ impl VFS {
         };
 
         self.mountpoints.push(mountpoint);
+        self.rebuild_mount_table();
         return Ok(());
     }
 
     /// is the mount point exists at given path? if yes return the index
     /// or return `None`.
     pub fn get_mount_index(&self, path: &str) -> Option<usize> {
-        for (idx, mount) in self.mountpoints.iter().enumerate() {
-            if mount.path == path {
-                return Some(idx);
-            }
-        }
-        None
+        self.mount_table.get(mount_key(path)).map(|idx| *idx)
     }
 
     pub fn remove_mount(&mut self, path: &str) -> Result<(), FSError> {
@@ -80,37 +115,81 @@ impl VFS {
             }
 
             self.mountpoints.remove(mount_index);
+            self.rebuild_mount_table();
             return Ok(());
         }
         Err(FSError::NotFound)
     }
 
-    /// returns the index of matching mountpoint
-    /// resolves the path using longest prefix search to get the
-    /// latest mountpoint matching the given path prefix
+    /// returns the index of matching mountpoint and the length of its path.
+    /// resolves the path using longest prefix search, the prefix must end at
+    /// a component boundary, so `/dev/pts/0` picks `/dev/pts` over `/dev`
+    /// and `/devices` does not match `/dev`.
     /// the path provided to this function must be cananoized path.
     pub fn get_matching_mountpoint(&self, path: &str) -> Result<(usize, usize), FSError> {
-        let mut curr_prefix_length = 0;
-        let mut curr_index: i32 = -1;
+        // walk up the components, the first mountpoint found is the longest one.
+        let mut candidate = mount_key(path);
+        loop {
+            if let Some(&idx) = self.mount_table.get(candidate) {
+                let prefix_length = self.mountpoints[idx].path.len();
+                return Ok((idx, prefix_length));
+            }
 
-        for (idx, mountpoint) in self.mountpoints.iter().enumerate() {
-            if path.starts_with(&mountpoint.path) {
-                // longest prefix?
-                let new_length = mountpoint.path.len();
-                if mountpoint.path.len() > curr_prefix_length {
-                    curr_prefix_length = new_length;
-                    curr_index = idx as i32;
-                }
+            if candidate == "/" {
+                return Err(FSError::NotFound);
             }
+
+            candidate = match candidate.rfind('/') {
+                Some(0) => "/",
+                Some(pos) => &candidate[0..pos],
+                None => return Err(FSError::NotFound),
+            };
         }
+    }
 
-        if curr_index < 0 {
-            // no mountpoint found:
-            return Err(FSError::NotFound);
+    /// same as `get_matching_mountpoint`, but remembers the result for the directory
+    /// of the path, so every file of an already seen directory resolves in one lookup.
+    pub fn resolve_mountpoint(&mut self, path: &str) -> Result<(usize, usize), FSError> {
+        // the path itself can be a mountpoint which is not visible from its directory.
+        if let Some(idx) = self.get_mount_index(path) {
+            return Ok((idx, self.mountpoints[idx].path.len()));
+        }
+
+        let dir = dir_component(path);
+        if let Some(&resolved) = self.resolve_cache.get(dir) {
+            return Ok(resolved);
+        }
+
+        let resolved = self.get_matching_mountpoint(dir);
+        if resolved.is_err() {
+            return resolved;
         }
 
-        // safe to convert because index >= 0
-        Ok((curr_index as usize, curr_prefix_length))
+        if self.resolve_cache.len() >= RESOLVE_CACHE_SIZE {
+            self.resolve_cache.clear();
+        }
+
+        let resolved = resolved.unwrap();
+        self.resolve_cache.insert(String::from(dir), resolved);
+        Ok(resolved)
+    }
+
+    /// finds the mountpoint that served this file descriptor.
+    fn get_fd_mount_index(&self, fd: &FileDescriptor) -> Option<usize> {
+        for (idx, mountpoint) in self.mountpoints.iter().enumerate() {
+            let is_owner = match (fd, mountpoint.mountinfo.as_ref()) {
+                (FileDescriptor::DevFSNode(_), MountInfo::DevFS(_)) => true,
+                (FileDescriptor::TarFSNode(tarfd), MountInfo::TarFS(tar_driver)) => {
+                    tar_driver.device == tarfd.driver_name
+                }
+                _ => false,
+            };
+
+            if is_owner {
+                return Some(idx);
+            }
+        }
+        None
     }
 
     /// dumps all the mountpoints
@@ -131,40 +210,57 @@ impl FSOps for VFS {
 
         let formatted_path = formatted_path_opt.unwrap();
 
-        let mp_result = self.get_matching_mountpoint(&formatted_path);
+        let mp_result = self.resolve_mountpoint(&formatted_path);
         if mp_result.is_err() {
             return Err(FSError::NotFound);
         }
 
         let (mp_index, spl_pos) = mp_result.unwrap();
+        // the mountpoint path can have a trailing slash which the path doesn't.
+        let spl_pos = core::cmp::min(spl_pos, formatted_path.len());
+        let (_, remaining_path) = formatted_path.split_at(spl_pos);
+
         let entry: &mut VFSMountPoint = self.mountpoints.get_mut(mp_index).unwrap();
-        match entry.mountinfo.as_mut() {
-            MountInfo::DevFS(dev_driver) => {
-                let (_, remaining_path) = formatted_path.split_at(spl_pos);
-                return dev_driver.open(&remaining_path, flags);
-            }
-            MountInfo::TarFS(tar_driver) => {
-                let (_, remaining_path) = formatted_path.split_at(spl_pos);
-                return tar_driver.open(&remaining_path, flags);
-            }
-            _ => {
-                return Err(FSError::NotYetImplemented);
-            }
+        let open_result = match entry.mountinfo.as_mut() {
+            MountInfo::DevFS(dev_driver) => dev_driver.open(&remaining_path, flags),
+            MountInfo::TarFS(tar_driver) => tar_driver.open(&remaining_path, flags),
+            _ => Err(FSError::NotYetImplemented),
+        };
+
+        if open_result.is_ok() {
+            entry.incr_refcount();
         }
+        open_result
     }
 
-    fn close(&self, fd: &FileDescriptor) -> Result<(), FSError> {
-        // get the longest prefix mountpoint:
-        match fd {
+    fn close(&mut self, fd: &FileDescriptor) -> Result<(), FSError> {
+        let close_result = match fd {
             FileDescriptor::DevFSNode(_) => {
                 // create a new devfs driver and issue close:
-                let devfs_driver = DevFSDriver::new();
-                return devfs_driver.close(fd);
+                let mut devfs_driver = DevFSDriver::new();
+                devfs_driver.close(fd)
             }
-            _ => {
-                return Err(FSError::NotYetImplemented);
+            FileDescriptor::TarFSNode(tarfd) => {
+                let mut tar_driver = TarFSDriver::new_from_drive(&tarfd.driver_name);
+                tar_driver.close(fd)
+            }
+            FileDescriptor::PipeNode(pipefd) => {
+                pipefd.close();
+                Ok(())
+            }
+            FileDescriptor::SocketNode(sockfd) => {
+                // the port and the handle in the socket set are given back here.
+                sockfd.close().map_err(|_| FSError::IOError)
+            }
+            _ => Err(FSError::NotYetImplemented),
+        };
+
+        if close_result.is_ok() {
+            if let Some(idx) = self.get_fd_mount_index(fd) {
+                self.mountpoints[idx].decr_refcount();
             }
         }
+        close_result
     }
 }
 
@@ -179,6 +275,10 @@ impl FDOps for VFS {
                 let tarfs_driver = TarFSDriver::new_from_drive(&tarfd.driver_name);
                 return tarfs_driver.read(fd, buffer);
             }
+            FileDescriptor::PipeNode(pipefd) => {
+                // never waits here, see sys_read for the blocking reads.
+                return pipefd.try_read(buffer);
+            }
             _ => {
                 return Err(FSError::NotYetImplemented);
             }
@@ -195,6 +295,9 @@ impl FDOps for VFS {
                 let tarfs_driver = TarFSDriver::new_from_drive(&tarfd.driver_name);
                 return tarfs_driver.write(fd, buffer);
             }
+            FileDescriptor::PipeNode(pipefd) => {
+                return pipefd.try_write(buffer);
+            }
             _ => {
                 return Err(FSError::NotYetImplemented);
             }
@@ -235,11 +338,30 @@ impl FDOps for VFS {
                 let trafs_driver = TarFSDriver::new_from_drive(&tarfd.driver_name);
                 return trafs_driver.fstat(fd);
             }
+            FileDescriptor::PipeNode(pipefd) => {
+                return Ok(pipefd.fstat());
+            }
             _ => {
                 return Err(FSError::NotYetImplemented);
             }
         }
     }
+
+    fn truncate(&self, fd: &mut FileDescriptor, length: usize) -> Result<(), FSError> {
+        match fd {
+            FileDescriptor::TarFSNode(tarfd) => {
+                let tarfs_driver = TarFSDriver::new_from_drive(&tarfd.driver_name);
+                return tarfs_driver.truncate(fd, length);
+            }
+            FileDescriptor::DevFSNode(_) => {
+                // devices have no size to set.
+                return Err(FSError::InvalidOperation);
+            }
+            _ => {
+                return Err(FSError::ReadOnly);
+            }
+        }
+    }
 }
 
 lazy_static! {
@@ -247,8 +369,65 @@ lazy_static! {
 }
 
 pub fn setup_fs() {
+    #[cfg(feature = "debug_checks")]
+    test_nested_mounts();
+
     log::info!(
         "VFS set-up successful, n_mountpoints={}",
         FILESYSTEM.lock().mountpoints.len()
     )
 }
+
+/// name of the driver that serves the path, the tarfs ones by their device.
+#[cfg(feature = "debug_checks")]
+fn resolved_driver(vfs: &mut VFS, path: &str) -> String {
+    let (idx, _) = vfs.resolve_mountpoint(path).expect("path did not resolve");
+    match vfs.mountpoints[idx].mountinfo.as_ref() {
+        MountInfo::DevFS(_) => String::from("devfs"),
+        MountInfo::TarFS(tar_driver) => tar_driver.device.clone(),
+        _ => String::from("other"),
+    }
+}
+
+#[cfg(feature = "debug_checks")]
+fn test_nested_mounts() {
+    let mut vfs = VFS::empty();
+    let mounts = [
+        ("/", MountInfo::TarFS(TarFSDriver::new_from_drive("root"))),
+        ("/dev/", MountInfo::DevFS(DevFSDriver::new())),
+        (
+            "/dev/pts",
+            MountInfo::TarFS(TarFSDriver::new_from_drive("pts")),
+        ),
+    ];
+    for (path, mountinfo) in mounts {
+        assert!(vfs.mount_at(path, mountinfo).is_ok());
+    }
+    assert!(vfs
+        .mount_at("/dev", MountInfo::DevFS(DevFSDriver::new()))
+        .is_err());
+
+    // twice, the second time from the cache.
+    for _ in 0..2 {
+        assert_eq!(resolved_driver(&mut vfs, "/sbin/sys_shell"), "root");
+        assert_eq!(resolved_driver(&mut vfs, "/devices/sda"), "root");
+        assert_eq!(resolved_driver(&mut vfs, "/dev"), "devfs");
+        assert_eq!(resolved_driver(&mut vfs, "/dev/sda"), "devfs");
+        assert_eq!(resolved_driver(&mut vfs, "/dev/ptsx"), "devfs");
+        assert_eq!(resolved_driver(&mut vfs, "/dev/pts"), "pts");
+        assert_eq!(resolved_driver(&mut vfs, "/dev/pts/0"), "pts");
+    }
+
+    // the cached directories must not outlive the mount.
+    assert!(vfs.remove_mount("/dev/pts").is_ok());
+    assert_eq!(resolved_driver(&mut vfs, "/dev/pts/0"), "devfs");
+    assert!(vfs
+        .mount_at(
+            "/dev/pts/",
+            MountInfo::TarFS(TarFSDriver::new_from_drive("pts"))
+        )
+        .is_ok());
+    assert_eq!(resolved_driver(&mut vfs, "/dev/pts/0"), "pts");
+
+    log::info!("Passed VFS nested mount test.");
+}
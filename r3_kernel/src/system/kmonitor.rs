@@ -0,0 +1,374 @@
+extern crate alloc;
+extern crate log;
+extern crate spin;
+
+use crate::cpu;
+use crate::drivers::keyboard::OnKeyCallback;
+use crate::drivers::pci;
+use crate::drivers::tty::{self, SYSTEM_TTY};
+use crate::drivers::uart::UART_DRIVER;
+use crate::mm::{heap, phy::PhysicalMemoryManager, VirtualAddress};
+use crate::system;
+use crate::system::net::{dns, iface, napi, types::NETWORK_IFACE_QUEUE};
+use crate::system::process::{self, PID, PROCESS_POOL};
+use crate::system::tasking::{
+    schedule_yield, Sched, ThreadSuspendType, ThreadWakeupType, SCHEDULER,
+};
+use crate::system::thread::{self, ThreadID};
+
+use alloc::{collections::VecDeque, format, string::String, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// a small command line that lives in the kernel, for when the shell is gone or
+// stuck. it runs as a kernel thread, F12 moves the keyboard between the monitor
+// and the terminal, the serial port is always read by it.
+
+const PROMPT: &str = "kmon> ";
+const MAX_LINE_LEN: usize = 128;
+
+/// ticks between two looks at the serial port.
+const POLL_TICKS: usize = 1;
+
+const BACKSPACE: char = '\x08';
+const DELETE: char = '\x7F';
+
+const NO_MONITOR_THREAD: u64 = u64::MAX;
+
+/// true while the keyboard belongs to the monitor.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static MONITOR_THREAD: AtomicU64 = AtomicU64::new(NO_MONITOR_THREAD);
+
+lazy_static! {
+    /// keys typed into the monitor, filled by the keyboard interrupt.
+    static ref PENDING_KEYS: Mutex<VecDeque<char>> = Mutex::new(VecDeque::new());
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Console {
+    Terminal,
+    Serial,
+}
+
+impl Console {
+    fn print(&self, string: &str) {
+        cpu::without_interrupts(|| match self {
+            Console::Terminal => SYSTEM_TTY.lock().write(string.as_bytes()),
+            Console::Serial => {
+                if let Some(uart) = UART_DRIVER.as_ref() {
+                    let string = string.replace('\n', "\r\n");
+                    uart.lock().write_from_buffer(string.as_bytes());
+                }
+            }
+        });
+    }
+
+    fn erase_char(&self) {
+        cpu::without_interrupts(|| match self {
+            Console::Terminal => SYSTEM_TTY.lock().erase_char(),
+            Console::Serial => {
+                if let Some(uart) = UART_DRIVER.as_ref() {
+                    uart.lock().write_from_buffer(b"\x08 \x08");
+                }
+            }
+        });
+    }
+}
+
+/// the line being typed on one of the consoles.
+struct LineEditor {
+    console: Console,
+    line: String,
+}
+
+impl LineEditor {
+    fn new(console: Console) -> Self {
+        LineEditor {
+            console,
+            line: String::new(),
+        }
+    }
+
+    fn feed(&mut self, key: char) {
+        match key {
+            '\r' | '\n' => {
+                self.console.print("\n");
+                let line = core::mem::replace(&mut self.line, String::new());
+                run_command(self.console, line.trim());
+                self.console.print(PROMPT);
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    self.console.erase_char();
+                }
+            }
+            _ if key.is_control() || self.line.len() >= MAX_LINE_LEN => {}
+            _ => {
+                self.line.push(key);
+                self.console.print(&format!("{}", key));
+            }
+        }
+    }
+}
+
+fn on_key(key: char) {
+    PENDING_KEYS.lock().push_back(key);
+
+    // the monitor sleeps between the polls of the serial port, the interrupted
+    // code might hold the scheduler, the key is then seen on the next poll.
+    if let Some(mut sched_lock) = SCHEDULER.try_lock() {
+        let tid = ThreadID::new(MONITOR_THREAD.load(Ordering::SeqCst));
+        sched_lock.check_wakeup(ThreadWakeupType::Wake(tid));
+    }
+}
+
+/// called by the keyboard on F12, returns the handler that takes the keys from now on.
+pub fn toggle() -> OnKeyCallback {
+    if MONITOR_THREAD.load(Ordering::SeqCst) == NO_MONITOR_THREAD {
+        return tty::on_kbd_data;
+    }
+
+    let active = !ACTIVE.load(Ordering::SeqCst);
+    ACTIVE.store(active, Ordering::SeqCst);
+    if active {
+        on_key
+    } else {
+        tty::on_kbd_data
+    }
+}
+
+fn print_help(console: Console) {
+    console.print(
+        "commands:\n  \
+         ps          list the processes\n  \
+         mem         physical memory and kernel heap usage\n  \
+         lspci       list the PCI devices\n  \
+         net         network interface state\n  \
+         kill <pid>  terminate a user process\n  \
+         help        this text\n",
+    );
+}
+
+fn print_processes(console: Console) {
+    // the pool is taken by the syscalls of the processes being listed.
+    let lines: Vec<String> = cpu::without_interrupts(|| {
+        PROCESS_POOL
+            .lock()
+            .pool_map
+            .values()
+            .map(|proc| {
+                format!(
+                    "{:>5} {:>5} {:<6} {:>7} {:<10} {}\n",
+                    proc.pid.as_u64(),
+                    proc.ppid.as_u64(),
+                    if proc.user { "user" } else { "kernel" },
+                    proc.threads.len(),
+                    format!("{:?}", proc.state),
+                    proc.name
+                )
+            })
+            .collect()
+    });
+
+    console.print("  PID  PPID MODE   THREADS STATE      NAME\n");
+    for line in lines {
+        console.print(&line);
+    }
+}
+
+fn print_memory(console: Console) {
+    let (total_frames, free_frames) = PhysicalMemoryManager::frame_stats();
+    let (heap_size, heap_used) = cpu::without_interrupts(|| heap::heap_stats());
+
+    console.print(&format!(
+        "frames: {} total, {} used, {} free ({} KiB free)\n",
+        total_frames,
+        total_frames - free_frames,
+        free_frames,
+        free_frames * 4
+    ));
+    console.print(&format!(
+        "heap: {} KiB, {} KiB used, {} KiB free\n",
+        heap_size / 1024,
+        heap_used / 1024,
+        (heap_size - heap_used) / 1024
+    ));
+}
+
+fn print_pci_devices(console: Console) {
+    let devices = pci::PCI_DEVICES.lock().clone();
+    console.print("BUS:DEV.FN VENDOR DEVICE CLASS\n");
+    for device in devices.iter() {
+        console.print(&format!(
+            " {:02x}:{:02x}.{:x}   {:04x}   {:04x}   {:02x}:{:02x}:{:02x}\n",
+            device.bus,
+            device.dev,
+            device.func,
+            device.vendor_id,
+            device.device_id,
+            device.class_code,
+            device.subclass,
+            device.prog_if
+        ));
+    }
+}
+
+fn print_network(console: Console) {
+    // the interrupt uses the interface and the device.
+    let (has_device, mac, ip_addr) = cpu::without_interrupts(|| {
+        let has_device = iface::has_physical_device();
+        let mac = if has_device {
+            iface::get_formatted_mac()
+        } else {
+            None
+        };
+
+        let iface_lock = iface::get_virtual_interface().lock();
+        let ip_addr = iface_lock.as_ref().and_then(|iface| iface.ipv4_address());
+        (has_device, mac, ip_addr)
+    });
+
+    console.print(&format!(
+        "interface: {}, {}\n",
+        if iface::is_up() { "up" } else { "down" },
+        if has_device { "physical" } else { "loopback" }
+    ));
+    console.print(&format!(
+        "mac: {}\n",
+        mac.unwrap_or_else(|| String::from("none"))
+    ));
+
+    match ip_addr {
+        Some(addr) => console.print(&format!("ipv4: {}\n", addr)),
+        None => console.print("ipv4: none\n"),
+    }
+
+    match dns::server() {
+        Some(server) => console.print(&format!("dns: {}\n", server)),
+        None => console.print("dns: none\n"),
+    }
+
+    console.print(&format!(
+        "rx: {} mode, threshold {} frames, {} received, {} dropped\n",
+        if napi::is_polling() {
+            "polling"
+        } else {
+            "interrupt"
+        },
+        napi::threshold(),
+        iface::n_rx_frames(),
+        NETWORK_IFACE_QUEUE.n_dropped()
+    ));
+}
+
+fn run_command(console: Console, line: &str) {
+    let mut words = line.split_whitespace();
+    let command = words.next();
+    match command {
+        None => {}
+        Some("help") => print_help(console),
+        Some("ps") => print_processes(console),
+        Some("mem") => print_memory(console),
+        Some("lspci") => print_pci_devices(console),
+        Some("net") => print_network(console),
+        Some("kill") => match words.next().map(|word| word.parse::<u64>()) {
+            Some(Ok(pid_no)) => {
+                let pid = PID::new(pid_no);
+                match system::kill_process(&pid, system::KILLED_EXIT_CODE) {
+                    Ok(()) => {
+                        log::info!("kmonitor: killed process {}", pid_no);
+                        console.print(&format!("killed {}\n", pid_no));
+                    }
+                    Err(reason) => console.print(&format!("kill: {}\n", reason)),
+                }
+            }
+            _ => console.print("usage: kill <pid>\n"),
+        },
+        Some(unknown) => console.print(&format!("{}: unknown command, try help\n", unknown)),
+    }
+}
+
+/// bytes waiting on the serial port.
+fn read_serial() -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if let Some(uart) = UART_DRIVER.as_ref() {
+        cpu::without_interrupts(|| {
+            let uart_lock = uart.lock();
+            while uart_lock.transit_received() && bytes.len() < MAX_LINE_LEN {
+                bytes.push(uart_lock.read_u8());
+            }
+        });
+    }
+    bytes
+}
+
+fn monitor_loop() -> ! {
+    let mut terminal = LineEditor::new(Console::Terminal);
+    let mut serial = LineEditor::new(Console::Serial);
+    let mut was_active = false;
+
+    serial.console.print(PROMPT);
+    loop {
+        let active = ACTIVE.load(Ordering::SeqCst);
+        if active && !was_active {
+            terminal
+                .console
+                .print("\nkernel monitor, F12 to leave, help for commands\n");
+            terminal.console.print(PROMPT);
+        } else if !active && was_active {
+            terminal.console.print("\n");
+        }
+        was_active = active;
+
+        loop {
+            let key = cpu::without_interrupts(|| PENDING_KEYS.lock().pop_front());
+            match key {
+                Some(key) => terminal.feed(key),
+                None => break,
+            }
+        }
+
+        for byte in read_serial() {
+            serial.feed(byte as char);
+        }
+
+        SCHEDULER
+            .lock()
+            .suspend_thread(ThreadSuspendType::SuspendSleep(POLL_TICKS));
+        schedule_yield();
+    }
+}
+
+fn monitor_thread() {
+    monitor_loop();
+}
+
+/// starts the monitor thread, needs the scheduler.
+pub fn start_monitor_thread() {
+    let process_res = process::new(format!("kernel_monitor"), false, "");
+    if process_res.is_err() {
+        log::error!(
+            "kmonitor: failed to create the monitor process: {:?}",
+            process_res.unwrap_err()
+        );
+        return;
+    }
+
+    let thread_res = thread::new_from_function(
+        &process_res.unwrap(),
+        format!("kmonitor"),
+        VirtualAddress::from_u64(monitor_thread as fn() as u64),
+    );
+
+    if thread_res.is_err() {
+        log::error!(
+            "kmonitor: failed to start the monitor thread: {:?}",
+            thread_res.unwrap_err()
+        );
+        return;
+    }
+
+    MONITOR_THREAD.store(thread_res.unwrap().as_u64(), Ordering::SeqCst);
+    log::info!("Started kernel monitor, press F12 or type on the serial port.");
+}
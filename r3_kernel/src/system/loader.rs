@@ -2,15 +2,33 @@ extern crate alloc;
 
 use crate::system::filesystem::vfs::FILESYSTEM;
 
-use crate::system::filesystem::{FDOps, FSOps, SeekType};
+use crate::system::filesystem::{FDOps, FSError, FSOps, SeekType};
 
-use alloc::vec::Vec;
+use alloc::{format, string::String, vec::Vec};
 use core::str;
 
+/// directories searched in order by execvp when the name has no '/' in it.
+pub const EXEC_SEARCH_PATH: &'static [&'static str] = &["/sbin", "/bin"];
+
+/// only the first block is looked at to find the `#!` line.
+const EXEC_HEADER_SIZE: usize = 512;
+
 #[derive(Debug, Clone)]
 pub enum LoadError {
     InvalidFormat,
     FileReadError,
+    NotFound,
+}
+
+/// how an executable file is run.
+#[derive(Debug, Clone)]
+pub enum ExecFormat {
+    ELF,
+    /// `#!interpreter [arg]`, the interpreter is run with the script path appended.
+    Script {
+        interpreter: String,
+        arg: Option<String>,
+    },
 }
 
 pub fn is_elf(binary: &[u8]) -> bool {
@@ -74,3 +92,105 @@ pub fn read_executable(path: &str) -> Result<Vec<u8>, LoadError> {
 
     Ok(binary_buffer)
 }
+
+fn file_exists(path: &str) -> bool {
+    let fd_res = FILESYSTEM.lock().open(path, 0);
+    if fd_res.is_err() {
+        return false;
+    }
+
+    let _ = FILESYSTEM.lock().close(&fd_res.unwrap());
+    true
+}
+
+/// names with a '/' are used as they are, bare names are looked up in `EXEC_SEARCH_PATH`.
+pub fn resolve_executable(name: &str) -> Result<String, LoadError> {
+    if name.len() == 0 {
+        return Err(LoadError::NotFound);
+    }
+
+    if name.contains('/') {
+        return Ok(String::from(name));
+    }
+
+    for dir in EXEC_SEARCH_PATH {
+        let candidate = format!("{}/{}", dir, name);
+        if file_exists(&candidate) {
+            return Ok(candidate);
+        }
+    }
+
+    Err(LoadError::NotFound)
+}
+
+/// parses the `#!` line, returns None if the interpreter is missing.
+fn parse_shebang(header: &[u8]) -> Option<(String, Option<String>)> {
+    let line_end = header
+        .iter()
+        .position(|c| *c == b'\n')
+        .unwrap_or(header.len());
+
+    let line_res = str::from_utf8(&header[2..line_end]);
+    if line_res.is_err() {
+        return None;
+    }
+
+    let line = line_res.unwrap().trim();
+    let mut parts = line.splitn(2, |c: char| c == ' ' || c == '\t');
+
+    let interpreter = parts.next().unwrap_or("");
+    if interpreter.len() == 0 {
+        return None;
+    }
+
+    // like linux, everything after the interpreter is passed as one argument.
+    let arg = parts
+        .next()
+        .map(|arg| arg.trim())
+        .filter(|arg| arg.len() > 0)
+        .map(|arg| String::from(arg));
+
+    Some((String::from(interpreter), arg))
+}
+
+/// reads the start of the file and decides how it can be executed,
+/// files that are neither ELF nor a script are `InvalidFormat`.
+pub fn probe_executable(path: &str) -> Result<ExecFormat, LoadError> {
+    let fd_res = FILESYSTEM.lock().open(path, 0);
+    if fd_res.is_err() {
+        return match fd_res.unwrap_err() {
+            FSError::NotFound | FSError::IllegalPath => Err(LoadError::NotFound),
+            _ => Err(LoadError::FileReadError),
+        };
+    }
+
+    let mut fd = fd_res.unwrap();
+    let mut header: Vec<u8> = Vec::new();
+    header.resize(EXEC_HEADER_SIZE, 0);
+
+    let read_res = FILESYSTEM.lock().read(&mut fd, &mut header);
+    let _ = FILESYSTEM.lock().close(&fd);
+
+    if read_res.is_err() {
+        log::debug!("exec probe failed, {:?}", read_res.unwrap_err());
+        return Err(LoadError::FileReadError);
+    }
+
+    let header = &header[0..read_res.unwrap()];
+
+    if is_elf(header) {
+        return Ok(ExecFormat::ELF);
+    }
+
+    if header.len() >= 2 && &header[0..2] == b"#!" {
+        let shebang = parse_shebang(header);
+        if shebang.is_none() {
+            return Err(LoadError::InvalidFormat);
+        }
+
+        let (interpreter, arg) = shebang.unwrap();
+        return Ok(ExecFormat::Script { interpreter, arg });
+    }
+
+    Err(LoadError::InvalidFormat)
+}
@@ -1,18 +1,133 @@
 pub mod abi;
 pub mod filesystem;
+#[cfg(feature = "kmonitor")]
+pub mod kmonitor;
 pub mod loader;
 pub mod net;
 pub mod posix;
 pub mod process;
+pub mod rlimit;
+#[cfg(feature = "selftest")]
+pub mod selftest;
 pub mod tasking;
 pub mod thread;
 pub mod timer;
 pub mod utils;
+pub mod vdso;
 
+use crate::cpu;
 use tasking::Sched;
 
+use alloc::format;
+
+/// the first user program, build with `R3_INIT=<path>` in the environment to boot another one.
+pub const INIT_PATH: &'static str = match option_env!("R3_INIT") {
+    Some(path) => path,
+    None => "/sbin/sys_shell",
+};
+
+/// starts the init program, it is the only user process that can change the system
+/// settings, there are no users or capabilities yet.
+pub fn start_init_program() -> Option<process::PID> {
+    let pid_opt = spawn_program(INIT_PATH);
+    if let Some(pid) = pid_opt.as_ref() {
+        process::set_init_program(pid);
+    }
+    pid_opt
+}
+
+#[inline]
+pub fn is_init_program(pid: &process::PID) -> bool {
+    pid.as_u64() != process::INIT_PID && process::init_pid().as_u64() == pid.as_u64()
+}
+
+/// starts `path` as a new process, logs and gives up if it's not an ELF binary.
+pub fn spawn_program(path: &str) -> Option<process::PID> {
+    match loader::probe_executable(path) {
+        Ok(loader::ExecFormat::ELF) => {}
+        Ok(_) => {
+            log::error!("{} is not an ELF binary, not starting it.", path);
+            return None;
+        }
+        Err(err) => {
+            log::error!("{} can't be started: {:?}", path, err);
+            return None;
+        }
+    }
+
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let process_result = process::new(format!("{}", name), true, path);
+    if process_result.is_err() {
+        log::error!(
+            "Failed to create the process for {}: {:?}",
+            path,
+            process_result.unwrap_err()
+        );
+        return None;
+    }
+
+    let pid = process_result.unwrap();
+    let thread_result = thread::new_main_thread(&pid, format!("main"));
+    if thread_result.is_err() {
+        log::error!("Failed to run {} thread: {:?}", path, thread_result.unwrap_err());
+        return None;
+    }
+
+    Some(pid)
+}
+
+/// exit code of killed processes, same as a shell reports SIGKILL.
+pub const KILLED_EXIT_CODE: i64 = 128 + 9;
+
+/// terminates the process like it called exit, only user processes can be killed.
+/// init is refused, the orphans of the killed process are handed over to it.
+pub fn kill_process(pid: &process::PID, code: i64) -> Result<(), &'static str> {
+    if pid.as_u64() == process::INIT_PID || is_init_program(pid) {
+        return Err("init can't be killed");
+    }
+
+    let is_usermode = cpu::without_interrupts(|| {
+        process::PROCESS_POOL
+            .lock()
+            .get_ref(pid)
+            .map(|proc| proc.is_usermode())
+    });
+
+    match is_usermode {
+        None => return Err("no such process"),
+        Some(false) => return Err("kernel processes can't be killed"),
+        Some(true) => {}
+    }
+
+    timer::pause_events();
+    let tids = tasking::SCHEDULER.lock().remove_process_threads(pid);
+    for tid in tids {
+        thread::release_tid(tid);
+    }
+
+    let remove_result = process::PROCESS_POOL.lock().remove_process(pid, code);
+    tasking::SCHEDULER
+        .lock()
+        .check_wakeup(tasking::ThreadWakeupType::FromWait(pid.clone()));
+    timer::resume_events();
+
+    if remove_result.is_err() {
+        log::error!(
+            "failed to remove process {}: {:?}",
+            pid.as_u64(),
+            remove_result.unwrap_err()
+        );
+        return Err("the process could not be removed");
+    }
+
+    Ok(())
+}
+
 pub fn init_tasking() {
+    vdso::setup_time_page();
     process::setup_process_pool();
+    #[cfg(feature = "debug_checks")]
+    utils::CodeMapper::test_huge_code_pages();
     tasking::setup_scheduler();
 }
 
@@ -29,12 +144,23 @@ pub fn init_networking() {
     net::init_networking();
 }
 
+/// needs the scheduler, so it's started after `init_tasking`.
+pub fn start_network_thread() {
+    net::napi::start_poll_thread();
+}
+
+/// these are read from the copy kept by the scheduler, no lock is taken.
 #[inline]
 pub fn current_tid() -> Option<thread::ThreadID> {
-    tasking::SCHEDULER.lock().current_tid()
+    tasking::current::tid()
 }
 
 #[inline]
 pub fn current_pid() -> Option<process::PID> {
-    tasking::SCHEDULER.lock().current_pid()
+    tasking::current::pid()
+}
+
+#[inline]
+pub fn current_ppid() -> Option<process::PID> {
+    tasking::current::ppid()
 }
@@ -13,7 +13,7 @@ use smoltcp::time::Instant;
 use smoltcp::wire::IpCidr;
 use spin::{Mutex, MutexGuard};
 
-use crate::system::net::iface;
+use crate::system::net::{dns, iface};
 use crate::system::net::types::SOCKETS_SET;
 use crate::system::timer::{wait_ns, PosixTimeval};
 
@@ -54,6 +54,14 @@ impl DHCPClient {
         log::info!("initialized DHCPv4 client")
     }
 
+    /// drops the client, used when the interface is configured statically.
+    pub fn stop() {
+        let mut dhcp_lock = DHCP_CLIENT.lock();
+        if dhcp_lock.is_some() {
+            *dhcp_lock = None;
+            log::info!("stopped DHCPv4 client");
+        }
+    }
 
     fn bounded_poll_dhcp_over_iface(
         iface_lock: &mut iface::LockedEthernetInterface,
@@ -149,7 +157,7 @@ impl DHCPClient {
 
     pub fn dhcp_next_poll(dhcp_lock: &mut LockedDHCPClient, instant: Instant) {
         if dhcp_lock.is_none() {
-            log::error!("cannot poll over empty DHCP client");
+            // the client is stopped when the interface is configured statically.
             return;
         }
 
@@ -188,7 +196,11 @@ impl DHCPClient {
                 .expect("failed to add a new ipv4 gateway route.");
         }
 
-        // 3. TODO: Do something with DNS address
+        // 3. Use the first DNS server offered (option 6):
+        if let Some(dns_server) = config.dns_servers.iter().flatten().next() {
+            dns::set_server(*dns_server);
+        }
+
         Ok(())
     }
 
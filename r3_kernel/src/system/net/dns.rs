@@ -0,0 +1,452 @@
+extern crate alloc;
+extern crate log;
+extern crate smoltcp;
+extern crate spin;
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use lazy_static::lazy_static;
+use smoltcp::wire::Ipv4Address;
+use spin::Mutex;
+
+use crate::drivers::random::SystemRandomDevice;
+use crate::system::net::types::{self, SocketFn, TransportType};
+use crate::system::net::udp::UDPSocket;
+use crate::system::timer::{self, Time};
+
+// a minimal stub resolver, only A records over UDP are supported.
+
+const DNS_PORT: u16 = 53;
+
+/// the resolver used with the static IP, same as the default gateway.
+pub const DEFAULT_DNS_SERVER: [u8; 4] = [192, 168, 0, 1];
+
+/// time given to the server for each attempt.
+const DNS_TIMEOUT_NS: u64 = 2 * Time::Second as u64;
+const DNS_MAX_ATTEMPTS: usize = 3;
+
+/// answers are cached for their TTL, but never longer than this.
+const DNS_MAX_TTL_SECS: u64 = 24 * 60 * 60;
+const DNS_MAX_CACHE_ENTRIES: usize = 32;
+
+const DNS_HEADER_SIZE: usize = 12;
+const DNS_MAX_MESSAGE_SIZE: usize = 512;
+const DNS_MAX_NAME_LEN: usize = 253;
+const DNS_MAX_LABEL_LEN: usize = 63;
+
+const DNS_FLAG_RESPONSE: u16 = 1 << 15;
+const DNS_FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const DNS_RCODE_MASK: u16 = 0xF;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_CLASS_IN: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DNSError {
+    /// no resolver was learned from DHCP or set statically
+    NoServer,
+    /// the name is empty, too long or has an empty label
+    InvalidName,
+    /// the server did not answer in time
+    Timeout,
+    /// the name does not exist (NXDOMAIN) or has no A record
+    NameNotFound,
+    /// the server answered with an error code other than NXDOMAIN
+    ServerFailure,
+    /// the answer could not be parsed
+    InvalidResponse,
+    SocketError,
+}
+
+struct CacheEntry {
+    address: [u8; 4],
+    expires_ns: u64,
+}
+
+lazy_static! {
+    static ref DNS_SERVER: Mutex<Option<Ipv4Address>> = Mutex::new(None);
+    static ref DNS_CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+}
+
+/// replaces the resolver, the cached answers of the old one are dropped.
+pub fn set_server(server: Ipv4Address) {
+    let mut server_lock = DNS_SERVER.lock();
+    if *server_lock != Some(server) {
+        log::info!("Using DNS server {}", server);
+        *server_lock = Some(server);
+        DNS_CACHE.lock().clear();
+    }
+}
+
+pub fn server() -> Option<Ipv4Address> {
+    *DNS_SERVER.lock()
+}
+
+/// dotted decimal names are returned as is, without a query.
+fn parse_ipv4(name: &str) -> Option<[u8; 4]> {
+    let mut address: [u8; 4] = [0; 4];
+    let mut parts = name.split('.');
+    for index in 0..4 {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 || !part.bytes().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+        let value: u16 = part.parse().ok()?;
+        if value > u8::MAX as u16 {
+            return None;
+        }
+        address[index] = value as u8;
+    }
+
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(address)
+}
+
+#[inline]
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    if offset + 2 > message.len() {
+        return None;
+    }
+    Some(u16::from_be_bytes([message[offset], message[offset + 1]]))
+}
+
+#[inline]
+fn read_u32(message: &[u8], offset: usize) -> Option<u32> {
+    if offset + 4 > message.len() {
+        return None;
+    }
+    Some(u32::from_be_bytes([
+        message[offset],
+        message[offset + 1],
+        message[offset + 2],
+        message[offset + 3],
+    ]))
+}
+
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, DNSError> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > DNS_MAX_NAME_LEN {
+        return Err(DNSError::InvalidName);
+    }
+
+    let mut query: Vec<u8> = Vec::with_capacity(DNS_HEADER_SIZE + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&DNS_FLAG_RECURSION_DESIRED.to_be_bytes());
+    // one question, no answer, authority or additional records.
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > DNS_MAX_LABEL_LEN {
+            return Err(DNSError::InvalidName);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// returns the offset right after the (possibly compressed) name at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(offset)? as usize;
+        if length == 0 {
+            return Some(offset + 1);
+        }
+
+        // a pointer ends the name.
+        if length & 0xC0 == 0xC0 {
+            return if offset + 2 <= message.len() {
+                Some(offset + 2)
+            } else {
+                None
+            };
+        }
+
+        offset += length + 1;
+    }
+}
+
+/// returns the first A record of the answer and it's TTL in seconds.
+fn parse_response(id: u16, message: &[u8]) -> Result<([u8; 4], u32), DNSError> {
+    let header = (
+        read_u16(message, 0),
+        read_u16(message, 2),
+        read_u16(message, 4),
+        read_u16(message, 6),
+    );
+    let (resp_id, flags, n_questions, n_answers) = match header {
+        (Some(resp_id), Some(flags), Some(n_questions), Some(n_answers)) => {
+            (resp_id, flags, n_questions, n_answers)
+        }
+        _ => return Err(DNSError::InvalidResponse),
+    };
+
+    if resp_id != id || flags & DNS_FLAG_RESPONSE == 0 {
+        return Err(DNSError::InvalidResponse);
+    }
+
+    match flags & DNS_RCODE_MASK {
+        0 => {}
+        DNS_RCODE_NXDOMAIN => return Err(DNSError::NameNotFound),
+        _ => return Err(DNSError::ServerFailure),
+    }
+
+    let mut offset = DNS_HEADER_SIZE;
+    for _ in 0..n_questions {
+        match skip_name(message, offset) {
+            // type and class
+            Some(next) => offset = next + 4,
+            None => return Err(DNSError::InvalidResponse),
+        }
+    }
+
+    for _ in 0..n_answers {
+        let name_end = skip_name(message, offset);
+        if name_end.is_none() {
+            return Err(DNSError::InvalidResponse);
+        }
+
+        let record = name_end.unwrap();
+        let fields = (
+            read_u16(message, record),
+            read_u16(message, record + 2),
+            read_u32(message, record + 4),
+            read_u16(message, record + 8),
+        );
+        let (rtype, rclass, ttl, rdlength) = match fields {
+            (Some(rtype), Some(rclass), Some(ttl), Some(rdlength)) => {
+                (rtype, rclass, ttl, rdlength as usize)
+            }
+            _ => return Err(DNSError::InvalidResponse),
+        };
+
+        let rdata = record + 10;
+        if rdata + rdlength > message.len() {
+            return Err(DNSError::InvalidResponse);
+        }
+
+        // CNAMEs come before the A record they point to, skip them.
+        if rtype == DNS_TYPE_A && rclass == DNS_CLASS_IN && rdlength == 4 {
+            let mut address: [u8; 4] = [0; 4];
+            address.copy_from_slice(&message[rdata..rdata + 4]);
+            return Ok((address, ttl));
+        }
+
+        offset = rdata + rdlength;
+    }
+
+    Err(DNSError::NameNotFound)
+}
+
+fn cache_lookup(name: &str) -> Option<[u8; 4]> {
+    let mut cache_lock = DNS_CACHE.lock();
+    let now = timer::monotonic_ns();
+    match cache_lock.get(name) {
+        Some(entry) if entry.expires_ns > now => Some(entry.address),
+        Some(_) => {
+            cache_lock.remove(name);
+            None
+        }
+        None => None,
+    }
+}
+
+fn cache_insert(name: &str, address: [u8; 4], ttl: u32) {
+    if ttl == 0 {
+        return;
+    }
+
+    let ttl = core::cmp::min(ttl as u64, DNS_MAX_TTL_SECS);
+    let now = timer::monotonic_ns();
+
+    let mut cache_lock = DNS_CACHE.lock();
+    cache_lock.retain(|_, entry| entry.expires_ns > now);
+    if cache_lock.len() >= DNS_MAX_CACHE_ENTRIES {
+        // full of live entries, drop the one that expires first.
+        let first = cache_lock
+            .iter()
+            .min_by_key(|(_, entry)| entry.expires_ns)
+            .map(|(name, _)| name.clone());
+        if let Some(first) = first {
+            cache_lock.remove(&first);
+        }
+    }
+
+    cache_lock.insert(
+        String::from(name),
+        CacheEntry {
+            address,
+            expires_ns: now + ttl * Time::Second as u64,
+        },
+    );
+}
+
+/// sends the query and waits for the answer, retried on timeouts.
+fn query_server(server: Ipv4Address, name: &str) -> Result<([u8; 4], u32), DNSError> {
+    let mut id_bytes: [u8; 2] = [0; 2];
+    SystemRandomDevice::empty().fill_bytes(&mut id_bytes);
+    let id = u16::from_be_bytes(id_bytes);
+
+    let query = build_query(id, name);
+    if query.is_err() {
+        return Err(query.unwrap_err());
+    }
+    let query = query.unwrap();
+
+    // connected, so only the datagrams of the server are received.
+    let server_addr = types::SocketAddr::from_values(TransportType::AFInet, server.0, DNS_PORT);
+    let mut socket = UDPSocket::empty();
+    if socket.connect(server_addr).is_err() {
+        let _ = socket.close();
+        return Err(DNSError::SocketError);
+    }
+
+    let mut response: [u8; DNS_MAX_MESSAGE_SIZE] = [0; DNS_MAX_MESSAGE_SIZE];
+    let mut result = Err(DNSError::Timeout);
+
+    'attempts: for _ in 0..DNS_MAX_ATTEMPTS {
+        if socket.send(&query).is_err() {
+            result = Err(DNSError::SocketError);
+            break;
+        }
+
+        // anything that is not the answer to this query is ignored.
+        loop {
+            match socket.recv_timeout(&mut response, DNS_TIMEOUT_NS) {
+                Ok(n_bytes) => match parse_response(id, &response[0..n_bytes]) {
+                    Err(DNSError::InvalidResponse) => continue,
+                    parsed => {
+                        result = parsed;
+                        break 'attempts;
+                    }
+                },
+                Err(types::SocketError::Timeout) => {
+                    log::debug!("dns: no answer for {} from {}", name, server);
+                    break;
+                }
+                Err(_) => {
+                    result = Err(DNSError::SocketError);
+                    break 'attempts;
+                }
+            }
+        }
+    }
+
+    let _ = socket.close();
+    result
+}
+
+/// resolves the name to an IPv4 address, answers are cached for their TTL.
+pub fn resolve(name: &str) -> Result<[u8; 4], DNSError> {
+    if let Some(address) = parse_ipv4(name) {
+        return Ok(address);
+    }
+
+    let name = name.trim_end_matches('.');
+    if let Some(address) = cache_lookup(name) {
+        return Ok(address);
+    }
+
+    let server = server();
+    if server.is_none() {
+        return Err(DNSError::NoServer);
+    }
+
+    let query_result = query_server(server.unwrap(), name);
+    if query_result.is_err() {
+        return Err(query_result.unwrap_err());
+    }
+
+    let (address, ttl) = query_result.unwrap();
+    log::debug!("dns: {} is {:?}, ttl={}", name, address, ttl);
+    cache_insert(name, address, ttl);
+    Ok(address)
+}
+
+/// builds the messages by hand, so this needs neither a link nor a server.
+#[cfg(feature = "debug_checks")]
+pub fn test_messages() {
+    const TEST_ID: u16 = 0x1234;
+    const DNS_TYPE_CNAME: u16 = 5;
+
+    let query = build_query(TEST_ID, "example.com.").unwrap();
+    let mut expected: Vec<u8> = Vec::new();
+    expected.extend_from_slice(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    expected.extend_from_slice(b"\x07example\x03com\x00");
+    expected.extend_from_slice(&[0, 1, 0, 1]);
+    assert_eq!(query, expected);
+
+    assert_eq!(build_query(TEST_ID, ""), Err(DNSError::InvalidName));
+    assert_eq!(build_query(TEST_ID, "example..com"), Err(DNSError::InvalidName));
+    let long_label = "a".repeat(DNS_MAX_LABEL_LEN + 1);
+    assert_eq!(build_query(TEST_ID, &long_label), Err(DNSError::InvalidName));
+
+    // the answers point back at the name in the question.
+    let record = |rtype: u16, ttl: u32, rdata: &[u8]| -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(&[0xC0, DNS_HEADER_SIZE as u8]);
+        bytes.extend_from_slice(&rtype.to_be_bytes());
+        bytes.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        bytes.extend_from_slice(&ttl.to_be_bytes());
+        bytes.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(rdata);
+        bytes
+    };
+
+    let response = |rcode: u16, answers: &[Vec<u8>]| -> Vec<u8> {
+        let mut message = query.clone();
+        let flags = DNS_FLAG_RESPONSE | DNS_FLAG_RECURSION_DESIRED | rcode;
+        message[2..4].copy_from_slice(&flags.to_be_bytes());
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        for answer in answers {
+            message.extend_from_slice(answer);
+        }
+        message
+    };
+
+    let a_record = record(DNS_TYPE_A, 300, &[93, 184, 216, 34]);
+    let cname_record = record(DNS_TYPE_CNAME, 60, b"\x03www\xC0\x0C");
+
+    let answer = response(0, &[a_record.clone()]);
+    assert_eq!(parse_response(TEST_ID, &answer), Ok(([93, 184, 216, 34], 300)));
+
+    let after_cname = response(0, &[cname_record.clone(), a_record.clone()]);
+    assert_eq!(
+        parse_response(TEST_ID, &after_cname),
+        Ok(([93, 184, 216, 34], 300))
+    );
+
+    let only_cname = response(0, &[cname_record]);
+    assert_eq!(parse_response(TEST_ID, &only_cname), Err(DNSError::NameNotFound));
+
+    let nxdomain = response(DNS_RCODE_NXDOMAIN, &[]);
+    assert_eq!(parse_response(TEST_ID, &nxdomain), Err(DNSError::NameNotFound));
+
+    let server_failure = response(2, &[]);
+    assert_eq!(
+        parse_response(TEST_ID, &server_failure),
+        Err(DNSError::ServerFailure)
+    );
+
+    // the address is cut short, then the record and the header.
+    for cut in [2, a_record.len() - 2, answer.len() - 6] {
+        assert_eq!(
+            parse_response(TEST_ID, &answer[0..answer.len() - cut]),
+            Err(DNSError::InvalidResponse)
+        );
+    }
+
+    assert_eq!(
+        parse_response(TEST_ID + 1, &answer),
+        Err(DNSError::InvalidResponse)
+    );
+    assert_eq!(parse_response(TEST_ID, &query), Err(DNSError::InvalidResponse));
+
+    log::info!("Passed DNS message test.");
+}
@@ -4,9 +4,13 @@ extern crate log;
 extern crate smoltcp;
 extern crate spin;
 
+use crate::cpu;
 use crate::cpu::hw_interrupts;
 use crate::drivers;
+use crate::system::net::dhcp;
+use crate::system::net::dns;
 use crate::system::net::ip_utils;
+use crate::system::net::napi;
 use crate::system::net::process::process_network_packet_event;
 use crate::system::net::types;
 
@@ -14,11 +18,12 @@ use smoltcp::iface::{EthernetInterface, EthernetInterfaceBuilder, NeighborCache,
 use smoltcp::phy::Device;
 use smoltcp::phy::{DeviceCapabilities, RxToken, TxToken};
 use smoltcp::time::Instant;
-use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
 use smoltcp::Error as NetError;
 use smoltcp::Result as NetResult;
 
-use core::sync::atomic::AtomicU64;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use spin::{Mutex, MutexGuard};
 
@@ -37,10 +42,17 @@ static PHY_ETHERNET_DRIVER: Mutex<Option<Box<PhyNetDevType>>> = Mutex::new(None)
 /// Ethernet interface type
 pub static ETHERNET_INTERFACE: Mutex<Option<EthernetInterfaceType>> = Mutex::new(None);
 
+/// when the interface is down, frames are neither received nor transmitted.
+static INTERFACE_UP: AtomicBool = AtomicBool::new(true);
+
+/// frames handed to smoltcp since boot, sampled twice it gives the receive rate.
+static RX_FRAMES: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone)]
 pub enum PhyNetdevError {
     NoPhysicalDevice,
     NoTxBuffer,
+    FrameTooLarge,
     NoInterruptLine,
     NoMTU,
     InterruptHandlingError,
@@ -49,6 +61,22 @@ pub enum PhyNetdevError {
     PollingModeError,
 }
 
+#[derive(Debug, Clone)]
+pub enum IfaceConfigError {
+    NoPhysicalDevice,
+    InvalidPrefix,
+    InvalidAddress,
+    InvalidGateway,
+}
+
+/// static configuration of the interface, set at runtime from the userspace.
+#[derive(Debug, Clone)]
+pub struct IfaceConfig {
+    pub cidr: Ipv4Cidr,
+    pub gateway: Option<Ipv4Address>,
+    pub up: bool,
+}
+
 /// stores the stats of the network interface
 pub struct VirtualNetworkDeviceStats {
     pub n_tx_packets: AtomicU64,
@@ -86,6 +114,9 @@ pub trait PhysicalNetworkDevice {
 
     /// poll for packet
     fn poll_for_frame(&mut self, max_polls: usize) -> Result<&'static [u8], PhyNetdevError>;
+
+    /// mask or unmask only the receive interrupt, used while the frames are polled under load
+    fn set_rx_interrupts(&mut self, enable: bool) -> Result<(), PhyNetdevError>;
 }
 
 pub type PhyNetDevType = dyn PhysicalNetworkDevice + Sync + Send;
@@ -102,8 +133,8 @@ pub struct VirtualTx {}
 
 /// Smoltcp token type for Reception
 pub struct VirtualRx {
-    /// recv_buffer is a vector view over the DMA slice of the packet
-    pub recv_buffer: Vec<u8>,
+    /// copy of the frame in a buffer from `RX_BUFFER_POOL`, it goes back to the pool on drop.
+    pub recv_buffer: types::NetworkInterfacePacket,
 }
 
 impl TxToken for VirtualTx {
@@ -112,7 +143,6 @@ impl TxToken for VirtualTx {
     where
         F: FnOnce(&mut [u8]) -> NetResult<R>,
     {
-
         // hold CPU lock, so no events can occur during this time
         // timer::hold_cpu_lock();
 
@@ -131,7 +161,16 @@ impl TxToken for VirtualTx {
             return Err(NetError::Illegal);
         }
 
-        let buffer = &mut buffer_res.unwrap()[0..len];
+        let tx_buffer = buffer_res.unwrap();
+        if len > tx_buffer.len() {
+            log::error!(
+                "interface error: frame of {} bytes exceeds the tx buffer",
+                len
+            );
+            return Err(NetError::Exhausted);
+        }
+
+        let buffer = &mut tx_buffer[0..len];
         let buffer_copy_res = f(buffer);
 
         if buffer_copy_res.is_err() {
@@ -162,6 +201,16 @@ impl RxToken for VirtualRx {
     }
 }
 
+impl Drop for VirtualRx {
+    fn drop(&mut self) {
+        let buffer = mem::take(&mut self.recv_buffer);
+        // the network interrupt takes the pool lock too.
+        cpu::without_interrupts(|| {
+            types::RX_BUFFER_POOL.lock().release(buffer);
+        });
+    }
+}
+
 impl VirtualNetworkDevice {
     pub fn is_loopback(&self) -> bool {
         self.is_in_loopback
@@ -173,6 +222,16 @@ impl<'a> Device<'a> for VirtualNetworkDevice {
     type RxToken = VirtualRx;
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        if !is_up() {
+            // drain the queue, so stale frames are not seen once the interface is up again.
+            if let Ok(stale_buffer) = types::NETWORK_IFACE_QUEUE.pop() {
+                cpu::without_interrupts(|| {
+                    types::RX_BUFFER_POOL.lock().release(stale_buffer);
+                });
+            }
+            return None;
+        }
+
         let mut phy_dev_lock = PHY_ETHERNET_DRIVER.lock();
 
         if let Some(phy_dev) = phy_dev_lock.as_mut() {
@@ -180,11 +239,19 @@ impl<'a> Device<'a> for VirtualNetworkDevice {
                 // poll for frame
                 let poll_result = phy_dev.poll_for_frame(MAX_POLL_CYCLES);
                 if let Ok(buffer) = poll_result {
-                    let recv_buffer = buffer.to_vec();
-                    return Some((VirtualRx { recv_buffer }, VirtualTx {}));
+                    // the interrupt takes the pool too, it must not come in while it is held.
+                    let recv_buffer_opt = cpu::without_interrupts(|| {
+                        types::RX_BUFFER_POOL.lock().copy_frame(buffer)
+                    });
+                    if let Some(recv_buffer) = recv_buffer_opt {
+                        RX_FRAMES.fetch_add(1, Ordering::Relaxed);
+                        return Some((VirtualRx { recv_buffer }, VirtualTx {}));
+                    }
+                    log::trace!("dropping network packet because no rx buffer is free");
                 }
             } else {
-                if let Ok(recv_buffer) = types::NETWORK_IFACE_QUEUE.lock().pop() {
+                if let Ok(recv_buffer) = types::NETWORK_IFACE_QUEUE.pop() {
+                    RX_FRAMES.fetch_add(1, Ordering::Relaxed);
                     return Some((VirtualRx { recv_buffer }, VirtualTx {}));
                 }
             }
@@ -193,6 +260,9 @@ impl<'a> Device<'a> for VirtualNetworkDevice {
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        if !is_up() {
+            return None;
+        }
         Some(VirtualTx {})
     }
 
@@ -223,14 +293,47 @@ impl<'a> Device<'a> for VirtualNetworkDevice {
     }
 }
 /// the function will be called from the device's receiver function
-/// triggered by the network interrupt and there is a frame in DMA buffer.
+/// triggered by the network interrupt or by the NAPI poll, with
+/// `PHY_ETHERNET_DRIVER` held, when there is a frame in DMA buffer.
 /// The parameter `buffer` contains the read-only slice view of the
-/// DMA buffer.
+/// DMA buffer. The device can write over the slice once the next frame
+/// arrives, so it is copied to a preallocated buffer before it is queued.
 pub fn handle_recv_packet(buffer: &[u8]) {
-    let packet_vec = buffer.to_vec();
-    if let Err(_) = types::NETWORK_IFACE_QUEUE.lock().push(packet_vec) {
-        log::debug!("dropping network packet because interface queue is full")
+    napi::count_rx_frame();
+
+    let queue = &types::NETWORK_IFACE_QUEUE;
+    if queue.is_full() {
+        queue.count_drop();
+        log::trace!("dropping network packet because interface queue is full");
+        return;
+    }
+
+    let packet_opt = types::RX_BUFFER_POOL.lock().copy_frame(buffer);
+    if packet_opt.is_none() {
+        queue.count_drop();
+        log::trace!("dropping network packet because no rx buffer is free");
+        return;
     }
+
+    // the caller holds the device lock, so no other producer pushes between
+    // the check above and this push and the ring can't be full.
+    let _ = queue.push(packet_opt.unwrap());
+}
+
+/// hands the frame to the receive path as if the device had received it,
+/// returns false if there is no device.
+#[cfg(feature = "debug_checks")]
+pub fn inject_frame(frame: &[u8]) -> bool {
+    cpu::without_interrupts(|| {
+        let phy_lock = PHY_ETHERNET_DRIVER.lock();
+        if phy_lock.is_none() {
+            return false;
+        }
+
+        // the producers of the queue hold the device lock.
+        handle_recv_packet(frame);
+        true
+    })
 }
 
 fn create_unspecified_interface(mac_addr: &[u8]) -> EthernetInterfaceType {
@@ -253,22 +356,37 @@ fn create_static_ip_interface(
     gateway: &str,
     ip: &str,
 ) -> Option<EthernetInterfaceType> {
+    let gateway_ip = ip_utils::get_ipv4_from_string(gateway)?;
+    let (ip, prefix) = ip_utils::get_ipv4_with_prefix_from_string(ip)?;
+
+    Some(create_ip_interface(
+        mac,
+        Ipv4Cidr::new(ip, prefix as u8),
+        Some(gateway_ip),
+    ))
+}
+
+fn create_ip_interface(
+    mac: &[u8],
+    cidr: Ipv4Cidr,
+    gateway: Option<Ipv4Address>,
+) -> EthernetInterfaceType {
     let mut routes = Routes::new(BTreeMap::new());
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
     // set gateway as the default route:
-    let gateway_ip = ip_utils::get_ipv4_from_string(gateway)?;
+    if let Some(gateway_ip) = gateway {
+        routes
+            .add_default_ipv4_route(gateway_ip)
+            .expect("failed to add default ip to the network routes");
+    }
 
-    routes
-        .add_default_ipv4_route(gateway_ip)
-        .expect("failed to add default ip to the network routes");
     // set provided static IP
-    let (ip, prefix) = ip_utils::get_ipv4_with_prefix_from_string(ip)?;
-    let ip_addrs = [IpCidr::new(IpAddress::from(ip), prefix as u8)];
+    let ip_addrs = [IpCidr::Ipv4(cidr)];
 
     log::info!(
-        "creating static IP interface ip={}, gateway={}",
-        DEFAULT_STATIC_IP,
-        DEFAULT_GATEWAY
+        "creating static IP interface ip={}, gateway={:?}",
+        cidr,
+        gateway
     );
 
     // create the ethernet interface
@@ -283,7 +401,7 @@ fn create_static_ip_interface(
 
     log::debug!("configure static IP complete");
 
-    Some(iface)
+    iface
 }
 
 fn create_loopback_interface() -> EthernetInterfaceType {
@@ -342,23 +460,63 @@ pub fn setup_network_interface() {
 }
 
 pub fn network_interrupt_handler() {
-
     if let Some(mut net_dev_lock) = PHY_ETHERNET_DRIVER.try_lock() {
         if net_dev_lock.is_some() {
-            let result = net_dev_lock.as_mut().unwrap().handle_interrupt();
+            let net_dev = net_dev_lock.as_mut().unwrap();
+            let result = net_dev.handle_interrupt();
             if result.is_err() {
-                log::debug!(
+                log::trace!(
                     "failed to handle device interrupt: {:?}",
                     result.unwrap_err()
                 );
             }
+
+            // too many frames, let the poll thread take them in batches.
+            if napi::should_poll() && net_dev.set_rx_interrupts(false).is_ok() {
+                napi::schedule_poll();
+            }
         }
-    
+
         drop(net_dev_lock);
         process_network_packet_event();
     }
 }
 
+pub fn has_physical_device() -> bool {
+    PHY_ETHERNET_DRIVER.lock().is_some()
+}
+
+/// takes up to `budget` frames from the device to the RX queue, returns how many
+/// were taken. called by the poll thread with the interrupts disabled.
+pub fn poll_device(budget: usize) -> usize {
+    let mut phy_lock = PHY_ETHERNET_DRIVER.lock();
+    if phy_lock.is_none() {
+        return 0;
+    }
+
+    let phy_dev = phy_lock.as_mut().unwrap();
+    let mut n_frames = 0;
+    while n_frames < budget {
+        match phy_dev.poll_for_frame(1) {
+            Ok(frame) => handle_recv_packet(frame),
+            Err(_) => break,
+        }
+        n_frames += 1;
+    }
+
+    n_frames
+}
+
+pub fn set_rx_interrupts(enable: bool) {
+    let mut phy_lock = PHY_ETHERNET_DRIVER.lock();
+    if phy_lock.is_some() {
+        let result = phy_lock.as_mut().unwrap().set_rx_interrupts(enable);
+        if result.is_err() {
+            log::debug!("failed to switch the rx interrupt: {:?}", result.unwrap_err());
+        }
+    }
+}
+
 pub fn get_formatted_mac() -> Option<String> {
     let phy_dev_lock = PHY_ETHERNET_DRIVER.lock();
     if let Ok(mac_bytes) = phy_dev_lock.as_ref().unwrap().get_mac_address() {
@@ -382,7 +540,6 @@ pub fn set_polling_mode() {
 }
 
 pub fn set_interrupt_mode() {
-
     let mut phy_lock = PHY_ETHERNET_DRIVER.lock();
     if phy_lock.is_some() {
         let result = phy_lock.as_mut().unwrap().set_polling_mode(false);
@@ -418,4 +575,96 @@ pub fn switch_to_static_ip() {
     // create an interface with static IP assigned
     let net_device = create_static_ip_interface(&mac_bytes, DEFAULT_GATEWAY, DEFAULT_STATIC_IP);
     *ETHERNET_INTERFACE.lock() = net_device;
+    dns::set_server(Ipv4Address::from_bytes(&dns::DEFAULT_DNS_SERVER));
+}
+
+#[inline]
+pub fn is_up() -> bool {
+    INTERFACE_UP.load(Ordering::SeqCst)
+}
+
+#[inline]
+pub fn n_rx_frames() -> u64 {
+    RX_FRAMES.load(Ordering::Relaxed)
+}
+
+/// checks that the address can be assigned to a host on the given network.
+pub fn validate_config(config: &IfaceConfig) -> Result<(), IfaceConfigError> {
+    let prefix = config.cidr.prefix_len();
+    if prefix == 0 || prefix > 32 {
+        return Err(IfaceConfigError::InvalidPrefix);
+    }
+
+    let address = config.cidr.address();
+    if !address.is_unicast() || address.is_loopback() {
+        return Err(IfaceConfigError::InvalidAddress);
+    }
+
+    // /31 and /32 networks have no network or broadcast address.
+    if prefix < 31 {
+        if address == config.cidr.network().address() {
+            return Err(IfaceConfigError::InvalidAddress);
+        }
+        if Some(address) == config.cidr.broadcast() {
+            return Err(IfaceConfigError::InvalidAddress);
+        }
+    }
+
+    if let Some(gateway) = config.gateway {
+        if !gateway.is_unicast()
+            || gateway == address
+            || !config.cidr.network().contains_addr(&gateway)
+        {
+            return Err(IfaceConfigError::InvalidGateway);
+        }
+    }
+
+    Ok(())
+}
+
+/// replaces the interface with a new one configured with the given address and routes,
+/// sockets live in the socket set and are not affected. DHCP is stopped, so the
+/// lease renewal doesn't override the static address.
+pub fn configure_static_ip(config: &IfaceConfig) -> Result<(), IfaceConfigError> {
+    let validate_result = validate_config(config);
+    if validate_result.is_err() {
+        return Err(validate_result.unwrap_err());
+    }
+
+    let phy_dev_lock = PHY_ETHERNET_DRIVER.lock();
+    if phy_dev_lock.is_none() {
+        return Err(IfaceConfigError::NoPhysicalDevice);
+    }
+
+    let mac_bytes_res = phy_dev_lock.as_ref().unwrap().get_mac_address();
+    drop(phy_dev_lock);
+    if mac_bytes_res.is_err() {
+        log::error!("failed to get device MAC address");
+        return Err(IfaceConfigError::NoPhysicalDevice);
+    }
+
+    let mac_bytes = mac_bytes_res.unwrap();
+    let net_device = create_ip_interface(&mac_bytes, config.cidr, config.gateway);
+
+    // same order as the packet processing path: interface first, then DHCP. the
+    // network interrupt takes the same locks, so it must not fire in between.
+    cpu::without_interrupts(|| {
+        let mut iface_lock = ETHERNET_INTERFACE.lock();
+        *iface_lock = Some(net_device);
+        dhcp::DHCPClient::stop();
+        INTERFACE_UP.store(config.up, Ordering::SeqCst);
+    });
+
+    log::info!(
+        "reconfigured network interface ip={}, gateway={:?}, up={}",
+        config.cidr,
+        config.gateway,
+        config.up
+    );
+    Ok(())
+}
+
+pub fn set_interface_up(up: bool) {
+    INTERFACE_UP.store(up, Ordering::SeqCst);
+    log::info!("network interface is {}", if up { "up" } else { "down" });
 }
@@ -1,6 +1,8 @@
 pub mod dhcp;
+pub mod dns;
 pub mod iface;
 pub mod ip_utils;
+pub mod napi;
 pub mod types;
 pub mod process;
 pub mod udp;
@@ -11,6 +13,15 @@ pub fn init_networking() {
     iface::setup_network_interface();
     types::setup_socket_set();
 
+    #[cfg(feature = "debug_checks")]
+    udp::test_connect();
+    #[cfg(feature = "debug_checks")]
+    udp::test_unbound();
+    #[cfg(feature = "debug_checks")]
+    udp::test_peer_filter();
+    #[cfg(feature = "debug_checks")]
+    dns::test_messages();
+
     let mac_address_opt = iface::get_formatted_mac();
 
     if let Some(mac_address) = mac_address_opt {
@@ -0,0 +1,200 @@
+extern crate alloc;
+extern crate log;
+
+use crate::cpu;
+use crate::mm::VirtualAddress;
+use crate::system::net::iface;
+use crate::system::net::process::process_network_packet_event;
+use crate::system::process;
+use crate::system::tasking::{self, schedule_yield, Sched, ThreadSuspendType, SCHEDULER};
+use crate::system::thread::{self, ThreadID};
+use crate::system::timer::{self, Time};
+
+use alloc::format;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+// NAPI style receive: every frame raises an interrupt until more than the
+// threshold arrive within one window. then the RX interrupt is masked and the
+// poll thread drains the device in batches, once it finds the device empty
+// the interrupt is unmasked again.
+
+/// RX frames within a window that switch the device to polling, 0 never switches.
+pub const NAPI_DEFAULT_THRESHOLD: usize = 32;
+const NAPI_WINDOW_NS: u64 = 10 * Time::MilliSecond as u64;
+
+/// frames taken from the device before they are run through the stack.
+const NAPI_BUDGET: usize = 16;
+
+const NO_POLL_THREAD: u64 = u64::MAX;
+
+static THRESHOLD: AtomicUsize = AtomicUsize::new(NAPI_DEFAULT_THRESHOLD);
+/// true while the RX interrupt is masked and the poll thread owns the device.
+static POLLING: AtomicBool = AtomicBool::new(false);
+static POLL_THREAD: AtomicU64 = AtomicU64::new(NO_POLL_THREAD);
+
+static WINDOW_START_NS: AtomicU64 = AtomicU64::new(0);
+static WINDOW_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// number of switches to polling since boot.
+static N_POLL_SWITCHES: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_threshold(threshold: usize) {
+    THRESHOLD.store(threshold, Ordering::SeqCst);
+    log::info!("napi: polling threshold is {} frames", threshold);
+}
+
+#[inline]
+pub fn threshold() -> usize {
+    THRESHOLD.load(Ordering::SeqCst)
+}
+
+#[inline]
+pub fn is_polling() -> bool {
+    POLLING.load(Ordering::SeqCst)
+}
+
+/// counts a frame taken by the interrupt, the frames of the poll thread don't count.
+pub fn count_rx_frame() {
+    if is_polling() {
+        return;
+    }
+
+    let now = timer::monotonic_ns();
+    if now.saturating_sub(WINDOW_START_NS.load(Ordering::SeqCst)) >= NAPI_WINDOW_NS {
+        WINDOW_START_NS.store(now, Ordering::SeqCst);
+        WINDOW_FRAMES.store(0, Ordering::SeqCst);
+    }
+
+    WINDOW_FRAMES.fetch_add(1, Ordering::SeqCst);
+}
+
+/// checked by the network interrupt, true if the device should be handed to the poll thread.
+pub fn should_poll() -> bool {
+    let threshold = threshold();
+    threshold != 0
+        && !is_polling()
+        && POLL_THREAD.load(Ordering::SeqCst) != NO_POLL_THREAD
+        && WINDOW_FRAMES.load(Ordering::SeqCst) >= threshold
+}
+
+/// called by the network interrupt once the RX interrupt is masked.
+pub fn schedule_poll() {
+    POLLING.store(true, Ordering::SeqCst);
+    N_POLL_SWITCHES.fetch_add(1, Ordering::SeqCst);
+    log::debug!(
+        "napi: {} frames in the window, switching to polling",
+        WINDOW_FRAMES.load(Ordering::SeqCst)
+    );
+
+    tasking::wake_thread(ThreadID::new(POLL_THREAD.load(Ordering::SeqCst)));
+}
+
+/// the device is empty, frames are taken by the interrupt again.
+fn leave_polling() {
+    WINDOW_START_NS.store(timer::monotonic_ns(), Ordering::SeqCst);
+    WINDOW_FRAMES.store(0, Ordering::SeqCst);
+    POLLING.store(false, Ordering::SeqCst);
+    iface::set_rx_interrupts(true);
+    log::debug!(
+        "napi: device drained, switching to interrupts, {} switches so far",
+        N_POLL_SWITCHES.load(Ordering::SeqCst)
+    );
+}
+
+/// sleeps until schedule_poll wakes the thread. the interrupt can't come in
+/// between the check and the suspend, so the wakeup is not missed.
+fn park() {
+    cpu::without_interrupts(|| {
+        if is_polling() {
+            return;
+        }
+
+        SCHEDULER
+            .lock()
+            .suspend_thread(ThreadSuspendType::SuspendUntilWoken);
+        schedule_yield();
+    });
+}
+
+fn yield_now() {
+    {
+        let mut sched_lock = SCHEDULER.lock();
+        if !sched_lock.has_other_runnable() {
+            return;
+        }
+        sched_lock.yield_current();
+    }
+
+    schedule_yield();
+}
+
+fn poll_loop() -> ! {
+    loop {
+        if !is_polling() {
+            park();
+            continue;
+        }
+
+        // the interrupt is the other producer of the RX queue, it would find the
+        // device lock taken and skip the device, so it is kept out until the
+        // batch is queued.
+        let n_frames = cpu::without_interrupts(|| {
+            let n_frames = iface::poll_device(NAPI_BUDGET);
+            if n_frames > 0 {
+                process_network_packet_event();
+            }
+
+            if n_frames < NAPI_BUDGET {
+                leave_polling();
+            }
+            n_frames
+        });
+
+        // more frames are waiting, give the others a chance before the next batch.
+        if n_frames == NAPI_BUDGET {
+            yield_now();
+        }
+    }
+}
+
+fn poll_thread() {
+    poll_loop();
+}
+
+/// starts the kernel thread that drains the device under load, the device
+/// stays in interrupt mode if there is no physical device or no thread.
+pub fn start_poll_thread() {
+    if !iface::has_physical_device() {
+        return;
+    }
+
+    let process_res = process::new(format!("kernel_network"), false, "");
+    if process_res.is_err() {
+        log::error!(
+            "napi: failed to create the network process: {:?}",
+            process_res.unwrap_err()
+        );
+        return;
+    }
+
+    let thread_res = thread::new_from_function(
+        &process_res.unwrap(),
+        format!("napi_poll"),
+        VirtualAddress::from_u64(poll_thread as fn() as u64),
+    );
+
+    if thread_res.is_err() {
+        log::error!(
+            "napi: failed to start the poll thread: {:?}",
+            thread_res.unwrap_err()
+        );
+        return;
+    }
+
+    POLL_THREAD.store(thread_res.unwrap().as_u64(), Ordering::SeqCst);
+    log::info!(
+        "Started network poll thread, threshold={} frames per {}ms.",
+        threshold(),
+        NAPI_WINDOW_NS / Time::MilliSecond as u64
+    );
+}
@@ -24,9 +24,12 @@ pub fn process_network_packet_event() {
     let instant = Instant::from_millis(ts as i64);
 
     loop {
-        // check if it is DHCP packet
-        let dhcp_result =
-            DHCPClient::check_dhcp_packet(&mut iface_lock, &mut dhcp_lock, instant, &mut sockets);
+        // check if it is DHCP packet, DHCP is not used with a static config.
+        let dhcp_result = if dhcp_lock.is_some() {
+            DHCPClient::check_dhcp_packet(&mut iface_lock, &mut dhcp_lock, instant, &mut sockets)
+        } else {
+            Ok(None)
+        };
 
         if dhcp_result.is_err() {
             log::debug!("DHCP Error: {:?}", dhcp_result.unwrap_err());
@@ -7,29 +7,64 @@ extern crate spin;
 use crate::mm;
 
 use alloc::{collections::BTreeSet, vec, vec::Vec};
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use smoltcp::socket::SocketSet;
 use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
 use spin::Mutex;
 
+/// slots of the RX ring, a power of two so the indices wrap around cleanly.
 const MAX_IFACE_QUEUE_SIZE: usize = 64;
 
-type NetworkInterfacePacket = Vec<u8>;
+/// largest frame taken from the device, MTU + ethernet header + VLAN tag + CRC.
+pub const MAX_FRAME_SIZE: usize = 1526;
+
+/// one buffer for every queue slot and a few more for the frames being processed.
+const RX_POOL_SIZE: usize = MAX_IFACE_QUEUE_SIZE + 4;
+
+pub type NetworkInterfacePacket = Vec<u8>;
 
 pub enum NetworkInterfaceQueueError {
     QueueEmpty = 0,
     QueueFull = 1,
 }
 
+/// single producer, single consumer ring of received frames. frames are pushed
+/// by the network interrupt and by the NAPI poll thread, both with
+/// `PHY_ETHERNET_DRIVER` held, so only one of them pushes at a time. the
+/// interface poll (under the interface lock) is the only consumer, so the ring
+/// itself takes no lock and the interrupt never spins on one held by the
+/// thread it interrupted.
 pub struct NetworkInterfaceQueue {
-    pub queue: Vec<NetworkInterfacePacket>,
+    slots: Vec<UnsafeCell<Option<NetworkInterfacePacket>>>,
+    /// next slot to pop, only written by the consumer.
+    head: AtomicUsize,
+    /// next slot to push, only written by the producer.
+    tail: AtomicUsize,
+    /// frames dropped because the ring or the buffer pool was full.
+    dropped: AtomicU64,
+}
+
+// a slot is owned by the producer until tail moves past it and by the
+// consumer until head moves past it, so the two never touch the same slot.
+unsafe impl Sync for NetworkInterfaceQueue {}
+
+/// preallocated buffers the received frames are copied to, so the RX path
+/// does not allocate for every packet.
+pub struct PacketBufferPool {
+    free: Vec<NetworkInterfacePacket>,
 }
 
 pub static SOCKETS_SET: Mutex<Option<SocketSet>> = Mutex::new(None);
 
 lazy_static! {
-    pub static ref NETWORK_IFACE_QUEUE: Mutex<NetworkInterfaceQueue> =
-        Mutex::new(NetworkInterfaceQueue::new());
+    pub static ref NETWORK_IFACE_QUEUE: NetworkInterfaceQueue = NetworkInterfaceQueue::new();
+}
+
+lazy_static! {
+    pub static ref RX_BUFFER_POOL: Mutex<PacketBufferPool> = Mutex::new(PacketBufferPool::new());
 }
 
 lazy_static! {
@@ -39,35 +74,120 @@ lazy_static! {
 
 impl NetworkInterfaceQueue {
     pub fn new() -> Self {
+        let mut slots = Vec::with_capacity(MAX_IFACE_QUEUE_SIZE);
+        for _ in 0..MAX_IFACE_QUEUE_SIZE {
+            slots.push(UnsafeCell::new(None));
+        }
+
         Self {
-            queue: Vec::with_capacity(MAX_IFACE_QUEUE_SIZE),
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
         }
     }
 
+    /// called only by the producer, the frame is dropped and counted if the ring is full.
     #[inline]
-    pub fn push(&mut self, data: NetworkInterfacePacket) -> Result<(), NetworkInterfaceQueueError> {
-        if self.queue.len() < MAX_IFACE_QUEUE_SIZE {
-            self.queue.push(data);
-            return Ok(());
+    pub fn push(&self, data: NetworkInterfacePacket) -> Result<(), NetworkInterfaceQueueError> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= MAX_IFACE_QUEUE_SIZE {
+            self.count_drop();
+            return Err(NetworkInterfaceQueueError::QueueFull);
+        }
+
+        unsafe {
+            *self.slots[tail % MAX_IFACE_QUEUE_SIZE].get() = Some(data);
         }
+        // publish the slot to the consumer.
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
 
-        Err(NetworkInterfaceQueueError::QueueFull)
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= MAX_IFACE_QUEUE_SIZE
     }
 
+    /// called only by the consumer, frames come out in the order they were pushed.
     #[inline]
-    pub fn pop(&mut self) -> Result<NetworkInterfacePacket, NetworkInterfaceQueueError> {
-        if let Some(data) = self.queue.pop() {
-            return Ok(data);
+    pub fn pop(&self) -> Result<NetworkInterfacePacket, NetworkInterfaceQueueError> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return Err(NetworkInterfaceQueueError::QueueEmpty);
+        }
+
+        let data = unsafe { (*self.slots[head % MAX_IFACE_QUEUE_SIZE].get()).take() };
+        // hand the slot back to the producer.
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+
+        match data {
+            Some(data) => Ok(data),
+            None => Err(NetworkInterfaceQueueError::QueueEmpty),
         }
+    }
 
-        Err(NetworkInterfaceQueueError::QueueEmpty)
+    #[inline]
+    pub fn count_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// number of frames dropped since boot.
+    #[inline]
+    pub fn n_dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+impl PacketBufferPool {
+    pub fn new() -> Self {
+        let mut free = Vec::with_capacity(RX_POOL_SIZE);
+        for _ in 0..RX_POOL_SIZE {
+            free.push(Vec::with_capacity(MAX_FRAME_SIZE));
+        }
+
+        Self { free }
+    }
+
+    /// copies the frame to a free buffer, returns None if all the buffers are in use
+    /// or the frame does not fit in one.
+    #[inline]
+    pub fn copy_frame(&mut self, frame: &[u8]) -> Option<NetworkInterfacePacket> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return None;
+        }
+
+        let mut buffer = self.free.pop()?;
+        buffer.clear();
+        buffer.extend_from_slice(frame);
+        Some(buffer)
+    }
+
+    /// puts the buffer back, buffers that did not come from the pool are dropped.
+    #[inline]
+    pub fn release(&mut self, buffer: NetworkInterfacePacket) {
+        if buffer.capacity() >= MAX_FRAME_SIZE && self.free.len() < RX_POOL_SIZE {
+            self.free.push(buffer);
+        }
+    }
+
+    #[inline]
+    pub fn n_free(&self) -> usize {
+        self.free.len()
     }
 }
 
 pub fn setup_interface_queue() {
     log::info!(
-        "initialized network interface queue with size={}",
-        NETWORK_IFACE_QUEUE.lock().queue.capacity()
+        "initialized network interface queue with size={}, rx buffers={}",
+        NETWORK_IFACE_QUEUE.capacity(),
+        RX_BUFFER_POOL.lock().n_free()
     );
 }
 
@@ -77,8 +197,41 @@ pub fn setup_socket_set() {
 
 pub type TransportLayerPort = u16;
 pub type TransportLayerPorts = BTreeSet<TransportLayerPort>;
+
+/// ports handed out to sockets that send before bind, the IANA dynamic range.
+const EPHEMERAL_PORT_START: TransportLayerPort = 49152;
+const EPHEMERAL_PORT_END: TransportLayerPort = 65535;
+
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORT_START);
+
+/// picks the next free port in the ephemeral range and marks it as used,
+/// the search starts after the last allocated port so the ports are not reused right away.
+pub fn allocate_ephemeral_port(ports: &mut TransportLayerPorts) -> Option<TransportLayerPort> {
+    let range_size = (EPHEMERAL_PORT_END - EPHEMERAL_PORT_START) as u32 + 1;
+    let start = NEXT_EPHEMERAL_PORT.load(Ordering::SeqCst);
+
+    for step in 0..range_size {
+        let offset = (start - EPHEMERAL_PORT_START) as u32 + step;
+        let port = EPHEMERAL_PORT_START + (offset % range_size) as TransportLayerPort;
+        if !ports.contains(&port) {
+            ports.insert(port);
+
+            let next = if port == EPHEMERAL_PORT_END {
+                EPHEMERAL_PORT_START
+            } else {
+                port + 1
+            };
+            NEXT_EPHEMERAL_PORT.store(next, Ordering::SeqCst);
+            return Some(port);
+        }
+    }
+
+    None
+}
 pub type TransportSocketFlags = u16;
 
+/// only IPv4 is supported, smoltcp is built without `proto-ipv6`, so IPv6
+/// addresses are rejected with `UnsupportedFamily` instead of being taken as IPv4.
 #[derive(Debug, Clone)]
 pub enum TransportType {
     // TODO: Support IPv6 network backend
@@ -86,6 +239,9 @@ pub enum TransportType {
     AFInet = 2,
 }
 
+/// address family of IPv6 socket addresses, same as linux.
+pub const AF_INET6: TransportSocketFlags = 10;
+
 #[derive(Debug, Clone)]
 pub enum TransportSocketTypes {
     SockStream = 1,
@@ -138,8 +294,8 @@ impl SocketAddr {
     pub fn from_inet_addr(ep: &IpEndpoint) -> SocketAddr {
         let ip_addr = match ep.addr {
             IpAddress::Ipv4(addr) => addr.0,
-            // TODO: Support IPv6 network backend, ipv6 address will be treated
-            // as unspecified as of now.
+            // only the unspecified address is left, there is no IPv6 variant
+            // without `proto-ipv6`.
             _ => Ipv4Address::UNSPECIFIED.0,
         };
 
@@ -174,32 +330,36 @@ impl SocketAddr {
         ep_addr
     }
 
-    pub fn from_memory_view(vaddr: mm::VirtualAddress) -> Option<SocketAddr> {
-        // first we will typecast this to 
-        let sock_family: &TransportSocketFlags = unsafe { &*vaddr.get_ptr() };
+    pub fn from_memory_view(vaddr: mm::VirtualAddress) -> Result<SocketAddr, SocketError> {
+        // the address comes from the user, it need not be aligned.
+        let sock_family: TransportSocketFlags = unsafe { ptr::read_unaligned(vaddr.get_ptr()) };
         match sock_family {
             // AFInet
             2 => {
-                let netsock_view: &NetworkSocketAddress = unsafe { &*vaddr.get_ptr() };
+                let netsock_view: NetworkSocketAddress =
+                    unsafe { ptr::read_unaligned(vaddr.get_ptr()) };
                 let net_addr = NetworkSocketAddress {
-                    family: *sock_family,
+                    family: sock_family,
                     port: netsock_view.port,
                     address: netsock_view.address,
                     padding: [0; 8]
                 };
 
-                return Some(SocketAddr::Network(net_addr));
+                return Ok(SocketAddr::Network(net_addr));
             }
             1 => {
                 // TODO:
                 let unix_addr = UnixSocketAddress {
-                    family: *sock_family
+                    family: sock_family
                 };
 
-                return Some(SocketAddr::Unix(unix_addr));
+                return Ok(SocketAddr::Unix(unix_addr));
+            }
+            AF_INET6 => {
+                return Err(SocketError::UnsupportedFamily);
             }
             _ => {
-                return None;
+                return Err(SocketError::InvalidAddress);
             }
         }
     }
@@ -229,14 +389,80 @@ pub enum SocketError {
     SendError,
     RecvError,
     BindError,
+    NotBound,
+    UnsupportedOption,
+    InvalidOption,
+    /// the address family is not supported, IPv6 as of now
+    UnsupportedFamily,
+    /// nothing arrived before the deadline
+    Timeout,
+    /// connect to the current peer, or sendto another one on a connected socket
+    IsConnected,
+    /// send without a destination on a socket that is not connected
+    NotConnected,
     WIP
 }
 
+/// option level and names, values are same as linux. IP_TOS is not supported,
+/// smoltcp has no way to set the TOS byte of the packets it builds.
+pub const SOL_IP: usize = 0;
+pub const IP_TTL: usize = 2;
+
+/// TTL used for outgoing packets when not set by the user.
+pub const DEFAULT_IP_TTL: u8 = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SocketOption {
+    /// time to live (hop limit) of outgoing IP packets
+    IPTimeToLive(u8),
+}
+
+impl SocketOption {
+    /// builds the option from setsockopt style level, name and value.
+    pub fn from_values(level: usize, name: usize, value: u32) -> Result<SocketOption, SocketError> {
+        if level != SOL_IP {
+            return Err(SocketError::UnsupportedOption);
+        }
+
+        match name {
+            IP_TTL => {
+                if value == 0 || value > u8::MAX as u32 {
+                    return Err(SocketError::InvalidOption);
+                }
+                Ok(SocketOption::IPTimeToLive(value as u8))
+            }
+            _ => Err(SocketError::UnsupportedOption),
+        }
+    }
+
+    #[inline]
+    pub fn value(&self) -> u32 {
+        match self {
+            SocketOption::IPTimeToLive(ttl) => *ttl as u32,
+        }
+    }
+}
+
 pub trait SocketFn {
     /// bind socket to specified address, throw SocketError if not possible
     fn bind(&self, addr: SocketAddr) -> Result<(), SocketError>;
-    /// send data to the destination address, throw SocketError if not possible 
+    /// send data to the destination address, throw SocketError if not possible.
+    /// an unbound socket is bound to an ephemeral port first.
     fn sendto(&self, addr: SocketAddr, buffer: &[u8]) -> Result<usize, SocketError>;
-    /// receive data from the destination address, throw SocketError if not possible 
+    /// receive data from the destination address, throw SocketError if not possible.
+    /// returns `NotBound` if the socket was never bound, nothing could arrive on it.
     fn recvfrom(&self, buffer: &mut [u8]) -> Result<(usize, SocketAddr), SocketError>; 
+    /// sets the default peer, connecting again to another peer replaces it.
+    /// only datagrams from the peer are received after this.
+    fn connect(&mut self, addr: SocketAddr) -> Result<(), SocketError>;
+    /// send data to the connected peer, `NotConnected` if there is none.
+    fn send(&self, buffer: &[u8]) -> Result<usize, SocketError>;
+    /// receive data from the connected peer, `NotConnected` if there is none.
+    fn recv(&self, buffer: &mut [u8]) -> Result<usize, SocketError>;
+    /// close the socket, release it's port and free the handle from the socket set
+    fn close(&self) -> Result<(), SocketError>;
+    /// set the socket option, throw SocketError if the option is not supported
+    fn set_option(&mut self, option: SocketOption) -> Result<(), SocketError>;
+    /// get the current value of socket option with given level and name
+    fn get_option(&self, level: usize, name: usize) -> Result<SocketOption, SocketError>;
 }
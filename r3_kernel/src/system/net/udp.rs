@@ -4,12 +4,18 @@ extern crate alloc;
 use crate::system::net::{types, process::process_network_packet_event};
 use crate::cpu;
 use crate::system::tasking;
+use crate::system::timer;
 
 use smoltcp::socket;
-use alloc::vec;
+use smoltcp::wire::IpEndpoint;
+use alloc::{sync::Arc, vec};
+use core::fmt;
+use spin::Mutex;
 
 pub struct UDPSocket {
-    sock_handle: socket::SocketHandle
+    sock_handle: socket::SocketHandle,
+    /// default destination set by connect, datagrams from others are dropped.
+    peer: Option<IpEndpoint>,
 }
 
 const UDP_TX_BUFFER_LENGTH: usize = 4096;
@@ -30,52 +36,96 @@ impl UDPSocket {
             vec![0; UDP_TX_BUFFER_LENGTH]
         );
 
-        let socket = socket::UdpSocket::new(udp_rx_buf, udp_tx_buf);
-        let sock_handle = types::SOCKETS_SET.lock().as_mut().unwrap().add(socket);
-        UDPSocket { sock_handle } 
-    }
-}
+        let mut socket = socket::UdpSocket::new(udp_rx_buf, udp_tx_buf);
+        socket.set_hop_limit(Some(types::DEFAULT_IP_TTL));
 
-impl types::SocketFn for UDPSocket {
-    fn bind(&self, addr: types::SocketAddr) -> Result<(), types::SocketError> {
-        let ip_endpoint_opt = addr.to_inet_addr();
-        if ip_endpoint_opt.is_none() {
-            return Err(types::SocketError::InvalidAddress);
+        let sock_handle = types::SOCKETS_SET.lock().as_mut().unwrap().add(socket);
+        UDPSocket {
+            sock_handle,
+            peer: None,
         }
+    }
 
-        let ip_endpoint = ip_endpoint_opt.unwrap();
-
-        let mut current_endpoints = types::CURRENT_TL_PORTS.lock();
+    /// local port of the socket, 0 if it is not bound yet.
+    fn local_port(&self) -> types::TransportLayerPort {
+        let mut sock_set_lock = types::SOCKETS_SET.lock();
+        let sock_set = sock_set_lock.as_mut().unwrap();
 
-        if current_endpoints.contains(&ip_endpoint.port) {
-            return Err(types::SocketError::PortAlreadyInUse);
-        }
+        let udp_socket = sock_set.get::<socket::UdpSocket>(self.sock_handle);
+        udp_socket.endpoint().port
+    }
 
-        // create a socket
+    /// binds the socket to the port, the port must already be marked as used.
+    fn bind_port(
+        &self,
+        port: types::TransportLayerPort,
+        current_endpoints: &mut types::TransportLayerPorts,
+    ) -> Result<(), types::SocketError> {
         let mut sock_set_lock = types::SOCKETS_SET.lock();
         let sock_set = sock_set_lock.as_mut().unwrap();
 
         let mut udp_socket = sock_set.get::<socket::UdpSocket>(self.sock_handle);
 
-        // bind to this port
-        let bind_res = udp_socket.bind(ip_endpoint.port);
+        let bind_res = udp_socket.bind(port);
         if bind_res.is_err() {
+            current_endpoints.remove(&port);
             return Err(types::SocketError::BindError);
         }
 
-        // add this port to endpoints list
-        current_endpoints.insert(ip_endpoint.port);
-
         Ok(())
     }
 
-    fn sendto(&self, addr: types::SocketAddr, buffer: &[u8]) -> Result<usize, types::SocketError> {
-        let ip_endpoint_opt = addr.to_inet_addr();
-        if ip_endpoint_opt.is_none() {
-            return Err(types::SocketError::InvalidAddress);
+    /// like linux, a socket that sends before bind is bound to an ephemeral port.
+    fn ensure_bound(&self) -> Result<(), types::SocketError> {
+        if self.local_port() != 0 {
+            return Ok(());
         }
 
-        let ip_endpoint = ip_endpoint_opt.unwrap();
+        let mut current_endpoints = types::CURRENT_TL_PORTS.lock();
+        let port_opt = types::allocate_ephemeral_port(&mut current_endpoints);
+        if port_opt.is_none() {
+            return Err(types::SocketError::BindError);
+        }
+
+        let port = port_opt.unwrap();
+        log::debug!("auto-binding UDP socket to ephemeral port {}", port);
+        self.bind_port(port, &mut current_endpoints)
+    }
+
+    /// like recvfrom, but gives up with `Timeout` once `timeout_ns` have passed.
+    pub fn recvfrom_timeout(
+        &self,
+        buffer: &mut [u8],
+        timeout_ns: u64,
+    ) -> Result<(usize, types::SocketAddr), types::SocketError> {
+        self.recv_until(buffer, Some(timer::monotonic_ns() + timeout_ns))
+    }
+
+    /// like recv, but gives up with `Timeout` once `timeout_ns` have passed.
+    pub fn recv_timeout(
+        &self,
+        buffer: &mut [u8],
+        timeout_ns: u64,
+    ) -> Result<usize, types::SocketError> {
+        if self.peer.is_none() {
+            return Err(types::SocketError::NotConnected);
+        }
+
+        match self.recvfrom_timeout(buffer, timeout_ns) {
+            Ok((n_bytes, _)) => Ok(n_bytes),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn send_to_endpoint(
+        &self,
+        ip_endpoint: IpEndpoint,
+        buffer: &[u8],
+    ) -> Result<usize, types::SocketError> {
+        let bind_result = self.ensure_bound();
+        if bind_result.is_err() {
+            return Err(bind_result.unwrap_err());
+        }
 
         let mut sockets_lock = types::SOCKETS_SET.lock();
         let all_socks = sockets_lock.as_mut().unwrap();
@@ -101,29 +151,406 @@ impl types::SocketFn for UDPSocket {
         Ok(buffer.len())
     }
 
-    fn recvfrom(&self, buffer: &mut [u8]) -> Result<(usize, types::SocketAddr), types::SocketError> {
+    fn recv_until(
+        &self,
+        buffer: &mut [u8],
+        deadline_ns: Option<u64>,
+    ) -> Result<(usize, types::SocketAddr), types::SocketError> {
+        if self.local_port() == 0 {
+            return Err(types::SocketError::NotBound);
+        }
+
         cpu::enable_interrupts();
         let wait_res = tasking::wait_until_return(|| {
-            let mut sock_lock = types::SOCKETS_SET.lock();
-            let all_socks = sock_lock.as_mut().unwrap();
+            match self.take_datagram(buffer) {
+                // this buffer is empty, No data
+                Ok(None) => match deadline_ns {
+                    Some(deadline) if timer::monotonic_ns() >= deadline => {
+                        Err(types::SocketError::Timeout)
+                    }
+                    _ => Ok(None),
+                },
+                result => result,
+            }
+        });
+
+        // handle the error
+        wait_res
+    }
 
-            let mut udp_socket = all_socks.get::<socket::UdpSocket>(self.sock_handle);
-            let recv_result = udp_socket.recv();
-            match recv_result {
+    /// moves the next datagram into the buffer, None if there is none yet.
+    fn take_datagram(
+        &self,
+        buffer: &mut [u8],
+    ) -> Result<Option<(usize, types::SocketAddr)>, types::SocketError> {
+        let mut sock_lock = types::SOCKETS_SET.lock();
+        let all_socks = sock_lock.as_mut().unwrap();
+
+        let mut udp_socket = all_socks.get::<socket::UdpSocket>(self.sock_handle);
+
+        // a connected socket skips everything that is not from the peer.
+        loop {
+            match udp_socket.recv() {
+                Ok((_, ip_endpoint))
+                    if self.peer.map_or(false, |peer| peer != ip_endpoint) =>
+                {
+                    log::trace!("dropped a datagram from {}, not the peer", ip_endpoint);
+                }
                 Ok((payload, ip_endpoint)) => {
-                    buffer[0..payload.len()].copy_from_slice(payload);
+                    // datagrams larger than the buffer are truncated, like linux.
+                    let n_bytes = core::cmp::min(payload.len(), buffer.len());
+                    buffer[0..n_bytes].copy_from_slice(&payload[0..n_bytes]);
                     let sock_addr = types::SocketAddr::from_inet_addr(&ip_endpoint);
-                    return Ok(Some((payload.len(), sock_addr)))
+                    return Ok(Some((n_bytes, sock_addr)))
                 }
-                // this buffer is empty, No data
-                Err(smoltcp::Error::Exhausted) => Ok(None),
+                Err(smoltcp::Error::Exhausted) => return Ok(None),
                 Err(_) => {
                     return Err(types::SocketError::RecvError)
-                } 
+                }
             }
-        });
+        }
+    }
+}
 
-        // handle the error
-        wait_res
+/// the socket behind a file-descriptor, the descriptors duplicated by dup or fork
+/// share it, so it is closed only with the last of them.
+#[derive(Clone)]
+pub struct UDPSocketDescriptor {
+    pub socket: Arc<Mutex<UDPSocket>>,
+}
+
+impl fmt::Debug for UDPSocketDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UDPSocketDescriptor").finish()
+    }
+}
+
+impl UDPSocketDescriptor {
+    pub fn new() -> Self {
+        UDPSocketDescriptor {
+            socket: Arc::new(Mutex::new(UDPSocket::empty())),
+        }
+    }
+
+    #[inline]
+    pub fn close(&self) -> Result<(), types::SocketError> {
+        types::SocketFn::close(&*self.socket.lock())
+    }
+}
+
+impl types::SocketFn for UDPSocket {
+    fn bind(&self, addr: types::SocketAddr) -> Result<(), types::SocketError> {
+        let ip_endpoint_opt = addr.to_inet_addr();
+        if ip_endpoint_opt.is_none() {
+            return Err(types::SocketError::InvalidAddress);
+        }
+
+        let ip_endpoint = ip_endpoint_opt.unwrap();
+
+        if self.local_port() != 0 {
+            // already bound, either explicitly or by an earlier sendto.
+            return Err(types::SocketError::BindError);
+        }
+
+        let mut current_endpoints = types::CURRENT_TL_PORTS.lock();
+
+        // port 0 asks for any free port.
+        let port = if ip_endpoint.port == 0 {
+            let port_opt = types::allocate_ephemeral_port(&mut current_endpoints);
+            if port_opt.is_none() {
+                return Err(types::SocketError::BindError);
+            }
+            port_opt.unwrap()
+        } else {
+            if current_endpoints.contains(&ip_endpoint.port) {
+                return Err(types::SocketError::PortAlreadyInUse);
+            }
+
+            // add this port to endpoints list
+            current_endpoints.insert(ip_endpoint.port);
+            ip_endpoint.port
+        };
+
+        // bind to this port
+        self.bind_port(port, &mut current_endpoints)
+    }
+
+    fn sendto(&self, addr: types::SocketAddr, buffer: &[u8]) -> Result<usize, types::SocketError> {
+        let ip_endpoint_opt = addr.to_inet_addr();
+        if ip_endpoint_opt.is_none() {
+            return Err(types::SocketError::InvalidAddress);
+        }
+
+        let ip_endpoint = ip_endpoint_opt.unwrap();
+
+        // a connected socket only talks to it's peer.
+        if self.peer.is_some() && self.peer != Some(ip_endpoint) {
+            return Err(types::SocketError::IsConnected);
+        }
+
+        self.send_to_endpoint(ip_endpoint, buffer)
+    }
+
+    /// an unbound socket can never receive anything, so instead of blocking forever
+    /// this returns `NotBound`.
+    fn recvfrom(&self, buffer: &mut [u8]) -> Result<(usize, types::SocketAddr), types::SocketError> {
+        self.recv_until(buffer, None)
+    }
+
+    /// like linux, an unbound socket is bound to an ephemeral port here.
+    fn connect(&mut self, addr: types::SocketAddr) -> Result<(), types::SocketError> {
+        let ip_endpoint_opt = addr.to_inet_addr();
+        if ip_endpoint_opt.is_none() {
+            return Err(types::SocketError::InvalidAddress);
+        }
+
+        let ip_endpoint = ip_endpoint_opt.unwrap();
+        if ip_endpoint.port == 0 || ip_endpoint.addr.is_unspecified() {
+            return Err(types::SocketError::InvalidAddress);
+        }
+
+        if self.peer == Some(ip_endpoint) {
+            return Err(types::SocketError::IsConnected);
+        }
+
+        let bind_result = self.ensure_bound();
+        if bind_result.is_err() {
+            return Err(bind_result.unwrap_err());
+        }
+
+        self.peer = Some(ip_endpoint);
+        Ok(())
+    }
+
+    fn send(&self, buffer: &[u8]) -> Result<usize, types::SocketError> {
+        match self.peer {
+            Some(peer) => self.send_to_endpoint(peer, buffer),
+            None => Err(types::SocketError::NotConnected),
+        }
+    }
+
+    fn recv(&self, buffer: &mut [u8]) -> Result<usize, types::SocketError> {
+        if self.peer.is_none() {
+            return Err(types::SocketError::NotConnected);
+        }
+
+        match self.recv_until(buffer, None) {
+            Ok((n_bytes, _)) => Ok(n_bytes),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn close(&self) -> Result<(), types::SocketError> {
+        let mut sock_lock = types::SOCKETS_SET.lock();
+        let all_socks = sock_lock.as_mut().unwrap();
+
+        let udp_socket = all_socks.get::<socket::UdpSocket>(self.sock_handle);
+        let local_port = udp_socket.endpoint().port;
+        drop(udp_socket);
+
+        // free the handle from the socket set
+        all_socks.remove(self.sock_handle);
+        // bind takes the ports before the socket set, so they are not held together here.
+        drop(sock_lock);
+
+        // release the port, so it can be bound again
+        if local_port != 0 {
+            types::CURRENT_TL_PORTS.lock().remove(&local_port);
+        }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn set_option(&mut self, option: types::SocketOption) -> Result<(), types::SocketError> {
+        match option {
+            types::SocketOption::IPTimeToLive(ttl) => {
+                let mut sock_lock = types::SOCKETS_SET.lock();
+                let all_socks = sock_lock.as_mut().unwrap();
+
+                // smoltcp applies this as the hop limit of every packet sent from this socket
+                let mut udp_socket = all_socks.get::<socket::UdpSocket>(self.sock_handle);
+                udp_socket.set_hop_limit(Some(ttl));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_option(&self, level: usize, name: usize) -> Result<types::SocketOption, types::SocketError> {
+        if level != types::SOL_IP {
+            return Err(types::SocketError::UnsupportedOption);
+        }
+
+        match name {
+            types::IP_TTL => {
+                let mut sock_lock = types::SOCKETS_SET.lock();
+                let all_socks = sock_lock.as_mut().unwrap();
+
+                let udp_socket = all_socks.get::<socket::UdpSocket>(self.sock_handle);
+                let ttl = udp_socket.hop_limit().unwrap_or(types::DEFAULT_IP_TTL);
+                Ok(types::SocketOption::IPTimeToLive(ttl))
+            }
+            _ => Err(types::SocketError::UnsupportedOption),
+        }
+    }
+}
+
+/// only the socket state is checked, nothing is sent, so this works without a link.
+#[cfg(feature = "debug_checks")]
+pub fn test_connect() {
+    use types::{SocketError, SocketFn, TransportType};
+
+    let peer = types::SocketAddr::from_values(TransportType::AFInet, [192, 168, 0, 1], 9);
+    let other = types::SocketAddr::from_values(TransportType::AFInet, [192, 168, 0, 1], 10);
+    let mut socket = UDPSocket::empty();
+
+    assert!(matches!(socket.send(b"r3"), Err(SocketError::NotConnected)));
+    assert!(socket.connect(peer).is_ok());
+    assert!(socket.local_port() != 0);
+    assert!(matches!(socket.connect(peer), Err(SocketError::IsConnected)));
+    assert!(matches!(socket.sendto(other, b"r3"), Err(SocketError::IsConnected)));
+
+    // re-connecting to another peer replaces the old one.
+    assert!(socket.connect(other).is_ok());
+    assert!(matches!(socket.connect(other), Err(SocketError::IsConnected)));
+
+    let unspecified = types::SocketAddr::from_values(TransportType::AFInet, [0, 0, 0, 0], 9);
+    assert!(matches!(socket.connect(unspecified), Err(SocketError::InvalidAddress)));
+
+    let _ = socket.close();
+    log::info!("Passed UDP connect test.");
+}
+
+/// recvfrom before bind fails instead of blocking forever, sendto before bind
+/// takes an ephemeral port that close gives back.
+#[cfg(feature = "debug_checks")]
+pub fn test_unbound() {
+    use types::{SocketError, SocketFn, TransportType};
+
+    let peer = types::SocketAddr::from_values(TransportType::AFInet, [192, 168, 0, 1], 9);
+    let mut buffer: [u8; 16] = [0; 16];
+
+    let socket = UDPSocket::empty();
+    assert!(matches!(socket.recvfrom(&mut buffer), Err(SocketError::NotBound)));
+    assert!(matches!(socket.recvfrom_timeout(&mut buffer, 0), Err(SocketError::NotBound)));
+    // failing to receive does not bind it.
+    assert_eq!(socket.local_port(), 0);
+
+    // the link is not configured yet, so the datagram is only queued.
+    assert_eq!(cpu::without_interrupts(|| socket.sendto(peer, b"r3")).ok(), Some(2));
+    let port = socket.local_port();
+    assert!(port != 0);
+    assert!(types::CURRENT_TL_PORTS.lock().contains(&port));
+
+    // the next send keeps the port, and it can't be bound again.
+    assert_eq!(cpu::without_interrupts(|| socket.sendto(peer, b"r3")).ok(), Some(2));
+    assert_eq!(socket.local_port(), port);
+    let any = types::SocketAddr::from_values(TransportType::AFInet, [0, 0, 0, 0], 0);
+    assert!(matches!(socket.bind(any), Err(SocketError::BindError)));
+
+    // bound now, so the receive waits for a datagram instead of failing.
+    assert!(matches!(socket.take_datagram(&mut buffer), Ok(None)));
+
+    let _ = socket.close();
+    assert!(!types::CURRENT_TL_PORTS.lock().contains(&port));
+    log::info!("Passed UDP unbound socket test.");
+}
+
+/// an ethernet frame with the datagram, sent to the broadcast address so
+/// the interface takes it before it has an address of it's own.
+#[cfg(feature = "debug_checks")]
+fn datagram_frame(from: IpEndpoint, to_port: types::TransportLayerPort, payload: &[u8]) -> vec::Vec<u8> {
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::{
+        EthernetAddress, EthernetFrame, EthernetProtocol, IpAddress, IpProtocol, Ipv4Address,
+        Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr,
+    };
+
+    let src_addr = match from.addr {
+        IpAddress::Ipv4(addr) => addr,
+        _ => unreachable!(),
+    };
+    let dst_addr = Ipv4Address::BROADCAST;
+
+    let udp_repr = UdpRepr { src_port: from.port, dst_port: to_port, payload };
+    let ip_repr = Ipv4Repr {
+        src_addr,
+        dst_addr,
+        protocol: IpProtocol::Udp,
+        payload_len: udp_repr.buffer_len(),
+        hop_limit: types::DEFAULT_IP_TTL,
+    };
+
+    let caps = ChecksumCapabilities::default();
+    let mut buffer = vec![0; EthernetFrame::<&[u8]>::header_len() + ip_repr.buffer_len() + udp_repr.buffer_len()];
+
+    let mut frame = EthernetFrame::new_unchecked(&mut buffer[..]);
+    frame.set_dst_addr(EthernetAddress::BROADCAST);
+    frame.set_src_addr(EthernetAddress([0x02, 0, 0, 0, 0, 0x01]));
+    frame.set_ethertype(EthernetProtocol::Ipv4);
+
+    let mut ip_packet = Ipv4Packet::new_unchecked(frame.payload_mut());
+    ip_repr.emit(&mut ip_packet, &caps);
+
+    let mut udp_packet = UdpPacket::new_unchecked(ip_packet.payload_mut());
+    udp_repr.emit(&mut udp_packet, &IpAddress::Ipv4(src_addr), &IpAddress::Ipv4(dst_addr), &caps);
+
+    buffer
+}
+
+/// the frames go through the receive queue as if the device got them from the peer
+/// and from another host on the same address, only the ones from the peer are read.
+#[cfg(feature = "debug_checks")]
+pub fn test_peer_filter() {
+    use crate::system::net::iface;
+    use types::{SocketFn, TransportType};
+
+    let peer = types::SocketAddr::from_values(TransportType::AFInet, [192, 168, 0, 1], 9);
+    let other = types::SocketAddr::from_values(TransportType::AFInet, [192, 168, 0, 1], 10);
+    let (peer_endpoint, other_endpoint) = (peer.to_inet_addr().unwrap(), other.to_inet_addr().unwrap());
+
+    let mut connected = UDPSocket::empty();
+    assert!(connected.connect(peer).is_ok());
+    // the link is not configured yet, so the datagram is only queued.
+    assert_eq!(cpu::without_interrupts(|| connected.send(b"ping")).ok(), Some(4));
+
+    let unconnected = UDPSocket::empty();
+    let any = types::SocketAddr::from_values(TransportType::AFInet, [0, 0, 0, 0], 0);
+    assert!(unconnected.bind(any).is_ok());
+
+    let frames = [
+        datagram_frame(other_endpoint, connected.local_port(), b"other"),
+        datagram_frame(peer_endpoint, connected.local_port(), b"peer"),
+        datagram_frame(other_endpoint, unconnected.local_port(), b"other"),
+        datagram_frame(peer_endpoint, unconnected.local_port(), b"peer"),
+    ];
+
+    for frame in frames.iter() {
+        if !iface::inject_frame(frame) {
+            log::info!("Skipped UDP peer filter test, there is no network device.");
+            let _ = connected.close();
+            let _ = unconnected.close();
+            return;
+        }
+    }
+    cpu::without_interrupts(|| process_network_packet_event());
+
+    let mut buffer: [u8; 16] = [0; 16];
+
+    // the datagram from the other port is dropped on the way.
+    let received = connected.take_datagram(&mut buffer).ok().flatten();
+    assert_eq!(received.map(|(n_bytes, _)| n_bytes), Some(4));
+    assert_eq!(&buffer[0..4], b"peer");
+    assert!(matches!(connected.take_datagram(&mut buffer), Ok(None)));
+
+    // a socket that is not connected takes them all, in order.
+    for expected in [&b"other"[..], &b"peer"[..]].iter() {
+        let received = unconnected.take_datagram(&mut buffer).ok().flatten();
+        assert_eq!(received.map(|(n_bytes, _)| n_bytes), Some(expected.len()));
+        assert_eq!(&buffer[0..expected.len()], *expected);
+    }
+    assert!(matches!(unconnected.take_datagram(&mut buffer), Ok(None)));
+
+    let _ = connected.close();
+    let _ = unconnected.close();
+    log::info!("Passed UDP peer filter test.");
+}
@@ -1,25 +1,51 @@
 use crate::system::abi;
-use crate::system::timer;
+use crate::system::timer::{self, Time};
 
 const REALTIME_CLOCK: i32 = 0;
 const MONOTONIC_CLOCK: i32 = 1;
 
+#[inline]
+fn copy_timeval(timeval: timer::PosixTimeval, timeval_buffer: abi::UserAddress) {
+    let tval_buffer: &mut timer::PosixTimeval = unsafe { &mut *timeval_buffer.get_mut_ptr() };
+    tval_buffer.tv_sec = timeval.tv_sec;
+    tval_buffer.tv_usec = timeval.tv_usec;
+}
+
 pub fn sys_clock_gettime(
     clock_sel: i32,
     timeval_buffer: abi::UserAddress,
 ) -> Result<isize, abi::Errno> {
     let timeval = match clock_sel {
-        // TODO: Handle time with unix epoch
-        REALTIME_CLOCK => timer::PosixTimeval::from_ticks(),
-        MONOTONIC_CLOCK => timer::PosixTimeval::from_ticks(),
-        _ => return Err(abi::Errno::ENOSYS),
+        REALTIME_CLOCK => timer::PosixTimeval::from_ns(timer::realtime_ns()),
+        // same clock as the vDSO time page, so the userspace fallback is consistent.
+        MONOTONIC_CLOCK => timer::PosixTimeval::from_ns(timer::monotonic_ns()),
+        _ => return Err(abi::Errno::EINVAL),
     };
 
     // return this timeval, copy this to userpace buffer provided
-    let tval_buffer: &mut timer::PosixTimeval = unsafe { &mut *timeval_buffer.get_mut_ptr() };
-    tval_buffer.tv_sec = timeval.tv_sec;
-    tval_buffer.tv_usec = timeval.tv_usec;
+    copy_timeval(timeval, timeval_buffer);
 
     // everything ok
     Ok(0)
 }
+
+/// `timeval_buffer` can be None, then only the clock id is checked.
+pub fn sys_clock_getres(
+    clock_sel: i32,
+    timeval_buffer: Option<abi::UserAddress>,
+) -> Result<isize, abi::Errno> {
+    if clock_sel != REALTIME_CLOCK && clock_sel != MONOTONIC_CLOCK {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    // both the clocks count TSC cycles, but they are returned as a timeval,
+    // so a microsecond is the finest step userspace can see.
+    let step_us = Time::MicroSecond as u64;
+    let resolution_ns = ((timer::clock_resolution_ns() + step_us - 1) / step_us) * step_us;
+
+    if let Some(timeval_buffer) = timeval_buffer {
+        copy_timeval(timer::PosixTimeval::from_ns(resolution_ns), timeval_buffer);
+    }
+
+    Ok(0)
+}
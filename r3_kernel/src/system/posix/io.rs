@@ -3,9 +3,12 @@ extern crate bitflags;
 use crate::mm::VirtualAddress;
 use crate::system;
 use crate::system::abi;
-use crate::system::filesystem::{vfs::FILESYSTEM, FDOps, FSOps, POSIXOpenFlags, SeekType};
+use crate::system::filesystem::detect::STORAGE_DEVICE_MAJOR;
+use crate::system::filesystem::{
+    pipe, vfs::FILESYSTEM, FDOps, FSError, FSOps, FileDescriptor, POSIXOpenFlags, SeekType,
+};
 use crate::system::process::{Process, PROCESS_POOL};
-use crate::system::utils::ProcessFDPool;
+use crate::system::utils::{ProcessError, ProcessFDPool};
 
 use core::ptr;
 
@@ -20,20 +23,27 @@ pub fn sys_open(path: &str, flags: POSIXOpenFlags) -> Result<isize, abi::Errno>
 
     let mut proc_pool = PROCESS_POOL.lock();
     let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
-    if flags.contains(POSIXOpenFlags::O_CREAT) {
-        log::error!("File creation is not implemented yet.");
-        return Err(abi::Errno::EINVAL);
-    }
-
+    // the existence check and the creation both happen under the filesystem lock.
     let fd_result = FILESYSTEM.lock().open(&path, flags.bits());
     if fd_result.is_err() {
-        log::error!("File {} not found {:?}.", path, fd_result.unwrap_err());
-        return Err(abi::Errno::EEXIST);
+        let err = fd_result.unwrap_err();
+        log::error!("Failed to open file {}, err={:?}.", path, err);
+        return Err(match err {
+            FSError::NotFound => abi::Errno::ENOENT,
+            FSError::AlreadyExist => abi::Errno::EEXIST,
+            FSError::IllegalPath => abi::Errno::ENAMETOOLONG,
+            FSError::IOError => abi::Errno::EIO,
+            _ => abi::Errno::EINVAL,
+        });
     }
 
     // create the file-descriptor-index
     let fd = fd_result.unwrap();
-    let fd_res = ProcessFDPool::put(&mut proc_ref.proc_data.as_mut().unwrap(), fd);
+    let fd_res = ProcessFDPool::put(
+        &mut proc_ref.proc_data.as_mut().unwrap(),
+        fd,
+        flags.contains(POSIXOpenFlags::O_CLOEXEC),
+    );
     if fd_res.is_err() {
         log::error!("Process wide number of open file-descriptors limit has been reached.");
         return Err(abi::Errno::EMFILE);
@@ -63,15 +73,30 @@ pub fn sys_read(
         return Err(abi::Errno::EBADF);
     }
 
-    let fdref = fdref_opt.unwrap();
+    let mut file = fdref_opt.unwrap().file.lock();
+    if !has_access(&file, false) {
+        return Err(abi::Errno::EBADF);
+    }
 
-    // TODO: Check if the file was opened for reading
     let mut buffer =
         unsafe { &mut *ptr::slice_from_raw_parts_mut(buffer_addr.get_mut_ptr::<u8>(), size) };
-    let read_res = FILESYSTEM.lock().read(&mut fdref.fd, &mut buffer);
+
+    // the writer needs the locks to fill the pipe, so they are released before waiting.
+    if let Some(pipefd) = as_pipe(&file) {
+        drop(file);
+        drop(proc_pool);
+
+        let read_res = pipefd.read(&mut buffer);
+        if read_res.is_err() {
+            return Err(rw_errno(read_res.unwrap_err()));
+        }
+        return Ok(read_res.unwrap() as isize);
+    }
+
+    let read_res = FILESYSTEM.lock().read(&mut file, &mut buffer);
 
     if read_res.is_err() {
-        return Err(abi::Errno::EIO);
+        return Err(rw_errno(read_res.unwrap_err()));
     }
 
     // seek to length:
@@ -92,12 +117,6 @@ pub fn sys_write(
         return Err(abi::Errno::EINVAL);
     }
 
-    // TODO: make writes possible on all the types of files
-    if fd_index == 0 || fd_index > 2 {
-        log::error!("As of now, writes are possible on stdout, stderr.");
-        return Err(abi::Errno::EINVAL);
-    }
-
     let mut proc_pool = PROCESS_POOL.lock();
     let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
 
@@ -107,28 +126,105 @@ pub fn sys_write(
         return Err(abi::Errno::EBADF);
     }
 
-    let fdref = fdref_opt.unwrap();
+    let mut file = fdref_opt.unwrap().file.lock();
+    if !has_access(&file, true) {
+        return Err(abi::Errno::EBADF);
+    }
 
-    // TODO: Check if the file was opened for writing
     let buffer = unsafe { &*ptr::slice_from_raw_parts(buffer_addr.get_ptr::<u8>(), size) };
-    let read_res = FILESYSTEM.lock().write(&mut fdref.fd, &buffer);
+
+    // same as sys_read, the reader needs the locks to make room.
+    if let Some(pipefd) = as_pipe(&file) {
+        drop(file);
+        drop(proc_pool);
+
+        let write_res = pipefd.write(&buffer);
+        if write_res.is_err() {
+            return Err(rw_errno(write_res.unwrap_err()));
+        }
+        return Ok(write_res.unwrap() as isize);
+    }
+
+    let read_res = FILESYSTEM.lock().write(&mut file, &buffer);
 
     if read_res.is_err() {
-        return Err(abi::Errno::EIO);
+        return Err(rw_errno(read_res.unwrap_err()));
     }
 
     // return the number of bytes wrote
     return Ok(read_res.unwrap() as isize);
 }
 
-pub fn sys_close(fd_index: usize) -> Result<isize, abi::Errno> {
+/// the access mode of the open flags allows the read or write.
+#[inline]
+fn mode_allows(flags: u32, write: bool) -> bool {
+    let open_flags = POSIXOpenFlags::from_bits_truncate(flags);
+    if write {
+        open_flags.intersects(POSIXOpenFlags::O_WRONLY | POSIXOpenFlags::O_RDWR)
+    } else {
+        !open_flags.contains(POSIXOpenFlags::O_WRONLY)
+    }
+}
+
+/// files and devices remember the access mode they were opened with, each end
+/// of a pipe goes one way only. sockets and the rest are not checked.
+#[inline]
+fn has_access(fd: &FileDescriptor, write: bool) -> bool {
+    match fd {
+        FileDescriptor::TarFSNode(tarfd) => mode_allows(tarfd.flags, write),
+        FileDescriptor::DevFSNode(devfd) => mode_allows(devfd.flags, write),
+        FileDescriptor::PipeNode(pipefd) => pipefd.write_end == write,
+        _ => true,
+    }
+}
+
+#[inline]
+fn as_pipe(fd: &FileDescriptor) -> Option<pipe::PipeDescriptor> {
+    match fd {
+        FileDescriptor::PipeNode(pipefd) => Some(pipefd.clone()),
+        _ => None,
+    }
+}
+
+/// errors of a read or write on a descriptor that is open.
+#[inline]
+fn rw_errno(err: FSError) -> abi::Errno {
+    match err {
+        FSError::NoSpace => abi::Errno::ENOSPC,
+        FSError::InvalidSeek => abi::Errno::EINVAL,
+        FSError::AlignmentError => abi::Errno::EINVAL,
+        FSError::WouldBlock => abi::Errno::EAGAIN,
+        FSError::BrokenPipe => abi::Errno::EPIPE,
+        _ => abi::Errno::EIO,
+    }
+}
+
+/// stream devices like the tty and serial port don't have a position.
+#[inline]
+fn is_seekable(fd: &FileDescriptor) -> bool {
+    match fd {
+        FileDescriptor::DevFSNode(devfd) => devfd.major == STORAGE_DEVICE_MAJOR as u32,
+        FileDescriptor::TarFSNode(_) => true,
+        _ => false,
+    }
+}
+
+pub fn sys_pread(
+    fd_index: usize,
+    buffer_addr: VirtualAddress,
+    size: usize,
+    offset: usize,
+) -> Result<isize, abi::Errno> {
     let pid = system::current_pid();
     if pid.is_none() {
         log::error!("PID is null.");
         return Err(abi::Errno::EINVAL);
     }
 
-    // call close on the file-system and remove the fd
+    if offset > u32::MAX as usize {
+        return Err(abi::Errno::EINVAL);
+    }
+
     let mut proc_pool = PROCESS_POOL.lock();
     let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
 
@@ -138,19 +234,222 @@ pub fn sys_close(fd_index: usize) -> Result<isize, abi::Errno> {
         return Err(abi::Errno::EBADF);
     }
 
-    let fdref = fdref_opt.unwrap();
+    let file = fdref_opt.unwrap().file.lock();
+    if !is_seekable(&file) {
+        return Err(abi::Errno::ESPIPE);
+    }
+
+    if !has_access(&file, false) {
+        return Err(abi::Errno::EBADF);
+    }
+
+    // read through a copy of the descriptor, so the offset of the original stays as is.
+    let mut positioned_fd = (*file).clone();
+    let fs_lock = FILESYSTEM.lock();
+    if fs_lock
+        .seek(&mut positioned_fd, offset as u32, SeekType::SEEK_SET)
+        .is_err()
+    {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut buffer =
+        unsafe { &mut *ptr::slice_from_raw_parts_mut(buffer_addr.get_mut_ptr::<u8>(), size) };
+    let read_res = fs_lock.read(&mut positioned_fd, &mut buffer);
+    if read_res.is_err() {
+        return Err(rw_errno(read_res.unwrap_err()));
+    }
+
+    Ok(read_res.unwrap() as isize)
+}
+
+pub fn sys_pwrite(
+    fd_index: usize,
+    buffer_addr: VirtualAddress,
+    size: usize,
+    offset: usize,
+) -> Result<isize, abi::Errno> {
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    if offset > u32::MAX as usize {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+    let fdref_opt = ProcessFDPool::get_mut(proc_data, fd_index);
+    if fdref_opt.is_none() {
+        return Err(abi::Errno::EBADF);
+    }
+
+    let mut file = fdref_opt.unwrap().file.lock();
+    if !is_seekable(&file) {
+        return Err(abi::Errno::ESPIPE);
+    }
+
+    if !has_access(&file, true) {
+        return Err(abi::Errno::EBADF);
+    }
+
+    let buffer = unsafe { &*ptr::slice_from_raw_parts(buffer_addr.get_ptr::<u8>(), size) };
+
+    let mut positioned_fd = (*file).clone();
+    let fs_lock = FILESYSTEM.lock();
+    let write_res = match positioned_fd {
+        FileDescriptor::TarFSNode(ref tarfd) => {
+            // tarfs is append only, a write anywhere but the end of the file
+            // would land at the end anyway.
+            if offset != tarfd.size {
+                return Err(abi::Errno::EINVAL);
+            }
+            fs_lock.write(&mut positioned_fd, &buffer)
+        }
+        _ => {
+            if fs_lock
+                .seek(&mut positioned_fd, offset as u32, SeekType::SEEK_SET)
+                .is_err()
+            {
+                return Err(abi::Errno::EINVAL);
+            }
+            fs_lock.write(&mut positioned_fd, &buffer)
+        }
+    };
+
+    if write_res.is_err() {
+        return Err(rw_errno(write_res.unwrap_err()));
+    }
+
+    // the file might have grown, keep the size but not the position.
+    if let (FileDescriptor::TarFSNode(original), FileDescriptor::TarFSNode(written)) =
+        (&mut *file, &positioned_fd)
+    {
+        original.size = written.size;
+    }
+
+    Ok(write_res.unwrap() as isize)
+}
+
+pub fn sys_close(fd_index: usize) -> Result<isize, abi::Errno> {
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    // call close on the file-system and remove the fd
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+
+    // closes the node and removes it from the process, the node must be
+    // closed only once, otherwise the mountpoint references drift.
+    let remove_res = ProcessFDPool::remove(proc_data, fd_index);
+    match remove_res {
+        Ok(()) => Ok(0),
+        Err(ProcessError::InvalidFD) => Err(abi::Errno::EBADF),
+        Err(_) => Err(abi::Errno::EIO),
+    }
+}
+
+/// the new descriptor shares the offset with the old one, closing either keeps the file open.
+pub fn sys_dup(fd_index: usize) -> Result<isize, abi::Errno> {
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+    match ProcessFDPool::dup(proc_data, fd_index) {
+        Ok(new_index) => Ok(new_index as isize),
+        Err(ProcessError::MaxFDLimit) => Err(abi::Errno::EMFILE),
+        Err(_) => Err(abi::Errno::EBADF),
+    }
+}
+
+/// both ends get the flags, fds receives the read end and then the write end.
+pub fn sys_pipe2(fds: VirtualAddress, flags_arg: usize) -> Result<isize, abi::Errno> {
+    // unknown bits are lost by the truncation, the known ones must be supported.
+    let supported = POSIXOpenFlags::O_NONBLOCK | POSIXOpenFlags::O_CLOEXEC;
+    let flags = POSIXOpenFlags::from_bits_truncate(flags_arg as u32);
+    if flags.bits() as usize != flags_arg || !supported.contains(flags) {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+
+    let cloexec = flags.contains(POSIXOpenFlags::O_CLOEXEC);
+    let (read_end, write_end) = pipe::new_pipe(flags.bits());
+
+    let read_res = ProcessFDPool::put(proc_data, FileDescriptor::PipeNode(read_end), cloexec);
+    if read_res.is_err() {
+        return Err(abi::Errno::EMFILE);
+    }
 
-    // call close
-    let close_res = FILESYSTEM.lock().close(&fdref.fd);
-    if close_res.is_err() {
-        return Err(abi::Errno::EIO);
+    let read_index = read_res.unwrap();
+    let write_res = ProcessFDPool::put(proc_data, FileDescriptor::PipeNode(write_end), cloexec);
+    if write_res.is_err() {
+        // closing the read end drops the pipe with it.
+        let _ = ProcessFDPool::remove(proc_data, read_index);
+        return Err(abi::Errno::EMFILE);
     }
 
-    // remove from process pool
-    let _ = ProcessFDPool::remove(proc_data, fd_index);
+    let indices: [abi::CInt; 2] = [read_index as abi::CInt, write_res.unwrap() as abi::CInt];
+    abi::copy_to_buffer(indices, fds);
     Ok(0)
 }
 
+// fcntl commands and descriptor flags, same values as linux
+const F_GETFD: usize = 1;
+const F_SETFD: usize = 2;
+const FD_CLOEXEC: usize = 1;
+
+/// only the descriptor flags are supported as of now.
+pub fn sys_fcntl(fd_index: usize, command: usize, arg: usize) -> Result<isize, abi::Errno> {
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+    let entry_opt = ProcessFDPool::get_mut(proc_data, fd_index);
+    if entry_opt.is_none() {
+        return Err(abi::Errno::EBADF);
+    }
+
+    let entry = entry_opt.unwrap();
+    match command {
+        F_GETFD => Ok(if entry.cloexec { FD_CLOEXEC as isize } else { 0 }),
+        F_SETFD => {
+            entry.cloexec = arg & FD_CLOEXEC == FD_CLOEXEC;
+            Ok(0)
+        }
+        _ => Err(abi::Errno::EINVAL),
+    }
+}
+
 pub fn sys_lseek(fd_index: usize, offset: u32, whence: u8) -> Result<isize, abi::Errno> {
     let seek_type = match whence {
         0 => SeekType::SEEK_SET,
@@ -175,8 +474,8 @@ pub fn sys_lseek(fd_index: usize, offset: u32, whence: u8) -> Result<isize, abi:
         return Err(abi::Errno::EBADF);
     }
 
-    let fdref = fdref_opt.unwrap();
-    let seek_res = FILESYSTEM.lock().seek(&mut fdref.fd, offset, seek_type);
+    let mut file = fdref_opt.unwrap().file.lock();
+    let seek_res = FILESYSTEM.lock().seek(&mut file, offset, seek_type);
     if seek_res.is_err() {
         return Err(abi::Errno::EINVAL);
     }
@@ -202,7 +501,7 @@ pub fn sys_fstat(fd_index: usize, stat_buf: VirtualAddress) -> Result<isize, abi
         return Err(abi::Errno::EBADF);
     }
 
-    let mut fd = fdref_opt.unwrap().fd.clone();
+    let mut fd = fdref_opt.unwrap().file.lock().clone();
     let stat_result = FILESYSTEM.lock().fstat(&mut fd);
 
     if stat_result.is_err() {
@@ -220,8 +519,11 @@ pub fn sys_lstat(path: &str, stat_buf: VirtualAddress) -> Result<isize, abi::Err
         return open_res;
     }
 
-    let fd_index = open_res.unwrap();
-    sys_fstat(fd_index as usize, stat_buf)
+    // the descriptor is only needed for the stat, don't leak it.
+    let fd_index = open_res.unwrap() as usize;
+    let stat_res = sys_fstat(fd_index, stat_buf);
+    let _ = sys_close(fd_index);
+    stat_res
 }
 
 pub fn sys_ioctl(fd_index: usize, command: usize, arg: usize) -> Result<isize, abi::Errno> {
@@ -241,12 +543,65 @@ pub fn sys_ioctl(fd_index: usize, command: usize, arg: usize) -> Result<isize, a
         return Err(abi::Errno::EBADF);
     }
 
-    let fdref = fdref_opt.unwrap();
+    let mut file = fdref_opt.unwrap().file.lock();
 
-    let ioctl_res = FILESYSTEM.lock().ioctl(&mut fdref.fd, command, arg);
+    let ioctl_res = FILESYSTEM.lock().ioctl(&mut file, command, arg);
     if ioctl_res.is_err() {
-        return Err(abi::Errno::ENOTTY);
+        return Err(match ioctl_res.unwrap_err() {
+            FSError::NoSuchProcess => abi::Errno::ESRCH,
+            _ => abi::Errno::ENOTTY,
+        });
     }
 
     Ok(ioctl_res.unwrap() as isize)
 }
+
+fn truncate_fd(fd: &mut FileDescriptor, length: usize) -> Result<isize, abi::Errno> {
+    let truncate_res = FILESYSTEM.lock().truncate(fd, length);
+    match truncate_res {
+        Ok(()) => Ok(0),
+        Err(FSError::ReadOnly) => Err(abi::Errno::EROFS),
+        Err(FSError::IOError) => Err(abi::Errno::EIO),
+        Err(_) => Err(abi::Errno::EINVAL),
+    }
+}
+
+pub fn sys_ftruncate(fd_index: usize, length: isize) -> Result<isize, abi::Errno> {
+    if length < 0 {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+    let fdref_opt = ProcessFDPool::get_mut(proc_data, fd_index);
+    if fdref_opt.is_none() {
+        return Err(abi::Errno::EBADF);
+    }
+
+    let mut file = fdref_opt.unwrap().file.lock();
+    truncate_fd(&mut file, length as usize)
+}
+
+pub fn sys_truncate(path: &str, length: isize) -> Result<isize, abi::Errno> {
+    if length < 0 {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let open_res = sys_open(&path, POSIXOpenFlags::O_WRONLY);
+    if open_res.is_err() {
+        return open_res;
+    }
+
+    let fd_index = open_res.unwrap() as usize;
+    let truncate_res = sys_ftruncate(fd_index, length);
+    let _ = sys_close(fd_index);
+    truncate_res
+}
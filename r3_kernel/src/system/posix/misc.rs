@@ -1,7 +1,16 @@
-use crate::acpi::power;
+extern crate log;
+
+use crate::acpi::{madt, power};
 use crate::drivers::random;
+use crate::mm::paging::PageSize;
+use crate::mm::phy::PhysicalMemoryManager;
 use crate::mm::VirtualAddress;
+use crate::system;
 use crate::system::abi;
+use crate::system::process::{Process, MAX_PROCESSES, PROCESS_POOL};
+use crate::system::rlimit::{RLimit, RLimitError, RLIMIT_NOFILE};
+use crate::system::tasking::idle;
+use crate::system::utils::MAX_FILE_DESCRIPTORS;
 
 use core::ptr;
 
@@ -11,6 +20,92 @@ const UNAME_STRINGS: &'static [&'static str] = &["r3-os", "unknown", "0.0.1", "r
 
 const FIELD_LEN: usize = 65;
 
+// sysconf keys, same values as glibc
+const SC_CHILD_MAX: usize = 1;
+const SC_OPEN_MAX: usize = 4;
+const SC_PAGESIZE: usize = 30;
+const SC_NPROCESSORS_CONF: usize = 83;
+const SC_NPROCESSORS_ONLN: usize = 84;
+const SC_PHYS_PAGES: usize = 85;
+const SC_AVPHYS_PAGES: usize = 86;
+
+/// all the values exposed by sysconf, taken from the kernel constants directly.
+fn sysconf_value(key: usize) -> Option<usize> {
+    match key {
+        SC_CHILD_MAX => Some(MAX_PROCESSES),
+        // the soft limit of the caller, like glibc.
+        SC_OPEN_MAX => Some(
+            current_limit(RLIMIT_NOFILE)
+                .map(|limit| limit.current as usize)
+                .unwrap_or(MAX_FILE_DESCRIPTORS),
+        ),
+        SC_PAGESIZE => Some(PageSize::Page4KiB.size() as usize),
+        // only the boot processor runs as of now, the counts are what the MADT reports.
+        SC_NPROCESSORS_CONF => Some(madt::cpu_count() + madt::online_capable_count()),
+        SC_NPROCESSORS_ONLN => Some(madt::cpu_count()),
+        SC_PHYS_PAGES => Some(PhysicalMemoryManager::frame_stats().0),
+        SC_AVPHYS_PAGES => Some(PhysicalMemoryManager::frame_stats().1),
+        _ => None,
+    }
+}
+
+pub fn sys_sysconf(key: usize) -> Result<isize, abi::Errno> {
+    match sysconf_value(key) {
+        Some(value) => Ok(value as isize),
+        None => Err(abi::Errno::EINVAL),
+    }
+}
+
+fn current_limit(resource: usize) -> Result<RLimit, abi::Errno> {
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &Process = proc_pool.get_ref(&pid.unwrap()).unwrap();
+
+    match proc_ref.proc_data.as_ref().unwrap().limits.get(resource) {
+        Ok(limit) => Ok(limit),
+        Err(_) => Err(abi::Errno::EINVAL),
+    }
+}
+
+pub fn sys_getrlimit(resource: usize, limit_addr: VirtualAddress) -> Result<isize, abi::Errno> {
+    let limit_res = current_limit(resource);
+    if limit_res.is_err() {
+        return Err(limit_res.unwrap_err());
+    }
+
+    abi::copy_to_buffer(limit_res.unwrap(), limit_addr);
+    Ok(0)
+}
+
+pub fn sys_setrlimit(resource: usize, limit_addr: VirtualAddress) -> Result<isize, abi::Errno> {
+    let limit: RLimit = unsafe { *limit_addr.get_ptr() };
+
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+
+    match proc_ref.proc_data.as_mut().unwrap().limits.set(resource, limit) {
+        Ok(()) => Ok(0),
+        Err(RLimitError::NotPermitted) => Err(abi::Errno::EPERM),
+        Err(_) => Err(abi::Errno::EINVAL),
+    }
+}
+
+pub fn sys_cpustat(buffer_addr: VirtualAddress) -> Result<isize, abi::Errno> {
+    abi::copy_to_buffer(idle::cpu_stat(), buffer_addr);
+    Ok(0)
+}
+
 pub fn sys_uname(buffer_addr: VirtualAddress) -> Result<isize, abi::Errno> {
     unsafe {
         let mut buffer_ptr = buffer_addr.get_mut_ptr::<u8>();
@@ -3,9 +3,12 @@ extern crate log;
 use crate::system;
 use crate::system::abi;
 use crate::system::process::{Process, PROCESS_POOL};
-use crate::system::utils::ProcessHeapAllocator;
+use crate::system::utils::{CodeMapper, ProcessHeapAllocator};
 
-use crate::mm::VirtualAddress;
+use crate::mm::paging::PageSize;
+use crate::mm::{Alignment, VirtualAddress};
+
+const MADV_DONTNEED: usize = 4;
 
 pub fn sys_brk(addr: VirtualAddress) -> Result<isize, abi::Errno> {
     let pid = system::current_pid();
@@ -65,3 +68,57 @@ pub fn sys_sbrk(size: usize) -> Result<isize, abi::Errno> {
 
     Ok(current_end_addr.as_u64() as isize)
 }
+
+/// only MADV_DONTNEED does something, the other advice is taken as a hint and ignored.
+/// the range must be page aligned and inside the heap or a demand paged segment.
+pub fn sys_madvise(addr: VirtualAddress, length: usize, advice: usize) -> Result<isize, abi::Errno> {
+    let page_size = PageSize::Page4KiB.size();
+    if !addr.is_aligned_at(page_size) {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    if length == 0 {
+        return Ok(0);
+    }
+
+    let start = addr.as_u64();
+    let end_opt = start.checked_add(Alignment::align_up(length as u64, page_size));
+    if end_opt.is_none() || !abi::is_in_userspace(start) || !abi::is_in_userspace(end_opt.unwrap())
+    {
+        return Err(abi::Errno::EINVAL);
+    }
+    let end = end_opt.unwrap();
+
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+    if proc_ref.proc_data.is_none() {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+    let vmm = proc_ref.pt_root.as_mut().unwrap();
+
+    let in_heap = ProcessHeapAllocator::contains(proc_data, start, end);
+    let in_image = !in_heap && CodeMapper::is_demand_paged_range(vmm, start, end);
+    if !in_heap && !in_image {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    if advice != MADV_DONTNEED {
+        return Ok(0);
+    }
+
+    if in_heap {
+        ProcessHeapAllocator::discard(proc_data, vmm, start, end);
+    } else {
+        CodeMapper::discard_pages(proc_data, vmm, start, end);
+    }
+
+    Ok(0)
+}
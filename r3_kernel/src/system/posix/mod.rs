@@ -2,6 +2,7 @@ pub mod gettime;
 pub mod io;
 pub mod misc;
 pub mod mm;
+pub mod net;
 pub mod sched;
 
 use crate::mm::VirtualAddress;
@@ -26,16 +27,39 @@ const SYSCALL_NO_FORK: usize = 11;
 const SYSCALL_NO_BRK: usize = 12;
 const SYSCALL_NO_SBRK: usize = 13;
 const SYSCALL_NO_IOCTL: usize = 16;
+const SYSCALL_NO_PREAD: usize = 17;
+const SYSCALL_NO_PWRITE: usize = 18;
+const SYSCALL_NO_PIPE: usize = 22;
+const SYSCALL_NO_MADVISE: usize = 28;
+const SYSCALL_NO_DUP: usize = 32;
+const SYSCALL_NO_SOCKET: usize = 41;
 const SYSCALL_NO_YIELD: usize = 42;
 const SYSCALL_NO_TID: usize = 43;
 const SYSCALL_NO_SLEEP: usize = 46;
 const SYSCALL_NO_WAIT: usize = 47;
 const SYSCALL_NO_SHUTDOWN: usize = 48;
 const SYSCALL_NO_REBOOT: usize = 49;
+const SYSCALL_NO_BIND: usize = 50;
+const SYSCALL_NO_SETSOCKOPT: usize = 54;
+const SYSCALL_NO_GETSOCKOPT: usize = 55;
+const SYSCALL_NO_CLONE: usize = 56;
 const SYSCALL_NO_EXECVP: usize = 59;
+const SYSCALL_NO_THREAD_EXIT: usize = 60;
 const SYSCALL_NO_UNAME: usize = 63;
 const SYSCALL_NO_GETRANDOM: usize = 64;
+const SYSCALL_NO_FCNTL: usize = 72;
+const SYSCALL_NO_TRUNCATE: usize = 76;
+const SYSCALL_NO_FTRUNCATE: usize = 77;
+const SYSCALL_NO_GETRLIMIT: usize = 97;
+const SYSCALL_NO_SYSCONF: usize = 99;
+const SYSCALL_NO_CPUSTAT: usize = 100;
+const SYSCALL_NO_IFCONFIG: usize = 101;
+const SYSCALL_NO_GETHOSTBYNAME: usize = 102;
+const SYSCALL_NO_PRCTL: usize = 157;
+const SYSCALL_NO_SETRLIMIT: usize = 160;
 const SYSCALL_NO_GETTIME: usize = 228;
+const SYSCALL_NO_CLOCK_GETRES: usize = 229;
+const SYSCALL_NO_PIPE2: usize = 293;
 
 #[inline]
 pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackFrame) -> isize {
@@ -44,8 +68,10 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
     let arg0 = regs.rdi as usize;
     let arg1 = regs.rsi as usize;
     let arg2 = regs.rdx as usize;
+    let arg3 = regs.r10 as usize;
+    let arg4 = regs.r8 as usize;
 
-    log::debug!(
+    log::trace!(
         "SYSCALL: sys_no={}, arg0=0x{:x}, arg1=0x{:x}, arg2=0x{:x}",
         sys_no,
         arg0,
@@ -67,6 +93,20 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
 
             res
         }
+        SYSCALL_NO_CLOCK_GETRES => {
+            let clock_type = arg0 as i32;
+            // the buffer is optional, like in POSIX.
+            let res = if arg1 == 0 {
+                gettime::sys_clock_getres(clock_type, None)
+            } else if !abi::is_in_userspace(arg1 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                let userptr = abi::UserAddress::from_u64(arg1 as u64);
+                gettime::sys_clock_getres(clock_type, Some(userptr))
+            };
+
+            res
+        }
         SYSCALL_NO_OPEN => {
             let res = if !abi::is_in_userspace(arg0 as u64) {
                 Err(abi::Errno::EFAULT)
@@ -99,9 +139,39 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
             };
             res
         }
+        SYSCALL_NO_PREAD => {
+            let res = if !abi::is_in_userspace(arg1 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                io::sys_pread(arg0, VirtualAddress::from_u64(arg1 as u64), arg2, arg3)
+            };
+            res
+        }
+        SYSCALL_NO_PWRITE => {
+            let res = if !abi::is_in_userspace(arg1 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                io::sys_pwrite(arg0, VirtualAddress::from_u64(arg1 as u64), arg2, arg3)
+            };
+            res
+        }
         SYSCALL_NO_LSEEK => io::sys_lseek(arg0, arg1 as u32, arg2 as u8),
         SYSCALL_NO_CLOSE => io::sys_close(arg0),
+        SYSCALL_NO_DUP => io::sys_dup(arg0),
+        SYSCALL_NO_PIPE | SYSCALL_NO_PIPE2 => {
+            // pipe is pipe2 without flags, the two ints end 8 bytes after the pointer.
+            let flags = if sys_no == SYSCALL_NO_PIPE { 0 } else { arg1 };
+            let res = if !abi::is_in_userspace(arg0 as u64) || !abi::is_in_userspace(arg0 as u64 + 7)
+            {
+                Err(abi::Errno::EFAULT)
+            } else {
+                io::sys_pipe2(VirtualAddress::from_u64(arg0 as u64), flags)
+            };
+            res
+        }
+        SYSCALL_NO_FCNTL => io::sys_fcntl(arg0, arg1, arg2),
         SYSCALL_NO_EXIT => sched::sys_exit(arg0 as i64),
+        SYSCALL_NO_THREAD_EXIT => sched::sys_thread_exit(arg0 as i64),
         SYSCALL_NO_FSTAT => {
             let res = if !abi::is_in_userspace(arg1 as u64) {
                 Err(abi::Errno::EFAULT)
@@ -125,6 +195,20 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
 
             res
         }
+        SYSCALL_NO_TRUNCATE => {
+            let res = if !abi::is_in_userspace(arg0 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                let path_res = abi::copy_cstring(VirtualAddress::from_u64(arg0 as u64), 512);
+                match path_res {
+                    Err(err_code) => Err(err_code),
+                    Ok(path) => io::sys_truncate(&path, arg1 as isize),
+                }
+            };
+
+            res
+        }
+        SYSCALL_NO_FTRUNCATE => io::sys_ftruncate(arg0, arg1 as isize),
         SYSCALL_NO_UNAME => {
             let res = if !abi::is_in_userspace(arg0 as u64) {
                 Err(abi::Errno::EFAULT)
@@ -134,8 +218,42 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
 
             res
         }
+        SYSCALL_NO_SYSCONF => misc::sys_sysconf(arg0),
+        SYSCALL_NO_CPUSTAT => {
+            let res = if !abi::is_in_userspace(arg0 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                misc::sys_cpustat(VirtualAddress::from_u64(arg0 as u64))
+            };
+            res
+        }
+        SYSCALL_NO_GETRLIMIT => {
+            let res = if !abi::is_in_userspace(arg1 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                misc::sys_getrlimit(arg0, VirtualAddress::from_u64(arg1 as u64))
+            };
+            res
+        }
+        SYSCALL_NO_PRCTL => {
+            let res = if !abi::is_in_userspace(arg1 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                sched::sys_prctl(arg0, VirtualAddress::from_u64(arg1 as u64))
+            };
+            res
+        }
+        SYSCALL_NO_SETRLIMIT => {
+            let res = if !abi::is_in_userspace(arg1 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                misc::sys_setrlimit(arg0, VirtualAddress::from_u64(arg1 as u64))
+            };
+            res
+        }
         SYSCALL_NO_BRK => mm::sys_brk(VirtualAddress::from_u64(arg0 as u64)),
         SYSCALL_NO_SBRK => mm::sys_sbrk(arg0),
+        SYSCALL_NO_MADVISE => mm::sys_madvise(VirtualAddress::from_u64(arg0 as u64), arg1, arg2),
         SYSCALL_NO_IOCTL => io::sys_ioctl(arg0, arg1, arg2),
         SYSCALL_NO_YIELD => sched::sys_yield(),
         SYSCALL_NO_SLEEP => {
@@ -152,6 +270,65 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
         SYSCALL_NO_PPID => sched::sys_ppid(),
         SYSCALL_NO_TID => sched::sys_tid(),
         SYSCALL_NO_FORK => sched::sys_fork(&regs, &frame),
+        SYSCALL_NO_CLONE => {
+            let res = if !abi::is_in_userspace(arg0 as u64) || !abi::is_in_userspace(arg2 as u64)
+            {
+                Err(abi::Errno::EFAULT)
+            } else {
+                sched::sys_thread_create(
+                    VirtualAddress::from_u64(arg0 as u64),
+                    arg1 as u64,
+                    VirtualAddress::from_u64(arg2 as u64),
+                )
+            };
+
+            res
+        }
+        SYSCALL_NO_SOCKET => net::sys_socket(arg0, arg1, arg2),
+        SYSCALL_NO_BIND => net::sys_bind(arg0, VirtualAddress::from_u64(arg1 as u64), arg2),
+        SYSCALL_NO_SETSOCKOPT => {
+            let res = if !abi::is_in_userspace(arg3 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                net::sys_setsockopt(arg0, arg1, arg2, VirtualAddress::from_u64(arg3 as u64), arg4)
+            };
+            res
+        }
+        SYSCALL_NO_GETSOCKOPT => {
+            let res = if !abi::is_in_userspace(arg3 as u64) || !abi::is_in_userspace(arg4 as u64)
+            {
+                Err(abi::Errno::EFAULT)
+            } else {
+                net::sys_getsockopt(
+                    arg0,
+                    arg1,
+                    arg2,
+                    VirtualAddress::from_u64(arg3 as u64),
+                    VirtualAddress::from_u64(arg4 as u64),
+                )
+            };
+            res
+        }
+        SYSCALL_NO_IFCONFIG => {
+            let res = if !abi::is_in_userspace(arg0 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                net::sys_ifconfig(VirtualAddress::from_u64(arg0 as u64))
+            };
+            res
+        }
+        SYSCALL_NO_GETHOSTBYNAME => {
+            let res = if !abi::is_in_userspace(arg0 as u64) || !abi::is_in_userspace(arg1 as u64) {
+                Err(abi::Errno::EFAULT)
+            } else {
+                let name_res = abi::copy_cstring(VirtualAddress::from_u64(arg0 as u64), 256);
+                match name_res {
+                    Err(err_code) => Err(err_code),
+                    Ok(name) => net::sys_gethostbyname(&name, VirtualAddress::from_u64(arg1 as u64)),
+                }
+            };
+            res
+        }
         SYSCALL_NO_SHUTDOWN => misc::sys_shutdown(),
         SYSCALL_NO_REBOOT => misc::sys_reboot(),
         SYSCALL_NO_EXECVP => {
@@ -160,7 +337,7 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
             } else {
                 let path_res = abi::copy_cstring(VirtualAddress::from_u64(arg0 as u64), 512);
                 let execvp_res = match path_res {
-                    Ok(path) => sched::sys_execvp(&path, frame),
+                    Ok(path) => sched::sys_execvp(&path, regs, frame),
                     Err(err_code) => Err(err_code),
                 };
 
@@ -188,7 +365,7 @@ pub fn dispatch_syscall(regs: &mut SyscallRegsState, frame: &mut InterruptStackF
     };
 
     if syscall_result.is_err() {
-        log::debug!("System Call {} cought error - {:?}", sys_no, syscall_result);
+        log::trace!("System Call {} cought error - {:?}", sys_no, syscall_result);
         return syscall_result.unwrap_err() as isize;
     }
 
@@ -0,0 +1,294 @@
+use crate::mm::VirtualAddress;
+use crate::system;
+use crate::system::abi;
+use crate::system::filesystem::FileDescriptor;
+use crate::system::net::dns::{self, DNSError};
+use crate::system::net::iface::{self, IfaceConfig, IfaceConfigError};
+use crate::system::net::napi;
+use crate::system::net::types::{
+    SocketAddr, SocketError, SocketFn, SocketOption, TransportSocketTypes, TransportType,
+};
+use crate::system::net::udp::UDPSocketDescriptor;
+use crate::system::process::{Process, PROCESS_POOL};
+use crate::system::utils::ProcessFDPool;
+
+use core::{mem, ptr};
+
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+/// size of the option values, all the supported options are C ints.
+const OPTION_VALUE_SIZE: usize = mem::size_of::<u32>();
+
+/// size of sockaddr_in, the addresses passed in must be at least this long.
+const SOCKADDR_SIZE: usize = 16;
+/// the protocol can be left 0, UDP is the only one for datagram sockets.
+const IPPROTO_UDP: usize = 17;
+
+/// ifconfig flags: bring the interface up, replace the address and gateway.
+const IFCONFIG_UP: u32 = 1 << 0;
+const IFCONFIG_SET_ADDRESS: u32 = 1 << 1;
+/// only the NAPI threshold is changed, the rest of the request is ignored.
+const IFCONFIG_SET_NAPI_THRESHOLD: u32 = 1 << 2;
+
+/// layout of the ifconfig request, must be kept in sync with the copy in userspace-rs.
+/// a zero gateway means no default route.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct IfconfigRequest {
+    pub address: [u8; 4],
+    pub gateway: [u8; 4],
+    pub prefix: u32,
+    pub flags: u32,
+    /// RX frames per window that switch the device to polling, 0 turns it off.
+    pub napi_threshold: u32,
+}
+
+#[inline]
+fn socket_errno(err: SocketError) -> abi::Errno {
+    match err {
+        SocketError::UnsupportedOption => abi::Errno::ENOPROTOOPT,
+        SocketError::InvalidOption => abi::Errno::EINVAL,
+        SocketError::InvalidAddress => abi::Errno::EINVAL,
+        SocketError::NotBound => abi::Errno::EINVAL,
+        SocketError::UnsupportedFamily => abi::Errno::EAFNOSUPPORT,
+        SocketError::Timeout => abi::Errno::ETIMEDOUT,
+        SocketError::IsConnected => abi::Errno::EISCONN,
+        SocketError::NotConnected => abi::Errno::ENOTCONN,
+        SocketError::PortAlreadyInUse => abi::Errno::EADDRINUSE,
+        SocketError::BindError => abi::Errno::EINVAL,
+        _ => abi::Errno::EIO,
+    }
+}
+
+/// returns the socket behind the file-descriptor.
+#[inline]
+fn get_socket(fd: &FileDescriptor) -> Option<UDPSocketDescriptor> {
+    match fd {
+        FileDescriptor::SocketNode(sockfd) => Some(sockfd.clone()),
+        _ => None,
+    }
+}
+
+/// copies the socket address from the userspace, `addr_len` is the size of the buffer.
+fn read_socket_addr(addr: VirtualAddress, addr_len: usize) -> Result<SocketAddr, abi::Errno> {
+    if addr_len < SOCKADDR_SIZE {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let end = addr.as_u64().saturating_add(addr_len as u64);
+    if !abi::is_in_userspace(addr.as_u64()) || !abi::is_in_userspace(end) {
+        return Err(abi::Errno::EFAULT);
+    }
+
+    SocketAddr::from_memory_view(addr).map_err(socket_errno)
+}
+
+/// returns the socket behind `fd_index` of the current process.
+fn current_socket(fd_index: usize) -> Result<UDPSocketDescriptor, abi::Errno> {
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+    let fdref_opt = ProcessFDPool::get_mut(proc_data, fd_index);
+    if fdref_opt.is_none() {
+        return Err(abi::Errno::EBADF);
+    }
+
+    let file = fdref_opt.unwrap().file.lock();
+    match get_socket(&file) {
+        Some(sockfd) => Ok(sockfd),
+        None => Err(abi::Errno::ENOTSOCK),
+    }
+}
+
+/// only UDP over IPv4 is supported as of now.
+pub fn sys_socket(domain: usize, sock_type: usize, protocol: usize) -> Result<isize, abi::Errno> {
+    if domain != TransportType::AFInet as usize {
+        return Err(abi::Errno::EAFNOSUPPORT);
+    }
+
+    if sock_type != TransportSocketTypes::SockDgram as usize
+        || (protocol != 0 && protocol != IPPROTO_UDP)
+    {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let pid = system::current_pid();
+    if pid.is_none() {
+        log::error!("PID is null.");
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let mut proc_pool = PROCESS_POOL.lock();
+    let proc_ref: &mut Process = proc_pool.get_mut_ref(&pid.unwrap()).unwrap();
+    let proc_data = proc_ref.proc_data.as_mut().unwrap();
+
+    let sockfd = UDPSocketDescriptor::new();
+    let fd_res = ProcessFDPool::put(proc_data, FileDescriptor::SocketNode(sockfd.clone()), false);
+    if fd_res.is_err() {
+        // no descriptor will ever close it, so it is closed here.
+        let _ = sockfd.close();
+        return Err(abi::Errno::EMFILE);
+    }
+
+    Ok(fd_res.unwrap() as isize)
+}
+
+/// port 0 binds to any free port, the address is not checked against the interface.
+pub fn sys_bind(
+    fd_index: usize,
+    addr: VirtualAddress,
+    addr_len: usize,
+) -> Result<isize, abi::Errno> {
+    let sock_addr = read_socket_addr(addr, addr_len);
+    if sock_addr.is_err() {
+        return Err(sock_addr.unwrap_err());
+    }
+
+    let sockfd = current_socket(fd_index);
+    if sockfd.is_err() {
+        return Err(sockfd.unwrap_err());
+    }
+
+    let bind_res = sockfd.unwrap().socket.lock().bind(sock_addr.unwrap());
+    if bind_res.is_err() {
+        return Err(socket_errno(bind_res.unwrap_err()));
+    }
+
+    Ok(0)
+}
+
+pub fn sys_setsockopt(
+    fd_index: usize,
+    level: usize,
+    name: usize,
+    value_addr: VirtualAddress,
+    value_len: usize,
+) -> Result<isize, abi::Errno> {
+    if value_len < OPTION_VALUE_SIZE {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let sockfd = current_socket(fd_index);
+    if sockfd.is_err() {
+        return Err(sockfd.unwrap_err());
+    }
+
+    let value: u32 = unsafe { ptr::read_unaligned(value_addr.get_ptr()) };
+    let option_res = SocketOption::from_values(level, name, value);
+    if option_res.is_err() {
+        return Err(socket_errno(option_res.unwrap_err()));
+    }
+
+    let set_res = sockfd.unwrap().socket.lock().set_option(option_res.unwrap());
+    if set_res.is_err() {
+        return Err(socket_errno(set_res.unwrap_err()));
+    }
+
+    Ok(0)
+}
+
+pub fn sys_getsockopt(
+    fd_index: usize,
+    level: usize,
+    name: usize,
+    value_addr: VirtualAddress,
+    len_addr: VirtualAddress,
+) -> Result<isize, abi::Errno> {
+    let value_len: u32 = unsafe { ptr::read_unaligned(len_addr.get_ptr()) };
+    if (value_len as usize) < OPTION_VALUE_SIZE {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let sockfd = current_socket(fd_index);
+    if sockfd.is_err() {
+        return Err(sockfd.unwrap_err());
+    }
+
+    let option_res = sockfd.unwrap().socket.lock().get_option(level, name);
+    if option_res.is_err() {
+        return Err(socket_errno(option_res.unwrap_err()));
+    }
+
+    unsafe {
+        ptr::write_unaligned(value_addr.get_mut_ptr(), option_res.unwrap().value());
+        ptr::write_unaligned(len_addr.get_mut_ptr(), OPTION_VALUE_SIZE as u32);
+    }
+
+    Ok(0)
+}
+
+/// only the init program can reconfigure the interface.
+pub fn sys_ifconfig(request_addr: VirtualAddress) -> Result<isize, abi::Errno> {
+    let pid = system::current_pid();
+    if pid.is_none() || !system::is_init_program(&pid.unwrap()) {
+        return Err(abi::Errno::EPERM);
+    }
+
+    // the request comes from user memory, it can be at any address.
+    let request: IfconfigRequest = unsafe { ptr::read_unaligned(request_addr.get_ptr()) };
+    if request.flags & IFCONFIG_SET_NAPI_THRESHOLD != 0 {
+        napi::set_threshold(request.napi_threshold as usize);
+        return Ok(0);
+    }
+
+    let up = request.flags & IFCONFIG_UP != 0;
+
+    if request.flags & IFCONFIG_SET_ADDRESS == 0 {
+        iface::set_interface_up(up);
+        return Ok(0);
+    }
+
+    // reject it here, the cidr can't even be built with a bad prefix.
+    if request.prefix == 0 || request.prefix > 32 {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let gateway = Ipv4Address::from_bytes(&request.gateway);
+    let config = IfaceConfig {
+        cidr: Ipv4Cidr::new(
+            Ipv4Address::from_bytes(&request.address),
+            request.prefix as u8,
+        ),
+        gateway: if gateway.is_unspecified() {
+            None
+        } else {
+            Some(gateway)
+        },
+        up,
+    };
+
+    match iface::configure_static_ip(&config) {
+        Ok(()) => Ok(0),
+        Err(IfaceConfigError::NoPhysicalDevice) => Err(abi::Errno::ENODEV),
+        Err(err) => {
+            log::debug!("rejected interface config {:?}: {:?}", config, err);
+            Err(abi::Errno::EINVAL)
+        }
+    }
+}
+
+/// resolves the name to an IPv4 address, the 4 bytes are written to `address_addr`.
+/// no lock is held while waiting for the answer, the process pool is not needed here.
+pub fn sys_gethostbyname(name: &str, address_addr: VirtualAddress) -> Result<isize, abi::Errno> {
+    match dns::resolve(name) {
+        Ok(address) => {
+            abi::copy_to_buffer(address, address_addr);
+            Ok(0)
+        }
+        Err(DNSError::InvalidName) => Err(abi::Errno::EINVAL),
+        Err(DNSError::NameNotFound) => Err(abi::Errno::ENOENT),
+        Err(DNSError::Timeout) => Err(abi::Errno::ETIMEDOUT),
+        Err(DNSError::NoServer) => Err(abi::Errno::ENETUNREACH),
+        Err(err) => {
+            log::debug!("failed to resolve {}, err={:?}", name, err);
+            Err(abi::Errno::EIO)
+        }
+    }
+}
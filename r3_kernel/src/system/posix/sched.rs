@@ -1,22 +1,38 @@
 extern crate alloc;
 extern crate log;
 
+use crate::mm::paging::PageEntryFlags;
 use crate::mm::VirtualAddress;
+use crate::system;
 use crate::system::abi;
 use crate::system::process::{Process, PROCESS_POOL};
 use crate::system::tasking::schedule_yield;
 use crate::system::tasking::{Sched, ThreadSuspendType, ThreadWakeupType, SCHEDULER};
-use crate::system::thread::{ContextType, Thread};
+use crate::system::thread::{self, ContextType, Thread};
 use crate::system::timer::PosixTimeval;
 use crate::system::timer::{pause_events, resume_events};
+use crate::system::utils::{CodeMapper, ProcessStackManager};
 
+use crate::cpu::fpu::FPUState;
 use crate::cpu::interrupts::InterruptStackFrame;
 use crate::cpu::state::{CPURegistersState, SyscallRegsState};
 use crate::system::process::PID;
 
-use alloc::format;
+use crate::system::loader::{self, ExecFormat, LoadError};
+
+use alloc::{format, string::String, vec, vec::Vec};
 
 pub fn sys_yield() -> Result<isize, abi::Errno> {
+    {
+        let mut sched_lock = SCHEDULER.lock();
+        // nothing else can run, the thread continues without giving up
+        // the rest of it's quantum.
+        if !sched_lock.has_other_runnable() {
+            return Ok(1);
+        }
+        sched_lock.yield_current();
+    }
+
     schedule_yield();
     Ok(1)
 }
@@ -38,34 +54,68 @@ pub fn sys_sleep_us(timeval_addr: VirtualAddress) -> Result<isize, abi::Errno> {
 }
 
 pub fn sys_pid() -> Result<isize, abi::Errno> {
-    let current_pid = SCHEDULER.lock().current_pid().unwrap();
+    let current_pid = system::current_pid().unwrap();
     Ok(current_pid.as_u64() as isize)
 }
 
 pub fn sys_ppid() -> Result<isize, abi::Errno> {
-    let current_pid = SCHEDULER.lock().current_pid().unwrap();
-    let ppid = PROCESS_POOL
-        .lock()
-        .get_mut_ref(&current_pid)
-        .unwrap()
-        .ppid
-        .as_u64();
+    let ppid = system::current_ppid().unwrap().as_u64();
     Ok(ppid as isize)
 }
 
 pub fn sys_tid() -> Result<isize, abi::Errno> {
-    let current_tid = SCHEDULER.lock().current_tid().unwrap().as_u64();
+    let current_tid = system::current_tid().unwrap().as_u64();
     Ok(current_tid as isize)
 }
 
+/// checks if the address space of the parent can be cloned safely.
+fn validate_fork(parent_pid: &PID, rsp: u64) -> Result<(), abi::Errno> {
+    let mut proc_lock = PROCESS_POOL.lock();
+    let parent_opt = proc_lock.get_ref(parent_pid);
+    if parent_opt.is_none() {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let parent = parent_opt.unwrap();
+    if parent.proc_data.is_none() {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    // POSIX gives the child only the calling thread, which is what it gets here.
+    // the stacks of the other threads are in the user's memory, nothing runs on
+    // them in the child. only the main stack is cloned, so the caller has to be
+    // on it, a fork from another thread fails below.
+    if !ProcessStackManager::is_on_cloned_stack(parent.proc_data.as_ref().unwrap(), rsp) {
+        log::warn!(
+            "fork called from a stack that would not be cloned, rsp={:x}",
+            rsp
+        );
+        return Err(abi::Errno::EINVAL);
+    }
+
+    Ok(())
+}
+
 pub fn sys_fork(regs: &SyscallRegsState, frame: &InterruptStackFrame) -> Result<isize, abi::Errno> {
+    let parent_pid = SCHEDULER.lock().current_pid().unwrap();
+
+    let validate_res = validate_fork(&parent_pid, frame.stack_pointer);
+    if validate_res.is_err() {
+        return Err(validate_res.unwrap_err());
+    }
+
     // disable interrupts
     pause_events();
 
-    let parent_pid = SCHEDULER.lock().current_pid().unwrap();
-
     // spawn a new process, which is the child
-    let child = Process::create_from_parent(&parent_pid);
+    let child_res = Process::create_from_parent(&parent_pid);
+    if child_res.is_err() {
+        resume_events();
+        log::warn!("fork failed: {:?}", child_res.unwrap_err());
+        return Err(abi::Errno::EAGAIN);
+    }
+
+    let child = child_res.unwrap();
     let child_pid = child.pid.clone();
     // a new process has been created, which has the same data as that of the parent.
     // register this process
@@ -95,12 +145,21 @@ pub fn sys_fork(regs: &SyscallRegsState, frame: &InterruptStackFrame) -> Result<
     state.cs = frame.code_segment;
 
     // create a thread from this state:
-    let thread = Thread::new_from_parent(
+    let thread_res = Thread::new_from_parent(
         format!("th_{}_{}", parent_pid.as_u64(), child_pid.as_u64()),
         child_pid.clone(),
         &ContextType::SavedContext(state),
-    )
-    .expect("Failed to create child thread");
+    );
+
+    if thread_res.is_err() {
+        // the child never ran, drop it again.
+        log::warn!("fork failed: {:?}", thread_res.unwrap_err());
+        let _ = PROCESS_POOL.lock().remove_process(&child_pid, 0);
+        resume_events();
+        return Err(abi::Errno::EAGAIN);
+    }
+
+    let thread = thread_res.unwrap();
     resume_events();
 
     // add this thread to the queue:
@@ -110,20 +169,280 @@ pub fn sys_fork(regs: &SyscallRegsState, frame: &InterruptStackFrame) -> Result<
     Ok(child_pid.as_u64() as isize)
 }
 
-pub fn sys_execvp(path: &str, ist: &mut InterruptStackFrame) -> Result<isize, abi::Errno> {
+pub fn sys_thread_create(
+    entry: VirtualAddress,
+    arg: u64,
+    stack_end: VirtualAddress,
+) -> Result<isize, abi::Errno> {
+    let pid = SCHEDULER.lock().current_pid().unwrap();
+
+    // the stack grows down, so the stack end must be aligned
+    // and the page just below it must be mapped and writable by the user.
+    if stack_end.as_u64() < 8 || !stack_end.is_aligned_at(16) {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let stack_top = VirtualAddress::from_u64(stack_end.as_u64() - 8);
+    if !abi::is_in_userspace(stack_top.as_u64()) || !abi::is_in_userspace(entry.as_u64()) {
+        return Err(abi::Errno::EFAULT);
+    }
+
+    let required_flags =
+        PageEntryFlags::PRESENT | PageEntryFlags::READ_WRITE | PageEntryFlags::USERSPACE;
+
+    let mut proc_lock = PROCESS_POOL.lock();
+    let proc_opt = proc_lock.get_mut_ref(&pid);
+    if proc_opt.is_none() {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let proc_ref = proc_opt.unwrap();
+    if proc_ref.pt_root.is_none() {
+        return Err(abi::Errno::EINVAL);
+    }
+
+    let vmm = proc_ref.pt_root.as_ref().unwrap();
+    // a stack in a demand paged segment (BSS) may not have been touched yet.
+    CodeMapper::map_demand_page(vmm, &stack_top);
+    let stack_flags = vmm.get_page_flags(&stack_top);
+    if stack_flags.is_none() || !stack_flags.unwrap().contains(required_flags) {
+        return Err(abi::Errno::EFAULT);
+    }
+
+    if !CodeMapper::is_user_executable(vmm, &entry) {
+        return Err(abi::Errno::EFAULT);
+    }
+
+    // the return address of the entry, there is no user code to return to, so
+    // returning faults at 0 and threads must end with sys_thread_exit.
+    abi::copy_to_buffer(0u64, stack_top);
+
+    let n_threads = proc_ref.threads.len();
+    drop(proc_lock);
+
+    pause_events();
+    let thread_res = Thread::new_user_thread(
+        pid.clone(),
+        format!("th_{}_{}", pid.as_u64(), n_threads),
+        entry,
+        arg,
+        stack_end,
+    );
+
+    if thread_res.is_err() {
+        resume_events();
+        log::error!("failed to create thread: {:?}", thread_res.unwrap_err());
+        return Err(abi::Errno::EAGAIN);
+    }
+
+    let thread = thread_res.unwrap();
+    let tid = thread.thread_id;
+    SCHEDULER.lock().add_new_thread(thread);
+    resume_events();
+
+    Ok(tid.as_u64() as isize)
+}
+
+/// ends the calling thread, the last thread of a process takes the process with it.
+pub fn sys_thread_exit(code: i64) -> Result<isize, abi::Errno> {
     pause_events();
     let pid = SCHEDULER.lock().current_pid().unwrap();
-    let code_start = PROCESS_POOL.lock().reset_process(&pid, path);
+    let n_threads = PROCESS_POOL
+        .lock()
+        .get_ref(&pid)
+        .map(|proc| proc.threads.len())
+        .unwrap_or(0);
+
+    if n_threads <= 1 {
+        resume_events();
+        return sys_exit(code);
+    }
+
+    let exited_opt = SCHEDULER.lock().exit_current_thread();
+    if let Some(exited) = exited_opt {
+        log::debug!(
+            "Thread {} exited with code={}",
+            exited.thread_id.as_u64(),
+            code
+        );
+
+        let mut proc_lock = PROCESS_POOL.lock();
+        if let Some(proc) = proc_lock.get_mut_ref(&pid) {
+            let _ = proc.remove_thread(exited.thread_id);
+            // the main thread's stack stays, it goes away with the process.
+            if let (Some(stack), Some(proc_data)) =
+                (exited.syscall_stack_start, proc.proc_data.as_mut())
+            {
+                let _ = ProcessStackManager::free_syscall_stack(proc_data, stack);
+            }
+        }
+        drop(proc_lock);
+
+        thread::release_tid(exited.thread_id);
+    }
+
+    resume_events();
+    schedule_yield();
+
+    // you should never come here!
+    Ok(1 as isize)
+}
+
+fn load_error_to_errno(err: LoadError) -> abi::Errno {
+    match err {
+        LoadError::NotFound => abi::Errno::ENOENT,
+        LoadError::InvalidFormat => abi::Errno::ENOEXEC,
+        LoadError::FileReadError => abi::Errno::EIO,
+    }
+}
+
+/// finds the binary to load and the arguments it gets, nothing in the
+/// process is touched here, so errors can still be returned to the caller.
+fn prepare_exec(name: &str) -> Result<(String, Vec<String>), abi::Errno> {
+    let path_res = loader::resolve_executable(name);
+    if path_res.is_err() {
+        return Err(load_error_to_errno(path_res.unwrap_err()));
+    }
+
+    let path = path_res.unwrap();
+    let format_res = loader::probe_executable(&path);
+    if format_res.is_err() {
+        return Err(load_error_to_errno(format_res.unwrap_err()));
+    }
+
+    match format_res.unwrap() {
+        ExecFormat::ELF => Ok((path.clone(), vec![path])),
+        ExecFormat::Script { interpreter, arg } => {
+            // the interpreter has to be a binary, scripts can't be nested.
+            let interp_res = loader::probe_executable(&interpreter);
+            match interp_res {
+                Ok(ExecFormat::ELF) => {}
+                Ok(ExecFormat::Script { .. }) => return Err(abi::Errno::ENOEXEC),
+                Err(err) => return Err(load_error_to_errno(err)),
+            }
+
+            let mut args = vec![interpreter.clone()];
+            if let Some(arg) = arg {
+                args.push(arg);
+            }
+            args.push(path);
+
+            Ok((interpreter, args))
+        }
+    }
+}
+
+pub fn sys_execvp(
+    name: &str,
+    regs: &mut SyscallRegsState,
+    ist: &mut InterruptStackFrame,
+) -> Result<isize, abi::Errno> {
+    let prepare_res = prepare_exec(name);
+    if prepare_res.is_err() {
+        return Err(prepare_res.unwrap_err());
+    }
+
+    let (path, args) = prepare_res.unwrap();
+
+    pause_events();
+    let pid = SCHEDULER.lock().current_pid().unwrap();
+    // a script keeps it's own name, not the interpreter's.
+    let basename = name.rsplit('/').next().unwrap_or(name);
+    let code_start = PROCESS_POOL
+        .lock()
+        .reset_process(&pid, &path, String::from(basename));
     // reset the thread's internal stack to point to the start from end
     let stack_addr = SCHEDULER.lock().reset_current_thread_stack();
+    // the new program starts with clean FPU registers, they are saved on the next switch.
+    FPUState::new().restore();
+    let (stack_pointer, argv) = ProcessStackManager::push_arguments(stack_addr, &args);
 
     // set the interrupt stack frame registers
-    ist.stack_pointer = stack_addr.as_u64();
+    ist.stack_pointer = stack_pointer.as_u64();
     ist.instruction_pointer = code_start.as_u64();
+
+    // also passed in registers, so `_start(argc, argv)` works without asm.
+    regs.rdi = args.len() as u64;
+    regs.rsi = argv.as_u64();
     resume_events();
     Ok(0)
 }
 
+/// names of the current thread and process.
+pub const PR_SET_NAME: usize = 15;
+pub const PR_GET_NAME: usize = 16;
+pub const PR_SET_PROCESS_NAME: usize = 1000;
+pub const PR_GET_PROCESS_NAME: usize = 1001;
+
+/// size of the name buffers given to prctl, the NUL included.
+pub const TASK_NAME_LEN: usize = 16;
+
+/// longest prefix of the name that fits a prctl buffer with the NUL.
+fn fit_name(name: &str) -> &str {
+    let mut end = core::cmp::min(name.len(), TASK_NAME_LEN - 1);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[0..end]
+}
+
+pub fn sys_prctl(option: usize, name_addr: VirtualAddress) -> Result<isize, abi::Errno> {
+    match option {
+        PR_SET_NAME | PR_SET_PROCESS_NAME => {
+            let name_res = abi::copy_cstring(name_addr, TASK_NAME_LEN);
+            if name_res.is_err() {
+                return Err(name_res.unwrap_err());
+            }
+
+            let name = name_res.unwrap();
+            if name.is_empty() {
+                return Err(abi::Errno::EINVAL);
+            }
+
+            if option == PR_SET_NAME {
+                SCHEDULER.lock().set_current_thread_name(name);
+                return Ok(0);
+            }
+
+            let pid = SCHEDULER.lock().current_pid().unwrap();
+            let mut proc_lock = PROCESS_POOL.lock();
+            let proc_opt = proc_lock.get_mut_ref(&pid);
+            if proc_opt.is_none() {
+                return Err(abi::Errno::EINVAL);
+            }
+            proc_opt.unwrap().name = name;
+            Ok(0)
+        }
+        PR_GET_NAME | PR_GET_PROCESS_NAME => {
+            let name_opt = if option == PR_GET_NAME {
+                SCHEDULER.lock().current_thread_name()
+            } else {
+                let pid = SCHEDULER.lock().current_pid().unwrap();
+                PROCESS_POOL
+                    .lock()
+                    .get_ref(&pid)
+                    .map(|process| process.name.clone())
+            };
+
+            if name_opt.is_none() {
+                return Err(abi::Errno::EINVAL);
+            }
+
+            let name = name_opt.unwrap();
+            let copy_res = abi::copy_pad_buffer(
+                fit_name(&name).as_bytes(),
+                name_addr.get_mut_ptr::<u8>(),
+                TASK_NAME_LEN,
+            );
+            if copy_res.is_err() {
+                return Err(copy_res.unwrap_err());
+            }
+            Ok(0)
+        }
+        _ => Err(abi::Errno::EINVAL),
+    }
+}
+
 pub fn sys_exit(code: i64) -> Result<isize, abi::Errno> {
     pause_events();
     let pid = SCHEDULER.lock().current_pid().unwrap();
@@ -3,12 +3,14 @@ extern crate log;
 extern crate spin;
 
 use crate::cpu::mmu;
+use crate::drivers::tty;
 use crate::mm::paging::{KernelVirtualMemoryManager, VirtualMemoryManager};
 use crate::mm::{PhysicalAddress, VirtualAddress};
+use crate::system::tasking::current;
 use crate::system::thread::ThreadID;
 use crate::system::utils::{
     create_cloned_layout, create_default_descriptors, create_process_layout, reset_layout,
-    ProcessData,
+    IDAllocator, ProcessData,
 };
 
 use lazy_static::lazy_static;
@@ -18,22 +20,50 @@ use core::mem;
 use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 
-static CURRENT_PID: AtomicU64 = AtomicU64::new(0);
+/// max number of processes alive at the same time, kernel processes included.
+pub const MAX_PROCESSES: usize = 64;
 
-pub fn new_pid() -> PID {
-    let current = CURRENT_PID.load(Ordering::SeqCst);
-    if current + 1 == u64::max_value() {
-        panic!("Could not create process, Out of PIDs");
-    }
+/// the first process, created at boot by the kernel. orphans are handed over
+/// to it until the init program runs.
+pub const INIT_PID: u64 = 0;
+
+/// pid of the init program, `INIT_PID` until it is started and after it exits.
+static INIT_PROGRAM_PID: AtomicU64 = AtomicU64::new(INIT_PID);
+
+lazy_static! {
+    static ref PID_ALLOCATOR: Mutex<IDAllocator> = Mutex::new(IDAllocator::new(MAX_PROCESSES));
+}
+
+/// returns None once `MAX_PROCESSES` are alive, pids of removed processes are reused.
+pub fn new_pid() -> Option<PID> {
+    PID_ALLOCATOR.lock().alloc().map(|pid| PID(pid))
+}
+
+pub fn release_pid(pid: &PID) {
+    PID_ALLOCATOR.lock().release(pid.as_u64());
+}
+
+pub fn set_init_program(pid: &PID) {
+    INIT_PROGRAM_PID.store(pid.as_u64(), Ordering::SeqCst);
+}
+
+/// the process orphans are reparented to.
+#[inline]
+pub fn init_pid() -> PID {
+    PID(INIT_PROGRAM_PID.load(Ordering::SeqCst))
+}
 
-    CURRENT_PID.store(current + 1, Ordering::SeqCst);
-    PID(current)
+/// true from the time the pid is handed out until it's process is removed, this
+/// doesn't need the process pool, so it can be used with the pool locked.
+pub fn pid_exists(pid: &PID) -> bool {
+    PID_ALLOCATOR.lock().is_in_use(pid.as_u64())
 }
 
 #[derive(Debug)]
 pub enum ProcessError {
     UnknownThreadID,
     UnknwonPID,
+    TooManyProcesses,
 }
 
 #[derive(Clone, Debug)]
@@ -78,14 +108,20 @@ pub struct Process {
     /// root page table, if there are any
     pub pt_root: Option<Box<VirtualMemoryManager>>,
     /// process data, this will be null for kernel process
-    pub proc_data: Option<ProcessData>
+    pub proc_data: Option<ProcessData>,
 }
 
 impl Process {
     #[inline]
-    pub fn create_from_parent(ppid: &PID) -> Self {
+    pub fn create_from_parent(ppid: &PID) -> Result<Self, ProcessError> {
+        // take the pid first, nothing needs to be undone if we are out of them.
+        let pid_opt = new_pid();
+        if pid_opt.is_none() {
+            return Err(ProcessError::TooManyProcesses);
+        }
+
+        let pid = pid_opt.unwrap();
         let (mut vmm, frame_addr) = KernelVirtualMemoryManager::new_vmm();
-        let pid = new_pid();
 
         let mut parent_lock = PROCESS_POOL.lock();
         let parent_opt = parent_lock.get_mut_ref(&ppid);
@@ -102,7 +138,7 @@ impl Process {
             &mut vmm,
         ));
 
-        Process {
+        Ok(Process {
             pid,
             ppid: parent.pid.clone(), // as of now
             state: ProcessState::NoThreads,
@@ -112,13 +148,18 @@ impl Process {
             name: parent.name.clone(),
             pt_root: Some(Box::new(vmm)),
             proc_data,
-        }
+        })
     }
 
     #[inline]
-    pub fn create_user_process(name: String, path: &str) -> Self {
+    pub fn create_user_process(name: String, path: &str) -> Result<Self, ProcessError> {
+        let pid_opt = new_pid();
+        if pid_opt.is_none() {
+            return Err(ProcessError::TooManyProcesses);
+        }
+
+        let pid = pid_opt.unwrap();
         let (mut vmm, frame_addr) = KernelVirtualMemoryManager::new_vmm();
-        let pid = new_pid();
 
         let proc_data = if path.len() > 0 {
             let mut p_data = create_process_layout(path, &mut vmm);
@@ -128,9 +169,9 @@ impl Process {
             None
         };
 
-        Process {
+        Ok(Process {
             pid,
-            ppid: PID(0), // as of now
+            ppid: PID(INIT_PID), // as of now
             state: ProcessState::NoThreads,
             cr3: frame_addr.as_u64(),
             threads: Vec::new(),
@@ -138,10 +179,10 @@ impl Process {
             name,
             pt_root: Some(Box::new(vmm)),
             proc_data,
-        }
+        })
     }
 
-    pub fn empty(name: String, user: bool, path: &str) -> Self {
+    pub fn empty(name: String, user: bool, path: &str) -> Result<Self, ProcessError> {
         if user {
             // create and return the user process:
             return Self::create_user_process(name, &path);
@@ -149,11 +190,14 @@ impl Process {
 
         // return the process:
         let kernel_cr3 = PhysicalAddress::from_u64(mmu::read_cr3());
-        let pid = new_pid();
+        let pid_opt = new_pid();
+        if pid_opt.is_none() {
+            return Err(ProcessError::TooManyProcesses);
+        }
 
-        Process {
-            pid,
-            ppid: PID(0), // as of now,
+        Ok(Process {
+            pid: pid_opt.unwrap(),
+            ppid: PID(INIT_PID), // as of now,
             state: ProcessState::NoThreads,
             cr3: kernel_cr3.as_u64(),
             threads: Vec::new(),
@@ -161,7 +205,7 @@ impl Process {
             name,
             pt_root: None,
             proc_data: None,
-        }
+        })
     }
 
     #[inline]
@@ -248,6 +292,8 @@ pub struct ProcessPoolManager {
     pub user_proc_count: usize,
     pub kernel_proc_count: usize,
     pub pool_map: BTreeMap<u64, Process>,
+    /// exit codes of the processes that are gone, kept until the pid is reused.
+    exit_codes: BTreeMap<u64, i64>,
 }
 
 impl ProcessPoolManager {
@@ -257,6 +303,7 @@ impl ProcessPoolManager {
             user_proc_count: 0,
             kernel_proc_count: 0,
             pool_map: BTreeMap::new(),
+            exit_codes: BTreeMap::new(),
         }
     }
 
@@ -292,10 +339,28 @@ impl ProcessPoolManager {
         // remove the process:
         let mut proc = res.unwrap();
         proc.exit(code);
+        self.exit_codes.insert(pid.as_u64(), code);
+        tty::release_foreground(pid);
+        current::clear_parent(pid);
+
+        // init can't be killed, but it can exit.
+        if init_pid().as_u64() == pid.as_u64() {
+            INIT_PROGRAM_PID.store(INIT_PID, Ordering::SeqCst);
+        }
+
+        // the children outlive their parent, they are reparented to init.
+        let init = init_pid();
+        for child in self.pool_map.values_mut() {
+            if child.ppid.as_u64() == pid.as_u64() {
+                child.ppid = init.clone();
+                current::set_parent(&child.pid, &child.ppid);
+            }
+        }
 
         let is_usermode = proc.is_usermode();
         // drop the process
         mem::drop(proc);
+        release_pid(pid);
 
         if is_usermode {
             self.user_proc_count -= 1;
@@ -315,12 +380,21 @@ impl ProcessPoolManager {
             self.kernel_proc_count += 1;
         }
 
+        current::set_parent(&process.pid, &process.ppid);
+        self.exit_codes.remove(&pid);
         self.pool_map.insert(pid, process);
     }
 
+    /// the code the process exited with, None while it is still running.
+    #[inline]
+    pub fn take_exit_code(&mut self, pid: &PID) -> Option<i64> {
+        self.exit_codes.remove(&pid.as_u64())
+    }
+
     #[inline]
-    pub fn reset_process(&mut self, pid: &PID, path: &str) -> VirtualAddress {
+    pub fn reset_process(&mut self, pid: &PID, path: &str, name: String) -> VirtualAddress {
         let process_mut: &mut Process = self.pool_map.get_mut(&pid.as_u64()).unwrap();
+        process_mut.name = name;
 
         // reset the internal layout:
         reset_layout(
@@ -344,9 +418,14 @@ pub fn setup_process_pool() {
     );
 }
 
-pub fn new(name: String, is_user: bool, path: &str) -> PID {
-    let process = Process::empty(name, is_user, &path);
+pub fn new(name: String, is_user: bool, path: &str) -> Result<PID, ProcessError> {
+    let process_res = Process::empty(name, is_user, &path);
+    if process_res.is_err() {
+        return Err(process_res.unwrap_err());
+    }
+
+    let process = process_res.unwrap();
     let pid = process.pid.clone();
     PROCESS_POOL.lock().add_process(process);
-    pid
+    Ok(pid)
 }
@@ -0,0 +1,105 @@
+use crate::system::utils::{MAX_FILE_DESCRIPTORS, THREAD_STACK_SIZE, USER_HEAP_END};
+
+// resources, same values as linux
+pub const RLIMIT_DATA: usize = 2;
+pub const RLIMIT_STACK: usize = 3;
+pub const RLIMIT_NOFILE: usize = 7;
+
+/// same layout as `struct rlimit`
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RLimit {
+    pub current: u64,
+    pub max: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum RLimitError {
+    UnknownResource,
+    /// the soft limit is above the hard limit
+    InvalidLimit,
+    /// the hard limit can only be lowered, and never above the system limit
+    NotPermitted,
+}
+
+/// system wide limit of each resource, processes start with it as both
+/// their soft and hard limits.
+#[inline]
+fn system_limit(resource: usize) -> Option<u64> {
+    match resource {
+        RLIMIT_NOFILE => Some(MAX_FILE_DESCRIPTORS as u64),
+        // thread stacks have a fixed size, a lower limit makes new stacks fail.
+        RLIMIT_STACK => Some(THREAD_STACK_SIZE),
+        RLIMIT_DATA => Some(USER_HEAP_END),
+        _ => None,
+    }
+}
+
+/// limits of a process, copied to the child on fork and kept across exec.
+#[derive(Debug, Clone)]
+pub struct ResourceLimits {
+    /// number of open file descriptors
+    pub nofile: RLimit,
+    /// size of a thread stack in bytes
+    pub stack: RLimit,
+    /// size of the heap in bytes
+    pub data: RLimit,
+}
+
+impl ResourceLimits {
+    pub fn system_default() -> Self {
+        let limit = |resource| {
+            let value = system_limit(resource).unwrap();
+            RLimit {
+                current: value,
+                max: value,
+            }
+        };
+
+        ResourceLimits {
+            nofile: limit(RLIMIT_NOFILE),
+            stack: limit(RLIMIT_STACK),
+            data: limit(RLIMIT_DATA),
+        }
+    }
+
+    #[inline]
+    fn get_mut(&mut self, resource: usize) -> Option<&mut RLimit> {
+        match resource {
+            RLIMIT_NOFILE => Some(&mut self.nofile),
+            RLIMIT_STACK => Some(&mut self.stack),
+            RLIMIT_DATA => Some(&mut self.data),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, resource: usize) -> Result<RLimit, RLimitError> {
+        match resource {
+            RLIMIT_NOFILE => Ok(self.nofile),
+            RLIMIT_STACK => Ok(self.stack),
+            RLIMIT_DATA => Ok(self.data),
+            _ => Err(RLimitError::UnknownResource),
+        }
+    }
+
+    /// there are no privileged processes, so the hard limit can't be raised.
+    pub fn set(&mut self, resource: usize, limit: RLimit) -> Result<(), RLimitError> {
+        let system_max = system_limit(resource);
+        let current_opt = self.get_mut(resource);
+        if current_opt.is_none() || system_max.is_none() {
+            return Err(RLimitError::UnknownResource);
+        }
+
+        if limit.current > limit.max {
+            return Err(RLimitError::InvalidLimit);
+        }
+
+        let current = current_opt.unwrap();
+        if limit.max > current.max || limit.max > system_max.unwrap() {
+            return Err(RLimitError::NotPermitted);
+        }
+
+        *current = limit;
+        Ok(())
+    }
+}
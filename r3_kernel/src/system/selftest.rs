@@ -0,0 +1,161 @@
+extern crate alloc;
+extern crate log;
+
+use crate::cpu;
+use crate::cpu::exceptions::FAULT_EXIT_CODE;
+use crate::mm::VirtualAddress;
+use crate::system;
+use crate::system::process::{self, PROCESS_POOL};
+use crate::system::tasking::{schedule_yield, Sched, ThreadSuspendType, SCHEDULER};
+use crate::system::thread;
+
+use alloc::format;
+
+// the userland test programs and the code each one has to exit with, they print
+// "<name>: PASS" or "<name>: FAIL, ..." on the terminal before exiting. the kernel
+// has no command line, so the set is picked at build time with the selftest feature.
+const SELFTEST_PROGRAMS: &'static [(&'static str, i64)] = &[
+    // killed by the kernel for dereferencing NULL.
+    ("/sbin/fault_test", FAULT_EXIT_CODE),
+    ("/sbin/fd_share_test", EXIT_PASS),
+    ("/sbin/fd_test", EXIT_PASS),
+    ("/sbin/ata_share_test", EXIT_PASS),
+    ("/sbin/cloexec_test", EXIT_PASS),
+    ("/sbin/ata_eof_test", EXIT_PASS),
+    ("/sbin/dns_test", EXIT_PASS),
+    ("/sbin/yield_test", EXIT_PASS),
+    ("/sbin/madvise_test", EXIT_PASS),
+    ("/sbin/rlimit_test", EXIT_PASS),
+    ("/sbin/prctl_test", EXIT_PASS),
+    ("/sbin/clock_test", EXIT_PASS),
+    ("/sbin/fpu_test", EXIT_PASS),
+    ("/sbin/pipe_test", EXIT_PASS),
+    ("/sbin/tty_test", EXIT_PASS),
+    ("/sbin/kstack_test", EXIT_PASS),
+    ("/sbin/thread_test", EXIT_PASS),
+    ("/sbin/socket_test", EXIT_PASS),
+    ("/sbin/tarfs_test", EXIT_PASS),
+    ("/sbin/protect_test", EXIT_PASS),
+    ("/sbin/fork_test", EXIT_PASS),
+];
+
+/// same as `library::testing::EXIT_PASS` in userland.
+const EXIT_PASS: i64 = 0;
+/// a test that is still running after this long is killed and counted as failed.
+const TIMEOUT_TICKS: usize = 600;
+const POLL_TICKS: usize = 1;
+
+enum TestResult {
+    Passed,
+    Failed(i64),
+    TimedOut,
+    NotStarted,
+}
+
+fn sleep(ticks: usize) {
+    SCHEDULER
+        .lock()
+        .suspend_thread(ThreadSuspendType::SuspendSleep(ticks));
+    schedule_yield();
+}
+
+/// starts the program and waits for it to exit, the next one runs after that.
+fn run_program(path: &str, expected_code: i64) -> TestResult {
+    let pid_opt = system::spawn_program(path);
+    if pid_opt.is_none() {
+        return TestResult::NotStarted;
+    }
+
+    let pid = pid_opt.unwrap();
+    let mut waited = 0;
+    while waited < TIMEOUT_TICKS {
+        let code_opt = cpu::without_interrupts(|| PROCESS_POOL.lock().take_exit_code(&pid));
+        if let Some(code) = code_opt {
+            if code == expected_code {
+                return TestResult::Passed;
+            }
+            return TestResult::Failed(code);
+        }
+
+        sleep(POLL_TICKS);
+        waited += POLL_TICKS;
+    }
+
+    if let Err(err) = system::kill_process(&pid, system::KILLED_EXIT_CODE) {
+        log::error!("selftest: failed to kill {}: {}", path, err);
+    }
+    TestResult::TimedOut
+}
+
+fn runner_loop() -> ! {
+    log::info!("Running {} self test programs.", SELFTEST_PROGRAMS.len());
+
+    let (mut n_passed, mut n_failed, mut n_skipped) = (0, 0, 0);
+    for (path, expected_code) in SELFTEST_PROGRAMS {
+        match run_program(path, *expected_code) {
+            TestResult::Passed => {
+                log::info!("selftest: {} passed.", path);
+                n_passed += 1;
+            }
+            TestResult::Failed(code) => {
+                log::error!(
+                    "selftest: {} failed, exit code {}, expected {}.",
+                    path,
+                    code,
+                    expected_code
+                );
+                n_failed += 1;
+            }
+            TestResult::TimedOut => {
+                log::error!("selftest: {} did not exit in time, killed it.", path);
+                n_failed += 1;
+            }
+            TestResult::NotStarted => {
+                log::error!("selftest: {} could not be started, skipped.", path);
+                n_skipped += 1;
+            }
+        }
+    }
+
+    log::info!(
+        "selftest: {} passed, {} failed, {} skipped of {} programs.",
+        n_passed,
+        n_failed,
+        n_skipped,
+        SELFTEST_PROGRAMS.len()
+    );
+
+    // nothing else to do, stay out of the run queue.
+    loop {
+        sleep(TIMEOUT_TICKS);
+    }
+}
+
+fn runner_thread() {
+    runner_loop();
+}
+
+/// starts the thread that runs the test programs one after the other, needs the scheduler.
+pub fn run() {
+    let process_res = process::new(format!("kernel_selftest"), false, "");
+    if process_res.is_err() {
+        log::error!(
+            "selftest: failed to create the runner process: {:?}",
+            process_res.unwrap_err()
+        );
+        return;
+    }
+
+    let thread_res = thread::new_from_function(
+        &process_res.unwrap(),
+        format!("selftest_runner"),
+        VirtualAddress::from_u64(runner_thread as fn() as u64),
+    );
+
+    if thread_res.is_err() {
+        log::error!(
+            "selftest: failed to start the runner thread: {:?}",
+            thread_res.unwrap_err()
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use crate::system::process::{MAX_PROCESSES, PID};
+use crate::system::thread::ThreadID;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// identity of the thread running on this CPU, written by the scheduler when it
+// switches threads, so getpid(), gettid() and getppid() don't take any lock.
+// there is only the BSP scheduler as of now, so one copy is enough.
+
+/// stored when no thread is running.
+const NO_ID: u64 = u64::MAX;
+
+static CURRENT_TID: AtomicU64 = AtomicU64::new(NO_ID);
+static CURRENT_PID: AtomicU64 = AtomicU64::new(NO_ID);
+
+/// only copied into PARENT_PIDS, every element gets it's own atomic.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PARENT: AtomicU64 = AtomicU64::new(NO_ID);
+
+/// parent of every process, indexed by pid (pids are always below `MAX_PROCESSES`).
+/// it is not kept with the current thread, because reparenting changes it
+/// while the threads of the process are not running.
+static PARENT_PIDS: [AtomicU64; MAX_PROCESSES] = [NO_PARENT; MAX_PROCESSES];
+
+#[inline]
+pub fn set_current(tid: ThreadID, pid: &PID) {
+    CURRENT_TID.store(tid.as_u64(), Ordering::SeqCst);
+    CURRENT_PID.store(pid.as_u64(), Ordering::SeqCst);
+}
+
+#[inline]
+pub fn clear_current() {
+    CURRENT_TID.store(NO_ID, Ordering::SeqCst);
+    CURRENT_PID.store(NO_ID, Ordering::SeqCst);
+}
+
+#[inline]
+pub fn tid() -> Option<ThreadID> {
+    let tid = CURRENT_TID.load(Ordering::SeqCst);
+    if tid == NO_ID {
+        return None;
+    }
+    Some(ThreadID::new(tid))
+}
+
+#[inline]
+pub fn pid() -> Option<PID> {
+    let pid = CURRENT_PID.load(Ordering::SeqCst);
+    if pid == NO_ID {
+        return None;
+    }
+    Some(PID::new(pid))
+}
+
+#[inline]
+pub fn ppid() -> Option<PID> {
+    let pid = pid()?;
+    let ppid = PARENT_PIDS[pid.as_u64() as usize].load(Ordering::SeqCst);
+    if ppid == NO_ID {
+        return None;
+    }
+    Some(PID::new(ppid))
+}
+
+/// called when a process is added to the pool and when it is reparented.
+#[inline]
+pub fn set_parent(pid: &PID, ppid: &PID) {
+    PARENT_PIDS[pid.as_u64() as usize].store(ppid.as_u64(), Ordering::SeqCst);
+}
+
+#[inline]
+pub fn clear_parent(pid: &PID) {
+    PARENT_PIDS[pid.as_u64() as usize].store(NO_ID, Ordering::SeqCst);
+}
@@ -0,0 +1,125 @@
+extern crate log;
+
+use core::arch::asm;
+
+use crate::cpu::disable_interrupts;
+use crate::system::thread::ThreadID;
+use crate::system::timer::{self, Time};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// utilization is re-computed once per window
+const UTILIZATION_WINDOW_NS: u64 = Time::Second as u64;
+
+/// monotonic time at which the idle thread went into HLT, 0 when it is not halted.
+static IDLE_SINCE: AtomicU64 = AtomicU64::new(0);
+/// tid of the idle thread, u64::MAX until it is started.
+static IDLE_THREAD: AtomicU64 = AtomicU64::new(u64::MAX);
+/// tid of the thread that runs only when nothing else but idle is runnable.
+static LOW_PRIORITY_THREAD: AtomicU64 = AtomicU64::new(u64::MAX);
+/// total time spent in HLT by the idle thread since boot.
+static IDLE_TOTAL_NS: AtomicU64 = AtomicU64::new(0);
+
+/// start of the current window and the idle total at that point.
+static WINDOW_START_NS: AtomicU64 = AtomicU64::new(0);
+static WINDOW_IDLE_NS: AtomicU64 = AtomicU64::new(0);
+/// utilization of the last completed window, in percent.
+static UTILIZATION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct CPUStat {
+    pub uptime_ns: u64,
+    pub idle_ns: u64,
+    /// busy percentage of the last one second window
+    pub utilization: u64,
+}
+
+pub fn set_idle_thread(tid: ThreadID) {
+    IDLE_THREAD.store(tid.as_u64(), Ordering::SeqCst);
+}
+
+#[inline]
+pub fn is_idle_thread(tid: &ThreadID) -> bool {
+    IDLE_THREAD.load(Ordering::SeqCst) == tid.as_u64()
+}
+
+/// the scheduler passes over this thread while any other one but idle can run,
+/// only one thread can be low priority.
+pub fn set_low_priority_thread(tid: ThreadID) {
+    LOW_PRIORITY_THREAD.store(tid.as_u64(), Ordering::SeqCst);
+}
+
+#[inline]
+pub fn is_low_priority_thread(tid: &ThreadID) -> bool {
+    LOW_PRIORITY_THREAD.load(Ordering::SeqCst) == tid.as_u64()
+}
+
+#[inline]
+fn enter_idle() {
+    // 0 is used as "not idle", the TSC is way past it after boot.
+    let now = timer::monotonic_ns();
+    IDLE_SINCE.store(if now == 0 { 1 } else { now }, Ordering::SeqCst);
+}
+
+#[inline]
+fn leave_idle(now: u64) {
+    let since = IDLE_SINCE.swap(0, Ordering::SeqCst);
+    if since != 0 && now > since {
+        IDLE_TOTAL_NS.fetch_add(now - since, Ordering::SeqCst);
+    }
+}
+
+/// called from the timer handle before the next thread is picked. if the idle
+/// thread was halted, the halt ends here, because the idle thread might not be
+/// scheduled again for a while and that time belongs to other threads.
+pub fn account_tick() {
+    let now = timer::monotonic_ns();
+    leave_idle(now);
+
+    let window_start = WINDOW_START_NS.load(Ordering::SeqCst);
+    if window_start == 0 {
+        WINDOW_START_NS.store(now, Ordering::SeqCst);
+        WINDOW_IDLE_NS.store(IDLE_TOTAL_NS.load(Ordering::SeqCst), Ordering::SeqCst);
+        return;
+    }
+
+    let elapsed = now.saturating_sub(window_start);
+    if elapsed < UTILIZATION_WINDOW_NS {
+        return;
+    }
+
+    let idle_total = IDLE_TOTAL_NS.load(Ordering::SeqCst);
+    let idle = core::cmp::min(
+        idle_total.saturating_sub(WINDOW_IDLE_NS.load(Ordering::SeqCst)),
+        elapsed,
+    );
+
+    UTILIZATION.store(((elapsed - idle) * 100) / elapsed, Ordering::SeqCst);
+    WINDOW_START_NS.store(now, Ordering::SeqCst);
+    WINDOW_IDLE_NS.store(idle_total, Ordering::SeqCst);
+}
+
+/// body of the idle thread, halts until the next interrupt and counts the time.
+pub fn idle_loop() -> ! {
+    loop {
+        // interrupts are masked between marking the thread as idle and the HLT,
+        // sti delays them by one instruction, so the wakeup can't be missed.
+        disable_interrupts();
+        enter_idle();
+        unsafe {
+            asm!("sti; hlt", options(nomem, nostack));
+        }
+
+        // woken up by an interrupt other than the timer.
+        leave_idle(timer::monotonic_ns());
+    }
+}
+
+pub fn cpu_stat() -> CPUStat {
+    CPUStat {
+        uptime_ns: timer::monotonic_ns(),
+        idle_ns: IDLE_TOTAL_NS.load(Ordering::SeqCst),
+        utilization: UTILIZATION.load(Ordering::SeqCst),
+    }
+}
@@ -1,3 +1,5 @@
+pub mod current;
+pub mod idle;
 pub mod srbs;
 pub mod wait_queue;
 
@@ -10,9 +12,13 @@ use crate::cpu::state::CPURegistersState;
 use crate::mm::VirtualAddress;
 use crate::system::process::PID;
 use crate::system::tasking::srbs::SimpleRoundRobinSchduler;
-use crate::system::thread::{Thread, ThreadID};
+use crate::system::thread::{Thread, ThreadID, MAX_THREADS};
 use crate::system::timer::SystemTimer;
+use crate::system::vdso;
 
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -27,6 +33,8 @@ pub enum ThreadSuspendType {
     Nothing,
     SuspendSleep(usize),
     SuspendWait(PID),
+    /// sleeps until another thread or an interrupt wakes it with `Wake`.
+    SuspendUntilWoken,
 }
 
 #[derive(Debug, Clone)]
@@ -34,6 +42,8 @@ pub enum ThreadWakeupType {
     Nothing,
     FromSleep(usize),
     FromWait(PID),
+    /// wakes the sleeping thread before it's time is up, or the parked one.
+    Wake(ThreadID),
 }
 
 /// The trait can be implemented by any schedulable entity.
@@ -61,6 +71,10 @@ pub trait Sched {
     /// and it's entry will be removed from everywhere. Including the process
     fn exit(&mut self, code: i64);
 
+    /// removes only the current thread, the rest of the process keeps running.
+    /// returns it, the caller releases it's tid and stacks.
+    fn exit_current_thread(&mut self) -> Option<Thread>;
+
     /// this function should return the current thread ID
     /// that called this function, or that was scheduled.
     fn current_tid(&self) -> Option<ThreadID>;
@@ -73,8 +87,24 @@ pub trait Sched {
     /// suspend current thread to sleep for x ticks
     fn suspend_thread(&mut self, suspend_type: ThreadSuspendType);
 
+    /// move the current thread to the back of the queue on the next schedule.
+    fn yield_current(&mut self);
+
+    /// true if a thread other than the current one is ready to run.
+    fn has_other_runnable(&self) -> bool;
+
     /// reset current thread
     fn reset_current_thread_stack(&mut self) -> VirtualAddress;
+
+    /// name of the current thread
+    fn current_thread_name(&self) -> Option<String>;
+
+    /// renames the current thread
+    fn set_current_thread_name(&mut self, name: String);
+
+    /// drops every thread of another process, from the run and wait queues.
+    /// returns their ids, the caller releases them.
+    fn remove_process_threads(&mut self, pid: &PID) -> Vec<ThreadID>;
 }
 
 lazy_static! {
@@ -82,6 +112,39 @@ lazy_static! {
         Mutex::new(SimpleRoundRobinSchduler::empty());
 }
 
+/// the wakeups that came in while the scheduler was held, one bit per tid.
+static DEFERRED_WAKES: [AtomicU64; MAX_THREADS / 64] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// wakes the thread, can be called from an interrupt handler. if the interrupted
+/// code holds the scheduler, the thread is woken on the next schedule instead.
+pub fn wake_thread(tid: ThreadID) {
+    if let Some(mut sched_lock) = SCHEDULER.try_lock() {
+        sched_lock.check_wakeup(ThreadWakeupType::Wake(tid));
+        return;
+    }
+
+    let index = tid.as_u64() as usize;
+    DEFERRED_WAKES[index / 64].fetch_or(1 << (index % 64), Ordering::SeqCst);
+}
+
+fn wake_deferred() {
+    for (word_index, word) in DEFERRED_WAKES.iter().enumerate() {
+        let mut bits = word.swap(0, Ordering::SeqCst);
+        while bits != 0 {
+            let tid = (word_index * 64) as u64 + bits.trailing_zeros() as u64;
+            bits &= bits - 1;
+            SCHEDULER
+                .lock()
+                .check_wakeup(ThreadWakeupType::Wake(ThreadID::new(tid)));
+        }
+    }
+}
+
 pub fn setup_scheduler() {
     log::info!(
         "Setup scheduler successful, initial thread={:?}",
@@ -96,21 +159,26 @@ pub fn setup_scheduler() {
 pub extern "sysv64" fn schedule_handle(state_repr: CPURegistersState) {
 
     LAPICUtils::eoi();
+    idle::account_tick();
 
     SCHEDULER.lock().save_current_ctx(state_repr);
+    vdso::update_time_page();
 
     // if any thread needs to wake up, wake them up.
     SCHEDULER
         .lock()
         .check_wakeup(ThreadWakeupType::FromSleep(1));
+    wake_deferred();
 
     let thread_opt = SCHEDULER.lock().lease_next_thread();
     if thread_opt.is_some() {
         let thread = thread_opt.unwrap();
+        current::set_current(thread.thread_id, &thread.parent_pid);
         SystemTimer::next_shot();
         thread.load_state();
     } else {
         // no threads were returned. Load and continue normally.
+        current::clear_current();
         SystemTimer::next_shot();
         CPURegistersState::load_state(&state_repr);
     }
@@ -148,3 +216,108 @@ where
         }
     }
 }
+
+/// checked for this many ticks before the preemption test gives up.
+#[cfg(feature = "debug_checks")]
+const PREEMPTION_TEST_TICKS: usize = 10;
+
+#[cfg(feature = "debug_checks")]
+static PREEMPTION_SPINNING: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(false);
+#[cfg(feature = "debug_checks")]
+static PREEMPTION_OTHER_RAN: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(feature = "debug_checks")]
+fn preemption_test_sleep(ticks: usize) {
+    SCHEDULER
+        .lock()
+        .suspend_thread(ThreadSuspendType::SuspendSleep(ticks));
+    schedule_yield();
+}
+
+/// never yields while it spins, like the idle thread never does.
+#[cfg(feature = "debug_checks")]
+fn preemption_spin_thread() {
+    use crate::acpi::lapic;
+    use crate::system::timer::{self, SYSTEM_TICK_DURATION};
+
+    PREEMPTION_SPINNING.store(true, Ordering::SeqCst);
+    let deadline = timer::monotonic_ns() + PREEMPTION_TEST_TICKS as u64 * SYSTEM_TICK_DURATION;
+    while !PREEMPTION_OTHER_RAN.load(Ordering::SeqCst) && timer::monotonic_ns() < deadline {
+        core::hint::spin_loop();
+    }
+
+    if PREEMPTION_OTHER_RAN.load(Ordering::SeqCst) {
+        log::info!("Passed preemption test, timer mode={:?}.", lapic::timer_mode());
+    } else {
+        log::error!(
+            "preemption test failed, a spinning thread kept the cpu, timer mode={:?}.",
+            lapic::timer_mode()
+        );
+    }
+
+    // kernel threads can't exit, stay out of the run queue.
+    loop {
+        preemption_test_sleep(PREEMPTION_TEST_TICKS);
+    }
+}
+
+/// wakes up from it's sleeps only if the timer ticks and takes the cpu away
+/// from the thread that has it, the spinning one or the idle thread.
+#[cfg(feature = "debug_checks")]
+fn preemption_check_thread() {
+    let idle_before = idle::cpu_stat().idle_ns;
+    while !PREEMPTION_SPINNING.load(Ordering::SeqCst) {
+        preemption_test_sleep(1);
+    }
+    PREEMPTION_OTHER_RAN.store(true, Ordering::SeqCst);
+
+    // the cpu is never idle during a busy boot, so it is only reported.
+    if idle::cpu_stat().idle_ns == idle_before {
+        log::info!("preemption test: the idle thread did not run, it was not checked.");
+    }
+
+    loop {
+        preemption_test_sleep(PREEMPTION_TEST_TICKS);
+    }
+}
+
+/// starts a thread that spins and one that has to run while it does, the result
+/// is logged once the timer ticks. build with the periodic LAPIC timer (the
+/// `--periodic-timer` flag of the run script) to check the fallback mode.
+#[cfg(feature = "debug_checks")]
+pub fn start_preemption_test() {
+    use crate::system::{process, thread};
+    use alloc::format;
+
+    let process_res = process::new(format!("kernel_preemption_test"), false, "");
+    if process_res.is_err() {
+        log::error!(
+            "preemption test: failed to create the process: {:?}",
+            process_res.unwrap_err()
+        );
+        return;
+    }
+
+    let pid = process_res.unwrap();
+    let threads: [(&str, fn()); 2] = [
+        ("preemption_spin", preemption_spin_thread),
+        ("preemption_check", preemption_check_thread),
+    ];
+    for (name, function) in threads.iter() {
+        let thread_res = thread::new_from_function(
+            &pid,
+            format!("{}", name),
+            VirtualAddress::from_u64(*function as u64),
+        );
+        if thread_res.is_err() {
+            log::error!(
+                "preemption test: failed to start {}: {:?}",
+                name,
+                thread_res.unwrap_err()
+            );
+            return;
+        }
+    }
+}
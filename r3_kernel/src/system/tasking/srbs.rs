@@ -4,11 +4,13 @@ use crate::alloc::boxed::Box;
 use crate::cpu::state::CPURegistersState;
 use crate::mm::VirtualAddress;
 use crate::system::process::PID;
+use crate::system::tasking::current;
+use crate::system::tasking::idle;
 use crate::system::tasking::wait_queue::WaitQueue;
 use crate::system::tasking::{Sched, ThreadSuspendType, ThreadWakeupType};
-use crate::system::thread::{ContextType, Thread, ThreadID};
+use crate::system::thread::{self, ContextType, Thread, ThreadID};
 
-use alloc::vec::Vec;
+use alloc::{string::String, vec::Vec};
 
 #[derive(Debug, Clone)]
 /// A scheduler that schedules tasks from
@@ -22,6 +24,36 @@ pub struct SimpleRoundRobinSchduler {
     pub wait_queue: WaitQueue,
     pub suspend_next: bool,
     pub suspend_type: ThreadSuspendType,
+    pub yield_next: bool,
+    /// index of the thread to run next when the current one left
+    /// or moved in the list, the slot after `thread_index` otherwise.
+    pub next_index: Option<usize>,
+}
+
+impl SimpleRoundRobinSchduler {
+    /// the thread that took the slot at `removed_idx` runs next.
+    #[inline]
+    fn continue_from(&mut self, removed_idx: usize) {
+        self.next_index = if removed_idx < self.thread_list.len() {
+            Some(removed_idx)
+        } else {
+            Some(0)
+        };
+    }
+
+    #[inline]
+    fn is_low_priority(&self, idx: usize) -> bool {
+        self.thread_list
+            .get(idx)
+            .map_or(false, |th| idle::is_low_priority_thread(&th.thread_id))
+    }
+
+    /// a runnable thread that is neither idle nor low priority.
+    fn has_normal_runnable(&self) -> bool {
+        self.thread_list.iter().any(|th| {
+            !idle::is_idle_thread(&th.thread_id) && !idle::is_low_priority_thread(&th.thread_id)
+        })
+    }
 }
 
 impl Sched for SimpleRoundRobinSchduler {
@@ -32,6 +64,8 @@ impl Sched for SimpleRoundRobinSchduler {
             wait_queue: WaitQueue::empty(),
             suspend_next: false,
             suspend_type: ThreadSuspendType::Nothing,
+            yield_next: false,
+            next_index: None,
         }
     }
 
@@ -45,6 +79,7 @@ impl Sched for SimpleRoundRobinSchduler {
         if let Some(thread_id) = self.thread_index {
             if let Some(thread_ref) = self.thread_list.get_mut(thread_id) {
                 thread_ref.context = Box::new(ContextType::SavedContext(state));
+                thread_ref.fpu_state.save();
             }
         }
 
@@ -55,8 +90,23 @@ impl Sched for SimpleRoundRobinSchduler {
             self.wait_queue
                 .dispatch_suspend(thread, self.suspend_type.clone());
             self.thread_index = None;
+            self.continue_from(thread_idx);
+            current::clear_current();
             self.suspend_next = false;
             self.suspend_type = ThreadSuspendType::Nothing;
+            self.yield_next = false;
+        }
+
+        if self.yield_next {
+            // the yielding thread goes to the back of the queue, behind
+            // every other runnable thread.
+            self.yield_next = false;
+            if let Some(thread_idx) = self.thread_index {
+                let thread = self.thread_list.remove(thread_idx);
+                self.thread_list.push(thread);
+                self.thread_index = None;
+                self.continue_from(thread_idx);
+            }
         }
     }
 
@@ -74,11 +124,44 @@ impl Sched for SimpleRoundRobinSchduler {
                 code
             );
             // remove the thread
-            self.thread_list.remove(thread_index);
+            let exited = self.thread_list.remove(thread_index);
             self.thread_index = None;
+            current::clear_current();
+            self.yield_next = false;
+
+            // exit terminates the whole process, the other threads can't run
+            // anymore, their pid and tids are going to be reused.
+            let pid = exited.parent_pid.clone();
+            let mut siblings = self.wait_queue.remove_process_threads(&pid);
+            self.thread_list.retain(|th| {
+                if th.parent_pid.as_u64() == pid.as_u64() {
+                    siblings.push(th.thread_id);
+                    return false;
+                }
+                true
+            });
+
+            thread::release_tid(exited.thread_id);
+            for tid in siblings {
+                thread::release_tid(tid);
+            }
+
+            // the siblings may have been before the exited thread.
+            self.continue_from(thread_index);
         }
     }
 
+    fn exit_current_thread(&mut self) -> Option<Thread> {
+        let thread_index = self.thread_index?;
+        let mut exited = self.thread_list.remove(thread_index);
+        exited.exit();
+        self.thread_index = None;
+        current::clear_current();
+        self.yield_next = false;
+        self.continue_from(thread_index);
+        Some(exited)
+    }
+
     fn lease_next_thread(&mut self) -> Option<Thread> {
         // got a schedule request
         if self.thread_list.is_empty() {
@@ -88,10 +171,19 @@ impl Sched for SimpleRoundRobinSchduler {
         // we have a thread
         let thread_ref_opt = {
             let n_threads = self.thread_list.len();
-            let thread_idx_ref = self.thread_index.get_or_insert(0);
             // round robin
-            let next_thread_idx = (*thread_idx_ref + 1) % n_threads;
-            *thread_idx_ref = next_thread_idx;
+            let mut next_thread_idx = match (self.next_index.take(), self.thread_index) {
+                (Some(next_idx), _) => next_idx % n_threads,
+                (None, Some(thread_idx)) => (thread_idx + 1) % n_threads,
+                (None, None) => 0,
+            };
+
+            // the low priority thread gives it's turn to the next thread if
+            // there is real work, it is the only one so the next is not it.
+            if self.is_low_priority(next_thread_idx) && self.has_normal_runnable() {
+                next_thread_idx = (next_thread_idx + 1) % n_threads;
+            }
+            self.thread_index = Some(next_thread_idx);
 
             self.thread_list.get_mut(next_thread_idx)
         };
@@ -137,6 +229,23 @@ impl Sched for SimpleRoundRobinSchduler {
         self.suspend_type = suspend_type;
     }
 
+    fn yield_current(&mut self) {
+        if self.thread_index.is_some() {
+            self.yield_next = true;
+        }
+    }
+
+    fn has_other_runnable(&self) -> bool {
+        // the idle thread is always in the list, yielding to it would just
+        // HLT away the rest of the tick.
+        self.thread_list
+            .iter()
+            .enumerate()
+            .any(|(idx, th)| {
+                Some(idx) != self.thread_index && !idle::is_idle_thread(&th.thread_id)
+            })
+    }
+
     fn reset_current_thread_stack(&mut self) -> VirtualAddress {
         if let Some(thread_idx) = self.thread_index {
             let thread_ref: &mut Thread = self.thread_list.get_mut(thread_idx).unwrap();
@@ -145,4 +254,60 @@ impl Sched for SimpleRoundRobinSchduler {
 
         VirtualAddress::from_u64(0)
     }
+
+    fn current_thread_name(&self) -> Option<String> {
+        if self.thread_index.is_none() {
+            return None;
+        }
+
+        let thread = self.thread_list.get(self.thread_index.unwrap());
+        Some(thread.as_ref().unwrap().name.clone())
+    }
+
+    fn set_current_thread_name(&mut self, name: String) {
+        if let Some(thread_idx) = self.thread_index {
+            let thread_ref: &mut Thread = self.thread_list.get_mut(thread_idx).unwrap();
+            thread_ref.name = name;
+        }
+    }
+
+    fn remove_process_threads(&mut self, pid: &PID) -> Vec<ThreadID> {
+        let mut removed = self.wait_queue.remove_process_threads(pid);
+
+        // every removed thread before them moves the current and the next one
+        // slot back.
+        let current_idx = self.thread_index;
+        let next_idx = self.next_index;
+        let (mut current_shift, mut next_shift) = (0, 0);
+        let mut current_removed = false;
+        let mut idx = 0;
+        self.thread_list.retain(|th| {
+            let keep = th.parent_pid.as_u64() != pid.as_u64();
+            if !keep {
+                removed.push(th.thread_id);
+                current_removed |= Some(idx) == current_idx;
+                current_shift += current_idx.map_or(0, |current| (idx < current) as usize);
+                next_shift += next_idx.map_or(0, |next| (idx < next) as usize);
+            }
+            idx += 1;
+            keep
+        });
+
+        if let Some(next) = next_idx {
+            self.continue_from(next - next_shift);
+        }
+
+        if let Some(current) = current_idx {
+            if current_removed {
+                self.thread_index = None;
+                if next_idx.is_none() {
+                    self.continue_from(current - current_shift);
+                }
+            } else {
+                self.thread_index = Some(current - current_shift);
+            }
+        }
+
+        removed
+    }
 }
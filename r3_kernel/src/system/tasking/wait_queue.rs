@@ -2,7 +2,7 @@ extern crate alloc;
 
 use crate::system::process::PID;
 use crate::system::tasking::{ThreadSuspendType, ThreadWakeupType};
-use crate::system::thread::Thread;
+use crate::system::thread::{Thread, ThreadID};
 
 use alloc::vec::Vec;
 
@@ -23,6 +23,8 @@ pub struct WaitQueue {
     /// contains a list of threads that are waiting
     pub sleep_threads: Vec<SleepingThread>,
     pub waiting_threads: Vec<WaitingThread>,
+    /// threads that sleep until they are woken, with no timeout.
+    pub parked_threads: Vec<Thread>,
 }
 
 impl WaitQueue {
@@ -31,6 +33,7 @@ impl WaitQueue {
         WaitQueue {
             sleep_threads: Vec::new(),
             waiting_threads: Vec::new(),
+            parked_threads: Vec::new(),
         }
     }
 
@@ -56,10 +59,44 @@ impl WaitQueue {
             ThreadSuspendType::SuspendSleep(ticks) => {
                 self.put_sleep(thread, ticks);
             }
+            ThreadSuspendType::SuspendUntilWoken => {
+                self.parked_threads.push(thread);
+            }
             ThreadSuspendType::Nothing => {}
         }
     }
 
+    /// drops the sleeping and waiting threads of the process, returns their ids.
+    pub fn remove_process_threads(&mut self, pid: &PID) -> Vec<ThreadID> {
+        let mut removed = Vec::new();
+
+        self.sleep_threads.retain(|entry| {
+            if entry.thread.parent_pid.as_u64() == pid.as_u64() {
+                removed.push(entry.thread.thread_id);
+                return false;
+            }
+            true
+        });
+
+        self.waiting_threads.retain(|entry| {
+            if entry.thread.parent_pid.as_u64() == pid.as_u64() {
+                removed.push(entry.thread.thread_id);
+                return false;
+            }
+            true
+        });
+
+        self.parked_threads.retain(|thread| {
+            if thread.parent_pid.as_u64() == pid.as_u64() {
+                removed.push(thread.thread_id);
+                return false;
+            }
+            true
+        });
+
+        removed
+    }
+
     #[inline]
     pub fn wake_waiting_threads(&mut self, pid: PID, run_queue: &mut Vec<Thread>) {
         self.waiting_threads = self
@@ -99,6 +136,26 @@ impl WaitQueue {
             .collect();
     }
 
+    #[inline]
+    pub fn wake_sleeping_thread(&mut self, tid: ThreadID, run_queue: &mut Vec<Thread>) {
+        let position = self
+            .sleep_threads
+            .iter()
+            .position(|entry| entry.thread.thread_id.as_u64() == tid.as_u64());
+        if let Some(index) = position {
+            run_queue.push(self.sleep_threads.remove(index).thread);
+            return;
+        }
+
+        let position = self
+            .parked_threads
+            .iter()
+            .position(|thread| thread.thread_id.as_u64() == tid.as_u64());
+        if let Some(index) = position {
+            run_queue.push(self.parked_threads.remove(index));
+        }
+    }
+
     #[inline]
     pub fn dispatch_wakeup(&mut self, wakeup_mode: ThreadWakeupType, run_queue: &mut Vec<Thread>) {
         match wakeup_mode {
@@ -108,6 +165,9 @@ impl WaitQueue {
             ThreadWakeupType::FromWait(pid) => {
                 self.wake_waiting_threads(pid, run_queue);
             }
+            ThreadWakeupType::Wake(tid) => {
+                self.wake_sleeping_thread(tid, run_queue);
+            }
             ThreadWakeupType::Nothing => {}
         }
     }
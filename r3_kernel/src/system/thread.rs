@@ -3,30 +3,61 @@ extern crate log;
 extern crate spin;
 
 use crate::cpu::{
-    mmu, segments, state::bootstrap_kernel_thread, state::CPURegistersState, syscall,
+    fpu::FPUState, interrupt_stacks, mmu, segments, state::bootstrap_kernel_thread,
+    state::CPURegistersState, syscall,
 };
 use crate::mm::{stack::STACK_ALLOCATOR, stack::STACK_SIZE, PhysicalAddress, VirtualAddress};
-use crate::system::process::{PID, PROCESS_POOL};
+use crate::system::process::{Process, PID, PROCESS_POOL};
 use crate::system::tasking::{Sched, SCHEDULER};
 
 use crate::system::utils;
 
 use alloc::{boxed::Box, string::String};
 use core::mem;
-use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
 
 pub type ThreadFn = fn();
 
-static CURRENT_TID: AtomicU64 = AtomicU64::new(0);
+/// max number of threads alive in the system.
+pub const MAX_THREADS: usize = 256;
+/// max number of threads a single process can create.
+pub const MAX_THREADS_PER_PROCESS: usize = 32;
 
-pub fn new_tid() -> ThreadID {
-    let current = CURRENT_TID.load(Ordering::SeqCst);
-    if current + 1 == u64::max_value() {
-        panic!("Could not create thread, Out of TIDs");
+lazy_static! {
+    static ref TID_ALLOCATOR: Mutex<utils::IDAllocator> =
+        Mutex::new(utils::IDAllocator::new(MAX_THREADS));
+}
+
+/// returns None once `MAX_THREADS` are alive, tids of exited threads are reused.
+pub fn new_tid() -> Option<ThreadID> {
+    TID_ALLOCATOR.lock().alloc().map(|tid| ThreadID(tid))
+}
+
+pub fn release_tid(tid: ThreadID) {
+    TID_ALLOCATOR.lock().release(tid.as_u64());
+}
+
+/// checks the limits before anything is allocated for the thread.
+fn reserve_tid(process: &Process) -> Result<ThreadID, ThreadError> {
+    // exited threads are removed from the process by sys_thread_exit,
+    // so this counts the live ones.
+    if process.threads.len() >= MAX_THREADS_PER_PROCESS {
+        log::warn!(
+            "process {} reached the limit of {} threads",
+            process.pid.as_u64(),
+            MAX_THREADS_PER_PROCESS
+        );
+        return Err(ThreadError::TooManyThreads);
     }
 
-    CURRENT_TID.store(current + 1, Ordering::SeqCst);
-    ThreadID(current)
+    let tid_opt = new_tid();
+    if tid_opt.is_none() {
+        log::warn!("system reached the limit of {} threads", MAX_THREADS);
+        return Err(ThreadError::TooManyThreads);
+    }
+
+    Ok(tid_opt.unwrap())
 }
 
 #[derive(Debug, Clone)]
@@ -48,6 +79,11 @@ pub enum ContextType {
 pub struct ThreadID(u64);
 
 impl ThreadID {
+    #[inline]
+    pub fn new(tid: u64) -> Self {
+        ThreadID(tid)
+    }
+
     #[inline]
     pub fn as_u64(&self) -> u64 {
         self.0
@@ -66,6 +102,7 @@ pub enum ThreadError {
     NoPID,
     OutOfStacks,
     UnknownThreadID,
+    TooManyThreads,
 }
 
 pub struct Context;
@@ -90,6 +127,8 @@ pub struct Thread {
     pub is_user: bool,
     pub cr3: u64,
     pub syscall_stack_start: Option<VirtualAddress>,
+    /// x87/SSE registers, saved and restored with the context.
+    pub fpu_state: Box<FPUState>,
 }
 
 impl Thread {
@@ -111,27 +150,43 @@ impl Thread {
             panic!("Kernel threads cannot load and run ELF binaries.");
         }
 
+        let tid_res = reserve_tid(parent_proc);
+        if tid_res.is_err() {
+            return Err(tid_res.unwrap_err());
+        }
+
+        let tid = tid_res.unwrap();
         let mut proc_data = parent_proc.proc_data.as_mut().unwrap();
 
         // allocate a stack
-        let stack_start = utils::ProcessStackManager::allocate_stack(
+        let stack_res = utils::ProcessStackManager::allocate_stack(
             &mut proc_data,
             parent_proc.pt_root.as_mut().unwrap().as_mut(),
             true,
-        )
-        .expect("Failed to allocate stack for user thread.");
+        );
+        if stack_res.is_err() {
+            log::warn!("Failed to allocate stack for user thread: {:?}", stack_res.unwrap_err());
+            release_tid(tid);
+            return Err(ThreadError::OutOfStacks);
+        }
+        let stack_start = stack_res.unwrap();
 
-        let syscall_stack_start = utils::ProcessStackManager::allocate_syscall_stack(
+        let syscall_stack_res = utils::ProcessStackManager::allocate_syscall_stack(
             &mut proc_data,
             parent_proc.pt_root.as_mut().unwrap().as_mut(),
             0,
-        )
-        .expect("Failed to allocate syscall stack");
+        );
+        if syscall_stack_res.is_err() {
+            log::warn!("Failed to allocate syscall stack: {:?}", syscall_stack_res.unwrap_err());
+            let _ = utils::ProcessStackManager::free_stack(&mut proc_data, stack_start);
+            release_tid(tid);
+            return Err(ThreadError::OutOfStacks);
+        }
+        let syscall_stack_start = syscall_stack_res.unwrap();
 
         // get the entrypoint:
         let entrypoint = proc_data.code_entry;
 
-        let tid = new_tid();
         parent_proc.add_thread(tid.clone());
 
         // init context
@@ -164,6 +219,7 @@ impl Thread {
             stack_start: stack_start,
             cr3: parent_proc.cr3,
             syscall_stack_start: Some(syscall_stack_start),
+            fpu_state: Box::new(FPUState::new()),
         })
     }
 
@@ -176,29 +232,52 @@ impl Thread {
 
         let child = proc_lock.get_mut_ref(&pid).unwrap();
 
+        let tid_res = reserve_tid(child);
+        if tid_res.is_err() {
+            return Err(tid_res.unwrap_err());
+        }
+
+        let tid = tid_res.unwrap();
         let rsp = match &state {
             ContextType::InitContext(ctx) => ctx.stack_end.as_u64(),
             ContextType::SavedContext(ctx) => ctx.rsp,
         };
 
         // allocate a new stack and copy the parent stack:
-        let stack_start = utils::ProcessStackManager::allocate_and_clone(
+        let stack_res = utils::ProcessStackManager::allocate_and_clone(
             &mut child.proc_data.as_mut().unwrap(),
             &mut child.pt_root.as_mut().unwrap(),
             rsp,
-        )
-        .expect("Failed to allocate stack for new thread.");
+        );
+        if stack_res.is_err() {
+            log::warn!("Failed to allocate stack for new thread: {:?}", stack_res.unwrap_err());
+            release_tid(tid);
+            return Err(ThreadError::OutOfStacks);
+        }
+        let stack_start = stack_res.unwrap();
 
-        let syscall_stack_start = utils::ProcessStackManager::allocate_syscall_stack(
+        let syscall_stack_res = utils::ProcessStackManager::allocate_syscall_stack(
             &mut child.proc_data.as_mut().unwrap(),
             &mut child.pt_root.as_mut().unwrap(),
             0,
-        )
-        .expect("Failed to allocate syscall stack");
+        );
+        if syscall_stack_res.is_err() {
+            log::warn!("Failed to allocate syscall stack: {:?}", syscall_stack_res.unwrap_err());
+            let _ = utils::ProcessStackManager::free_stack(
+                &mut child.proc_data.as_mut().unwrap(),
+                stack_start,
+            );
+            release_tid(tid);
+            return Err(ThreadError::OutOfStacks);
+        }
+        let syscall_stack_start = syscall_stack_res.unwrap();
 
-        let tid = new_tid();
         child.add_thread(tid.clone());
 
+        // called by the parent, the registers still hold it's state.
+        let mut fpu_state = Box::new(FPUState::new());
+        fpu_state.save();
+
         Ok(Thread {
             is_user: true,
             parent_pid: pid,
@@ -210,6 +289,88 @@ impl Thread {
             stack_start: stack_start,
             cr3: child.cr3,
             syscall_stack_start: Some(syscall_stack_start),
+            fpu_state,
+        })
+    }
+
+    /// creates a new user thread in the address space of the process,
+    /// the thread starts at `entry` with `arg` in rdi on the user provided stack.
+    /// rsp is `stack_end - 8`, where the caller put a zero return address, so the
+    /// entry sees the stack like a called function does (rsp + 8 is 16 aligned).
+    pub fn new_user_thread(
+        pid: PID,
+        name: String,
+        entry: VirtualAddress,
+        arg: u64,
+        stack_end: VirtualAddress,
+    ) -> Result<Self, ThreadError> {
+        let mut proc_lock = PROCESS_POOL.lock();
+
+        let parent_proc_opt = proc_lock.get_mut_ref(&pid);
+        if parent_proc_opt.is_none() {
+            return Err(ThreadError::NoPID);
+        }
+
+        let parent_proc = parent_proc_opt.unwrap();
+        if parent_proc.proc_data.is_none() || !parent_proc.is_usermode() {
+            return Err(ThreadError::NoPID);
+        }
+
+        let tid_res = reserve_tid(parent_proc);
+        if tid_res.is_err() {
+            return Err(tid_res.unwrap_err());
+        }
+
+        let tid = tid_res.unwrap();
+
+        // every thread needs it's own syscall stack
+        let syscall_stack_res = utils::ProcessStackManager::allocate_next_syscall_stack(
+            parent_proc.proc_data.as_mut().unwrap(),
+            parent_proc.pt_root.as_mut().unwrap().as_mut(),
+        );
+
+        if syscall_stack_res.is_err() {
+            log::error!(
+                "failed to allocate syscall stack: {:?}",
+                syscall_stack_res.unwrap_err()
+            );
+            release_tid(tid);
+            return Err(ThreadError::OutOfStacks);
+        }
+
+        let mut state = CPURegistersState::default();
+        state.rip = entry.as_u64();
+        state.rsp = stack_end.as_u64() - 8;
+        state.rdi = arg;
+        state.cs = segments::get_user_cs().0 as u64;
+        state.ss = segments::get_user_ds().0 as u64;
+        // enable interrupts
+        state.rflags = 0x200;
+
+        parent_proc.add_thread(tid.clone());
+
+        log::debug!(
+            "Initialized context for new user thread
+            thread_id={}, page_table=0x{:x}, rip=0x{:x},
+            stack_end=0x{:x}",
+            tid.as_u64(),
+            parent_proc.cr3,
+            entry.as_u64(),
+            stack_end.as_u64()
+        );
+
+        Ok(Thread {
+            is_user: true,
+            parent_pid: pid,
+            context: Box::new(ContextType::SavedContext(state)),
+            name,
+            thread_id: tid,
+            state: ThreadState::Waiting,
+            sched_count: 0,
+            stack_start: stack_end,
+            cr3: parent_proc.cr3,
+            syscall_stack_start: Some(syscall_stack_res.unwrap()),
+            fpu_state: Box::new(FPUState::new()),
         })
     }
 
@@ -230,20 +391,40 @@ impl Thread {
         // function exists, now check if it is a user process
         let proc = parent_proc.unwrap();
 
+        let tid_res = reserve_tid(proc);
+        if tid_res.is_err() {
+            return Err(tid_res.unwrap_err());
+        }
+
+        let tid = tid_res.unwrap();
+
         let stack: VirtualAddress;
         let func_addr: VirtualAddress;
 
         if proc.is_usermode() {
             let stack_alloc_result = STACK_ALLOCATOR.lock().alloc_stack();
             if stack_alloc_result.is_err() {
-                panic!("Out of stack memory. Failed to allocate memory for thread.");
+                log::error!("Out of stack memory. Failed to allocate memory for thread.");
+                release_tid(tid);
+                return Err(ThreadError::OutOfStacks);
             }
+            let kernel_stack = stack_alloc_result.unwrap();
             // allocate a new stack for the kernel
-            stack = utils::map_user_stack(
-                stack_alloc_result.unwrap(),
+            let map_result = utils::map_user_stack(
+                kernel_stack,
                 proc.threads.len(),
                 proc.pt_root.as_mut().unwrap().as_mut(),
             );
+            if map_result.is_err() {
+                log::error!("Failed to map user stack: {:?}", map_result.unwrap_err());
+                STACK_ALLOCATOR
+                    .lock()
+                    .free_stack(kernel_stack)
+                    .expect("Failed to free stack");
+                release_tid(tid);
+                return Err(ThreadError::OutOfStacks);
+            }
+            stack = map_result.unwrap();
 
             // map function to given address:
             func_addr =
@@ -251,7 +432,9 @@ impl Thread {
         } else {
             let stack_alloc_result = STACK_ALLOCATOR.lock().alloc_stack();
             if stack_alloc_result.is_err() {
-                panic!("Out of stack memory. Failed to allocate memory for thread.");
+                log::error!("Out of stack memory. Failed to allocate memory for thread.");
+                release_tid(tid);
+                return Err(ThreadError::OutOfStacks);
             }
             stack = stack_alloc_result.unwrap();
             func_addr = function_addr;
@@ -265,7 +448,6 @@ impl Thread {
             stack_end: VirtualAddress::from_u64(stack.as_u64() + STACK_SIZE as u64),
         };
 
-        let tid = new_tid();
         proc.add_thread(tid.clone());
 
         log::debug!(
@@ -292,6 +474,7 @@ impl Thread {
             stack_start: stack,
             cr3: parent_cr3,
             syscall_stack_start: None,
+            fpu_state: Box::new(FPUState::new()),
         })
     }
 
@@ -310,8 +493,25 @@ impl Thread {
         VirtualAddress::from_u64(self.stack_start.as_u64() + STACK_SIZE as u64)
     }
 
+    /// points the syscall IST slot and RSP0 at the syscall stack of this thread,
+    /// they can share the stack, a thread in ring 3 is never inside a system call.
+    /// nothing on RSP0 blocks today, faults run with interrupts off and the one that
+    /// kills the process never comes back, but a fault that sleeps would need it.
+    #[inline]
+    fn load_kernel_stacks(&self) {
+        if let Some(stack_start) = self.syscall_stack_start {
+            let stack_end = stack_start.as_u64() + utils::THREAD_SYSCALL_STACK_SIZE;
+            syscall::set_syscall_stack(stack_end);
+            interrupt_stacks::load_privilege_stack(stack_end);
+        } else {
+            syscall::set_default_syscall_stack();
+            interrupt_stacks::load_default_privilege_stack();
+        }
+    }
+
     #[inline]
     pub fn load_state(&self) {
+        self.fpu_state.restore();
         match self.context.as_ref() {
             ContextType::InitContext(ctx) => {
                 // initial context, create a new context object:
@@ -321,15 +521,7 @@ impl Thread {
                     (segments::get_kernel_cs().0, segments::get_kernel_ds().0)
                 };
 
-                if self.syscall_stack_start.is_some() {
-                    // load this custom stack:
-                    let stack_end = self.syscall_stack_start.unwrap().as_u64()
-                        + utils::THREAD_SYSCALL_STACK_SIZE;
-                    // set this stack
-                    syscall::set_syscall_stack(stack_end);
-                } else {
-                    syscall::set_default_syscall_stack();
-                }
+                self.load_kernel_stacks();
 
                 mmu::set_page_table_address(PhysicalAddress::from_u64(ctx.cr3_base));
 
@@ -343,16 +535,8 @@ impl Thread {
                 )
             }
             ContextType::SavedContext(ctx) => {
+                self.load_kernel_stacks();
                 // load page tables:
-                if self.syscall_stack_start.is_some() {
-                    // load this custom stack:
-                    let stack_end = self.syscall_stack_start.unwrap().as_u64()
-                        + utils::THREAD_SYSCALL_STACK_SIZE;
-                    // set this stack
-                    syscall::set_syscall_stack(stack_end);
-                } else {
-                    syscall::set_default_syscall_stack();
-                }
                 mmu::set_page_table_address(PhysicalAddress::from_u64(self.cr3));
                 CPURegistersState::load_state(&ctx)
             }
@@ -3,6 +3,7 @@ extern crate spin;
 
 use core::arch::asm;
 
+use crate::acpi::lapic::{self, LAPICUtils, LapicTimerMode};
 use crate::cpu::{enable_interrupts, disable_interrupts};
 use crate::cpu::tsc::{safe_ticks_from_ns, TSCTimerShot, TSC};
 use crate::mm::Alignment;
@@ -19,7 +20,7 @@ pub enum Time {
 }
 
 /// each tick contains these many time nanoseconds.
-const SYSTEM_TICK_DURATION: u64 = 100 * 1000000;
+pub const SYSTEM_TICK_DURATION: u64 = 100 * 1000000;
 
 /// SystemTicker that keeps tracks of number of
 /// ticks and provides few functions to manage timer.
@@ -71,24 +72,43 @@ impl SystemTicker {
 
 static SYSTEM_TICKS: Mutex<SystemTicker> = Mutex::new(SystemTicker::empty());
 
-/// Provides methods to control timer
+/// Provides methods to control timer, works with both the LAPIC timer modes.
+/// in periodic mode, a "shot" restarts the period, so the next thread
+/// always gets a full tick like it does with the TSC deadline.
 pub struct SystemTimer;
 
 impl SystemTimer {
     #[inline]
     pub fn next_shot() {
-        TSCTimerShot::reset_current_shot();
-        TSCTimerShot::create_shot_after_ns(SYSTEM_TICK_DURATION);
+        match lapic::timer_mode() {
+            LapicTimerMode::TSCDeadline => {
+                TSCTimerShot::reset_current_shot();
+                TSCTimerShot::create_shot_after_ns(SYSTEM_TICK_DURATION);
+            }
+            LapicTimerMode::Periodic => {
+                LAPICUtils::start_periodic_timer(lapic::periodic_initial_count());
+            }
+        }
     }
 
     #[inline]
     pub fn enable_shot() {
-        TSCTimerShot::create_shot_from_ns(SYSTEM_TICK_DURATION);
+        match lapic::timer_mode() {
+            LapicTimerMode::TSCDeadline => {
+                TSCTimerShot::create_shot_from_ns(SYSTEM_TICK_DURATION);
+            }
+            LapicTimerMode::Periodic => {
+                LAPICUtils::start_periodic_timer(lapic::periodic_initial_count());
+            }
+        }
     }
 
     #[inline]
     pub fn disable_shots() {
-        TSCTimerShot::reset_current_shot();
+        match lapic::timer_mode() {
+            LapicTimerMode::TSCDeadline => TSCTimerShot::reset_current_shot(),
+            LapicTimerMode::Periodic => LAPICUtils::stop_periodic_timer(),
+        }
     }
 
     /// This function will be called after every timer show
@@ -101,7 +121,7 @@ impl SystemTimer {
 
     #[inline]
     pub fn manual_shot() {
-        TSCTimerShot::reset_current_shot();
+        Self::disable_shots();
         // creates a manual time shot:
         unsafe {
             // call an interrupt over line 48
@@ -129,6 +149,45 @@ pub fn wait_ns(ns: u64) {
     }
 }
 
+/// converts TSC value to nanoseconds, 128-bit math is used to not overflow
+/// for long uptimes. The vDSO time page uses the same conversion.
+#[inline]
+pub fn tsc_to_ns(tsc: u64) -> u64 {
+    let frequency = TSC::read_cpu_frequency();
+    if frequency == 0 {
+        return 0;
+    }
+
+    ((tsc as u128 * Time::Second as u128) / frequency as u128) as u64
+}
+
+/// monotonic time in nanoseconds
+#[inline]
+pub fn monotonic_ns() -> u64 {
+    tsc_to_ns(TSC::read_tsc().u64())
+}
+
+/// unix time of the boot in nanoseconds.
+// TODO: read it from the CMOS RTC, until then the wall clock starts at the epoch.
+const BOOT_EPOCH_NS: u64 = 0;
+
+/// wall clock time in nanoseconds since the unix epoch.
+#[inline]
+pub fn realtime_ns() -> u64 {
+    BOOT_EPOCH_NS + monotonic_ns()
+}
+
+/// smallest step of both the clocks in nanoseconds, one TSC cycle.
+pub fn clock_resolution_ns() -> u64 {
+    let frequency = TSC::read_cpu_frequency();
+    if frequency == 0 {
+        // the TSC is not calibrated yet, the clocks read 0 until then.
+        return SYSTEM_TICK_DURATION;
+    }
+
+    core::cmp::max(Time::Second as u64 / frequency, 1)
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 pub struct PosixTimeval {
@@ -151,6 +210,16 @@ impl PosixTimeval {
         }
     }
 
+    pub fn from_ns(ns: u64) -> Self {
+        let seconds = ns / Time::Second as u64;
+        let offset_us = (ns % Time::Second as u64) / Time::MicroSecond as u64;
+
+        PosixTimeval {
+            tv_sec: seconds as i64,
+            tv_usec: offset_us as i64,
+        }
+    }
+
     #[inline]
     pub fn empty() -> Self {
         PosixTimeval {
@@ -174,13 +243,12 @@ impl PosixTimeval {
 
 /// this will disable timer ticks and interrupts
 pub fn pause_events() {
-    TSCTimerShot::reset_current_shot();
+    SystemTimer::disable_shots();
 }
 
 /// this will enable timer ticks and interrupts
 pub fn resume_events() {
-    TSCTimerShot::reset_current_shot();
-    TSCTimerShot::create_shot_after_ns(SYSTEM_TICK_DURATION);
+    SystemTimer::next_shot();
 }
 
 #[inline]
@@ -194,4 +262,3 @@ pub fn release_cpu_lock() {
     enable_interrupts();
     resume_events();
 }
-
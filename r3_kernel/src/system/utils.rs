@@ -1,31 +1,49 @@
 extern crate alloc;
 extern crate object;
 
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
+use crate::cpu;
+use crate::cpu::mmu;
 use crate::mm::stack::STACK_SIZE;
 use crate::system::filesystem::vfs::FILESYSTEM;
 use crate::system::filesystem::FSOps;
 use crate::system::filesystem::FileDescriptor;
+use crate::system::filesystem::POSIXOpenFlags;
 use crate::system::loader;
+use crate::system::rlimit::ResourceLimits;
+use crate::system::vdso;
 
 use core::{mem, ptr};
-use object::{Object, ObjectSegment};
+use lazy_static::lazy_static;
+use object::read::elf::{ElfFile64, ProgramHeader};
+use object::{Endianness, Object, ObjectSegment};
+use spin::Mutex;
 
 use crate::mm::{
-    paging::KernelVirtualMemoryManager, paging::Page, paging::PageEntryFlags, paging::PageTable,
-    paging::VirtualMemoryManager, phy::Frame, phy::PhysicalMemoryManager, Alignment, MemorySizes,
-    PageTableLevel, PhysicalAddress, VirtualAddress,
+    p_to_v, paging::KernelVirtualMemoryManager, paging::Page, paging::PageEntryFlags,
+    paging::PageTable, paging::VirtualMemoryManager, phy::Frame, phy::PhysicalMemoryManager,
+    Alignment, MemorySizes, PageTableLevel, PhysicalAddress, VirtualAddress,
 };
 
+// process layout -
+//
+// 0x000000400000     | .text, .bss, .data  | loaded at the ELF segment addresses
+// heap_start         | heap, grows up      | first 2MiB boundary after the last segment
+// USER_HEAP_END      | (unused)            | the heap never crosses this
+// 0x700000000000     | fork scratch        | USER_TEMP_STACK_MAPPING, 2MiB
+// USER_STACK_ADDRESS | stack slots         | PROCESS_STACKS_SIZE, up to USER_VIRT_END
+//
+// each stack slot is 4MiB: the 2MiB thread stack followed by a 2MiB gap, the
+// syscall stack of the slot lives in it's gap. argv is pushed at the top of the
+// thread stack. ELF processes and the processes running kernel functions in
+// user mode both take their stacks from the slots.
+
 /// Area in which user code will be allocated
 pub const USER_CODE_ADDRESS: u64 = 0x400000;
-/// Area in which user stack will be allocated
-pub const USER_STACK_ADDRESS: u64 = 0x200000000;
-
-// process layout -
-// | --- .text, .bss, .data ---- | ---- heap memory ----- | ---- stacks for each thread ------|
-// argv and envs |
 
 /// Start of the user virtual address space
 pub const USER_VIRT_START: u64 = 0;
@@ -38,6 +56,9 @@ pub const USER_VIRT_END: u64 = 0x800000000000;
 /// So each allocation will cost 2MiB physically and 4MiB virtually
 pub const PROCESS_STACKS_SIZE: u64 = 16 * MemorySizes::OneGiB as u64;
 
+/// Area in which user stacks will be allocated, the first stack slot.
+pub const USER_STACK_ADDRESS: u64 = USER_VIRT_END - PROCESS_STACKS_SIZE;
+
 /// Size of stack for each thread
 pub const THREAD_STACK_SIZE: u64 = 2 * MemorySizes::OneMib as u64;
 
@@ -50,9 +71,22 @@ pub const STACK_SPACE_OFFSET: u64 = 64 * MemorySizes::OneKiB as u64;
 
 pub const USER_TEMP_STACK_MAPPING: u64 = 0x700000000000;
 
+/// the heap stays below the fork scratch mapping and the stack space.
+pub const USER_HEAP_END: u64 = USER_TEMP_STACK_MAPPING;
+
+/// number of stack slots in the stack space, each slot is a 2MiB stack followed by a 2MiB gap
+pub const MAX_STACK_SLOTS: u64 = PROCESS_STACKS_SIZE / (THREAD_STACK_SIZE * 2);
+
 /// use huge pages to map heap
 pub const USE_HUGEPAGE_HEAP: bool = true;
 
+/// use huge pages to map the 2MiB aligned parts of large ELF segments
+pub const USE_HUGEPAGE_CODE: bool = true;
+
+/// map the pages of ELF segments on the first access instead of at exec time,
+/// a fault in a 2MiB page that one segment covers maps all of it at once.
+pub const DEMAND_PAGE_CODE: bool = true;
+
 /// maximum file-descriptors that a process can have open any time
 pub const MAX_FILE_DESCRIPTORS: usize = 512;
 
@@ -66,13 +100,21 @@ pub enum ProcessError {
     InvalidELF,
     MaxFDLimit,
     InvalidFD,
+    FDCloseError,
     CodeAllocationError,
 }
 
+/// an open file description, the descriptors duplicated from one another
+/// (by fork or dup) point to the same one, so they share the offset and the
+/// file is closed only when the last of them goes away.
+pub type OpenFileRef = Arc<Mutex<FileDescriptor>>;
+
 #[derive(Debug, Clone)]
 pub struct FDEntry {
     index: usize,
-    pub fd: FileDescriptor,
+    pub file: OpenFileRef,
+    /// closed by execvp, this belongs to the descriptor, not the shared open file.
+    pub cloexec: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -101,8 +143,59 @@ pub struct ProcessData {
     pub code_entry: VirtualAddress,
     /// code page count - code segment uses 4KiB pages
     pub code_pages: u64,
-    /// code references - number of child processes referencing the same section
-    pub code_ref: u64,
+    /// one reference for every process sharing the code section (and the lower half with
+    /// it), forked processes share it until they exec or exit.
+    pub code_ref: Arc<()>,
+    /// number of syscall stacks allocated, slot 0 is always used by the main thread
+    pub n_syscall_stacks: u64,
+    /// syscall stacks of exited threads, still mapped, given to the next new thread.
+    pub free_syscall_stacks: Vec<u64>,
+    /// segments that are mapped on page faults, None if the code was mapped at exec time.
+    pub lazy_image: Option<Arc<LazyImage>>,
+    /// setrlimit limits, checked when descriptors, stacks and heap are allocated.
+    pub limits: ResourceLimits,
+}
+
+impl ProcessData {
+    /// true while a forked process shares the code and the lower half with this one.
+    #[inline]
+    pub fn is_code_shared(&self) -> bool {
+        Arc::strong_count(&self.code_ref) > 1
+    }
+}
+
+/// an ELF segment (PT_LOAD) that is mapped page by page on first access.
+#[derive(Debug, Clone)]
+pub struct LazySegment {
+    /// address and size of the segment in memory
+    pub vaddr: u64,
+    pub mem_size: u64,
+    /// location of the file backed part in the image, the rest of the segment is BSS
+    pub file_offset: u64,
+    pub file_size: u64,
+    /// ELF p_flags of the segment, for the per-segment page protections
+    pub flags: u32,
+}
+
+/// execute and write bits of the ELF p_flags.
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+
+#[derive(Debug)]
+pub struct LazyImage {
+    /// contents of the executable, the pages are copied from here
+    pub data: Vec<u8>,
+    pub segments: Vec<LazySegment>,
+}
+
+lazy_static! {
+    /// the lazy images by the physical address of the page table they are mapped in.
+    /// the page fault handler can run while a syscall holds `PROCESS_POOL`,
+    /// so it finds the image here and not through the process.
+    static ref LAZY_IMAGES: Mutex<BTreeMap<u64, Arc<LazyImage>>> = Mutex::new(BTreeMap::new());
+    /// heap ranges given back with MADV_DONTNEED, by the page table they belong to.
+    /// their pages are mapped again as zeroed pages on the next access.
+    static ref DISCARDED_HEAP: Mutex<BTreeMap<u64, Vec<(u64, u64)>>> = Mutex::new(BTreeMap::new());
 }
 
 pub struct ProcessStackManager;
@@ -119,6 +212,12 @@ impl ProcessStackManager {
         }
     }
 
+    /// start of the thread stack in the given slot.
+    #[inline]
+    pub fn get_slot_addr(stack_space_start: VirtualAddress, slot: u64) -> VirtualAddress {
+        VirtualAddress::from_u64(stack_space_start.as_u64() + slot * THREAD_STACK_SIZE * 2)
+    }
+
     #[inline]
     pub fn get_stack_addr(proc_data: &mut ProcessData, index: u64) -> VirtualAddress {
         VirtualAddress::from_u64(
@@ -135,7 +234,9 @@ impl ProcessStackManager {
         vmm: &mut VirtualMemoryManager,
         stack_index: u64,
     ) -> Result<VirtualAddress, ProcessError> {
-        if stack_index > proc_data.n_stacks {
+        // syscall stacks live in the gaps between thread stacks,
+        // so every slot of the stack space can hold one.
+        if stack_index >= MAX_STACK_SLOTS {
             return Err(ProcessError::StackOOB);
         }
 
@@ -144,7 +245,13 @@ impl ProcessStackManager {
 
         // allocate memory:
         for idx in 0..n_pages {
-            let frame = PhysicalMemoryManager::alloc().expect("Physical Memory OOM");
+            let frame_opt = PhysicalMemoryManager::alloc();
+            if frame_opt.is_none() {
+                // the pages mapped so far go away with the process.
+                log::error!("Syscall stack allocation failed, out of memory!");
+                return Err(ProcessError::StackOOM);
+            }
+            let frame = frame_opt.unwrap();
             let page = Page::from_address(VirtualAddress::from_u64(
                 syscall_stack_addr.as_u64() + (idx as u64 * 4 * MemorySizes::OneKiB as u64),
             ));
@@ -156,24 +263,79 @@ impl ProcessStackManager {
         Ok(syscall_stack_addr)
     }
 
+    #[inline]
+    pub fn allocate_next_syscall_stack(
+        proc_data: &mut ProcessData,
+        vmm: &mut VirtualMemoryManager,
+    ) -> Result<VirtualAddress, ProcessError> {
+        // the stack of an exited thread is still mapped.
+        if let Some(stack_index) = proc_data.free_syscall_stacks.pop() {
+            return Ok(Self::get_stack_addr(proc_data, stack_index));
+        }
+
+        let stack_index = proc_data.n_syscall_stacks;
+        let stack_res = Self::allocate_syscall_stack(proc_data, vmm, stack_index);
+        if stack_res.is_ok() {
+            proc_data.n_syscall_stacks += 1;
+        }
+
+        stack_res
+    }
+
+    /// gives the syscall stack of an exited thread back to the process, the memory
+    /// stays mapped because the exiting thread is still running on it.
+    #[inline]
+    pub fn free_syscall_stack(
+        proc_data: &mut ProcessData,
+        addr: VirtualAddress,
+    ) -> Result<(), ProcessError> {
+        let first_stack = Self::get_stack_addr(proc_data, 0).as_u64();
+        let slot_size = THREAD_STACK_SIZE * 2;
+        if addr.as_u64() < first_stack || (addr.as_u64() - first_stack) % slot_size != 0 {
+            return Err(ProcessError::StackOOB);
+        }
+
+        let stack_index = (addr.as_u64() - first_stack) / slot_size;
+        // slot 0 belongs to the main thread, it goes away with the process.
+        if stack_index == 0 || stack_index >= proc_data.n_syscall_stacks {
+            return Err(ProcessError::StackOOB);
+        }
+
+        proc_data.free_syscall_stacks.push(stack_index);
+        Ok(())
+    }
+
     #[inline]
     pub fn allocate_stack(
         proc_data: &mut ProcessData,
         vmm: &mut VirtualMemoryManager,
         unmap_k: bool,
     ) -> Result<VirtualAddress, ProcessError> {
+        if THREAD_STACK_SIZE > proc_data.limits.stack.current {
+            log::debug!(
+                "Stack allocation failed, RLIMIT_STACK is {} bytes.",
+                proc_data.limits.stack.current
+            );
+            return Err(ProcessError::StackOOM);
+        }
+
         // is there a free stack in the pool?
         if proc_data.free_stack_holes.len() > 0 {
             let stk_index = proc_data.free_stack_holes.pop().unwrap();
             // return it's address:
-            let vaddr = VirtualAddress::from_u64(
-                proc_data.stack_space_start.as_u64() + (stk_index * 4 * MemorySizes::OneMib as u64),
-            );
+            let vaddr = Self::get_slot_addr(proc_data.stack_space_start, stk_index);
 
             Self::zero(vaddr);
             return Ok(vaddr);
         }
 
+        // n_stacks counts 2MiB units, a slot is a stack and it's gap.
+        let slot = proc_data.n_stacks / 2;
+        if slot >= MAX_STACK_SLOTS {
+            log::error!("Stack allocation failed, all {} slots are used.", MAX_STACK_SLOTS);
+            return Err(ProcessError::StackOOB);
+        }
+
         // allocate a new 2MiB stack:
         let alloc_result = PhysicalMemoryManager::alloc_huge_page();
         if alloc_result.is_none() {
@@ -181,10 +343,7 @@ impl ProcessStackManager {
             return Err(ProcessError::StackOOM);
         }
 
-        let page = Page::from_address(VirtualAddress::from_u64(
-            proc_data.stack_space_start.as_u64()
-                + proc_data.n_stacks * 4 * MemorySizes::OneMib as u64,
-        ));
+        let page = Page::from_address(Self::get_slot_addr(proc_data.stack_space_start, slot));
 
         let map_result = vmm.map_huge_page(
             page,
@@ -241,17 +400,26 @@ impl ProcessStackManager {
         addr: VirtualAddress,
     ) -> Result<(), ProcessError> {
         // check out of bounds
-        if addr.as_u64() > (proc_data.stack_space_start.as_u64() + STACK_SIZE as u64) {
+        let stack_space_start = proc_data.stack_space_start.as_u64();
+        if addr.as_u64() < stack_space_start
+            || addr.as_u64() >= stack_space_start + PROCESS_STACKS_SIZE
+        {
             return Err(ProcessError::StackOOB);
         }
 
-        let aligned_loc = Alignment::align_down(addr.as_u64(), 4 * MemorySizes::OneMib as u64);
-        let nth = aligned_loc / (4 * MemorySizes::OneMib as u64);
+        let nth = (addr.as_u64() - stack_space_start) / (THREAD_STACK_SIZE * 2);
 
         proc_data.free_stack_holes.push(nth);
         Ok(())
     }
 
+    /// fork only copies the first stack of the process, the caller must be running on it.
+    #[inline]
+    pub fn is_on_cloned_stack(proc_data: &ProcessData, rsp: u64) -> bool {
+        let stack_start = proc_data.stack_space_start.as_u64();
+        rsp >= stack_start && rsp <= stack_start + STACK_SIZE as u64
+    }
+
     #[inline]
     pub fn allocate_and_clone(
         child: &mut ProcessData,
@@ -293,6 +461,46 @@ impl ProcessStackManager {
             ptr::write_bytes(stack_ptr, 0, MemorySizes::OneMib as usize * 2);
         }
     }
+
+    /// writes the arguments at the top of a freshly reset stack in the sysv
+    /// layout: argc, argv[..], NULL, envp NULL, auxv AT_NULL. returns the new
+    /// stack pointer (16 byte aligned, pointing to argc) and the argv address.
+    pub fn push_arguments(
+        stack_end: VirtualAddress,
+        args: &[String],
+    ) -> (VirtualAddress, VirtualAddress) {
+        let mut top = stack_end.as_u64();
+        let mut arg_ptrs: Vec<u64> = Vec::with_capacity(args.len());
+
+        // strings go at the very top, the stack was zeroed, so they are null terminated.
+        for arg in args {
+            top -= arg.len() as u64 + 1;
+            unsafe {
+                ptr::copy_nonoverlapping(arg.as_ptr(), top as *mut u8, arg.len());
+                *((top + arg.len() as u64) as *mut u8) = 0;
+            }
+            arg_ptrs.push(top);
+        }
+
+        top = Alignment::align_down(top, 16);
+
+        // argc + argv + NULL + envp NULL + two auxv words
+        let n_words = 1 + arg_ptrs.len() + 1 + 1 + 2;
+        if n_words % 2 == 1 {
+            top -= 8;
+        }
+        top -= (n_words * 8) as u64;
+
+        let words = unsafe { &mut *ptr::slice_from_raw_parts_mut(top as *mut u64, n_words) };
+        words.fill(0);
+        words[0] = arg_ptrs.len() as u64;
+        words[1..1 + arg_ptrs.len()].copy_from_slice(&arg_ptrs);
+
+        (
+            VirtualAddress::from_u64(top),
+            VirtualAddress::from_u64(top + 8),
+        )
+    }
 }
 
 pub struct ProcessHeapAllocator;
@@ -316,6 +524,10 @@ impl ProcessHeapAllocator {
         // allocate the heap
         let mut n_pages = aligned_size / align_size;
 
+        if (proc_vmm.heap_pages + n_pages) * align_size > proc_vmm.limits.data.current {
+            return Err(ProcessError::HeapOOM);
+        }
+
         // can we re-use already allocated pages?
         if proc_vmm.heap_pages + n_pages <= proc_vmm.heap_alloc_pages {
             let current_pages = proc_vmm.heap_pages;
@@ -332,6 +544,15 @@ impl ProcessHeapAllocator {
             return Err(ProcessError::HeapOOM);
         }
 
+        // checked before anything is mapped, so the stacks are never overwritten.
+        if new_addr + n_pages * align_size > USER_HEAP_END {
+            log::error!(
+                "Heap expansion to 0x{:x} would cross the stack space.",
+                new_addr + n_pages * align_size
+            );
+            return Err(ProcessError::HeapOOB);
+        }
+
         for _ in 0..n_pages {
             let new_page = Page::from_address(VirtualAddress::from_u64(new_addr));
             let alloc_result = if USE_HUGEPAGE_HEAP {
@@ -397,30 +618,171 @@ impl ProcessHeapAllocator {
 
     #[inline]
     pub fn reset(proc_data: &mut ProcessData, vmm: &mut VirtualMemoryManager) {
-        let current_allocated = proc_data.heap_alloc_pages;
-        let unmap_start = proc_data.heap_start;
-
-        for idx in 0..current_allocated {
-            let page = if USE_HUGEPAGE_HEAP {
-                Page::from_address(VirtualAddress::from_u64(
-                    unmap_start.as_u64() + (idx as u64 * MemorySizes::OneMib as u64 * 2),
-                ))
+        let align_size = if USE_HUGEPAGE_HEAP {
+            2 * MemorySizes::OneMib as u64
+        } else {
+            4 * MemorySizes::OneKiB as u64
+        };
+
+        // discarded pages leave holes, and the ones touched again are 4KiB pages
+        // even in a huge page heap.
+        let mut addr = proc_data.heap_start.as_u64();
+        let end = addr + proc_data.heap_alloc_pages * align_size;
+        while addr < end {
+            let page_addr = VirtualAddress::from_u64(addr);
+            if vmm.translate(page_addr).is_none() {
+                addr = addr + 4 * MemorySizes::OneKiB as u64;
+                continue;
+            }
+
+            let step = if vmm.is_huge_page(&page_addr) {
+                2 * MemorySizes::OneMib as u64
             } else {
-                Page::from_address(VirtualAddress::from_u64(
-                    unmap_start.as_u64() + (idx as u64 * MemorySizes::OneKiB as u64 * 4),
-                ))
+                4 * MemorySizes::OneKiB as u64
             };
 
             // unmap the heap page:
-            vmm.unmap_page(page).expect("Failed to unmap the heap page");
+            vmm.unmap_page(Page::from_address(page_addr))
+                .expect("Failed to unmap the heap page");
+            addr = addr + step;
         }
 
+        cpu::without_interrupts(|| {
+            DISCARDED_HEAP.lock().remove(&vmm.l4_phy_addr.as_u64());
+        });
+
         // reset the heap:
         proc_data.max_heap_pages = 0;
         proc_data.heap_pages = 0;
         proc_data.heap_alloc_pages = 0;
     }
 
+    /// true if [start, end) is below the current break.
+    #[inline]
+    pub fn contains(proc_data: &mut ProcessData, start: u64, end: u64) -> bool {
+        start >= proc_data.heap_start.as_u64()
+            && end <= Self::current_end_address(proc_data).as_u64()
+    }
+
+    /// unmaps the pages of [start, end) and frees their frames, the range reads
+    /// as zeros afterwards. huge pages only partly in the range are zeroed in
+    /// place. when the lower half is shared with a forked process nothing is
+    /// unmapped, the whole range is zeroed in place.
+    pub fn discard(proc_data: &mut ProcessData, vmm: &mut VirtualMemoryManager, start: u64, end: u64) {
+        let page_size = 4 * MemorySizes::OneKiB as u64;
+        let huge_page_size = 2 * MemorySizes::OneMib as u64;
+        let shared = proc_data.is_code_shared();
+
+        let mut addr = start;
+        while addr < end {
+            let page_addr = VirtualAddress::from_u64(addr);
+            let frame_opt = vmm.translate_to_frame(&page_addr);
+            if frame_opt.is_none() {
+                // discarded before and not touched since
+                addr = addr + page_size;
+                continue;
+            }
+
+            let (page_start, page_end) = if vmm.is_huge_page(&page_addr) {
+                let huge_start = Alignment::align_down(addr, huge_page_size);
+                (huge_start, huge_start + huge_page_size)
+            } else {
+                (addr, addr + page_size)
+            };
+
+            if shared || page_start < start || page_end > end {
+                let zero_end = core::cmp::min(page_end, end);
+                unsafe {
+                    ptr::write_bytes(page_addr.get_mut_ptr::<u8>(), 0, (zero_end - addr) as usize);
+                }
+                addr = zero_end;
+                continue;
+            }
+
+            vmm.unmap_page(Page::from_address(page_addr))
+                .expect("Failed to unmap the heap page");
+            if page_end - page_start == huge_page_size {
+                PhysicalMemoryManager::free_huge_page(frame_opt.unwrap());
+            } else {
+                PhysicalMemoryManager::free(frame_opt.unwrap());
+            }
+            addr = page_end;
+        }
+
+        if shared {
+            return;
+        }
+
+        cpu::without_interrupts(|| {
+            let mut discarded_lock = DISCARDED_HEAP.lock();
+            let ranges = discarded_lock
+                .entry(vmm.l4_phy_addr.as_u64())
+                .or_insert(Vec::new());
+            if !ranges.iter().any(|(r_start, r_end)| *r_start <= start && *r_end >= end) {
+                ranges.push((start, end));
+            }
+        });
+    }
+
+    /// maps a zeroed page at `addr` if it was discarded and not touched since,
+    /// `vmm` must be the active address space. returns false if nothing was mapped.
+    pub fn map_zero_page(vmm: &VirtualMemoryManager, addr: &VirtualAddress) -> bool {
+        let is_discarded = cpu::without_interrupts(|| {
+            DISCARDED_HEAP
+                .lock()
+                .get(&vmm.l4_phy_addr.as_u64())
+                .map_or(false, |ranges| {
+                    ranges
+                        .iter()
+                        .any(|(start, end)| addr.as_u64() >= *start && addr.as_u64() < *end)
+                })
+        });
+
+        if !is_discarded {
+            return false;
+        }
+
+        let page_size = 4 * MemorySizes::OneKiB as u64;
+        let page_addr = VirtualAddress::from_u64(Alignment::align_down(addr.as_u64(), page_size));
+        if vmm.translate(page_addr).is_some() {
+            return false;
+        }
+
+        let frame_opt = PhysicalMemoryManager::alloc();
+        if frame_opt.is_none() {
+            log::error!("RAM OOM while mapping discarded page 0x{:x}", page_addr.as_u64());
+            return false;
+        }
+
+        let map_result = vmm.map_page(
+            Page::from_address(page_addr),
+            frame_opt.unwrap(),
+            PageEntryFlags::user_flags(),
+        );
+        if map_result.is_err() {
+            log::error!("failed to map discarded page: {:?}", map_result.unwrap_err());
+            return false;
+        }
+
+        unsafe {
+            ptr::write_bytes(page_addr.get_mut_ptr::<u8>(), 0, page_size as usize);
+        }
+        true
+    }
+
+    /// the heap can grow from heap_start up to USER_HEAP_END.
+    #[inline]
+    pub fn set_limit(proc_data: &mut ProcessData) {
+        let align_size = if USE_HUGEPAGE_HEAP {
+            2 * MemorySizes::OneMib as u64
+        } else {
+            4 * MemorySizes::OneKiB as u64
+        };
+
+        let max_heap_size = USER_HEAP_END.saturating_sub(proc_data.heap_start.as_u64());
+        proc_data.max_heap_pages = max_heap_size / align_size;
+    }
+
     #[inline]
     pub fn set_break_at(
         proc_data: &mut ProcessData,
@@ -477,7 +839,7 @@ impl CodeMapper {
         let file_buffer = file_buffer_res.unwrap();
         // map this buffer as ELF
         let buffer_ref = &file_buffer[0..];
-        let elf_result = object::File::parse(buffer_ref);
+        let elf_result = ElfFile64::<Endianness>::parse(buffer_ref);
 
         if elf_result.is_err() {
             log::error!("ELF Loader Error {:?}", elf_result.unwrap_err());
@@ -486,6 +848,8 @@ impl CodeMapper {
 
         let elf = elf_result.unwrap();
         let mut total_pages = 0;
+        let mut image_end = USER_CODE_ADDRESS;
+        let mut lazy_segments = Vec::new();
 
         // map all the segments:
         for segment in elf.segments() {
@@ -500,47 +864,97 @@ impl CodeMapper {
             let section_start = segment.address();
             let aligned_sec_start =
                 Alignment::align_down(section_start, 4 * MemorySizes::OneKiB as u64);
-            let aligned_size = Alignment::align_up(segment.size(), 4 * MemorySizes::OneKiB as u64);
+            let aligned_sec_end = Alignment::align_up(
+                section_start + segment.size(),
+                4 * MemorySizes::OneKiB as u64,
+            );
 
-            let n_pages = aligned_size / (4 * MemorySizes::OneKiB as u64);
+            let n_pages = (aligned_sec_end - aligned_sec_start) / (4 * MemorySizes::OneKiB as u64);
             total_pages = total_pages + n_pages;
 
-            for i in 0..n_pages {
+            if aligned_sec_end > USER_HEAP_END {
+                log::error!(
+                    "{} segment at 0x{:x} is out of the code area.",
+                    path,
+                    section_start
+                );
+                return Err(ProcessError::InvalidELF);
+            }
+            image_end = core::cmp::max(image_end, aligned_sec_end);
+
+            if DEMAND_PAGE_CODE {
+                // nothing is mapped now, the page faults bring the pages in.
+                let (file_offset, file_size) = segment.file_range();
+                let endian = elf.endian();
+                let flags = elf
+                    .raw_segments()
+                    .iter()
+                    .find(|header| {
+                        header.p_type(endian) == object::elf::PT_LOAD
+                            && header.p_vaddr(endian) == section_start
+                    })
+                    .map(|header| header.p_flags(endian))
+                    .unwrap_or(0);
+
+                lazy_segments.push(LazySegment {
+                    vaddr: section_start,
+                    mem_size: segment.size(),
+                    file_offset,
+                    file_size: core::cmp::min(file_size, segment.size()),
+                    flags,
+                });
+                continue;
+            }
+
+            let chunks = Self::segment_chunks(aligned_sec_start, aligned_sec_end);
+
+            for (chunk_addr, is_huge) in chunks.iter() {
                 // map kernel and user pages
-                let frame = PhysicalMemoryManager::alloc().expect("RAM OOM");
-                let page = Page::from_address(VirtualAddress::from_u64(
-                    aligned_sec_start + (i * 4 * MemorySizes::OneKiB as u64),
-                ));
-                KernelVirtualMemoryManager::pt()
-                    .map_page(page, frame, PageEntryFlags::user_flags())
-                    .expect("Failed to map kernel page while mapping code.");
-                vmm.map_page(page, frame, PageEntryFlags::user_flags())
-                    .expect("Failed to map user page while mapping code");
+                let page = Page::from_address(VirtualAddress::from_u64(*chunk_addr));
+                if *is_huge {
+                    let frame = PhysicalMemoryManager::alloc_huge_page().expect("RAM OOM");
+                    KernelVirtualMemoryManager::pt()
+                        .map_huge_page(page, frame, PageEntryFlags::user_hugepage_flags())
+                        .expect("Failed to map kernel huge page while mapping code.");
+                    vmm.map_huge_page(page, frame, PageEntryFlags::user_hugepage_flags())
+                        .expect("Failed to map user huge page while mapping code");
+                } else {
+                    let frame = PhysicalMemoryManager::alloc().expect("RAM OOM");
+                    KernelVirtualMemoryManager::pt()
+                        .map_page(page, frame, PageEntryFlags::user_flags())
+                        .expect("Failed to map kernel page while mapping code.");
+                    vmm.map_page(page, frame, PageEntryFlags::user_flags())
+                        .expect("Failed to map user page while mapping code");
+                }
+            }
+
+            // zero the whole layout, the region is virtually contiguous even if
+            // it is mapped with mixed page sizes, so BSS is zeroed across both.
+            let start_ptr = VirtualAddress::from_u64(aligned_sec_start).get_mut_ptr::<u8>();
+            unsafe {
+                ptr::write_bytes(
+                    start_ptr,
+                    0,
+                    (aligned_sec_end - aligned_sec_start) as usize,
+                );
             }
 
             if let Ok(data) = segment.data() {
-                // zero this layout
-                let start_ptr = VirtualAddress::from_u64(aligned_sec_start).get_mut_ptr::<u8>();
+                // write data, only the file backed part of the segment is copied
+                // the remaining part (if any) is BSS and stays zeroed.
+                let copy_size = core::cmp::min(data.len() as u64, segment.size());
                 unsafe {
-                    ptr::write_bytes(
-                        start_ptr,
-                        0,
-                        n_pages as usize * 4 * MemorySizes::OneKiB as usize,
-                    );
-                    // write data
                     ptr::copy_nonoverlapping(
                         data.as_ptr(),
                         start_ptr.add((segment.address() - aligned_sec_start) as usize),
-                        segment.size() as usize,
+                        copy_size as usize,
                     );
                 }
             }
 
             // unmap kernel entries:
-            for i in 0..n_pages {
-                let page = Page::from_address(VirtualAddress::from_u64(
-                    aligned_sec_start + (i * 4 * MemorySizes::OneKiB as u64),
-                ));
+            for (chunk_addr, _) in chunks.iter() {
+                let page = Page::from_address(VirtualAddress::from_u64(*chunk_addr));
                 KernelVirtualMemoryManager::pt()
                     .unmap_page(page)
                     .expect("Failed to unmap mapped kernel pages.");
@@ -548,19 +962,68 @@ impl CodeMapper {
         }
 
         let entry_addr = elf.entry();
+        // the image is kept by the lazy segments, it must not be borrowed anymore.
+        mem::drop(elf);
         proc_vmm.code_entry = VirtualAddress::from_u64(entry_addr);
         proc_vmm.code_pages = total_pages;
 
-        // mark the end of heap as 2MiB aligned page
-        let aligned_hugepage_size = Alignment::align_up(
-            total_pages * 4 * MemorySizes::OneKiB as u64,
-            2 * MemorySizes::OneMib as u64,
-        );
-        proc_vmm.heap_start = VirtualAddress::from_u64(aligned_hugepage_size);
+        if DEMAND_PAGE_CODE {
+            let image = Arc::new(LazyImage {
+                data: file_buffer,
+                segments: lazy_segments,
+            });
+            Self::register_lazy_image(vmm, image.clone());
+            proc_vmm.lazy_image = Some(image);
+        }
+
+        // the heap starts at the first 2MiB boundary after the image, the
+        // segments are not contiguous, so it can't be derived from the page count.
+        let aligned_image_end = Alignment::align_up(image_end, 2 * MemorySizes::OneMib as u64);
+        proc_vmm.heap_start = VirtualAddress::from_u64(aligned_image_end);
 
         Ok(())
     }
 
+    /// splits the 4KiB aligned region into pages, the 2MiB aligned part in the middle
+    /// is mapped using huge pages and the unaligned head and tail using 4KiB pages.
+    /// returns the list of (page address, is huge page)
+    fn segment_chunks(start: u64, end: u64) -> Vec<(u64, bool)> {
+        let small_size = 4 * MemorySizes::OneKiB as u64;
+        let huge_size = 2 * MemorySizes::OneMib as u64;
+
+        let huge_start = Alignment::align_up(start, huge_size);
+        let huge_end = Alignment::align_down(end, huge_size);
+
+        let mut chunks = Vec::new();
+        if !USE_HUGEPAGE_CODE || huge_end <= huge_start {
+            // the segment does not cover a full huge page
+            let mut addr = start;
+            while addr < end {
+                chunks.push((addr, false));
+                addr = addr + small_size;
+            }
+            return chunks;
+        }
+
+        let mut addr = start;
+        while addr < huge_start {
+            chunks.push((addr, false));
+            addr = addr + small_size;
+        }
+
+        while addr < huge_end {
+            chunks.push((addr, true));
+            addr = addr + huge_size;
+        }
+
+        while addr < end {
+            chunks.push((addr, false));
+            addr = addr + small_size;
+        }
+
+        chunks
+    }
+
     #[inline]
     pub fn share_pages(
         parent: &mut ProcessData,
@@ -583,16 +1046,36 @@ impl CodeMapper {
 
         child_pt.entries[l4_index.as_usize()] = parent_pt.entries[l4_index.as_usize()].clone();
 
-        parent.code_ref = parent.code_ref + 1;
-        child.code_ref = parent.code_ref;
+        child.code_ref = parent.code_ref.clone();
         child.code_pages = parent.code_pages;
+
+        // the pages discarded before the fork are unmapped from the shared tables,
+        // the child must be able to fault them back in as well.
+        cpu::without_interrupts(|| {
+            let mut discarded_lock = DISCARDED_HEAP.lock();
+            let ranges_opt = discarded_lock.get(&parent_vmm.l4_phy_addr.as_u64()).cloned();
+            if let Some(ranges) = ranges_opt {
+                discarded_lock.insert(child_vmm.l4_phy_addr.as_u64(), ranges);
+            }
+        });
+
+        // the page tables below are shared, a page brought in by either process is
+        // seen by both, so the pages not touched yet are still file contents.
+        if let Some(image) = &parent.lazy_image {
+            Self::register_lazy_image(child_vmm, image.clone());
+            child.lazy_image = Some(image.clone());
+        }
         child.heap_start = child.heap_start;
         child.heap_pages = child.heap_pages;
     }
 
     #[inline]
     pub fn unmap_code(proc_data: &mut ProcessData, vmm: &mut VirtualMemoryManager) {
-        if proc_data.code_ref > 0 {
+        if proc_data.lazy_image.take().is_some() {
+            Self::unregister_lazy_image(vmm);
+        }
+
+        if proc_data.is_code_shared() {
             // this is a shared codebase
             let l4_index =
                 VirtualAddress::from_u64(USER_CODE_ADDRESS).get_level_index(PageTableLevel::Level4);
@@ -600,41 +1083,544 @@ impl CodeMapper {
             let child_pt: &mut PageTable = unsafe { &mut *vmm.l4_virtual_address.get_mut_ptr() };
             child_pt.entries[l4_index.as_usize()].unmap_entry();
             proc_data.code_pages = 0;
+            // the others are left with one sharer less.
+            proc_data.code_ref = Arc::new(());
         } else {
-            // unmap
-            let start = VirtualAddress::from_u64(USER_CODE_ADDRESS);
-            for idx in 0..proc_data.code_pages {
-                let page = Page::from_address(VirtualAddress::from_u64(
-                    start.as_u64() + (idx as u64 * MemorySizes::OneKiB as u64 * 4),
-                ));
-                vmm.unmap_page(page).expect("Failed to unmap code-pages");
+            // unmap, the code can contain huge pages for large segments.
+            let start = USER_CODE_ADDRESS;
+            let end = start + (proc_data.code_pages * MemorySizes::OneKiB as u64 * 4);
+            let mut addr = start;
+            while addr < end {
+                let page_addr = VirtualAddress::from_u64(addr);
+                if vmm.translate(page_addr).is_none() {
+                    // demand paged, but never touched
+                    addr = addr + 4 * MemorySizes::OneKiB as u64;
+                    continue;
+                }
+
+                let step = if vmm.is_huge_page(&page_addr) {
+                    2 * MemorySizes::OneMib as u64
+                } else {
+                    4 * MemorySizes::OneKiB as u64
+                };
+                vmm.unmap_page(Page::from_address(page_addr))
+                    .expect("Failed to unmap code-pages");
+                addr = addr + step;
             }
             proc_data.code_pages = 0;
-            proc_data.code_ref = 0;
         }
     }
+
+    fn register_lazy_image(vmm: &VirtualMemoryManager, image: Arc<LazyImage>) {
+        // the page fault handler takes the lock too.
+        cpu::without_interrupts(|| {
+            LAZY_IMAGES.lock().insert(vmm.l4_phy_addr.as_u64(), image);
+        });
+    }
+
+    fn unregister_lazy_image(vmm: &VirtualMemoryManager) {
+        cpu::without_interrupts(|| {
+            LAZY_IMAGES.lock().remove(&vmm.l4_phy_addr.as_u64());
+        });
+    }
+
+    #[inline]
+    fn find_lazy_image(vmm: &VirtualMemoryManager) -> Option<Arc<LazyImage>> {
+        cpu::without_interrupts(|| LAZY_IMAGES.lock().get(&vmm.l4_phy_addr.as_u64()).cloned())
+    }
+
+    /// true if user code can run at `addr`: in an executable segment of the demand
+    /// paged image, or in a mapped user page if the code was mapped at exec time.
+    pub fn is_user_executable(vmm: &VirtualMemoryManager, addr: &VirtualAddress) -> bool {
+        let image_opt = Self::find_lazy_image(vmm);
+        if image_opt.is_none() {
+            let required_flags = PageEntryFlags::PRESENT | PageEntryFlags::USERSPACE;
+            return match vmm.get_page_flags(addr) {
+                Some(flags) => flags.contains(required_flags),
+                None => false,
+            };
+        }
+
+        let addr = addr.as_u64();
+        image_opt.unwrap().segments.iter().any(|segment| {
+            segment.flags & PF_X != 0
+                && addr >= segment.vaddr
+                && addr < segment.vaddr + segment.mem_size
+        })
+    }
+
+    /// maps and fills the page containing `addr` if it is part of a demand paged segment
+    /// and was not touched before. `vmm` must be the active address space. the page
+    /// is mapped with the protections of the segments in it. returns false if the
+    /// page was not mapped.
+    pub fn map_demand_page(vmm: &VirtualMemoryManager, addr: &VirtualAddress) -> bool {
+        let image_opt = Self::find_lazy_image(vmm);
+        if image_opt.is_none() {
+            return false;
+        }
+
+        let image = image_opt.unwrap();
+        if USE_HUGEPAGE_CODE && Self::map_demand_huge_page(vmm, &image, addr.as_u64()) {
+            return true;
+        }
+
+        let page_size = 4 * MemorySizes::OneKiB as u64;
+        let page_start = Alignment::align_down(addr.as_u64(), page_size);
+        let page_end = page_start + page_size;
+
+        let overlaps = |segment: &&LazySegment| {
+            segment.vaddr < page_end && segment.vaddr + segment.mem_size > page_start
+        };
+
+        if !image.segments.iter().any(|segment| overlaps(&segment)) {
+            return false;
+        }
+
+        let page_addr = VirtualAddress::from_u64(page_start);
+        if vmm.translate(page_addr).is_some() {
+            // the page is there, so this is not a fault for us.
+            return false;
+        }
+
+        let frame_opt = PhysicalMemoryManager::alloc();
+        if frame_opt.is_none() {
+            log::error!("RAM OOM while demand paging 0x{:x}", page_start);
+            return false;
+        }
+
+        // a page shared by two segments gets the permissions of both.
+        let segment_flags = image
+            .segments
+            .iter()
+            .filter(overlaps)
+            .fold(0, |flags, segment| flags | segment.flags);
+
+        let page_flags = Self::demand_page_flags(segment_flags);
+
+        // read-only pages can't be written through the user address, so the
+        // page is filled through the physical memory mapping before it is mapped.
+        let frame = frame_opt.unwrap();
+        Self::fill_demand_page(&image, page_start, p_to_v(frame.addr()));
+
+        let map_result = vmm.map_page(Page::from_address(page_addr), frame, page_flags);
+        if map_result.is_err() {
+            log::error!(
+                "failed to map demand paged page: {:?}",
+                map_result.unwrap_err()
+            );
+            PhysicalMemoryManager::free(frame);
+            return false;
+        }
+
+        true
+    }
+
+    /// protections of a demand paged page from the ELF p_flags of it's segments.
+    fn demand_page_flags(segment_flags: u32) -> PageEntryFlags {
+        let mut page_flags = PageEntryFlags::PRESENT | PageEntryFlags::USERSPACE;
+        if segment_flags & PF_W != 0 {
+            page_flags |= PageEntryFlags::READ_WRITE;
+        }
+        if segment_flags & PF_X == 0 && mmu::is_no_execute_enabled() {
+            page_flags |= PageEntryFlags::NO_EXECUTE;
+        }
+        page_flags
+    }
+
+    /// maps the whole 2MiB page around `addr` if one segment covers all of it,
+    /// like `segment_chunks` does at exec time. returns false if the page is not
+    /// covered, is already split into 4KiB pages or there is no huge frame left,
+    /// the 4KiB page is mapped then.
+    fn map_demand_huge_page(vmm: &VirtualMemoryManager, image: &LazyImage, addr: u64) -> bool {
+        let small_size = 4 * MemorySizes::OneKiB as u64;
+        let huge_size = 2 * MemorySizes::OneMib as u64;
+        let huge_start = Alignment::align_down(addr, huge_size);
+        let huge_end = huge_start + huge_size;
+
+        let segment_opt = image.segments.iter().find(|segment| {
+            segment.vaddr <= huge_start && segment.vaddr + segment.mem_size >= huge_end
+        });
+        if segment_opt.is_none() {
+            return false;
+        }
+
+        if vmm.translate(VirtualAddress::from_u64(addr)).is_some() {
+            return false;
+        }
+
+        let frame_opt = PhysicalMemoryManager::alloc_huge_page();
+        if frame_opt.is_none() {
+            return false;
+        }
+
+        let frame = frame_opt.unwrap();
+        let page_flags =
+            Self::demand_page_flags(segment_opt.unwrap().flags) | PageEntryFlags::HUGE_PAGE;
+        let page = Page::from_address(VirtualAddress::from_u64(huge_start));
+        if vmm.map_huge_page(page, frame, page_flags).is_err() {
+            // a 4KiB page of it was mapped before, it stays split.
+            PhysicalMemoryManager::free_huge_page(frame);
+            return false;
+        }
+
+        // nothing runs in this address space until the fault returns, so it is
+        // filled after mapping.
+        let mut page_start = huge_start;
+        while page_start < huge_end {
+            let offset = page_start - huge_start;
+            let dest = p_to_v(PhysicalAddress::from_u64(frame.addr().as_u64() + offset));
+            Self::fill_demand_page(image, page_start, dest);
+            page_start = page_start + small_size;
+        }
+
+        true
+    }
+
+    /// writes the contents of the page at `page_start` from the image to `dest`.
+    fn fill_demand_page(image: &LazyImage, page_start: u64, dest: VirtualAddress) {
+        let page_size = 4 * MemorySizes::OneKiB as u64;
+        let page_end = page_start + page_size;
+
+        let overlaps = |segment: &&LazySegment| {
+            segment.vaddr < page_end && segment.vaddr + segment.mem_size > page_start
+        };
+
+        // BSS and the part of the page outside of the segments stays zeroed,
+        // a page can hold the end of one segment and the start of the next.
+        let page_ptr = dest.get_mut_ptr::<u8>();
+        unsafe {
+            ptr::write_bytes(page_ptr, 0, page_size as usize);
+        }
+
+        for segment in image.segments.iter().filter(overlaps) {
+            let copy_start = core::cmp::max(page_start, segment.vaddr);
+            let copy_end = core::cmp::min(page_end, segment.vaddr + segment.file_size);
+            if copy_start >= copy_end {
+                continue;
+            }
+
+            let src_offset = (segment.file_offset + (copy_start - segment.vaddr)) as usize;
+            let copy_size = (copy_end - copy_start) as usize;
+            if src_offset + copy_size > image.data.len() {
+                log::error!("segment at 0x{:x} is outside of the image", segment.vaddr);
+                continue;
+            }
+
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    image.data.as_ptr().add(src_offset),
+                    page_ptr.add((copy_start - page_start) as usize),
+                    copy_size,
+                );
+            }
+        }
+    }
+
+    /// true if every page of [start, end) holds a part of a demand paged segment.
+    pub fn is_demand_paged_range(vmm: &VirtualMemoryManager, start: u64, end: u64) -> bool {
+        let image_opt = Self::find_lazy_image(vmm);
+        if image_opt.is_none() {
+            return false;
+        }
+
+        let image = image_opt.unwrap();
+        let page_size = 4 * MemorySizes::OneKiB as u64;
+        let mut page_start = start;
+        while page_start < end {
+            let page_end = page_start + page_size;
+            let overlaps = image.segments.iter().any(|segment| {
+                segment.vaddr < page_end && segment.vaddr + segment.mem_size > page_start
+            });
+            if !overlaps {
+                return false;
+            }
+            page_start = page_end;
+        }
+
+        true
+    }
+
+    /// unmaps the demand paged pages of [start, end) and frees their frames, they
+    /// are read from the image again on the next access. the pages are refilled
+    /// in place when the code is shared with a forked process.
+    pub fn discard_pages(
+        proc_data: &mut ProcessData,
+        vmm: &mut VirtualMemoryManager,
+        start: u64,
+        end: u64,
+    ) {
+        let image_opt = Self::find_lazy_image(vmm);
+        if image_opt.is_none() {
+            return;
+        }
+
+        let image = image_opt.unwrap();
+        let page_size = 4 * MemorySizes::OneKiB as u64;
+        let mut page_start = start;
+        while page_start < end {
+            let page_addr = VirtualAddress::from_u64(page_start);
+            let frame_opt = vmm.translate_to_frame(&page_addr);
+            if frame_opt.is_some() && vmm.is_huge_page(&page_addr) {
+                // a part of a huge page can't be given back, it is refilled in place.
+                let offset = page_start % (2 * MemorySizes::OneMib as u64);
+                let frame_addr = frame_opt.unwrap().addr().as_u64();
+                let dest = p_to_v(PhysicalAddress::from_u64(frame_addr + offset));
+                Self::fill_demand_page(&image, page_start, dest);
+            } else if frame_opt.is_some() {
+                if !proc_data.is_code_shared() {
+                    vmm.unmap_page(Page::from_address(page_addr))
+                        .expect("Failed to unmap code-pages");
+                    PhysicalMemoryManager::free(frame_opt.unwrap());
+                } else {
+                    // read-only pages are still the same as the image.
+                    let writable = vmm
+                        .get_page_flags(&page_addr)
+                        .map_or(false, |flags| flags.contains(PageEntryFlags::READ_WRITE));
+                    if writable {
+                        let dest = p_to_v(frame_opt.unwrap().addr());
+                        Self::fill_demand_page(&image, page_start, dest);
+                    }
+                }
+            }
+            page_start = page_start + page_size;
+        }
+    }
+
+    /// a segment that covers a 2MiB page gets it as one huge page, at exec time
+    /// and on the first fault alike, and the addresses in it translate to the
+    /// bytes of the image.
+    #[cfg(feature = "debug_checks")]
+    pub fn test_huge_code_pages() {
+        let small_size = 4 * MemorySizes::OneKiB as u64;
+        let huge_size = 2 * MemorySizes::OneMib as u64;
+        let huge_start = USER_CODE_ADDRESS + huge_size;
+        let huge_end = huge_start + huge_size;
+        let start = huge_start - 2 * small_size;
+        let end = huge_end + 2 * small_size;
+
+        let chunks = Self::segment_chunks(start, end);
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(chunks[2], (huge_start, true));
+        assert_eq!(chunks.iter().filter(|(_, is_huge)| *is_huge).count(), 1);
+
+        // a page less and it can't be a huge page.
+        let chunks = Self::segment_chunks(huge_start, huge_end - small_size);
+        assert!(chunks.iter().all(|(_, is_huge)| !*is_huge));
+
+        let size = end - start;
+        let image = Arc::new(LazyImage {
+            data: (0..size).map(|i| (i % 251) as u8).collect(),
+            segments: alloc::vec![LazySegment {
+                vaddr: start,
+                mem_size: size,
+                file_offset: 0,
+                file_size: size,
+                flags: PF_W,
+            }],
+        });
+
+        let (vmm, l4_addr) = KernelVirtualMemoryManager::new_vmm();
+        Self::register_lazy_image(&vmm, image.clone());
+
+        // the head, the huge page and the tail.
+        for addr in [start + 0x10, huge_start + 0x3_4567, end - 1].iter() {
+            assert!(Self::map_demand_page(&vmm, &VirtualAddress::from_u64(*addr)));
+        }
+        // the rest of the huge page came in with the first fault.
+        assert!(!Self::map_demand_page(
+            &vmm,
+            &VirtualAddress::from_u64(huge_end - 1)
+        ));
+
+        let huge_phy_addr = vmm.translate(VirtualAddress::from_u64(huge_start));
+        assert!(huge_phy_addr.is_some());
+        let huge_phy_addr = huge_phy_addr.unwrap().as_u64();
+        assert_eq!(huge_phy_addr % huge_size, 0);
+
+        let addresses = [
+            start,
+            start + 0x10,
+            huge_start - 2 * small_size + 0xFFF,
+            huge_start,
+            huge_start + 0x3_4567,
+            huge_start + small_size,
+            huge_end - 1,
+            end - small_size,
+            end - 1,
+        ];
+        for addr in addresses.iter() {
+            let virt_addr = VirtualAddress::from_u64(*addr);
+            let in_huge = *addr >= huge_start && *addr < huge_end;
+            assert_eq!(vmm.is_huge_page(&virt_addr), in_huge);
+
+            let phy_addr = vmm.translate(virt_addr);
+            assert!(phy_addr.is_some());
+            let phy_addr = phy_addr.unwrap();
+            if in_huge {
+                assert_eq!(phy_addr.as_u64(), huge_phy_addr + (*addr - huge_start));
+            }
+
+            let value = unsafe { ptr::read_volatile(p_to_v(phy_addr).get_ptr::<u8>()) };
+            assert_eq!(value, image.data[(*addr - start) as usize]);
+        }
+
+        // the page after the head was never touched.
+        assert!(vmm
+            .translate(VirtualAddress::from_u64(start + small_size))
+            .is_none());
+
+        Self::unregister_lazy_image(&vmm);
+        for (chunk_addr, is_huge) in Self::segment_chunks(start, end).iter() {
+            let page_addr = VirtualAddress::from_u64(*chunk_addr);
+            let frame_opt = vmm.translate_to_frame(&page_addr);
+            if frame_opt.is_none() {
+                continue;
+            }
+
+            vmm.unmap_page(Page::from_address(page_addr))
+                .expect("Failed to unmap test code page");
+            if *is_huge {
+                PhysicalMemoryManager::free_huge_page(frame_opt.unwrap());
+            } else {
+                PhysicalMemoryManager::free(frame_opt.unwrap());
+            }
+        }
+        PhysicalMemoryManager::free(Frame::from_address(l4_addr));
+
+        log::info!("Passed huge code page test.");
+    }
+}
+
+/// hands out ids below a limit, freed ids are reused oldest first so that
+/// a stale id stays unused for as long as possible.
+pub struct IDAllocator {
+    next: u64,
+    free: VecDeque<u64>,
+    live: usize,
+    limit: usize,
+}
+
+impl IDAllocator {
+    pub fn new(limit: usize) -> Self {
+        IDAllocator {
+            next: 0,
+            free: VecDeque::new(),
+            live: 0,
+            limit,
+        }
+    }
+
+    /// returns None if `limit` ids are already in use.
+    pub fn alloc(&mut self) -> Option<u64> {
+        if self.live >= self.limit {
+            return None;
+        }
+
+        // new ids are minted only when nothing is free, so they never go past the limit.
+        let id = match self.free.pop_front() {
+            Some(id) => id,
+            None => {
+                let id = self.next;
+                self.next += 1;
+                id
+            }
+        };
+
+        self.live += 1;
+        Some(id)
+    }
+
+    pub fn is_in_use(&self, id: u64) -> bool {
+        id < self.next && !self.free.contains(&id)
+    }
+
+    pub fn release(&mut self, id: u64) {
+        if !self.is_in_use(id) {
+            log::warn!("attempt to release id {} which is not in use", id);
+            return;
+        }
+
+        self.free.push_back(id);
+        self.live -= 1;
+    }
+
+    #[inline]
+    pub fn live(&self) -> usize {
+        self.live
+    }
 }
 
 pub struct ProcessFDPool;
 
 impl ProcessFDPool {
+    /// empty descriptors are never handed out to the syscalls, they are as good as closed.
     #[inline]
-    pub fn put(proc_data: &mut ProcessData, fd: FileDescriptor) -> Result<usize, ProcessError> {
-        if proc_data.file_descriptors.len() + 1 > MAX_FILE_DESCRIPTORS {
+    fn is_open_at(proc_data: &ProcessData, idx: usize, fd_index: usize) -> bool {
+        let entry = &proc_data.file_descriptors[idx];
+        entry.index == fd_index && !matches!(*entry.file.lock(), FileDescriptor::Empty)
+    }
+
+    #[inline]
+    pub fn put(
+        proc_data: &mut ProcessData,
+        fd: FileDescriptor,
+        cloexec: bool,
+    ) -> Result<usize, ProcessError> {
+        Self::put_shared(proc_data, Arc::new(Mutex::new(fd)), cloexec)
+    }
+
+    #[inline]
+    fn put_shared(
+        proc_data: &mut ProcessData,
+        file: OpenFileRef,
+        cloexec: bool,
+    ) -> Result<usize, ProcessError> {
+        if proc_data.file_descriptors.len() + 1 > proc_data.limits.nofile.current as usize {
             return Err(ProcessError::MaxFDLimit);
         }
 
         let index = proc_data.fd_index;
-        let fd_entry = FDEntry { index, fd };
+        let fd_entry = FDEntry {
+            index,
+            file,
+            cloexec,
+        };
         proc_data.fd_index = index + 1;
         proc_data.file_descriptors.push(fd_entry);
         Ok(index)
     }
 
+    /// creates a new descriptor that shares the open file description of `fd_index`,
+    /// the close-on-exec flag is not copied.
+    pub fn dup(proc_data: &mut ProcessData, fd_index: usize) -> Result<usize, ProcessError> {
+        let entry_opt = Self::get_mut(proc_data, fd_index);
+        if entry_opt.is_none() {
+            return Err(ProcessError::InvalidFD);
+        }
+
+        let file = entry_opt.unwrap().file.clone();
+        Self::put_shared(proc_data, file, false)
+    }
+
+    /// closes the underlying node only if this was the last reference to it.
+    fn release(entry: FDEntry) -> Result<(), ProcessError> {
+        match Arc::try_unwrap(entry.file) {
+            Ok(file) => {
+                let close_res = FILESYSTEM.lock().close(&file.into_inner());
+                if close_res.is_err() {
+                    return Err(ProcessError::FDCloseError);
+                }
+                Ok(())
+            }
+            // still used by another descriptor
+            Err(_) => Ok(()),
+        }
+    }
+
     #[inline]
     pub fn get_mut(proc_data: &mut ProcessData, fd_index: usize) -> Option<&mut FDEntry> {
         for idx in 0..proc_data.file_descriptors.len() {
-            if proc_data.file_descriptors[idx].index == fd_index {
+            if Self::is_open_at(proc_data, idx, fd_index) {
                 return proc_data.file_descriptors.get_mut(idx);
             }
         }
@@ -642,10 +1628,11 @@ impl ProcessFDPool {
         None
     }
 
+    /// the child gets the same open file descriptions as the parent, not copies.
     #[inline]
     pub fn clone(parent: &mut ProcessData, child: &mut ProcessData) {
-        for fd in parent.file_descriptors.iter() {
-            child.file_descriptors.push(fd.clone());
+        for entry in parent.file_descriptors.iter() {
+            child.file_descriptors.push(entry.clone());
         }
 
         child.fd_index = parent.fd_index;
@@ -654,12 +1641,10 @@ impl ProcessFDPool {
     #[inline]
     pub fn remove(proc_data: &mut ProcessData, fd_index: usize) -> Result<(), ProcessError> {
         for idx in 0..proc_data.file_descriptors.len() {
-            if proc_data.file_descriptors[idx].index == fd_index {
-                let _ = FILESYSTEM
-                    .lock()
-                    .close(&mut proc_data.file_descriptors.get_mut(idx).unwrap().fd);
-                proc_data.file_descriptors.remove(idx);
-                return Ok(());
+            if Self::is_open_at(proc_data, idx, fd_index) {
+                // the descriptor is released even if the close failed.
+                let entry = proc_data.file_descriptors.remove(idx);
+                return Self::release(entry);
             }
         }
 
@@ -668,29 +1653,37 @@ impl ProcessFDPool {
 
     #[inline]
     pub fn remove_all(proc_data: &mut ProcessData) {
-        for idx in 0..proc_data.file_descriptors.len() {
-            let _ = FILESYSTEM
-                .lock()
-                .close(&mut proc_data.file_descriptors.get_mut(idx).unwrap().fd);
+        for entry in proc_data.file_descriptors.drain(..) {
+            let _ = Self::release(entry);
         }
+    }
 
-        proc_data.file_descriptors.clear();
+    /// called on exec, the other descriptors stay open with the same numbers.
+    pub fn remove_cloexec(proc_data: &mut ProcessData) {
+        let mut idx = 0;
+        while idx < proc_data.file_descriptors.len() {
+            if proc_data.file_descriptors[idx].cloexec {
+                let entry = proc_data.file_descriptors.remove(idx);
+                let _ = Self::release(entry);
+                continue;
+            }
+            idx += 1;
+        }
     }
 }
 
 pub fn create_default_descriptors(proc_data: &mut ProcessData) {
+    // stdin, stdout and stderr share it, so it is opened for both reads and writes.
     let dev_fd = FILESYSTEM
         .lock()
-        .open("/dev/tty", 0)
+        .open("/dev/tty", POSIXOpenFlags::O_RDWR.bits())
         .expect("/dev/tty not found on this platform, cannot create process stdout.");
 
-    let stdin = dev_fd.clone();
-    let stdout = dev_fd.clone();
-    let stderr = dev_fd;
-
-    ProcessFDPool::put(proc_data, stdin).expect("Failed to create default stdin");
-    ProcessFDPool::put(proc_data, stdout).expect("Failed to create default stdin");
-    ProcessFDPool::put(proc_data, stderr).expect("Failed to create default stdin");
+    // one open file description for all the three, like a shell would set up.
+    let stdin =
+        ProcessFDPool::put(proc_data, dev_fd, false).expect("Failed to create default stdin");
+    ProcessFDPool::dup(proc_data, stdin).expect("Failed to create default stdout");
+    ProcessFDPool::dup(proc_data, stdin).expect("Failed to create default stderr");
 }
 
 pub fn create_cloned_layout(
@@ -699,7 +1692,7 @@ pub fn create_cloned_layout(
     child_vmm: &mut VirtualMemoryManager,
 ) -> ProcessData {
     // create an empty layout
-    let stack_space_start = VirtualAddress::from_u64(USER_VIRT_END - PROCESS_STACKS_SIZE);
+    let stack_space_start = VirtualAddress::from_u64(USER_STACK_ADDRESS);
 
     let mut proc_data = ProcessData {
         stack_space_start,
@@ -713,10 +1706,15 @@ pub fn create_cloned_layout(
         fd_index: 0,
         code_entry: VirtualAddress::from_u64(0),
         code_pages: 0,
-        code_ref: 0,
+        code_ref: Arc::new(()),
+        n_syscall_stacks: 1,
+        free_syscall_stacks: Vec::new(),
+        lazy_image: None,
+        limits: parent.limits.clone(),
     };
 
     CodeMapper::share_pages(parent, &mut proc_data, parent_vmm, child_vmm);
+    vdso::map_time_page(child_vmm);
     ProcessFDPool::clone(parent, &mut proc_data);
     proc_data
 }
@@ -728,14 +1726,16 @@ pub fn reset_layout(
     vmm: &mut VirtualMemoryManager,
     map_new: bool,
 ) -> VirtualAddress {
-    // remove file-descriptors:
-    layout.file_descriptors.clear();
-    layout.fd_index = 0;
-
-    ProcessFDPool::remove_all(layout);
+    // close and remove file-descriptors, this must happen before
+    // the list is cleared, otherwise the underlying nodes are never closed.
+    // exec keeps the descriptors that are not marked close-on-exec.
     if map_new {
-        create_default_descriptors(layout);
+        ProcessFDPool::remove_cloexec(layout);
+    } else {
+        ProcessFDPool::remove_all(layout);
+        layout.fd_index = 0;
     }
+
     // reset the heap:
     ProcessHeapAllocator::reset(layout, vmm);
     // reset the code:
@@ -743,13 +1743,14 @@ pub fn reset_layout(
     // allocate the new code:
     if map_new {
         CodeMapper::load_elf(layout, vmm, path).expect("Failed to load user-code");
+        ProcessHeapAllocator::set_limit(layout);
     }
     layout.code_entry
 }
 
 pub fn create_process_layout(path: &str, vmm: &mut VirtualMemoryManager) -> ProcessData {
     // create an empty layout
-    let stack_space_start = VirtualAddress::from_u64(USER_VIRT_END - PROCESS_STACKS_SIZE);
+    let stack_space_start = VirtualAddress::from_u64(USER_STACK_ADDRESS);
 
     let mut proc_data = ProcessData {
         stack_space_start,
@@ -763,23 +1764,22 @@ pub fn create_process_layout(path: &str, vmm: &mut VirtualMemoryManager) -> Proc
         fd_index: 0,
         code_entry: VirtualAddress::from_u64(0),
         code_pages: 0,
-        code_ref: 0,
+        code_ref: Arc::new(()),
+        n_syscall_stacks: 1,
+        free_syscall_stacks: Vec::new(),
+        lazy_image: None,
+        limits: ResourceLimits::system_default(),
     };
 
+    vdso::map_time_page(vmm);
+
     // create the code segment
     let code_alloc_result = CodeMapper::load_elf(&mut proc_data, vmm, &path);
     if code_alloc_result.is_err() {
         panic!("Failed to allocate code for the process.");
     }
 
-    let max_heap_size = stack_space_start.as_u64() - proc_data.heap_start.as_u64();
-    let max_heap_pages = if USE_HUGEPAGE_HEAP {
-        max_heap_size / (4 * MemorySizes::OneMib as u64)
-    } else {
-        max_heap_size / (4 * MemorySizes::OneKiB as u64)
-    };
-
-    proc_data.max_heap_pages = max_heap_pages;
+    ProcessHeapAllocator::set_limit(&mut proc_data);
     proc_data
 }
 
@@ -787,11 +1787,17 @@ pub fn map_user_stack(
     stack_addr: VirtualAddress,
     n_current_threads: usize,
     proc_vmm: &mut VirtualMemoryManager,
-) -> VirtualAddress {
+) -> Result<VirtualAddress, ProcessError> {
     // maps the stack address to user code's stack location
-    // using huge pages
-    let new_stack_address =
-        VirtualAddress::from_u64(USER_STACK_ADDRESS + (n_current_threads * STACK_SIZE) as u64);
+    // using huge pages, one stack slot per thread.
+    if n_current_threads as u64 >= MAX_STACK_SLOTS {
+        return Err(ProcessError::StackOOB);
+    }
+
+    let new_stack_address = ProcessStackManager::get_slot_addr(
+        VirtualAddress::from_u64(USER_STACK_ADDRESS),
+        n_current_threads as u64,
+    );
     // map the stack to it's virtual address:
     let stack_phy_address = KernelVirtualMemoryManager::pt().translate(stack_addr);
     if stack_phy_address.is_none() {
@@ -810,7 +1816,7 @@ pub fn map_user_stack(
     if res.is_err() {
         panic!("{:?}", res);
     }
-    return new_stack_address;
+    Ok(new_stack_address)
 }
 
 pub fn map_user_code(
@@ -0,0 +1,93 @@
+extern crate log;
+
+use crate::cpu::tsc::TSC;
+use crate::mm::paging::{Page, PageEntryFlags, PageSize, VirtualMemoryManager};
+use crate::mm::phy::{Frame, PhysicalMemoryManager};
+use crate::mm::{p_to_v, VirtualAddress};
+use crate::system::timer;
+
+use core::ptr;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+use lazy_static::lazy_static;
+
+/// user virtual address at which the time page is mapped (read-only) in every process
+pub const VDSO_TIME_ADDRESS: u64 = 0x600000000000;
+
+/// the page is considered stale if it was not updated for these many nanoseconds,
+/// userspace should fall back to the syscall then.
+const VDSO_MAX_AGE_NS: u64 = 4 * timer::SYSTEM_TICK_DURATION;
+
+/// Layout of the time page shared with the userspace, this must be kept in sync with
+/// the copy in userspace-rs. The sequence is odd while the kernel is updating the page.
+#[repr(C)]
+pub struct VDSOTimeData {
+    pub sequence: AtomicU64,
+    /// monotonic time in nanoseconds at the last update
+    pub base_ns: AtomicU64,
+    /// TSC value at the last update
+    pub base_tsc: AtomicU64,
+    /// TSC frequency in Hz
+    pub tsc_frequency: AtomicU64,
+    /// age after which the page is stale
+    pub max_age_ns: AtomicU64,
+}
+
+lazy_static! {
+    static ref VDSO_TIME_FRAME: Frame =
+        PhysicalMemoryManager::alloc().expect("Failed to allocate vDSO time page. OOM");
+}
+
+#[inline]
+fn time_data() -> &'static VDSOTimeData {
+    unsafe { &*p_to_v(VDSO_TIME_FRAME.addr()).get_ptr() }
+}
+
+/// updates the time page, called on every timer tick.
+pub fn update_time_page() {
+    let data = time_data();
+    let tsc = TSC::read_tsc().u64();
+
+    // writer side of the seqlock, readers retry while the sequence is odd or has changed.
+    let sequence = data.sequence.load(Ordering::Relaxed);
+    data.sequence.store(sequence + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    data.base_ns.store(timer::tsc_to_ns(tsc), Ordering::Relaxed);
+    data.base_tsc.store(tsc, Ordering::Relaxed);
+    data.tsc_frequency
+        .store(TSC::read_cpu_frequency(), Ordering::Relaxed);
+    data.max_age_ns.store(VDSO_MAX_AGE_NS, Ordering::Relaxed);
+
+    data.sequence.store(sequence + 2, Ordering::Release);
+}
+
+/// maps the time page into the given process address space.
+pub fn map_time_page(vmm: &mut VirtualMemoryManager) {
+    // read-only for the userspace, only the kernel updates it.
+    let flags = PageEntryFlags::PRESENT | PageEntryFlags::USERSPACE;
+
+    vmm.map_page(
+        Page::from_address(VirtualAddress::from_u64(VDSO_TIME_ADDRESS)),
+        *VDSO_TIME_FRAME,
+        flags,
+    )
+    .expect("Failed to map vDSO time page.");
+}
+
+pub fn setup_time_page() {
+    unsafe {
+        ptr::write_bytes(
+            p_to_v(VDSO_TIME_FRAME.addr()).get_mut_ptr::<u8>(),
+            0,
+            PageSize::Page4KiB.size() as usize,
+        );
+    }
+
+    update_time_page();
+    log::info!(
+        "Initialized vDSO time page at 0x{:x}, phy=0x{:x}",
+        VDSO_TIME_ADDRESS,
+        VDSO_TIME_FRAME.addr().as_u64()
+    );
+}
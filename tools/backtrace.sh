@@ -0,0 +1,16 @@
+#!/bin/bash
+
+# resolves the "bt #N 0x..." lines of a kernel backtrace to functions and lines.
+# usage: ./tools/backtrace.sh [serial log, default serial.out]
+
+LOG_FILE=${1:-serial.out}
+KERNEL_BINARY=kbin/x86_64/debug/r3_kernel
+
+if [[ ! -f $KERNEL_BINARY ]]; then
+    echo "kernel binary $KERNEL_BINARY not found, build the kernel first."
+    exit 1
+fi
+
+grep -o "bt #[0-9]* 0x[0-9a-f]*" $LOG_FILE | while read -r _ frame addr; do
+    echo "$frame $addr $(addr2line -f -C -p -e $KERNEL_BINARY $addr)"
+done
@@ -24,6 +24,28 @@ pushd userland/userspace-rs
     cargo xbuild
     cp target/x86_64/debug/echo_cli $proj_root/storage/tarfs/echo_cli
     cp target/x86_64/debug/sys_shell $proj_root/storage/tarfs/sys_shell
+    cp target/x86_64/debug/fault_test $proj_root/storage/tarfs/fault_test
+    cp target/x86_64/debug/dmesg $proj_root/storage/tarfs/dmesg
+    cp target/x86_64/debug/fd_share_test $proj_root/storage/tarfs/fd_share_test
+    cp target/x86_64/debug/fd_test $proj_root/storage/tarfs/fd_test
+    cp target/x86_64/debug/ata_share_test $proj_root/storage/tarfs/ata_share_test
+    cp target/x86_64/debug/cloexec_test $proj_root/storage/tarfs/cloexec_test
+    cp target/x86_64/debug/ata_eof_test $proj_root/storage/tarfs/ata_eof_test
+    cp target/x86_64/debug/dns_test $proj_root/storage/tarfs/dns_test
+    cp target/x86_64/debug/yield_test $proj_root/storage/tarfs/yield_test
+    cp target/x86_64/debug/madvise_test $proj_root/storage/tarfs/madvise_test
+    cp target/x86_64/debug/rlimit_test $proj_root/storage/tarfs/rlimit_test
+    cp target/x86_64/debug/prctl_test $proj_root/storage/tarfs/prctl_test
+    cp target/x86_64/debug/clock_test $proj_root/storage/tarfs/clock_test
+    cp target/x86_64/debug/fpu_test $proj_root/storage/tarfs/fpu_test
+    cp target/x86_64/debug/pipe_test $proj_root/storage/tarfs/pipe_test
+    cp target/x86_64/debug/tty_test $proj_root/storage/tarfs/tty_test
+    cp target/x86_64/debug/kstack_test $proj_root/storage/tarfs/kstack_test
+    cp target/x86_64/debug/thread_test $proj_root/storage/tarfs/thread_test
+    cp target/x86_64/debug/socket_test $proj_root/storage/tarfs/socket_test
+    cp target/x86_64/debug/tarfs_test $proj_root/storage/tarfs/tarfs_test
+    cp target/x86_64/debug/protect_test $proj_root/storage/tarfs/protect_test
+    cp target/x86_64/debug/fork_test $proj_root/storage/tarfs/fork_test
 popd
 
 # build tarfs
@@ -2,6 +2,25 @@
 
 QEMU_BINARY="qemu-system-x86_64"
 
+# --selftest boots the userland test programs instead of the init program
+# --kmonitor builds in the kernel monitor, the serial port becomes a pty to talk to it
+# --watchdog arms a 6300ESB watchdog, R3_WATCHDOG sets the timeout in seconds (10 by default)
+SERIAL="file:serial.out"
+WATCHDOG=""
+for arg in "$@"; do
+    if [[ "$arg" == "--selftest" ]]; then
+        export R3_KERNEL_FEATURES="$R3_KERNEL_FEATURES selftest"
+    fi
+    if [[ "$arg" == "--kmonitor" ]]; then
+        export R3_KERNEL_FEATURES="$R3_KERNEL_FEATURES kmonitor"
+        SERIAL="pty"
+    fi
+    if [[ "$arg" == "--watchdog" ]]; then
+        export R3_WATCHDOG="${R3_WATCHDOG:-10}"
+        WATCHDOG="-device i6300esb"
+    fi
+done
+
 if [[ "$1" == "--clean" || "$2" == "--clean" || "$3" == "--clean" ]]; then
     rm -r kbin
 fi
@@ -16,7 +35,7 @@ fi
 
 
 KERNEL_BIN_PATH="./kbin"
-QEMU_ARGS="-enable-kvm -cpu host -m 1G -M pc --serial file:serial.out"
+QEMU_ARGS="-enable-kvm -cpu host -m 1G -M pc --serial $SERIAL $WATCHDOG"
 
 if [[ "$1" == "--uefi" || "$2" == "--uefi" || "$3" == "--uefi" ]]; then
     KERNEL_BIN_PATH="$KERNEL_BIN_PATH/boot-uefi-r3_kernel.img"
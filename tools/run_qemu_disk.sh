@@ -2,6 +2,35 @@
 
 QEMU_BINARY="qemu-system-x86_64"
 
+# --selftest boots the userland test programs instead of the init program
+# --kmonitor builds in the kernel monitor, the serial port becomes a pty to talk to it
+# --watchdog arms a 6300ESB watchdog, R3_WATCHDOG sets the timeout in seconds (10 by default)
+# --ahci uses the q35 machine, the disks are attached to it's AHCI controller
+# --periodic-timer hides TSC-deadline from the kernel, so it runs on the periodic LAPIC timer
+MACHINE="pc"
+CPU="host"
+SERIAL="file:serial.out"
+WATCHDOG=""
+for arg in "$@"; do
+    if [[ "$arg" == "--selftest" ]]; then
+        export R3_KERNEL_FEATURES="$R3_KERNEL_FEATURES selftest"
+    fi
+    if [[ "$arg" == "--kmonitor" ]]; then
+        export R3_KERNEL_FEATURES="$R3_KERNEL_FEATURES kmonitor"
+        SERIAL="pty"
+    fi
+    if [[ "$arg" == "--watchdog" ]]; then
+        export R3_WATCHDOG="${R3_WATCHDOG:-10}"
+        WATCHDOG="-device i6300esb"
+    fi
+    if [[ "$arg" == "--ahci" ]]; then
+        MACHINE="q35"
+    fi
+    if [[ "$arg" == "--periodic-timer" ]]; then
+        CPU="host,-tsc-deadline"
+    fi
+done
+
 if [[ "$1" == "--clean" || "$2" == "--clean" || "$3" == "--clean" ]]; then
     rm -r kbin
 fi
@@ -22,7 +51,7 @@ INET_2="-nic tap,model=rtl8139"
 INET_3="-netdev tap,helper=/usr/lib/qemu/qemu-bridge-helper,id=r3_net -device rtl8139,netdev=r3_net,id=r3_net -object filter-dump,id=r3_net,netdev=r3_net,file=net_dump.dat"
 
 
-QEMU_ARGS="-enable-kvm -cpu host -M pc -m 1G -monitor stdio --serial file:serial.out -drive file=$STORAGE_DISK,format=raw,index=1,media=disk $INET_2"
+QEMU_ARGS="-enable-kvm -cpu $CPU -M $MACHINE -m 1G -monitor stdio --serial $SERIAL -drive file=$STORAGE_DISK,format=raw,index=1,media=disk $INET_2 $WATCHDOG"
 
 if [[ "$1" == "--uefi" || "$2" == "--uefi" || "$3" == "--uefi" ]]; then
     KERNEL_BIN_PATH="$KERNEL_BIN_PATH/boot-uefi-r3_kernel.img"
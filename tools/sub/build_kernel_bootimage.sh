@@ -3,7 +3,8 @@
 # first build the kernel
 pushd r3_kernel
     echo "Compiling kernel..."
-    cargo xbuild
+    # extra kernel features, like selftest, are passed in R3_KERNEL_FEATURES
+    cargo xbuild --features "$R3_KERNEL_FEATURES"
 popd
 
 KERNEL_PATH=$PWD/r3_kernel
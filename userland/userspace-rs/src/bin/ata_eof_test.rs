@@ -0,0 +1,127 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "ata_eof_test";
+
+const EINVAL: usize = 22;
+const ENOSPC: usize = 28;
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+const BLOCK_SIZE: usize = 512;
+/// offsets are 32-bit, the drive can't be addressed beyond this block.
+const MAX_BLOCKS: usize = u32::MAX as usize / BLOCK_SIZE;
+
+static mut BUFFER: [u8; 2 * BLOCK_SIZE] = [0; 2 * BLOCK_SIZE];
+
+fn read_at(fd: usize, block: usize, size: usize) -> usize {
+    unsafe { syscalls::sys_pread(fd, &mut BUFFER, size, block * BLOCK_SIZE) }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // the writes below must get to the offset checks, so it can't be read-only.
+    let fd = unsafe { syscalls::sys_open(b"/dev/hda\0", syscalls::O_RDWR) };
+    if fd <= 2 {
+        testing::fail(NAME, "failed to open /dev/hda", fd);
+    }
+
+    // every block before the end reads in full, so the first one that does
+    // not is the end of the drive.
+    let (mut low, mut high) = (0, MAX_BLOCKS);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if read_at(fd, middle, BLOCK_SIZE) == BLOCK_SIZE {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    let n_blocks = low;
+    if n_blocks == 0 || n_blocks == MAX_BLOCKS {
+        testing::fail(
+            NAME,
+            "could not find the end of the drive, blocks",
+            n_blocks,
+        );
+    }
+
+    // two blocks asked at the last one, only one of them exists.
+    let last = read_at(fd, n_blocks - 1, 2 * BLOCK_SIZE);
+    if last != BLOCK_SIZE {
+        testing::fail(NAME, "read at the last block was not cut at the end", last);
+    }
+
+    let at_end = read_at(fd, n_blocks, BLOCK_SIZE);
+    if at_end != 0 {
+        testing::fail(NAME, "read at the end did not return 0", at_end);
+    }
+
+    let past_end = read_at(fd, n_blocks + 1, BLOCK_SIZE);
+    if past_end != EINVAL {
+        testing::fail(NAME, "read past the end did not fail with EINVAL", past_end);
+    }
+
+    // the drive moves whole blocks, it can't start in the middle of one.
+    let unaligned = unsafe { syscalls::sys_pread(fd, &mut BUFFER, BLOCK_SIZE, 1) };
+    if unaligned != EINVAL {
+        testing::fail(NAME, "unaligned read did not fail with EINVAL", unaligned);
+    }
+
+    let unaligned = unsafe { syscalls::sys_pwrite(fd, &BUFFER, BLOCK_SIZE, BLOCK_SIZE + 1) };
+    if unaligned != EINVAL {
+        testing::fail(NAME, "unaligned write did not fail with EINVAL", unaligned);
+    }
+
+    let write_end = unsafe { syscalls::sys_pwrite(fd, &BUFFER, BLOCK_SIZE, n_blocks * BLOCK_SIZE) };
+    if write_end != ENOSPC {
+        testing::fail(NAME, "write at the end did not fail with ENOSPC", write_end);
+    }
+
+    // the end found by reading is the size lseek reports.
+    let size = n_blocks * BLOCK_SIZE;
+    let end = unsafe { syscalls::sys_lseek(fd, 0, SEEK_END) };
+    if end != size {
+        testing::fail(NAME, "SEEK_END did not return the size of the drive", end);
+    }
+
+    let past_end = unsafe { syscalls::sys_lseek(fd, 1, SEEK_END) };
+    if past_end != EINVAL {
+        testing::fail(
+            NAME,
+            "SEEK_END past the end did not fail with EINVAL",
+            past_end,
+        );
+    }
+
+    let past_end = unsafe { syscalls::sys_lseek(fd, 1, SEEK_CUR) };
+    if past_end != EINVAL {
+        testing::fail(
+            NAME,
+            "SEEK_CUR past the end did not fail with EINVAL",
+            past_end,
+        );
+    }
+
+    let past_end = unsafe { syscalls::sys_lseek(fd, size + BLOCK_SIZE, SEEK_SET) };
+    if past_end != EINVAL {
+        testing::fail(
+            NAME,
+            "SEEK_SET past the end did not fail with EINVAL",
+            past_end,
+        );
+    }
+
+    let start = unsafe { syscalls::sys_lseek(fd, 0, SEEK_SET) };
+    if start != 0 {
+        testing::fail(NAME, "SEEK_SET to the start did not return 0", start);
+    }
+
+    testing::pass_with(NAME, format_args!("{} blocks", n_blocks));
+}
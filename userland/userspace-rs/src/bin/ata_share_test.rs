@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::println;
+
+const NAME: &str = "ata_share_test";
+
+const BLOCK_SIZE: usize = 512;
+/// reads done by each of the threads
+const ROUNDS: usize = 200;
+/// how many times the main thread yields before giving up on the reader thread.
+const MAX_YIELDS: usize = 100000;
+const READER_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct ReaderStack([u8; READER_STACK_SIZE]);
+
+static mut READER_STACK: ReaderStack = ReaderStack([0; READER_STACK_SIZE]);
+static mut EXPECTED: [[u8; BLOCK_SIZE]; 2] = [[0; BLOCK_SIZE]; 2];
+
+static DRIVE_FD: AtomicUsize = AtomicUsize::new(0);
+static MISMATCHES: AtomicUsize = AtomicUsize::new(0);
+static READER_DONE: AtomicBool = AtomicBool::new(false);
+
+/// reads the block again and again, counting the reads that don't match the first one.
+fn read_repeatedly(block: usize) {
+    let fd = DRIVE_FD.load(Ordering::SeqCst);
+    let mut buffer = [0u8; BLOCK_SIZE];
+
+    for _ in 0..ROUNDS {
+        let n_read =
+            unsafe { syscalls::sys_pread(fd, &mut buffer, BLOCK_SIZE, block * BLOCK_SIZE) };
+        if n_read != BLOCK_SIZE || buffer != unsafe { EXPECTED[block] } {
+            MISMATCHES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+extern "C" fn reader_thread(block: usize) {
+    read_repeatedly(block);
+    READER_DONE.store(true, Ordering::SeqCst);
+
+    loop {
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    let fd = unsafe { syscalls::sys_open(b"/dev/hda\0", 0) };
+    if fd <= 2 {
+        testing::fail(NAME, "failed to open /dev/hda", fd);
+    }
+    DRIVE_FD.store(fd, Ordering::SeqCst);
+
+    // the reference copies are read while nothing else uses the drive.
+    for block in 0..2 {
+        let n_read = unsafe {
+            syscalls::sys_pread(fd, &mut EXPECTED[block], BLOCK_SIZE, block * BLOCK_SIZE)
+        };
+        if n_read != BLOCK_SIZE {
+            testing::fail_with(
+                NAME,
+                format_args!("failed to read block {}, error={}", block, n_read),
+            );
+        }
+    }
+
+    if unsafe { EXPECTED[0] == EXPECTED[1] } {
+        println!("ata_share_test: blocks 0 and 1 are identical, mixed up reads can't be detected");
+    }
+
+    let stack_end = unsafe { READER_STACK.0.as_ptr() as usize + READER_STACK_SIZE };
+    // if the thread can't be created, the test fails on the timeout below.
+    unsafe {
+        syscalls::sys_thread_create(reader_thread, 1, stack_end);
+    }
+
+    read_repeatedly(0);
+
+    for _ in 0..MAX_YIELDS {
+        if READER_DONE.load(Ordering::SeqCst) {
+            break;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+
+    let mismatches = MISMATCHES.load(Ordering::SeqCst);
+    if !READER_DONE.load(Ordering::SeqCst) {
+        testing::fail(NAME, "the reader thread did not finish, yields", MAX_YIELDS);
+    }
+    if mismatches != 0 {
+        testing::fail_with(
+            NAME,
+            format_args!("{} of {} reads returned wrong data", mismatches, 2 * ROUNDS),
+        );
+    }
+
+    testing::pass(NAME);
+}
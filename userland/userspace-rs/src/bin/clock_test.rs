@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::library::types::{ClockType, Timeval};
+use userspace_rs::library::vdso;
+
+const NAME: &str = "clock_test";
+
+const EINVAL: usize = 22;
+const N_READS: usize = 1000;
+
+fn read_clock_us(clock: ClockType) -> u64 {
+    let mut timeval = Timeval::default();
+    let result = unsafe { syscalls::sys_clock_gettime(clock as usize, &mut timeval) };
+    if result != 0 {
+        testing::fail(NAME, "clock_gettime failed", result);
+    }
+
+    let tv_sec = timeval.tv_sec;
+    let tv_usec = timeval.tv_usec;
+    if tv_usec < 0 || tv_usec >= 1000000 {
+        testing::fail(NAME, "tv_usec out of range", tv_usec as usize);
+    }
+    tv_sec as u64 * 1000000 + tv_usec as u64
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // the monotonic clock never goes back, not even between the syscall and the vDSO.
+    let mut last_us = read_clock_us(ClockType::Monotonic);
+    for _ in 0..N_READS {
+        let now_us = read_clock_us(ClockType::Monotonic);
+        if now_us < last_us {
+            testing::fail(
+                NAME,
+                "CLOCK_MONOTONIC went back by us",
+                (last_us - now_us) as usize,
+            );
+        }
+        last_us = now_us;
+
+        // the two round the nanoseconds a bit differently, allow a microsecond.
+        let vdso_us = vdso::monotonic_ns() / 1000;
+        if vdso_us + 1 < last_us {
+            testing::fail(
+                NAME,
+                "vDSO clock is behind the syscall by us",
+                (last_us - vdso_us) as usize,
+            );
+        }
+    }
+
+    let mut last_us = read_clock_us(ClockType::Realtime);
+    for _ in 0..N_READS {
+        let now_us = read_clock_us(ClockType::Realtime);
+        if now_us < last_us {
+            testing::fail(
+                NAME,
+                "CLOCK_REALTIME went back by us",
+                (last_us - now_us) as usize,
+            );
+        }
+        last_us = now_us;
+    }
+
+    for clock in [ClockType::Realtime, ClockType::Monotonic] {
+        let mut resolution = Timeval::default();
+        let result = unsafe { syscalls::sys_clock_getres(clock as usize, &mut resolution) };
+        if result != 0 {
+            testing::fail(NAME, "clock_getres failed", result);
+        }
+
+        let tv_sec = resolution.tv_sec;
+        let tv_usec = resolution.tv_usec;
+        if tv_sec != 0 || tv_usec <= 0 {
+            testing::fail(NAME, "unexpected clock resolution in us", tv_usec as usize);
+        }
+    }
+
+    let mut timeval = Timeval::default();
+    let result = unsafe { syscalls::sys_clock_gettime(100, &mut timeval) };
+    if result != EINVAL {
+        testing::fail(
+            NAME,
+            "clock_gettime of an unknown clock did not return EINVAL",
+            result,
+        );
+    }
+
+    let result = unsafe { syscalls::sys_clock_getres(100, &mut timeval) };
+    if result != EINVAL {
+        testing::fail(
+            NAME,
+            "clock_getres of an unknown clock did not return EINVAL",
+            result,
+        );
+    }
+
+    testing::pass(NAME);
+}
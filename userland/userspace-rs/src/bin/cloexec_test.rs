@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "cloexec_test";
+
+const EBADF: usize = 9;
+
+/// descriptors the first run opens, in order, right after stdin/stdout/stderr.
+const CLOEXEC_FD: usize = 3;
+const KEPT_FD: usize = 4;
+const FCNTL_FD: usize = 5;
+
+/// runs in the image loaded by execvp, only the descriptor without the flag must be left.
+fn check_after_exec() -> ! {
+    let cloexec = unsafe { syscalls::sys_fcntl(CLOEXEC_FD, syscalls::F_GETFD, 0) };
+    if cloexec != EBADF {
+        testing::fail(NAME, "O_CLOEXEC descriptor survived exec", cloexec);
+    }
+
+    let fcntl = unsafe { syscalls::sys_fcntl(FCNTL_FD, syscalls::F_GETFD, 0) };
+    if fcntl != EBADF {
+        testing::fail(NAME, "FD_CLOEXEC descriptor survived exec", fcntl);
+    }
+
+    let mut buffer: [u8; 4] = [0; 4];
+    let read = unsafe { syscalls::sys_pread(KEPT_FD, &mut buffer, 4, 0) };
+    if read != 4 || &buffer != b"\x7fELF" {
+        testing::fail(NAME, "kept descriptor is not readable after exec", read);
+    }
+
+    testing::pass(NAME);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // the kept descriptor is only open if this image was exec'd by the first run.
+    if unsafe { syscalls::sys_fcntl(KEPT_FD, syscalls::F_GETFD, 0) } == 0 {
+        check_after_exec();
+    }
+
+    let path = b"/sbin/cloexec_test\0";
+    let fds = unsafe {
+        [
+            syscalls::sys_open(path, syscalls::O_CLOEXEC),
+            syscalls::sys_open(path, 0),
+            syscalls::sys_open(path, 0),
+        ]
+    };
+
+    if fds != [CLOEXEC_FD, KEPT_FD, FCNTL_FD] {
+        testing::fail(NAME, "unexpected descriptor numbers, first one", fds[0]);
+    }
+
+    let flags = unsafe {
+        syscalls::sys_fcntl(FCNTL_FD, syscalls::F_SETFD, syscalls::FD_CLOEXEC);
+        [
+            syscalls::sys_fcntl(CLOEXEC_FD, syscalls::F_GETFD, 0),
+            syscalls::sys_fcntl(KEPT_FD, syscalls::F_GETFD, 0),
+            syscalls::sys_fcntl(FCNTL_FD, syscalls::F_GETFD, 0),
+        ]
+    };
+
+    let expected = [syscalls::FD_CLOEXEC, 0, syscalls::FD_CLOEXEC];
+    if flags != expected {
+        testing::fail(
+            NAME,
+            "wrong descriptor flags before exec, O_CLOEXEC one",
+            flags[0],
+        );
+    }
+
+    let result = unsafe { syscalls::sys_execvp(path) };
+    testing::fail(NAME, "execvp failed", result);
+}
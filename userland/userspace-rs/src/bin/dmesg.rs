@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+use core::str;
+use userspace_rs::library;
+
+use library::syscalls;
+use userspace_rs::{print, println};
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    let fd = unsafe { syscalls::sys_open(b"/dev/kmsg\0", 0) };
+    // errors come back as plain errno values, they never collide with
+    // a new descriptor because 0, 1 and 2 are taken by stdio.
+    if fd <= 2 {
+        println!("dmesg: failed to open /dev/kmsg");
+        loop {}
+    }
+
+    // every read returns one line, 0 means we reached the latest message.
+    let mut line_buffer: [u8; 512] = [0; 512];
+    loop {
+        let read_length = unsafe { syscalls::sys_read(fd, &mut line_buffer, 512) };
+        if read_length == 0 {
+            break;
+        }
+
+        match str::from_utf8(&line_buffer[0..read_length]) {
+            Ok(line) => {
+                print!("{}", line);
+            }
+            Err(_) => {
+                println!("<invalid line>");
+            }
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+
+    loop {}
+}
@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "dns_test";
+
+const ENOENT: usize = 2;
+const EINVAL: usize = 22;
+const ENETUNREACH: usize = 101;
+const ETIMEDOUT: usize = 110;
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    let mut address: [u8; 4] = [0; 4];
+
+    // dotted decimal names don't need the network.
+    let literal = unsafe { syscalls::sys_gethostbyname(b"10.0.2.2\0", &mut address) };
+    if literal != 0 || address != [10, 0, 2, 2] {
+        testing::fail(NAME, "literal address was not returned as is", literal);
+    }
+
+    let empty_label = unsafe { syscalls::sys_gethostbyname(b"example..com\0", &mut address) };
+    if empty_label != EINVAL {
+        testing::fail(
+            NAME,
+            "name with an empty label was not rejected",
+            empty_label,
+        );
+    }
+
+    // these need a resolver that is reachable.
+    let found = unsafe { syscalls::sys_gethostbyname(b"example.com\0", &mut address) };
+    if found == ETIMEDOUT || found == ENETUNREACH {
+        testing::pass_with(
+            NAME,
+            format_args!("no DNS server answered, skipped the lookups"),
+        );
+    }
+
+    if found != 0 {
+        testing::fail(NAME, "example.com was not resolved", found);
+    }
+
+    // the second lookup is answered from the cache.
+    let mut cached: [u8; 4] = [0; 4];
+    let again = unsafe { syscalls::sys_gethostbyname(b"example.com\0", &mut cached) };
+    if again != 0 || cached != address {
+        testing::fail(NAME, "cached lookup returned a different answer", again);
+    }
+
+    let missing = unsafe { syscalls::sys_gethostbyname(b"does-not-exist.invalid\0", &mut address) };
+    if missing != ENOENT {
+        testing::fail(NAME, "NXDOMAIN was not reported as ENOENT", missing);
+    }
+
+    testing::pass_with(
+        NAME,
+        format_args!(
+            "example.com is {}.{}.{}.{}",
+            cached[0], cached[1], cached[2], cached[3]
+        ),
+    );
+}
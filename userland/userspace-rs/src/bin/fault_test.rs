@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+use core::fmt::Write;
+use core::ptr;
+use userspace_rs::library::testing;
+use userspace_rs::println;
+
+const NAME: &str = "fault_test";
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    println!("Dereferencing a null pointer, the kernel should terminate this process.");
+
+    let value = unsafe { ptr::read_volatile(0 as *const u64) };
+
+    // you should never come here!
+    testing::fail(
+        NAME,
+        "read from null, fault handling is broken, value",
+        value as usize,
+    );
+}
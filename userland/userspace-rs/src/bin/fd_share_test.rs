@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "fd_share_test";
+
+const SEEK_CUR: usize = 1;
+/// tarfs can only seek in steps of one block
+const STEP: usize = 512;
+/// how many times the parent yields before giving up on the child.
+const MAX_YIELDS: usize = 1000;
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // the file is only used for it's offset, it is never read.
+    let fd = unsafe { syscalls::sys_open(b"/sbin/sys_shell\0", 0) };
+    if fd <= 2 {
+        testing::fail(NAME, "failed to open the test file", fd);
+    }
+
+    // dup() must share the offset with the original descriptor.
+    let dup_fd = unsafe { syscalls::sys_dup(fd) };
+    let dup_offset = unsafe {
+        syscalls::sys_lseek(dup_fd, STEP, SEEK_CUR);
+        syscalls::sys_lseek(fd, 0, SEEK_CUR)
+    };
+    unsafe {
+        syscalls::sys_close(dup_fd);
+    }
+
+    if dup_offset != STEP {
+        testing::fail_with(
+            NAME,
+            format_args!("offset after dup is {}, expected {}", dup_offset, STEP),
+        );
+    }
+
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        // child moves the shared offset by one more block and exits.
+        unsafe {
+            syscalls::sys_lseek(fd, STEP, SEEK_CUR);
+            syscalls::sys_close(fd);
+            syscalls::sys_exit(0);
+        }
+        loop {}
+    }
+
+    // wait for the seek of the child to show up on our descriptor.
+    let mut offset = dup_offset;
+    for _ in 0..MAX_YIELDS {
+        offset = unsafe { syscalls::sys_lseek(fd, 0, SEEK_CUR) };
+        if offset != dup_offset {
+            break;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    // the description must still be alive after the child closed it's copy.
+    let final_offset = unsafe { syscalls::sys_lseek(fd, STEP, SEEK_CUR) };
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+
+    if offset != 2 * STEP || final_offset != 3 * STEP {
+        testing::fail_with(
+            NAME,
+            format_args!(
+                "offsets are {} and {}, expected {} and {}",
+                offset,
+                final_offset,
+                2 * STEP,
+                3 * STEP
+            ),
+        );
+    }
+
+    testing::pass(NAME);
+}
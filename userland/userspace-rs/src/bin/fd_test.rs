@@ -0,0 +1,139 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "fd_test";
+
+const EBADF: usize = 9;
+/// never opened, the test opens only a few files.
+const UNUSED_FD: usize = 60;
+const FILE_PATH: &[u8] = b"/sbin/sys_shell\0";
+const TTY_PATH: &[u8] = b"/dev/tty\0";
+
+fn open(path: &[u8], flags: usize) -> usize {
+    let fd = unsafe { syscalls::sys_open(path, flags) };
+    if fd <= 2 {
+        testing::fail(NAME, "failed to open the test file", fd);
+    }
+    fd
+}
+
+/// every call on the fd must fail with EBADF, `reason` says what the fd is.
+fn expect_bad_fd(fd: usize, reason: &str) {
+    let mut byte: [u8; 1] = [0; 1];
+    let results = unsafe {
+        [
+            syscalls::sys_read(fd, &mut byte, 1),
+            syscalls::sys_write(fd, &byte, 1),
+            syscalls::sys_dup(fd),
+            syscalls::sys_close(fd),
+        ]
+    };
+
+    for result in results {
+        if result != EBADF {
+            testing::fail(NAME, reason, result);
+        }
+    }
+}
+
+fn test_unopened() {
+    expect_bad_fd(UNUSED_FD, "call on an unopened fd did not fail with EBADF");
+}
+
+/// the number of a closed fd is not handed out again, so it can't reach the
+/// file opened after it.
+fn test_closed() {
+    let closed = open(FILE_PATH, 0);
+    let result = unsafe { syscalls::sys_close(closed) };
+    if result != 0 {
+        testing::fail(NAME, "close failed", result);
+    }
+    expect_bad_fd(closed, "call on a closed fd did not fail with EBADF");
+
+    let fd = open(FILE_PATH, 0);
+    if fd == closed {
+        testing::fail(NAME, "the number of the closed fd was reused", fd);
+    }
+    expect_bad_fd(
+        closed,
+        "call on a closed fd after a new open did not fail with EBADF",
+    );
+
+    let mut byte: [u8; 1] = [0; 1];
+    let read = unsafe { syscalls::sys_read(fd, &mut byte, 1) };
+    if read != 1 {
+        testing::fail(NAME, "read on the new fd failed", read);
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+}
+
+/// a write of 0 bytes, the buffer still has to be in userspace.
+fn write_empty(fd: usize) -> usize {
+    let byte: [u8; 1] = [0; 1];
+    unsafe { syscalls::sys_write(fd, &byte, 0) }
+}
+
+/// devices keep the access mode they were opened with, like files do. the
+/// writes are empty so nothing shows up on the terminal.
+fn test_device_mode() {
+    let read_only = open(TTY_PATH, 0);
+    let result = write_empty(read_only);
+    if result != EBADF {
+        testing::fail(
+            NAME,
+            "write on a read-only device did not fail with EBADF",
+            result,
+        );
+    }
+
+    let write_only = open(TTY_PATH, syscalls::O_WRONLY);
+    let mut byte: [u8; 1] = [0; 1];
+    // checked before the tty would wait for input.
+    let result = unsafe { syscalls::sys_read(write_only, &mut byte, 1) };
+    if result != EBADF {
+        testing::fail(
+            NAME,
+            "read on a write-only device did not fail with EBADF",
+            result,
+        );
+    }
+    let result = write_empty(write_only);
+    if result != 0 {
+        testing::fail(NAME, "write on a write-only device failed", result);
+    }
+
+    let read_write = open(TTY_PATH, syscalls::O_RDWR);
+    let result = write_empty(read_write);
+    if result != 0 {
+        testing::fail(NAME, "write on a read-write device failed", result);
+    }
+
+    // stdout is a dup of stdin, both must be writable.
+    for fd in [0, 1] {
+        let result = write_empty(fd);
+        if result != 0 {
+            testing::fail(NAME, "write on stdio failed, fd", fd);
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(read_only);
+        syscalls::sys_close(write_only);
+        syscalls::sys_close(read_write);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    test_unopened();
+    test_closed();
+    test_device_mode();
+
+    testing::pass(NAME);
+}
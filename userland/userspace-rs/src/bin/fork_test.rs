@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "fork_test";
+
+const EAGAIN: usize = 11;
+/// more than the kernel can hold, one of these forks has to fail.
+const MAX_FORKS: usize = 128;
+/// how many times the parent yields while the children exit.
+const MAX_YIELDS: usize = 1000;
+
+fn new_pipe() -> [i32; 2] {
+    let mut fds: [i32; 2] = [0; 2];
+    let result = unsafe { syscalls::sys_pipe(&mut fds) };
+    if result != 0 {
+        testing::fail(NAME, "pipe failed", result);
+    }
+    fds
+}
+
+/// the child says it started, then waits for the write end of `hold` to be
+/// closed, the pipe gives EOF then.
+fn wait_for_parent(started: &[i32; 2], hold: &[i32; 2]) -> ! {
+    let mut byte: [u8; 1] = [0; 1];
+    unsafe {
+        syscalls::sys_write(started[1] as usize, &[1], 1);
+        syscalls::sys_close(started[1] as usize);
+        syscalls::sys_close(hold[1] as usize);
+        syscalls::sys_read(hold[0] as usize, &mut byte, 1);
+        syscalls::sys_exit(testing::EXIT_PASS);
+    }
+    loop {}
+}
+
+/// forks until the kernel runs out of processes or stacks, fork must fail
+/// with EAGAIN instead of taking the kernel down, and work again after that.
+fn test_fork_bomb() {
+    let started = new_pipe();
+    let hold = new_pipe();
+
+    // errors come back as positive numbers, so a child with pid 11 looks
+    // like EAGAIN. the children are counted through `started` instead.
+    let mut n_eagain = 0;
+    for _ in 0..MAX_FORKS {
+        let result = unsafe { syscalls::sys_fork() };
+        if result == 0 {
+            wait_for_parent(&started, &hold);
+        }
+        if result == EAGAIN {
+            n_eagain += 1;
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(started[1] as usize);
+        syscalls::sys_close(hold[1] as usize);
+    }
+
+    // EOF once every child has exited.
+    let mut n_children = 0;
+    let mut byte: [u8; 1] = [0; 1];
+    while unsafe { syscalls::sys_read(started[0] as usize, &mut byte, 1) } == 1 {
+        n_children += 1;
+    }
+    unsafe {
+        syscalls::sys_close(started[0] as usize);
+        syscalls::sys_close(hold[0] as usize);
+    }
+
+    let n_failed = MAX_FORKS - n_children;
+    if n_failed == 0 {
+        testing::fail(NAME, "no fork failed, forked", n_children);
+    }
+    if n_eagain < n_failed {
+        testing::fail(NAME, "fork did not fail with EAGAIN, failures", n_failed);
+    }
+
+    // the children gave back what they held, but may not be gone yet.
+    let mut result = EAGAIN;
+    for _ in 0..MAX_YIELDS {
+        result = unsafe { syscalls::sys_fork() };
+        if result == 0 {
+            unsafe {
+                syscalls::sys_exit(testing::EXIT_PASS);
+            }
+        }
+        if result != EAGAIN {
+            return;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    testing::fail(NAME, "fork still fails after the children exited", result);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    test_fork_bomb();
+
+    testing::pass(NAME);
+}
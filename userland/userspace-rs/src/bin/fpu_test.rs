@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "fpu_test";
+
+// the programs are built without sse, so nothing but the asm below touches the
+// xmm registers, whatever is found in them was left there by this thread.
+
+const N_WORKERS: usize = 2;
+/// additions done by each worker, with a yield in between.
+const ROUNDS: usize = 2000;
+/// busy loop between the additions, so the timer also preempts the workers.
+const SPIN: usize = 2000;
+const MAX_WAIT_YIELDS: usize = 1000000;
+const WORKER_STACK_SIZE: usize = 16 * 1024;
+
+/// the start value of each worker, the main thread keeps an other one.
+const BASES: [f64; N_WORKERS] = [1000.0, -5000.5];
+const MAIN_BASE: f64 = 42.25;
+
+#[repr(C, align(16))]
+struct WorkerStack([u8; WORKER_STACK_SIZE]);
+
+static mut WORKER_STACKS: [WorkerStack; N_WORKERS] = [
+    WorkerStack([0; WORKER_STACK_SIZE]),
+    WorkerStack([0; WORKER_STACK_SIZE]),
+];
+
+static DONE: AtomicUsize = AtomicUsize::new(0);
+/// 1 + the index of the first worker that found a foreign value.
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+
+/// xmm0 = value, xmm1 = 1.0
+fn load_counter(value: f64) {
+    unsafe {
+        asm!(
+            "movq xmm0, {0}",
+            "movq xmm1, {1}",
+            in(reg) value.to_bits(),
+            in(reg) 1.0f64.to_bits(),
+            options(nomem, nostack)
+        );
+    }
+}
+
+/// xmm0 += xmm1
+fn increment_counter() {
+    unsafe {
+        asm!("addsd xmm0, xmm1", options(nomem, nostack));
+    }
+}
+
+fn read_counter() -> u64 {
+    let bits: u64;
+    unsafe {
+        asm!("movq {0}, xmm0", out(reg) bits, options(nomem, nostack));
+    }
+    bits
+}
+
+extern "C" fn worker_thread(index: usize) {
+    let base = BASES[index];
+    load_counter(base);
+
+    for round in 1..=ROUNDS {
+        increment_counter();
+        for _ in 0..SPIN {
+            core::hint::spin_loop();
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+
+        if read_counter() != (base + round as f64).to_bits() {
+            let _ = FAILED.compare_exchange(0, index + 1, Ordering::SeqCst, Ordering::SeqCst);
+            break;
+        }
+    }
+
+    DONE.fetch_add(1, Ordering::SeqCst);
+    loop {
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    load_counter(MAIN_BASE);
+
+    for index in 0..N_WORKERS {
+        let stack_end = unsafe { WORKER_STACKS[index].0.as_ptr() as usize + WORKER_STACK_SIZE };
+        unsafe {
+            syscalls::sys_thread_create(worker_thread, index, stack_end);
+        }
+    }
+
+    for _ in 0..MAX_WAIT_YIELDS {
+        if DONE.load(Ordering::SeqCst) == N_WORKERS {
+            break;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    let done = DONE.load(Ordering::SeqCst);
+    if done != N_WORKERS {
+        testing::fail(NAME, "the workers did not finish, finished", done);
+    }
+
+    let failed = FAILED.load(Ordering::SeqCst);
+    if failed != 0 {
+        testing::fail(NAME, "found another thread's xmm0, worker", failed - 1);
+    }
+
+    let counter = read_counter();
+    if counter != MAIN_BASE.to_bits() {
+        testing::fail(
+            NAME,
+            "xmm0 of the main thread changed, bits",
+            counter as usize,
+        );
+    }
+
+    testing::pass(NAME);
+}
@@ -0,0 +1,209 @@
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "kstack_test";
+
+// the workers keep faulting on their own heap page from ring 3, page faults have
+// no IST stack so the CPU takes RSP0, the kernel stack of the running thread.
+// a demand fault runs with interrupts off and never schedules, so it comes back
+// before any other thread can use RSP0, the workers check that it comes back
+// with their own registers.
+//
+// the one path that switches threads while on RSP0 is a fault that kills the
+// process, it calls sys_exit and yields from there. the children are killed
+// that way while the workers fault, the workers must not notice it.
+
+const PAGE_SIZE: usize = 4096;
+const N_WORKERS: usize = 2;
+/// faults taken by each worker, with a yield in between.
+const ROUNDS: usize = 500;
+/// busy loop between the faults, so the timer also preempts the workers.
+const SPIN: usize = 2000;
+const MAX_WAIT_YIELDS: usize = 1000000;
+/// children killed by a fault while the workers run.
+const N_CHILDREN: usize = 8;
+/// yields of the parent between two children.
+const RELEASE_EVERY: usize = 50;
+const WORKER_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct WorkerStack([u8; WORKER_STACK_SIZE]);
+
+static mut WORKER_STACKS: [WorkerStack; N_WORKERS] = [
+    WorkerStack([0; WORKER_STACK_SIZE]),
+    WorkerStack([0; WORKER_STACK_SIZE]),
+];
+
+static HEAP_PAGES: AtomicUsize = AtomicUsize::new(0);
+static DONE: AtomicUsize = AtomicUsize::new(0);
+/// 1 + the index of the first worker that came back with a wrong state.
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+/// in a read-only segment, the children die writing to it.
+static READ_ONLY: [u8; 4] = [1, 2, 3, 4];
+
+/// writes to the page with the pattern in r12-r15, so the write faults with
+/// them live, returns false if they or the stack pointer changed.
+fn fault_with_pattern(page: usize, pattern: u64) -> bool {
+    let (r12, r13, r14, r15): (u64, u64, u64, u64);
+    let (sp_before, sp_after): (u64, u64);
+    unsafe {
+        asm!(
+            "mov {sp_before}, rsp",
+            "mov byte ptr [{page}], 1",
+            "mov {sp_after}, rsp",
+            page = in(reg) page,
+            sp_before = out(reg) sp_before,
+            sp_after = out(reg) sp_after,
+            inout("r12") pattern => r12,
+            inout("r13") !pattern => r13,
+            inout("r14") pattern.rotate_left(17) => r14,
+            inout("r15") pattern ^ 0x5A5A_5A5A_5A5A_5A5A => r15,
+            options(nostack)
+        );
+    }
+
+    r12 == pattern
+        && r13 == !pattern
+        && r14 == pattern.rotate_left(17)
+        && r15 == pattern ^ 0x5A5A_5A5A_5A5A_5A5A
+        && sp_before == sp_after
+}
+
+extern "C" fn worker_thread(index: usize) {
+    let page = HEAP_PAGES.load(Ordering::SeqCst) + index * PAGE_SIZE;
+
+    for round in 0..ROUNDS {
+        // the next write maps a zeroed page again.
+        let result = unsafe { syscalls::sys_madvise(page, PAGE_SIZE, syscalls::MADV_DONTNEED) };
+        let pattern = ((index as u64 + 1) << 48) | round as u64;
+        let bytes = unsafe { core::slice::from_raw_parts(page as *const u8, 2) };
+        if result != 0 || !fault_with_pattern(page, pattern) || bytes != [1, 0] {
+            let _ = FAILED.compare_exchange(0, index + 1, Ordering::SeqCst, Ordering::SeqCst);
+            break;
+        }
+
+        for _ in 0..SPIN {
+            core::hint::spin_loop();
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    DONE.fetch_add(1, Ordering::SeqCst);
+    loop {
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+}
+
+fn new_pipe() -> [i32; 2] {
+    let mut fds: [i32; 2] = [0; 2];
+    let result = unsafe { syscalls::sys_pipe(&mut fds) };
+    if result != 0 {
+        testing::fail(NAME, "pipe failed", result);
+    }
+    fds
+}
+
+/// waits for a byte on `release`, then faults, the child writes to `survived`
+/// only if the kernel let it live.
+fn start_child(release: &[i32; 2], survived: &[i32; 2]) {
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid != 0 {
+        return;
+    }
+
+    let mut byte: [u8; 1] = [0; 1];
+    unsafe {
+        syscalls::sys_close(release[1] as usize);
+        syscalls::sys_close(survived[0] as usize);
+        syscalls::sys_read(release[0] as usize, &mut byte, 1);
+        core::ptr::write_volatile(READ_ONLY.as_ptr() as *mut u8, 0);
+        syscalls::sys_write(survived[1] as usize, &[1], 1);
+        syscalls::sys_exit(testing::EXIT_PASS);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // fork works only with one thread, so the children are started first.
+    let release = new_pipe();
+    let survived = new_pipe();
+    for _ in 0..N_CHILDREN {
+        start_child(&release, &survived);
+    }
+    unsafe {
+        syscalls::sys_close(release[0] as usize);
+        syscalls::sys_close(survived[1] as usize);
+    }
+
+    // one more page, so the worker pages can start page aligned.
+    let heap_start = unsafe { syscalls::sys_sbrk((N_WORKERS + 1) * PAGE_SIZE) };
+    if heap_start as isize <= 0 {
+        testing::fail(NAME, "sbrk failed", heap_start);
+    }
+    HEAP_PAGES.store(
+        (heap_start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+        Ordering::SeqCst,
+    );
+
+    for index in 0..N_WORKERS {
+        let stack_end = unsafe { WORKER_STACKS[index].0.as_ptr() as usize + WORKER_STACK_SIZE };
+        unsafe {
+            syscalls::sys_thread_create(worker_thread, index, stack_end);
+        }
+    }
+
+    let mut n_released = 0;
+    for round in 0..MAX_WAIT_YIELDS {
+        if n_released < N_CHILDREN && round % RELEASE_EVERY == 0 {
+            unsafe {
+                syscalls::sys_write(release[1] as usize, &[1], 1);
+            }
+            n_released += 1;
+        }
+        if DONE.load(Ordering::SeqCst) == N_WORKERS && n_released == N_CHILDREN {
+            break;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    let done = DONE.load(Ordering::SeqCst);
+    if done != N_WORKERS {
+        testing::fail(NAME, "the workers did not finish, finished", done);
+    }
+
+    // EOF once all the children are gone, none of them may get past the fault.
+    let mut byte: [u8; 1] = [0; 1];
+    let read = unsafe {
+        syscalls::sys_close(release[1] as usize);
+        syscalls::sys_read(survived[0] as usize, &mut byte, 1)
+    };
+    if read != 0 {
+        testing::fail(
+            NAME,
+            "a child survived writing to read-only data, read",
+            read,
+        );
+    }
+
+    let failed = FAILED.load(Ordering::SeqCst);
+    if failed != 0 {
+        testing::fail(
+            NAME,
+            "came back from a fault with a wrong state, worker",
+            failed - 1,
+        );
+    }
+
+    testing::pass(NAME);
+}
@@ -0,0 +1,172 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "madvise_test";
+
+const PAGE_SIZE: usize = 4096;
+/// two huge pages of heap, so whole huge pages and single 4KiB pages are both covered.
+const HEAP_SIZE: usize = 4 * 1024 * 1024;
+const SC_AVPHYS_PAGES: usize = 86;
+const EINVAL: usize = 22;
+/// how many times the parent checks for the frames of the exited child.
+const MAX_YIELDS: usize = 1000;
+
+fn fill(region: &mut [u8], value: u8) {
+    for byte in region.iter_mut() {
+        *byte = value;
+    }
+}
+
+fn is_filled(region: &[u8], value: u8) -> bool {
+    region.iter().all(|byte| *byte == value)
+}
+
+/// a page discarded before fork is unmapped from the page tables the child
+/// shares, the child must get it back as a zeroed page like the parent would.
+fn test_fork_touch(heap: &mut [u8], heap_start: usize) {
+    fill(heap, 0xA5);
+    let result = unsafe { syscalls::sys_madvise(heap_start, PAGE_SIZE, syscalls::MADV_DONTNEED) };
+    if result != 0 {
+        testing::fail(NAME, "madvise before fork failed", result);
+    }
+
+    let mut fds: [i32; 2] = [0; 2];
+    let result = unsafe { syscalls::sys_pipe(&mut fds) };
+    if result != 0 {
+        testing::fail(NAME, "pipe failed", result);
+    }
+
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        // a fault that is not handled kills the child before it writes anything.
+        let zeroed = is_filled(&heap[0..PAGE_SIZE], 0) as u8;
+        fill(&mut heap[0..PAGE_SIZE], 0x11);
+        unsafe {
+            syscalls::sys_write(fds[1] as usize, &[zeroed], 1);
+            syscalls::sys_exit(testing::EXIT_PASS);
+        }
+    }
+
+    let mut zeroed: [u8; 1] = [0xFF; 1];
+    let mut eof: [u8; 1] = [0; 1];
+    let (read, eof_read) = unsafe {
+        syscalls::sys_close(fds[1] as usize);
+        let read = syscalls::sys_read(fds[0] as usize, &mut zeroed, 1);
+        // the child's end is closed when it is gone.
+        let eof_read = syscalls::sys_read(fds[0] as usize, &mut eof, 1);
+        syscalls::sys_close(fds[0] as usize);
+        (read, eof_read)
+    };
+
+    if read != 1 {
+        testing::fail(
+            NAME,
+            "child died touching a page discarded before fork",
+            read,
+        );
+    }
+    if zeroed[0] != 1 {
+        testing::fail(NAME, "child did not see the discarded page zeroed", 0);
+    }
+    if eof_read != 0 || !is_filled(&heap[0..PAGE_SIZE], 0x11) {
+        testing::fail(NAME, "page brought in by the child is not shared", eof_read);
+    }
+
+    // with the child gone, the frames are freed again instead of zeroed in place.
+    for _ in 0..MAX_YIELDS {
+        fill(heap, 0xA5);
+        let free_before = unsafe { syscalls::sys_sysconf(SC_AVPHYS_PAGES) };
+        unsafe {
+            syscalls::sys_madvise(heap_start, heap.len(), syscalls::MADV_DONTNEED);
+        }
+        let free_after = unsafe { syscalls::sys_sysconf(SC_AVPHYS_PAGES) };
+        if free_after > free_before {
+            return;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    testing::fail(
+        NAME,
+        "no frames were freed after the forked child exited",
+        0,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    let heap_start = unsafe { syscalls::sys_sbrk(HEAP_SIZE) };
+    if heap_start as isize <= 0 || heap_start % PAGE_SIZE != 0 {
+        testing::fail(NAME, "sbrk failed", heap_start);
+    }
+
+    let heap = unsafe { core::slice::from_raw_parts_mut(heap_start as *mut u8, HEAP_SIZE) };
+    fill(heap, 0xA5);
+
+    // a single page in the middle, the pages around it must not change.
+    let single = heap_start + 3 * PAGE_SIZE;
+    let result = unsafe { syscalls::sys_madvise(single, PAGE_SIZE, syscalls::MADV_DONTNEED) };
+    if result != 0 {
+        testing::fail(NAME, "madvise of a single page failed", result);
+    }
+    if !is_filled(&heap[3 * PAGE_SIZE..4 * PAGE_SIZE], 0) {
+        testing::fail(NAME, "discarded page is not zeroed", 3);
+    }
+    if !is_filled(&heap[0..3 * PAGE_SIZE], 0xA5) || !is_filled(&heap[4 * PAGE_SIZE..], 0xA5) {
+        testing::fail(NAME, "pages next to the discarded page changed", 0);
+    }
+
+    // the whole heap, the frames go back to the allocator.
+    let free_before = unsafe { syscalls::sys_sysconf(SC_AVPHYS_PAGES) };
+    let result = unsafe { syscalls::sys_madvise(heap_start, HEAP_SIZE, syscalls::MADV_DONTNEED) };
+    if result != 0 {
+        testing::fail(NAME, "madvise of the heap failed", result);
+    }
+    let free_after = unsafe { syscalls::sys_sysconf(SC_AVPHYS_PAGES) };
+    if free_after <= free_before {
+        testing::fail(NAME, "no frames were freed", free_after);
+    }
+
+    if !is_filled(heap, 0) {
+        testing::fail(NAME, "discarded heap is not zeroed", 0);
+    }
+
+    // the pages are usable again after being faulted back in.
+    fill(heap, 0x5A);
+    if !is_filled(heap, 0x5A) {
+        testing::fail(NAME, "heap is not writable after madvise", 0);
+    }
+
+    let result =
+        unsafe { syscalls::sys_madvise(heap_start + 1, PAGE_SIZE, syscalls::MADV_DONTNEED) };
+    if result != EINVAL {
+        testing::fail(NAME, "unaligned address was accepted", result);
+    }
+
+    let result = unsafe { syscalls::sys_madvise(PAGE_SIZE, PAGE_SIZE, syscalls::MADV_DONTNEED) };
+    if result != EINVAL {
+        testing::fail(NAME, "unmapped range was accepted", result);
+    }
+
+    let result = unsafe {
+        syscalls::sys_madvise(heap_start, HEAP_SIZE + PAGE_SIZE, syscalls::MADV_DONTNEED)
+    };
+    if result != EINVAL {
+        testing::fail(NAME, "range past the heap was accepted", result);
+    }
+
+    // other advice is ignored, the contents stay.
+    let result = unsafe { syscalls::sys_madvise(heap_start, HEAP_SIZE, syscalls::MADV_NORMAL) };
+    if result != 0 || !is_filled(heap, 0x5A) {
+        testing::fail(NAME, "MADV_NORMAL changed the heap", result);
+    }
+
+    test_fork_touch(heap, heap_start);
+
+    testing::pass(NAME);
+}
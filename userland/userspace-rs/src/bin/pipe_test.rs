@@ -0,0 +1,237 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "pipe_test";
+
+const EBADF: usize = 9;
+const EAGAIN: usize = 11;
+const EINVAL: usize = 22;
+const EPIPE: usize = 32;
+
+const O_APPEND: usize = 0o2000;
+
+/// descriptors the first run creates, in order, right after stdin/stdout/stderr.
+const CLOEXEC_READ_FD: usize = 3;
+const CLOEXEC_WRITE_FD: usize = 4;
+const KEPT_READ_FD: usize = 5;
+const KEPT_WRITE_FD: usize = 6;
+
+const MESSAGE: &[u8] = b"through exec";
+const CHILD_MESSAGE: &[u8] = b"from the child";
+/// more than the pipe holds.
+const FILL_LIMIT: usize = 64 * 1024;
+
+fn new_pipe(flags: usize) -> [usize; 2] {
+    let mut fds: [i32; 2] = [-1, -1];
+    let result = unsafe { syscalls::sys_pipe2(&mut fds, flags) };
+    if result != 0 {
+        testing::fail(NAME, "pipe2 failed", result);
+    }
+    [fds[0] as usize, fds[1] as usize]
+}
+
+fn test_bad_flags() {
+    let mut fds: [i32; 2] = [-1, -1];
+    for flags in [O_APPEND, 0x1, 1 << 31] {
+        let result = unsafe { syscalls::sys_pipe2(&mut fds, flags) };
+        if result != EINVAL {
+            testing::fail(
+                NAME,
+                "pipe2 with unsupported flags did not fail with EINVAL",
+                result,
+            );
+        }
+    }
+}
+
+fn test_nonblocking() {
+    let [read_fd, write_fd] = new_pipe(syscalls::O_NONBLOCK);
+    let mut buffer: [u8; 64] = [0; 64];
+    let size = buffer.len();
+
+    let empty = unsafe { syscalls::sys_read(read_fd, &mut buffer, size) };
+    if empty != EAGAIN {
+        testing::fail(
+            NAME,
+            "read on an empty pipe did not fail with EAGAIN",
+            empty,
+        );
+    }
+
+    let wrong_end = unsafe { syscalls::sys_write(read_fd, MESSAGE, MESSAGE.len()) };
+    if wrong_end != EBADF {
+        testing::fail(
+            NAME,
+            "write to the read end did not fail with EBADF",
+            wrong_end,
+        );
+    }
+
+    // the writes stop with EAGAIN once the pipe is full.
+    let mut written = 0;
+    loop {
+        let result = unsafe { syscalls::sys_write(write_fd, &buffer, size) };
+        if result == EAGAIN {
+            break;
+        }
+        if result == 0 || result > size {
+            testing::fail(NAME, "write to a non-blocking pipe failed", result);
+        }
+        written += result;
+        if written > FILL_LIMIT {
+            testing::fail(NAME, "the pipe never filled up, bytes", written);
+        }
+    }
+
+    // what was written is still there after the writer is gone, then the end of file.
+    unsafe {
+        syscalls::sys_close(write_fd);
+    }
+
+    let mut read = 0;
+    loop {
+        let result = unsafe { syscalls::sys_read(read_fd, &mut buffer, size) };
+        if result == 0 {
+            break;
+        }
+        if result > size {
+            testing::fail(NAME, "read of a full pipe failed", result);
+        }
+        read += result;
+    }
+
+    if read != written {
+        testing::fail(NAME, "bytes read back from the pipe", read);
+    }
+
+    unsafe {
+        syscalls::sys_close(read_fd);
+    }
+}
+
+fn test_blocking() {
+    let [read_fd, write_fd] = new_pipe(0);
+
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        unsafe {
+            syscalls::sys_close(read_fd);
+            syscalls::sys_write(write_fd, CHILD_MESSAGE, CHILD_MESSAGE.len());
+            syscalls::sys_close(write_fd);
+            syscalls::sys_exit(0);
+        }
+        loop {}
+    }
+
+    unsafe {
+        syscalls::sys_close(write_fd);
+    }
+
+    // this waits for the child, it may come in more than one piece.
+    let mut buffer: [u8; 64] = [0; 64];
+    let mut read = 0;
+    loop {
+        let result = unsafe { syscalls::sys_read(read_fd, &mut buffer[read..], 64 - read) };
+        if result == 0 {
+            break;
+        }
+        if result > 64 - read {
+            testing::fail(NAME, "blocking read failed", result);
+        }
+        read += result;
+    }
+
+    if &buffer[0..read] != CHILD_MESSAGE {
+        testing::fail(NAME, "wrong message from the child, bytes", read);
+    }
+
+    // nobody can read it anymore.
+    let [read_fd, write_fd] = new_pipe(0);
+    unsafe {
+        syscalls::sys_close(read_fd);
+    }
+    let broken = unsafe { syscalls::sys_write(write_fd, MESSAGE, MESSAGE.len()) };
+    if broken != EPIPE {
+        testing::fail(
+            NAME,
+            "write without readers did not fail with EPIPE",
+            broken,
+        );
+    }
+    unsafe {
+        syscalls::sys_close(write_fd);
+    }
+}
+
+/// runs in the image loaded by execvp, only the pipe without O_CLOEXEC must be left.
+fn check_after_exec() -> ! {
+    for fd in [CLOEXEC_READ_FD, CLOEXEC_WRITE_FD] {
+        let result = unsafe { syscalls::sys_fcntl(fd, syscalls::F_GETFD, 0) };
+        if result != EBADF {
+            testing::fail(NAME, "O_CLOEXEC end survived exec, descriptor", fd);
+        }
+    }
+
+    let mut buffer: [u8; 64] = [0; 64];
+    let size = buffer.len();
+    let read = unsafe { syscalls::sys_read(KEPT_READ_FD, &mut buffer, size) };
+    if read != MESSAGE.len() || &buffer[0..read] != MESSAGE {
+        testing::fail(NAME, "kept pipe lost the message across exec, bytes", read);
+    }
+
+    unsafe {
+        syscalls::sys_close(KEPT_READ_FD);
+        syscalls::sys_close(KEPT_WRITE_FD);
+    }
+
+    test_bad_flags();
+    test_nonblocking();
+    test_blocking();
+
+    testing::pass(NAME);
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // the kept pipe is only open if this image was exec'd by the first run.
+    if unsafe { syscalls::sys_fcntl(KEPT_READ_FD, syscalls::F_GETFD, 0) } == 0 {
+        check_after_exec();
+    }
+
+    let cloexec_fds = new_pipe(syscalls::O_CLOEXEC);
+    let kept_fds = new_pipe(0);
+    if cloexec_fds != [CLOEXEC_READ_FD, CLOEXEC_WRITE_FD]
+        || kept_fds != [KEPT_READ_FD, KEPT_WRITE_FD]
+    {
+        testing::fail(
+            NAME,
+            "unexpected descriptor numbers, first one",
+            cloexec_fds[0],
+        );
+    }
+
+    let flags = unsafe {
+        [
+            syscalls::sys_fcntl(CLOEXEC_READ_FD, syscalls::F_GETFD, 0),
+            syscalls::sys_fcntl(CLOEXEC_WRITE_FD, syscalls::F_GETFD, 0),
+        ]
+    };
+    if flags != [syscalls::FD_CLOEXEC, syscalls::FD_CLOEXEC] {
+        testing::fail(
+            NAME,
+            "O_CLOEXEC is not set on both ends, read end",
+            flags[0],
+        );
+    }
+
+    let written = unsafe { syscalls::sys_write(KEPT_WRITE_FD, MESSAGE, MESSAGE.len()) };
+    if written != MESSAGE.len() {
+        testing::fail(NAME, "write to the kept pipe failed", written);
+    }
+
+    let result = unsafe { syscalls::sys_execvp(b"/sbin/pipe_test\0") };
+    testing::fail(NAME, "execvp failed", result);
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::library::types::{PrctlOption, TASK_NAME_LEN};
+
+const NAME: &str = "prctl_test";
+
+const EINVAL: usize = 22;
+const ENAMETOOLONG: usize = 63;
+
+fn name_buffer(name: &[u8]) -> [u8; TASK_NAME_LEN] {
+    let mut buffer = [0u8; TASK_NAME_LEN];
+    buffer[0..name.len()].copy_from_slice(name);
+    buffer
+}
+
+fn get_name(option: PrctlOption) -> [u8; TASK_NAME_LEN] {
+    let mut buffer = [0xFFu8; TASK_NAME_LEN];
+    let result = unsafe { syscalls::sys_prctl(option as usize, &mut buffer) };
+    if result != 0 {
+        testing::fail(NAME, "reading the name failed", result);
+    }
+    buffer
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // started from /sbin/prctl_test, so the process is named after it.
+    if get_name(PrctlOption::GetProcessName) != name_buffer(b"prctl_test") {
+        testing::fail(NAME, "unexpected process name", 0);
+    }
+
+    if get_name(PrctlOption::GetName) != name_buffer(b"main") {
+        testing::fail(NAME, "unexpected thread name", 0);
+    }
+
+    let mut thread_name = name_buffer(b"worker-1");
+    let result = unsafe { syscalls::sys_prctl(PrctlOption::SetName as usize, &mut thread_name) };
+    if result != 0 {
+        testing::fail(NAME, "renaming the thread failed", result);
+    }
+    if get_name(PrctlOption::GetName) != name_buffer(b"worker-1") {
+        testing::fail(NAME, "thread name did not change", 0);
+    }
+
+    let mut proc_name = name_buffer(b"renamed");
+    let result =
+        unsafe { syscalls::sys_prctl(PrctlOption::SetProcessName as usize, &mut proc_name) };
+    if result != 0 {
+        testing::fail(NAME, "renaming the process failed", result);
+    }
+    if get_name(PrctlOption::GetProcessName) != name_buffer(b"renamed") {
+        testing::fail(NAME, "process name did not change", 0);
+    }
+
+    // no NUL in the buffer, the name can't fit.
+    let mut long_name = [b'a'; TASK_NAME_LEN];
+    let result = unsafe { syscalls::sys_prctl(PrctlOption::SetName as usize, &mut long_name) };
+    if result != ENAMETOOLONG {
+        testing::fail(NAME, "too long name did not return ENAMETOOLONG", result);
+    }
+
+    let mut bad_utf8 = name_buffer(&[0xC3, 0x28]);
+    let result = unsafe { syscalls::sys_prctl(PrctlOption::SetName as usize, &mut bad_utf8) };
+    if result != EINVAL {
+        testing::fail(NAME, "non UTF-8 name did not return EINVAL", result);
+    }
+
+    let mut empty = name_buffer(b"");
+    let result = unsafe { syscalls::sys_prctl(PrctlOption::SetName as usize, &mut empty) };
+    if result != EINVAL {
+        testing::fail(NAME, "empty name did not return EINVAL", result);
+    }
+
+    // the failed calls must not touch the name.
+    if get_name(PrctlOption::GetName) != name_buffer(b"worker-1") {
+        testing::fail(NAME, "thread name changed by a failed call", 0);
+    }
+
+    let result = unsafe { syscalls::sys_prctl(1234, &mut empty) };
+    if result != EINVAL {
+        testing::fail(NAME, "unknown option did not return EINVAL", result);
+    }
+
+    testing::pass(NAME);
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+use core::ptr;
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "protect_test";
+
+/// in a read-only segment.
+static READ_ONLY: [u8; 4] = [1, 2, 3, 4];
+/// a `ret` in a writable, not executable segment.
+static mut NOT_CODE: [u8; 1] = [0xC3];
+
+fn write_read_only() {
+    unsafe {
+        ptr::write_volatile(READ_ONLY.as_ptr() as *mut u8, 0);
+    }
+}
+
+fn run_data() {
+    // the write brings the page in, so the call faults on the protection.
+    unsafe {
+        let code = ptr::addr_of_mut!(NOT_CODE) as *mut u8;
+        ptr::write_volatile(code, 0xC3);
+        let entry: extern "C" fn() = core::mem::transmute(code);
+        entry();
+    }
+}
+
+/// runs the access in a child, it must be killed before it reports back.
+fn expect_killed(access: fn(), reason: &str) {
+    let mut fds: [i32; 2] = [0; 2];
+    let result = unsafe { syscalls::sys_pipe(&mut fds) };
+    if result != 0 {
+        testing::fail(NAME, "pipe failed", result);
+    }
+
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        access();
+        unsafe {
+            syscalls::sys_write(fds[1] as usize, &[1], 1);
+            syscalls::sys_exit(testing::EXIT_PASS);
+        }
+    }
+
+    let mut survived: [u8; 1] = [0; 1];
+    let read = unsafe {
+        syscalls::sys_close(fds[1] as usize);
+        let read = syscalls::sys_read(fds[0] as usize, &mut survived, 1);
+        syscalls::sys_close(fds[0] as usize);
+        read
+    };
+
+    if read != 0 {
+        testing::fail(NAME, reason, read);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    expect_killed(
+        write_read_only,
+        "write to a read-only segment did not fault, read",
+    );
+    expect_killed(run_data, "call into a data segment did not fault, read");
+
+    if READ_ONLY != [1, 2, 3, 4] {
+        testing::fail(NAME, "read-only data was changed", READ_ONLY[0] as usize);
+    }
+
+    testing::pass(NAME);
+}
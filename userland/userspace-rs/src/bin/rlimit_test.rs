@@ -0,0 +1,133 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::library::types::{RLimit, RLimitResource};
+
+const NAME: &str = "rlimit_test";
+
+const NOFILE_LIMIT: u64 = 8;
+const DATA_LIMIT: u64 = 4 * 1024 * 1024;
+const EPERM: usize = 1;
+const ENOMEM: usize = 12;
+const EINVAL: usize = 22;
+const EMFILE: usize = 24;
+
+fn get_limit(resource: RLimitResource) -> RLimit {
+    let mut limit = RLimit::default();
+    let result = unsafe { syscalls::sys_getrlimit(resource as usize, &mut limit) };
+    if result != 0 {
+        testing::fail(NAME, "getrlimit failed", result);
+    }
+    limit
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    let nofile = get_limit(RLimitResource::NoFile);
+    if nofile.current == 0 || nofile.current > nofile.max {
+        testing::fail(NAME, "bad default RLIMIT_NOFILE", nofile.current as usize);
+    }
+
+    // the hard limit can't be raised.
+    let raised = RLimit {
+        current: nofile.max + 1,
+        max: nofile.max + 1,
+    };
+    let result = unsafe { syscalls::sys_setrlimit(RLimitResource::NoFile as usize, &raised) };
+    if result != EPERM {
+        testing::fail(NAME, "raising the hard limit did not return EPERM", result);
+    }
+
+    let inverted = RLimit {
+        current: nofile.max,
+        max: NOFILE_LIMIT,
+    };
+    let result = unsafe { syscalls::sys_setrlimit(RLimitResource::NoFile as usize, &inverted) };
+    if result != EINVAL {
+        testing::fail(
+            NAME,
+            "soft limit above the hard limit did not return EINVAL",
+            result,
+        );
+    }
+
+    // lower the soft limit and run out of descriptors.
+    let lowered = RLimit {
+        current: NOFILE_LIMIT,
+        max: nofile.max,
+    };
+    let result = unsafe { syscalls::sys_setrlimit(RLimitResource::NoFile as usize, &lowered) };
+    if result != 0 {
+        testing::fail(NAME, "lowering RLIMIT_NOFILE failed", result);
+    }
+
+    let mut n_dups = 0;
+    loop {
+        let fd = unsafe { syscalls::sys_dup(1) };
+        if fd == EMFILE {
+            break;
+        }
+        if fd as u64 >= NOFILE_LIMIT {
+            testing::fail(NAME, "dup returned a descriptor above the limit", fd);
+        }
+        n_dups += 1;
+    }
+    if n_dups == 0 {
+        testing::fail(NAME, "no descriptor could be duplicated", 0);
+    }
+
+    // the child must see the same limits.
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        let child_nofile = get_limit(RLimitResource::NoFile);
+        if child_nofile.current != NOFILE_LIMIT || child_nofile.max != nofile.max {
+            testing::fail_with(
+                NAME,
+                format_args!(
+                    "child RLIMIT_NOFILE is {}, expected {}",
+                    child_nofile.current, NOFILE_LIMIT
+                ),
+            );
+        }
+        unsafe {
+            syscalls::sys_exit(testing::EXIT_PASS);
+        }
+        loop {}
+    }
+
+    // the soft limit can go back up to the hard limit.
+    let result = unsafe { syscalls::sys_setrlimit(RLimitResource::NoFile as usize, &nofile) };
+    if result != 0 {
+        testing::fail(NAME, "restoring RLIMIT_NOFILE failed", result);
+    }
+
+    // the heap can't grow past RLIMIT_DATA.
+    let data = get_limit(RLimitResource::Data);
+    let small_data = RLimit {
+        current: DATA_LIMIT,
+        max: data.max,
+    };
+    let result = unsafe { syscalls::sys_setrlimit(RLimitResource::Data as usize, &small_data) };
+    if result != 0 {
+        testing::fail(NAME, "lowering RLIMIT_DATA failed", result);
+    }
+
+    let result = unsafe { syscalls::sys_sbrk(2 * DATA_LIMIT as usize) };
+    if result != ENOMEM {
+        testing::fail(NAME, "sbrk past RLIMIT_DATA did not return ENOMEM", result);
+    }
+
+    let result = unsafe { syscalls::sys_sbrk(DATA_LIMIT as usize) };
+    if result == ENOMEM {
+        testing::fail(NAME, "sbrk below RLIMIT_DATA failed", result);
+    }
+
+    let unknown = unsafe { syscalls::sys_setrlimit(1000, &nofile) };
+    if unknown != EINVAL {
+        testing::fail(NAME, "unknown resource did not return EINVAL", unknown);
+    }
+
+    testing::pass(NAME);
+}
@@ -7,7 +7,10 @@ use userspace_rs::library;
 
 use library::utils::{
     get_uname, read_stdin, str_from_c_like_buffer, power_off_machine,
-    lstat,
+    lstat, cpustat, ifconfig, parse_ipv4, execvp,
+};
+use library::types::{
+    IfconfigRequest, IFCONFIG_SET_ADDRESS, IFCONFIG_SET_NAPI_THRESHOLD, IFCONFIG_UP,
 };
 use userspace_rs::{print, println};
 
@@ -62,6 +65,84 @@ fn sizeof(arg_str: &str) {
     }
 }
 
+#[inline]
+fn exec(arg_str: &str) {
+    // replaces the shell, so this only comes back on errors.
+    let exec_result = execvp(arg_str.trim());
+    if let Err(err_code) = exec_result {
+        println!("'exec' exited with invalid code: {}", err_code);
+    }
+}
+
+#[inline]
+fn cpu(_arg_str: &str) {
+    let stat_result = cpustat();
+    if let Ok(stat) = stat_result {
+        println!(
+            "cpu: {}% busy, idle {}ms of {}ms uptime",
+            stat.utilization,
+            stat.idle_ns / 1000000,
+            stat.uptime_ns / 1000000
+        );
+    } else {
+        println!(
+            "'cpu' exited with invalid code: {}",
+            stat_result.unwrap_err()
+        );
+    }
+}
+
+/// usage: ifconfig up|down, ifconfig napi <threshold> or ifconfig <ip>/<prefix> [gateway]
+fn ifconfig_cmd(arg_str: &str) {
+    let mut request = IfconfigRequest::default();
+    let mut args = arg_str.split_whitespace();
+
+    match args.next() {
+        Some("up") => request.flags = IFCONFIG_UP,
+        Some("down") => request.flags = 0,
+        Some("napi") => {
+            let threshold = args.next().and_then(|t| t.parse::<u32>().ok());
+            if threshold.is_none() {
+                println!("usage: ifconfig napi <threshold>, 0 turns polling off");
+                return;
+            }
+
+            request.napi_threshold = threshold.unwrap();
+            request.flags = IFCONFIG_SET_NAPI_THRESHOLD;
+        }
+        Some(cidr) => {
+            let mut cidr_parts = cidr.splitn(2, '/');
+            let address = cidr_parts.next().and_then(parse_ipv4);
+            let prefix = cidr_parts.next().and_then(|p| p.parse::<u32>().ok());
+            if address.is_none() || prefix.is_none() {
+                println!("ifconfig: invalid address {}", cidr);
+                return;
+            }
+
+            request.address = address.unwrap();
+            request.prefix = prefix.unwrap();
+            request.flags = IFCONFIG_UP | IFCONFIG_SET_ADDRESS;
+
+            if let Some(gateway_str) = args.next() {
+                if let Some(gateway) = parse_ipv4(gateway_str) {
+                    request.gateway = gateway;
+                } else {
+                    println!("ifconfig: invalid gateway {}", gateway_str);
+                    return;
+                }
+            }
+        }
+        None => {
+            println!("usage: ifconfig up|down|napi <threshold>|<ip>/<prefix> [gateway]");
+            return;
+        }
+    }
+
+    if let Err(code) = ifconfig(&request) {
+        println!("'ifconfig' exited with invalid code: {}", code);
+    }
+}
+
 #[inline(always)]
 fn get_string_view(buffer: &[u8], length: usize) -> &str {
     if let Ok(string) = str::from_utf8(&buffer[0..length]) {
@@ -94,6 +175,15 @@ fn exec_command(string: &str) {
         "sizeof" => {
             sizeof(&remaining_str);
         }
+        "cpu" => {
+            cpu(&remaining_str);
+        }
+        "ifconfig" => {
+            ifconfig_cmd(&remaining_str);
+        }
+        "exec" => {
+            exec(&remaining_str);
+        }
         _ => {
             println!("unknown command {}", command_str);
         }
@@ -0,0 +1,218 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::library::types::{SockAddrIn, AF_INET, AF_INET6, SOCK_DGRAM};
+
+const NAME: &str = "socket_test";
+
+const EADDRINUSE: usize = 98;
+const EAFNOSUPPORT: usize = 97;
+const EINVAL: usize = 22;
+const ENOPROTOOPT: usize = 92;
+const ENOTSOCK: usize = 88;
+
+/// TTL of the packets when it is not set.
+const DEFAULT_TTL: u32 = 64;
+
+/// below the ephemeral range, so no socket is bound to them by accident.
+const CLOSE_PORT: u16 = 7001;
+const EXIT_PORT: u16 = 7002;
+/// how many times the parent yields before giving up on the child.
+const MAX_YIELDS: usize = 1000;
+
+fn new_socket() -> usize {
+    let fd = unsafe { syscalls::sys_socket(AF_INET as usize, SOCK_DGRAM, 0) };
+    if fd <= 2 {
+        testing::fail(NAME, "failed to create a socket", fd);
+    }
+    fd
+}
+
+fn bind(fd: usize, port: u16) -> usize {
+    unsafe { syscalls::sys_bind(fd, &SockAddrIn::new([0; 4], port)) }
+}
+
+fn test_not_a_socket() {
+    let result = bind(0, CLOSE_PORT);
+    if result != ENOTSOCK {
+        testing::fail(
+            NAME,
+            "bind on the terminal did not fail with ENOTSOCK",
+            result,
+        );
+    }
+}
+
+/// only IPv4 addresses can be bound, IPv6 ones are not taken as IPv4.
+fn test_family() {
+    let fd = new_socket();
+    let mut addr = SockAddrIn::new([0; 4], CLOSE_PORT);
+    addr.family = AF_INET6;
+    let result = unsafe { syscalls::sys_bind(fd, &addr) };
+    if result != EAFNOSUPPORT {
+        testing::fail(
+            NAME,
+            "bind to an IPv6 address did not fail with EAFNOSUPPORT",
+            result,
+        );
+    }
+
+    addr.family = 0xFF;
+    let result = unsafe { syscalls::sys_bind(fd, &addr) };
+    if result != EINVAL {
+        testing::fail(
+            NAME,
+            "bind to an unknown family did not fail with EINVAL",
+            result,
+        );
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+}
+
+/// the port of a closed socket can be bound again.
+fn test_close() {
+    let first = new_socket();
+    let result = bind(first, CLOSE_PORT);
+    if result != 0 {
+        testing::fail(NAME, "bind failed", result);
+    }
+
+    let second = new_socket();
+    let result = bind(second, CLOSE_PORT);
+    if result != EADDRINUSE {
+        testing::fail(
+            NAME,
+            "second bind to the port did not fail with EADDRINUSE",
+            result,
+        );
+    }
+
+    unsafe {
+        syscalls::sys_close(first);
+    }
+    let result = bind(second, CLOSE_PORT);
+    if result != 0 {
+        testing::fail(NAME, "port was not released by close", result);
+    }
+
+    unsafe {
+        syscalls::sys_close(second);
+    }
+}
+
+fn get_ttl(fd: usize) -> u32 {
+    let mut ttl: u32 = 0;
+    let result =
+        unsafe { syscalls::sys_getsockopt(fd, syscalls::SOL_IP, syscalls::IP_TTL, &mut ttl) };
+    if result != 0 {
+        testing::fail(NAME, "getsockopt IP_TTL failed", result);
+    }
+    ttl
+}
+
+fn test_options() {
+    let fd = new_socket();
+    if get_ttl(fd) != DEFAULT_TTL {
+        testing::fail(NAME, "TTL of a new socket is", get_ttl(fd) as usize);
+    }
+
+    let result = unsafe { syscalls::sys_setsockopt(fd, syscalls::SOL_IP, syscalls::IP_TTL, &5) };
+    if result != 0 || get_ttl(fd) != 5 {
+        testing::fail(
+            NAME,
+            "setsockopt IP_TTL did not set it, TTL",
+            get_ttl(fd) as usize,
+        );
+    }
+
+    let result = unsafe { syscalls::sys_setsockopt(fd, syscalls::SOL_IP, syscalls::IP_TTL, &0) };
+    if result != EINVAL {
+        testing::fail(NAME, "TTL 0 did not fail with EINVAL", result);
+    }
+
+    // smoltcp can't put it in the packets, so it is not taken.
+    let result = unsafe { syscalls::sys_setsockopt(fd, syscalls::SOL_IP, syscalls::IP_TOS, &0x10) };
+    if result != ENOPROTOOPT {
+        testing::fail(NAME, "IP_TOS did not fail with ENOPROTOOPT", result);
+    }
+
+    let mut value: u32 = 0;
+    let result =
+        unsafe { syscalls::sys_getsockopt(0, syscalls::SOL_IP, syscalls::IP_TTL, &mut value) };
+    if result != ENOTSOCK {
+        testing::fail(
+            NAME,
+            "getsockopt on the terminal did not fail with ENOTSOCK",
+            result,
+        );
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+}
+
+/// the child exits without closing it's socket, the kernel has to.
+fn test_exit() {
+    let mut fds: [i32; 2] = [0; 2];
+    let result = unsafe { syscalls::sys_pipe(&mut fds) };
+    if result != 0 {
+        testing::fail(NAME, "pipe failed", result);
+    }
+
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        let fd = new_socket();
+        let result = bind(fd, EXIT_PORT);
+        unsafe {
+            syscalls::sys_write(fds[1] as usize, &[result as u8], 1);
+            syscalls::sys_exit(testing::EXIT_PASS);
+        }
+    }
+
+    let mut bound: [u8; 1] = [0xFF; 1];
+    unsafe {
+        syscalls::sys_close(fds[1] as usize);
+        syscalls::sys_read(fds[0] as usize, &mut bound, 1);
+        syscalls::sys_close(fds[0] as usize);
+    }
+    if bound[0] != 0 {
+        testing::fail(NAME, "bind in the child failed", bound[0] as usize);
+    }
+
+    let fd = new_socket();
+    let mut result = EADDRINUSE;
+    for _ in 0..MAX_YIELDS {
+        result = bind(fd, EXIT_PORT);
+        if result != EADDRINUSE {
+            break;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    if result != 0 {
+        testing::fail(NAME, "port of the exited child was not released", result);
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    test_not_a_socket();
+    test_family();
+    test_close();
+    test_options();
+    test_exit();
+
+    testing::pass(NAME);
+}
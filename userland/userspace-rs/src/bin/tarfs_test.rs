@@ -0,0 +1,394 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::library::types::{ClockType, Timeval};
+
+const NAME: &str = "tarfs_test";
+
+const ENOENT: usize = 2;
+const EEXIST: usize = 17;
+const EINVAL: usize = 22;
+const ESPIPE: usize = 29;
+const EROFS: usize = 30;
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+/// tarfs reads and seeks in steps of one block
+const BLOCK_SIZE: usize = 512;
+
+/// a new entry goes to the end of the archive, the only place tarfs can write to.
+const PWRITE_PATH: &[u8] = b"/sbin/tarfs_pwrite.dat\0";
+/// devices that can't be read at an offset.
+const STREAM_DEVICES: [&[u8]; 2] = [b"/dev/tty\0", b"/dev/serial\0"];
+
+/// tarfs can't remove files and the archive is kept between boots, so the
+/// files that must not exist yet get the time in their name.
+const EXCL_PREFIX: &[u8] = b"/sbin/tarfs_excl_";
+const TRUNC_PREFIX: &[u8] = b"/sbin/tarfs_trunc_";
+const SIZE_PREFIX: &[u8] = b"/sbin/tarfs_size_";
+const N_EXCL_ROUNDS: u64 = 4;
+/// prefix, 16 hex digits and the NUL.
+const PATH_SIZE: usize = 64;
+
+fn open(path: &[u8], flags: usize) -> usize {
+    let fd = unsafe { syscalls::sys_open(path, flags) };
+    if fd <= 2 {
+        testing::fail(NAME, "failed to open the test file", fd);
+    }
+    fd
+}
+
+/// `prefix` followed by `id` in hex and a NUL.
+fn unique_path(prefix: &[u8], id: u64, path: &mut [u8; PATH_SIZE]) -> usize {
+    let digits = b"0123456789abcdef";
+    path[..prefix.len()].copy_from_slice(prefix);
+    for idx in 0..16 {
+        path[prefix.len() + idx] = digits[((id >> (60 - idx * 4)) & 0xF) as usize];
+    }
+    path[prefix.len() + 16] = 0;
+    prefix.len() + 17
+}
+
+fn now_us() -> u64 {
+    let mut timeval = Timeval::default();
+    let result = unsafe { syscalls::sys_clock_gettime(ClockType::Realtime as usize, &mut timeval) };
+    if result != 0 {
+        testing::fail(NAME, "clock_gettime failed", result);
+    }
+
+    let tv_sec = timeval.tv_sec;
+    let tv_usec = timeval.tv_usec;
+    tv_sec as u64 * 1000000 + tv_usec as u64
+}
+
+fn pwrite_block(fd: usize, fill: u8, offset: usize) -> usize {
+    let block: [u8; BLOCK_SIZE] = [fill; BLOCK_SIZE];
+    unsafe { syscalls::sys_pwrite(fd, &block, BLOCK_SIZE, offset) }
+}
+
+/// pwrite works only at the end of the file and leaves the offset of the fd alone.
+fn test_pwrite() {
+    let fd = open(
+        PWRITE_PATH,
+        syscalls::O_CREAT | syscalls::O_RDWR | syscalls::O_TRUNC,
+    );
+
+    for (idx, fill) in [0x11, 0x22].iter().enumerate() {
+        let result = pwrite_block(fd, *fill, idx * BLOCK_SIZE);
+        if result != BLOCK_SIZE {
+            testing::fail(NAME, "pwrite at the end of the file failed", result);
+        }
+    }
+
+    let offset = unsafe { syscalls::sys_lseek(fd, BLOCK_SIZE, SEEK_SET) };
+    if offset != BLOCK_SIZE {
+        testing::fail(NAME, "lseek to the second block failed", offset);
+    }
+
+    let result = pwrite_block(fd, 0x33, 2 * BLOCK_SIZE);
+    if result != BLOCK_SIZE {
+        testing::fail(NAME, "pwrite at the end of the file failed", result);
+    }
+
+    // it would be appended instead of overwriting the first block.
+    let result = pwrite_block(fd, 0x44, 0);
+    if result != EINVAL {
+        testing::fail(
+            NAME,
+            "pwrite before the end of the file did not fail with EINVAL",
+            result,
+        );
+    }
+
+    let offset = unsafe { syscalls::sys_lseek(fd, 0, SEEK_CUR) };
+    if offset != BLOCK_SIZE {
+        testing::fail(NAME, "pwrite moved the offset of the fd to", offset);
+    }
+
+    let mut block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+    for (idx, fill) in [0x11, 0x22, 0x33].iter().enumerate() {
+        let read = unsafe { syscalls::sys_pread(fd, &mut block, BLOCK_SIZE, idx * BLOCK_SIZE) };
+        if read != BLOCK_SIZE || block.iter().any(|byte| byte != fill) {
+            testing::fail(NAME, "wrong data read back from block", idx);
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+}
+
+/// pread leaves the offset of the fd alone too, and finds nothing past the end
+/// of the file written by `test_pwrite`.
+fn test_pread() {
+    let fd = open(PWRITE_PATH, 0);
+    let offset = unsafe { syscalls::sys_lseek(fd, BLOCK_SIZE, SEEK_SET) };
+    if offset != BLOCK_SIZE {
+        testing::fail(NAME, "lseek to the second block failed", offset);
+    }
+
+    let mut block: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+    let read = unsafe { syscalls::sys_pread(fd, &mut block, BLOCK_SIZE, 2 * BLOCK_SIZE) };
+    if read != BLOCK_SIZE || block.iter().any(|byte| *byte != 0x33) {
+        testing::fail(NAME, "wrong data read back from block", 2);
+    }
+
+    let offset = unsafe { syscalls::sys_lseek(fd, 0, SEEK_CUR) };
+    if offset != BLOCK_SIZE {
+        testing::fail(NAME, "pread moved the offset of the fd to", offset);
+    }
+
+    // the read goes on from the offset of the fd, not from the pread.
+    let read = unsafe { syscalls::sys_read(fd, &mut block, BLOCK_SIZE) };
+    if read != BLOCK_SIZE || block.iter().any(|byte| *byte != 0x22) {
+        testing::fail(NAME, "read after pread did not read block", 1);
+    }
+
+    // the file has 3 blocks, both are block aligned so the seek takes them.
+    for index in [3, 8] {
+        let read = unsafe { syscalls::sys_pread(fd, &mut block, BLOCK_SIZE, index * BLOCK_SIZE) };
+        if read != 0 {
+            testing::fail(NAME, "pread past the end of the file read", read);
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+
+    for path in STREAM_DEVICES.iter() {
+        let fd = open(path, 0);
+        let result = unsafe { syscalls::sys_pread(fd, &mut block, BLOCK_SIZE, 0) };
+        if result != ESPIPE {
+            testing::fail(
+                NAME,
+                "pread on a stream device did not fail with ESPIPE",
+                result,
+            );
+        }
+        unsafe {
+            syscalls::sys_close(fd);
+        }
+    }
+}
+
+/// 0 if the exclusive create worked, the error otherwise.
+fn create_excl(path: &[u8]) -> usize {
+    let fd = unsafe {
+        syscalls::sys_open(
+            path,
+            syscalls::O_CREAT | syscalls::O_EXCL | syscalls::O_RDWR,
+        )
+    };
+    if fd <= 2 {
+        return fd;
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+    0
+}
+
+/// the parent and a child create the same file with O_EXCL at the same time,
+/// exactly one of them gets it.
+fn test_excl_race() {
+    let start = now_us();
+    for round in 0..N_EXCL_ROUNDS {
+        let mut path: [u8; PATH_SIZE] = [0; PATH_SIZE];
+        let path_len = unique_path(EXCL_PREFIX, start + round, &mut path);
+        let path = &path[..path_len];
+
+        let mut go: [i32; 2] = [0; 2];
+        let mut done: [i32; 2] = [0; 2];
+        for fds in [&mut go, &mut done] {
+            let result = unsafe { syscalls::sys_pipe(fds) };
+            if result != 0 {
+                testing::fail(NAME, "pipe failed", result);
+            }
+        }
+
+        let pid = unsafe { syscalls::sys_fork() };
+        if pid == 0 {
+            let mut byte: [u8; 1] = [0; 1];
+            unsafe {
+                syscalls::sys_read(go[0] as usize, &mut byte, 1);
+                let result = create_excl(path);
+                syscalls::sys_write(done[1] as usize, &[result as u8], 1);
+                syscalls::sys_exit(testing::EXIT_PASS);
+            }
+        }
+
+        // the child goes first on odd rounds, the parent on even ones.
+        let mut child_result: [u8; 1] = [0xFF; 1];
+        let parent_result = unsafe {
+            syscalls::sys_close(go[0] as usize);
+            syscalls::sys_close(done[1] as usize);
+            if round % 2 == 1 {
+                syscalls::sys_write(go[1] as usize, &[1], 1);
+                syscalls::sys_yield();
+                create_excl(path)
+            } else {
+                let result = create_excl(path);
+                syscalls::sys_write(go[1] as usize, &[1], 1);
+                result
+            }
+        };
+
+        let read = unsafe {
+            let read = syscalls::sys_read(done[0] as usize, &mut child_result, 1);
+            syscalls::sys_close(go[1] as usize);
+            syscalls::sys_close(done[0] as usize);
+            read
+        };
+        if read != 1 {
+            testing::fail(NAME, "the child did not report back, read", read);
+        }
+
+        let child_result = child_result[0] as usize;
+        let n_created = (parent_result == 0) as usize + (child_result == 0) as usize;
+        if n_created != 1 {
+            testing::fail(NAME, "O_EXCL create succeeded in processes", n_created);
+        }
+        if parent_result != 0 && parent_result != EEXIST {
+            testing::fail(
+                NAME,
+                "losing O_EXCL create did not fail with EEXIST",
+                parent_result,
+            );
+        }
+        if child_result != 0 && child_result != EEXIST {
+            testing::fail(
+                NAME,
+                "losing O_EXCL create did not fail with EEXIST",
+                child_result,
+            );
+        }
+    }
+}
+
+/// O_TRUNC without O_CREAT does not create the file.
+fn test_trunc_missing() {
+    let mut path: [u8; PATH_SIZE] = [0; PATH_SIZE];
+    let path_len = unique_path(TRUNC_PREFIX, now_us(), &mut path);
+    let path = &path[..path_len];
+
+    let result = unsafe { syscalls::sys_open(path, syscalls::O_RDWR | syscalls::O_TRUNC) };
+    if result != ENOENT {
+        testing::fail(
+            NAME,
+            "O_TRUNC of a missing file did not fail with ENOENT",
+            result,
+        );
+    }
+
+    let result = unsafe { syscalls::sys_open(path, syscalls::O_RDWR) };
+    if result != ENOENT {
+        testing::fail(NAME, "O_TRUNC created the missing file, open", result);
+    }
+}
+
+fn ftruncate(fd: usize, length: usize) {
+    let result = unsafe { syscalls::sys_ftruncate(fd, length) };
+    if result != 0 {
+        testing::fail(NAME, "ftruncate failed", result);
+    }
+}
+
+/// reads the block and checks that it holds `n_set` bytes of `fill`, then
+/// zeroes up to `size`, returns the fail reason otherwise.
+fn check_block(
+    fd: usize,
+    index: usize,
+    size: usize,
+    n_set: usize,
+    fill: u8,
+) -> Option<&'static str> {
+    let mut block: [u8; BLOCK_SIZE] = [0xFF; BLOCK_SIZE];
+    let read = unsafe { syscalls::sys_pread(fd, &mut block, BLOCK_SIZE, index * BLOCK_SIZE) };
+    if read != size {
+        return Some("wrong number of bytes read");
+    }
+    if block[..n_set].iter().any(|byte| *byte != fill) {
+        return Some("data before the new size was lost");
+    }
+    if block[n_set..size].iter().any(|byte| *byte != 0) {
+        return Some("grown region is not zeroed");
+    }
+    None
+}
+
+fn expect_block(fd: usize, index: usize, size: usize, n_set: usize, fill: u8, length: usize) {
+    if let Some(reason) = check_block(fd, index, size, n_set, fill) {
+        testing::fail(NAME, reason, length);
+    }
+}
+
+/// grows, shrinks and empties a new file, the last entry of the archive.
+fn test_truncate() {
+    let mut path: [u8; PATH_SIZE] = [0; PATH_SIZE];
+    let path_len = unique_path(SIZE_PREFIX, now_us(), &mut path);
+    let path = &path[..path_len];
+
+    let fd = open(path, syscalls::O_CREAT | syscalls::O_RDWR);
+    let data: [u8; 100] = [0xAB; 100];
+    let written = unsafe { syscalls::sys_write(fd, &data, data.len()) };
+    if written != data.len() {
+        testing::fail(NAME, "write to the new file failed", written);
+    }
+
+    ftruncate(fd, 1000);
+    expect_block(fd, 0, BLOCK_SIZE, 100, 0xAB, 1000);
+    expect_block(fd, 1, 1000 - BLOCK_SIZE, 0, 0, 1000);
+
+    ftruncate(fd, 50);
+    expect_block(fd, 0, 50, 50, 0xAB, 50);
+
+    // the bytes cut off before must not come back.
+    ftruncate(fd, 200);
+    expect_block(fd, 0, 200, 50, 0xAB, 200);
+
+    ftruncate(fd, 0);
+    expect_block(fd, 0, 0, 0, 0, 0);
+
+    let result = unsafe { syscalls::sys_ftruncate(fd, usize::MAX) };
+    if result != EINVAL {
+        testing::fail(NAME, "negative length did not fail with EINVAL", result);
+    }
+
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+
+    let result = unsafe { syscalls::sys_truncate(path, 10) };
+    if result != 0 {
+        testing::fail(NAME, "truncate by path failed", result);
+    }
+    let fd = open(path, syscalls::O_RDWR);
+    expect_block(fd, 0, 10, 0, 0, 10);
+    unsafe {
+        syscalls::sys_close(fd);
+    }
+
+    // the entries after it would be overwritten.
+    let result = unsafe { syscalls::sys_truncate(PWRITE_PATH, 0) };
+    if result != EROFS {
+        testing::fail(
+            NAME,
+            "truncate of an entry in the middle did not fail with EROFS",
+            result,
+        );
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    test_pwrite();
+    test_pread();
+    test_excl_race();
+    test_trunc_missing();
+    test_truncate();
+
+    testing::pass(NAME);
+}
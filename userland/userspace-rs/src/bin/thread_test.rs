@@ -0,0 +1,264 @@
+#![no_std]
+#![no_main]
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+
+const NAME: &str = "thread_test";
+
+const EAGAIN: usize = 11;
+const EFAULT: usize = 14;
+const EINVAL: usize = 22;
+
+/// more threads than a process can have at once, so the exited ones must be
+/// given back: their tid, their place in the process and their syscall stack.
+const ROUNDS: usize = 3 * 32;
+/// how many times the main thread yields while waiting for a worker.
+const MAX_YIELDS: usize = 100000;
+/// a worker may still be on it's way out when the next one starts, the stacks
+/// are used in turn so it is not overwritten.
+const N_STACKS: usize = 4;
+const WORKER_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct WorkerStack([u8; WORKER_STACK_SIZE]);
+
+static mut WORKER_STACKS: [WorkerStack; N_STACKS] = [
+    WorkerStack([0; WORKER_STACK_SIZE]),
+    WorkerStack([0; WORKER_STACK_SIZE]),
+    WorkerStack([0; WORKER_STACK_SIZE]),
+    WorkerStack([0; WORKER_STACK_SIZE]),
+];
+
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+/// the worker that is alive over the fork waits for the main thread to
+/// release it.
+static WAITER_STATE: AtomicUsize = AtomicUsize::new(0);
+const WAITER_STARTED: usize = 1;
+const WAITER_RELEASED: usize = 2;
+const WAITER_FINISHED: usize = 3;
+/// data, not code, threads can't start here.
+static NOT_CODE: AtomicUsize = AtomicUsize::new(0);
+/// the address of an aligned local of the thread modulo 16, plus one.
+static ENTRY_ALIGNMENT: AtomicUsize = AtomicUsize::new(0);
+
+/// the compiler places it from rsp as the ABI has it at the entry, without
+/// aligning it again, so a misaligned entry stack shows up in it's address.
+#[repr(C, align(16))]
+struct AlignedLocal([u8; 16]);
+
+extern "C" fn worker_thread(round: usize) {
+    FINISHED.store(round + 1, Ordering::SeqCst);
+    unsafe {
+        syscalls::sys_thread_exit(0);
+    }
+}
+
+extern "C" fn alignment_worker(_arg: usize) {
+    let local = AlignedLocal([0; 16]);
+    let address = core::hint::black_box(&local as *const AlignedLocal as usize);
+    ENTRY_ALIGNMENT.store(address % 16 + 1, Ordering::SeqCst);
+    unsafe {
+        syscalls::sys_thread_exit(0);
+    }
+}
+
+extern "C" fn waiting_worker(_arg: usize) {
+    WAITER_STATE.store(WAITER_STARTED, Ordering::SeqCst);
+    while WAITER_STATE.load(Ordering::SeqCst) != WAITER_RELEASED {
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    WAITER_STATE.store(WAITER_FINISHED, Ordering::SeqCst);
+    unsafe {
+        syscalls::sys_thread_exit(0);
+    }
+}
+
+fn stack_end(index: usize) -> usize {
+    unsafe { WORKER_STACKS[index].0.as_ptr() as usize + WORKER_STACK_SIZE }
+}
+
+fn test_bad_arguments() {
+    let result = unsafe { syscalls::sys_thread_create(worker_thread, 0, 0) };
+    if result != EINVAL {
+        testing::fail(NAME, "stack end 0 did not fail with EINVAL", result);
+    }
+
+    let result = unsafe { syscalls::sys_thread_create(worker_thread, 0, stack_end(0) - 8) };
+    if result != EINVAL {
+        testing::fail(
+            NAME,
+            "misaligned stack end did not fail with EINVAL",
+            result,
+        );
+    }
+
+    let result = unsafe { syscalls::sys_thread_create(worker_thread, 0, 0xFFFF_8000_0000_0000) };
+    if result != EFAULT {
+        testing::fail(NAME, "kernel stack end did not fail with EFAULT", result);
+    }
+
+    let data_entry: extern "C" fn(usize) =
+        unsafe { core::mem::transmute(&NOT_CODE as *const AtomicUsize as usize) };
+    let result = unsafe { syscalls::sys_thread_create(data_entry, 0, stack_end(0)) };
+    if result != EFAULT {
+        testing::fail(
+            NAME,
+            "entry outside the code did not fail with EFAULT",
+            result,
+        );
+    }
+
+    let unmapped_entry: extern "C" fn(usize) = unsafe { core::mem::transmute(0x10usize) };
+    let result = unsafe { syscalls::sys_thread_create(unmapped_entry, 0, stack_end(0)) };
+    if result != EFAULT {
+        testing::fail(NAME, "unmapped entry did not fail with EFAULT", result);
+    }
+}
+
+/// a thread starts like a called function, rsp + 8 is 16 aligned and holds a
+/// zero return address. the last stack is used, it is the last one the
+/// workers after this get.
+fn test_entry_alignment() {
+    let end = stack_end(N_STACKS - 1);
+    let return_slot = (end - 8) as *mut u64;
+    unsafe {
+        ptr::write_volatile(return_slot, u64::MAX);
+        syscalls::sys_thread_create(alignment_worker, 0, end);
+    }
+
+    wait_for(
+        &ENTRY_ALIGNMENT,
+        1,
+        "the thread did not run or it's stack is misaligned, alignment + 1",
+    );
+
+    let return_addr = unsafe { ptr::read_volatile(return_slot) };
+    if return_addr != 0 {
+        testing::fail(
+            NAME,
+            "the return address of the thread is not 0",
+            return_addr as usize,
+        );
+    }
+}
+
+/// one worker at a time, each one exits right away.
+fn test_exit_and_reuse() {
+    for round in 0..ROUNDS {
+        unsafe {
+            syscalls::sys_thread_create(worker_thread, round, stack_end(round % N_STACKS));
+        }
+
+        let mut yields = 0;
+        while FINISHED.load(Ordering::SeqCst) != round + 1 {
+            if yields == MAX_YIELDS {
+                testing::fail(
+                    NAME,
+                    "worker did not run, threads that exited before",
+                    round,
+                );
+            }
+            unsafe {
+                syscalls::sys_yield();
+            }
+            yields += 1;
+        }
+    }
+}
+
+fn wait_for(state: &AtomicUsize, value: usize, reason: &str) {
+    let mut yields = 0;
+    while state.load(Ordering::SeqCst) != value {
+        if yields == MAX_YIELDS {
+            testing::fail(NAME, reason, state.load(Ordering::SeqCst));
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+        yields += 1;
+    }
+}
+
+/// the child of a process with two threads gets only the one that forked, so
+/// it's process ends when that thread exits. the pipe is closed then.
+fn test_fork_with_threads() {
+    // the tid it returns can't be told apart from an error.
+    unsafe {
+        syscalls::sys_thread_create(waiting_worker, 0, stack_end(0));
+    }
+    wait_for(
+        &WAITER_STATE,
+        WAITER_STARTED,
+        "the waiting worker did not start",
+    );
+
+    let mut fds: [i32; 2] = [0; 2];
+    let result = unsafe { syscalls::sys_pipe2(&mut fds, syscalls::O_NONBLOCK) };
+    if result != 0 {
+        testing::fail(NAME, "pipe2 failed", result);
+    }
+
+    // the child must not touch the statics, their pages are shared with the parent.
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        unsafe {
+            syscalls::sys_write(fds[1] as usize, &[1], 1);
+            syscalls::sys_thread_exit(testing::EXIT_PASS);
+        }
+        testing::fail(NAME, "thread exit returned in the child", 0);
+    }
+
+    unsafe {
+        syscalls::sys_close(fds[1] as usize);
+    }
+
+    let (mut reported, mut exited) = (false, false);
+    let mut byte: [u8; 1] = [0; 1];
+    for _ in 0..MAX_YIELDS {
+        match unsafe { syscalls::sys_read(fds[0] as usize, &mut byte, 1) } {
+            0 => {
+                exited = true;
+                break;
+            }
+            1 => reported = true,
+            EAGAIN => unsafe {
+                syscalls::sys_yield();
+            },
+            result => testing::fail(NAME, "read from the child failed", result),
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(fds[0] as usize);
+    }
+    if !reported {
+        testing::fail(NAME, "the child did not run, fork returned", pid);
+    }
+    if !exited {
+        testing::fail(NAME, "the child outlived it's only thread, pid", pid);
+    }
+
+    // the worker of the parent was not taken away by the fork.
+    WAITER_STATE.store(WAITER_RELEASED, Ordering::SeqCst);
+    wait_for(
+        &WAITER_STATE,
+        WAITER_FINISHED,
+        "the worker did not run after the fork",
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    test_bad_arguments();
+    test_entry_alignment();
+    test_exit_and_reuse();
+    test_fork_with_threads();
+
+    testing::pass(NAME);
+}
@@ -0,0 +1,211 @@
+#![no_std]
+#![no_main]
+
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::library::types::{Termios, ECHO, ICANON, ISIG};
+
+const NAME: &str = "tty_test";
+
+const STDIN: usize = 0;
+const ESRCH: usize = 3;
+const EAGAIN: usize = 11;
+const END_OF_TEXT: u8 = 0x03;
+/// the highest pid, the processes alive during the test don't get this far.
+const UNUSED_PID: i32 = 63;
+/// how many times the test yields while waiting for the child to be killed.
+const MAX_YIELDS: usize = 100000;
+/// the child gives up after this many yields, long after the test stopped waiting.
+const CHILD_YIELDS: usize = 10 * MAX_YIELDS;
+/// yields a ^C that must not kill is given to take effect.
+const SETTLE_YIELDS: usize = 100;
+
+fn set_mode(termios: &Termios, lflag: u32) {
+    let mut mode = *termios;
+    mode.c_lflag = lflag;
+    // the input typed before the switch is dropped.
+    let result = unsafe { syscalls::sys_tcsetattr(STDIN, &mode, true) };
+    if result != 0 {
+        testing::fail(NAME, "tcsetattr failed", result);
+    }
+}
+
+fn get_mode() -> u32 {
+    let mut mode = Termios::default();
+    let result = unsafe { syscalls::sys_tcgetattr(STDIN, &mut mode) };
+    if result != 0 {
+        testing::fail(NAME, "tcgetattr failed", result);
+    }
+    mode.c_lflag
+}
+
+/// the line discipline itself is checked in the kernel, with the bytes fed to
+/// the input queue, here only the switch between the modes is.
+fn test_modes(termios: &Termios) {
+    for lflag in [ICANON, 0, ICANON | ECHO] {
+        set_mode(termios, lflag);
+        if get_mode() & (ICANON | ECHO) != lflag {
+            testing::fail(NAME, "mode was not switched, c_lflag", get_mode() as usize);
+        }
+    }
+}
+
+fn get_foreground() -> i32 {
+    let mut pid: i32 = 0;
+    let result =
+        unsafe { syscalls::sys_ioctl(STDIN, syscalls::TIOCGPGRP, &mut pid as *mut i32 as usize) };
+    if result != 0 {
+        testing::fail(NAME, "TIOCGPGRP failed", result);
+    }
+    pid
+}
+
+fn test_foreground_missing_pid() {
+    let foreground = get_foreground();
+    let result = unsafe {
+        syscalls::sys_ioctl(
+            STDIN,
+            syscalls::TIOCSPGRP,
+            &UNUSED_PID as *const i32 as usize,
+        )
+    };
+    if result != ESRCH {
+        testing::fail(
+            NAME,
+            "TIOCSPGRP to a missing pid did not fail with ESRCH",
+            result,
+        );
+    }
+    if get_foreground() != foreground {
+        testing::fail(
+            NAME,
+            "TIOCSPGRP to a missing pid changed the foreground to",
+            get_foreground() as usize,
+        );
+    }
+}
+
+fn set_foreground(pid: i32) {
+    let result =
+        unsafe { syscalls::sys_ioctl(STDIN, syscalls::TIOCSPGRP, &pid as *const i32 as usize) };
+    if result != 0 {
+        testing::fail(NAME, "TIOCSPGRP failed", result);
+    }
+}
+
+fn type_byte(byte: u8) {
+    let result =
+        unsafe { syscalls::sys_ioctl(STDIN, syscalls::TIOCSTI, &byte as *const u8 as usize) };
+    if result != 0 {
+        testing::fail(NAME, "TIOCSTI failed", result);
+    }
+}
+
+/// reads the pipe of the child for up to `yields` yields. 0 once the child is
+/// gone, 1 if it ran out of time and EAGAIN while it still runs.
+fn wait_child(fd: usize, yields: usize) -> usize {
+    let mut byte: [u8; 1] = [0; 1];
+    for _ in 0..yields {
+        match unsafe { syscalls::sys_read(fd, &mut byte, 1) } {
+            EAGAIN => unsafe {
+                syscalls::sys_yield();
+            },
+            result => return result,
+        }
+    }
+    EAGAIN
+}
+
+/// ^C kills the foreground process when ISIG is set, it is only input otherwise.
+fn test_interrupt(termios: &Termios) {
+    let foreground = get_foreground();
+    let mut fds: [i32; 2] = [0; 2];
+    let result = unsafe { syscalls::sys_pipe2(&mut fds, syscalls::O_NONBLOCK) };
+    if result != 0 {
+        testing::fail(NAME, "pipe2 failed", result);
+    }
+
+    let pid = unsafe { syscalls::sys_fork() };
+    if pid == 0 {
+        for _ in 0..CHILD_YIELDS {
+            unsafe {
+                syscalls::sys_yield();
+            }
+        }
+        unsafe {
+            syscalls::sys_write(fds[1] as usize, &[1], 1);
+            syscalls::sys_exit(testing::EXIT_PASS);
+        }
+    }
+
+    unsafe {
+        syscalls::sys_close(fds[1] as usize);
+    }
+    set_foreground(pid as i32);
+
+    // the ^C is dropped with the rest of the input by the next mode switch.
+    set_mode(termios, ICANON);
+    type_byte(END_OF_TEXT);
+    let result = wait_child(fds[0] as usize, SETTLE_YIELDS);
+    if result != EAGAIN {
+        testing::fail(NAME, "^C without ISIG ended the child, read", result);
+    }
+
+    set_mode(termios, ISIG | ICANON);
+    type_byte(END_OF_TEXT);
+    let result = wait_child(fds[0] as usize, MAX_YIELDS);
+    if result != 0 {
+        testing::fail(NAME, "^C did not kill the foreground process, read", result);
+    }
+
+    unsafe {
+        syscalls::sys_close(fds[0] as usize);
+    }
+
+    // the foreground went away with the child.
+    if get_foreground() != 0 {
+        testing::fail(
+            NAME,
+            "the killed process is still in the foreground",
+            get_foreground() as usize,
+        );
+    }
+    if foreground != 0 {
+        set_foreground(foreground);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    let mut termios = Termios::default();
+    let result = unsafe { syscalls::sys_tcgetattr(STDIN, &mut termios) };
+    if result != 0 {
+        testing::fail(NAME, "tcgetattr failed", result);
+    }
+    if termios.c_lflag & (ICANON | ECHO) != ICANON | ECHO {
+        testing::fail(
+            NAME,
+            "terminal does not start canonical with echo, c_lflag",
+            termios.c_lflag as usize,
+        );
+    }
+
+    test_modes(&termios);
+    test_foreground_missing_pid();
+    test_interrupt(&termios);
+
+    let result = unsafe { syscalls::sys_tcsetattr(STDIN, &termios, true) };
+    let mut restored = Termios::default();
+    unsafe {
+        syscalls::sys_tcgetattr(STDIN, &mut restored);
+    }
+    if result != 0 || restored.c_lflag != termios.c_lflag {
+        testing::fail(
+            NAME,
+            "settings were not restored, c_lflag",
+            restored.c_lflag as usize,
+        );
+    }
+
+    testing::pass(NAME);
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use userspace_rs::library::syscalls;
+use userspace_rs::library::testing;
+use userspace_rs::library::vdso;
+
+const NAME: &str = "yield_test";
+
+/// yields done by the main thread while the worker spins.
+const YIELDS: usize = 200;
+/// how many times the main thread yields while waiting for the worker to start.
+const MAX_START_YIELDS: usize = 100000;
+/// the worker must make progress across at least this share of the yields, in percent.
+const MIN_PROGRESS_PERCENT: usize = 90;
+/// a yield that gave away the quantum costs a whole 100ms tick, 200 of them
+/// would take 20s. other threads waking up now and then can cost a few ticks.
+const MAX_SOLITARY_MS: u64 = 500;
+const WORKER_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct WorkerStack([u8; WORKER_STACK_SIZE]);
+
+static mut WORKER_STACK: WorkerStack = WorkerStack([0; WORKER_STACK_SIZE]);
+
+static PROGRESS: AtomicUsize = AtomicUsize::new(0);
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// CPU bound, never yields on it's own while the test is running.
+extern "C" fn worker_thread(_arg: usize) {
+    while !STOP.load(Ordering::SeqCst) {
+        PROGRESS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    loop {
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn _start() {
+    // alone in the process, the yields must come straight back.
+    let start_ns = vdso::monotonic_ns();
+    for _ in 0..YIELDS {
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+    let solitary_ms = (vdso::monotonic_ns() - start_ns) / 1_000_000;
+    if solitary_ms > MAX_SOLITARY_MS {
+        testing::fail_with(
+            NAME,
+            format_args!(
+                "{} yields with nothing else to run took {}ms",
+                YIELDS, solitary_ms
+            ),
+        );
+    }
+
+    let stack_end = unsafe { WORKER_STACK.0.as_ptr() as usize + WORKER_STACK_SIZE };
+    unsafe {
+        syscalls::sys_thread_create(worker_thread, 0, stack_end);
+    }
+
+    for _ in 0..MAX_START_YIELDS {
+        if PROGRESS.load(Ordering::SeqCst) != 0 {
+            break;
+        }
+        unsafe {
+            syscalls::sys_yield();
+        }
+    }
+
+    if PROGRESS.load(Ordering::SeqCst) == 0 {
+        testing::fail(
+            NAME,
+            "the worker thread never ran, yields",
+            MAX_START_YIELDS,
+        );
+    }
+
+    // each yield puts this thread behind the worker, so the worker has to
+    // run in between.
+    let mut progressed = 0;
+    for _ in 0..YIELDS {
+        let before = PROGRESS.load(Ordering::SeqCst);
+        unsafe {
+            syscalls::sys_yield();
+        }
+        if PROGRESS.load(Ordering::SeqCst) != before {
+            progressed += 1;
+        }
+    }
+
+    STOP.store(true, Ordering::SeqCst);
+
+    if progressed * 100 < YIELDS * MIN_PROGRESS_PERCENT {
+        testing::fail_with(
+            NAME,
+            format_args!(
+                "the worker ran across only {} of {} yields",
+                progressed, YIELDS
+            ),
+        );
+    }
+
+    testing::pass(NAME);
+}
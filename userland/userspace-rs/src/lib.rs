@@ -14,12 +14,12 @@ fn panic(_info: &PanicInfo) -> ! {
 macro_rules! print {
 
     ($fmt:expr) => {
-        let mut sys_stdout = library::utils::SysStdout{};
+        let mut sys_stdout = $crate::library::utils::SysStdout{};
         let _ = sys_stdout.write_fmt(format_args!($fmt)).unwrap();
     };
 
     ($fmt:expr, $($arg:tt)*) => {
-        let mut sys_stdout = library::utils::SysStdout{};
+        let mut sys_stdout = $crate::library::utils::SysStdout{};
         let _ = sys_stdout.write_fmt(format_args!($fmt, $($arg)*)).unwrap();
     };
 }
@@ -28,14 +28,14 @@ macro_rules! print {
 macro_rules! println {
 
     ($fmt:expr) => {
-        let mut sys_stdout = library::utils::SysStdout{};
+        let mut sys_stdout = $crate::library::utils::SysStdout{};
         let _ = sys_stdout.write_fmt(format_args!(
             concat!($fmt, "\n")
         )).unwrap();
     };
 
     ($fmt:expr, $($arg:tt)*) => (
-        let mut sys_stdout = library::utils::SysStdout{};
+        let mut sys_stdout = $crate::library::utils::SysStdout{};
         let _ = sys_stdout.write_fmt(format_args!(
             concat!($fmt, "\n"), $($arg)*
         ));
@@ -1,3 +1,5 @@
 pub mod syscalls;
+pub mod testing;
 pub mod types;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod vdso;
\ No newline at end of file
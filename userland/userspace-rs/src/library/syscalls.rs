@@ -1,12 +1,45 @@
 use core::arch::asm;
-use crate::library::types::{UTSName, FStatInfo};
+use crate::library::types::{UTSName, FStatInfo, Timeval, CPUStat, IfconfigRequest, RLimit, SockAddrIn, Termios, TASK_NAME_LEN};
 
 pub enum SyscallNumbers {
     Read = 0,
     Write = 1,
+    Open = 2,
+    Close = 3,
+    Exit = 4,
     LStat = 6,
+    LSeek = 8,
+    Fork = 11,
+    Sbrk = 13,
+    Ioctl = 16,
+    PRead = 17,
+    PWrite = 18,
+    Pipe = 22,
+    Madvise = 28,
+    Dup = 32,
+    Socket = 41,
+    Yield = 42,
     Shutdown = 48,
+    Bind = 50,
+    SetSockOpt = 54,
+    GetSockOpt = 55,
+    Clone = 56,
+    Execvp = 59,
+    ThreadExit = 60,
     Uname = 63,
+    Fcntl = 72,
+    Truncate = 76,
+    FTruncate = 77,
+    GetRLimit = 97,
+    Sysconf = 99,
+    CPUStat = 100,
+    Ifconfig = 101,
+    GetHostByName = 102,
+    Prctl = 157,
+    SetRLimit = 160,
+    GetTime = 228,
+    ClockGetRes = 229,
+    Pipe2 = 293,
 }
 
 #[inline(always)]
@@ -63,6 +96,46 @@ unsafe fn syscall_3(arg0: usize, arg1: usize, arg2: usize, sys_no: usize) -> usi
     syscall_result
 }
 
+#[inline(always)]
+unsafe fn syscall_4(arg0: usize, arg1: usize, arg2: usize, arg3: usize, sys_no: usize) -> usize {
+    let syscall_result : usize;
+    asm!(
+        "int 0x80",
+        in("rax") sys_no,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        in("r10") arg3,
+        lateout("rax") syscall_result
+    );
+
+    syscall_result
+}
+
+#[inline(always)]
+unsafe fn syscall_5(
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+    sys_no: usize,
+) -> usize {
+    let syscall_result : usize;
+    asm!(
+        "int 0x80",
+        in("rax") sys_no,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        in("r10") arg3,
+        in("r8") arg4,
+        lateout("rax") syscall_result
+    );
+
+    syscall_result
+}
+
 pub unsafe fn sys_write(fd: usize, buffer: &[u8], size: usize) -> usize {
     let addr = buffer.as_ptr() as usize;
     syscall_3(fd, addr, size, SyscallNumbers::Write as usize)
@@ -73,11 +146,190 @@ pub unsafe fn sys_read(fd: usize, buffer: &mut [u8], size: usize) -> usize {
     syscall_3(fd, addr, size, SyscallNumbers::Read as usize)
 }
 
+/// path must be nul terminated.
+pub unsafe fn sys_open(path: &[u8], flags: usize) -> usize {
+    let addr = path.as_ptr() as usize;
+    syscall_2(addr, flags, SyscallNumbers::Open as usize)
+}
+
+pub unsafe fn sys_close(fd: usize) -> usize {
+    syscall_1(fd, SyscallNumbers::Close as usize)
+}
+
+pub unsafe fn sys_dup(fd: usize) -> usize {
+    syscall_1(fd, SyscallNumbers::Dup as usize)
+}
+
+pub const O_WRONLY: usize = 0o1;
+pub const O_RDWR: usize = 0o2;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_NONBLOCK: usize = 0o4000;
+pub const O_CLOEXEC: usize = 0o2000000;
+
+/// fds gets the read end and then the write end.
+pub unsafe fn sys_pipe(fds: &mut [i32; 2]) -> usize {
+    syscall_1(fds.as_mut_ptr() as usize, SyscallNumbers::Pipe as usize)
+}
+
+pub unsafe fn sys_pipe2(fds: &mut [i32; 2], flags: usize) -> usize {
+    syscall_2(fds.as_mut_ptr() as usize, flags, SyscallNumbers::Pipe2 as usize)
+}
+
+pub const F_GETFD: usize = 1;
+pub const F_SETFD: usize = 2;
+pub const FD_CLOEXEC: usize = 1;
+
+pub unsafe fn sys_fcntl(fd: usize, command: usize, arg: usize) -> usize {
+    syscall_3(fd, command, arg, SyscallNumbers::Fcntl as usize)
+}
+
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSF: usize = 0x5404;
+pub const TIOCGPGRP: usize = 0x540F;
+pub const TIOCSPGRP: usize = 0x5410;
+/// feeds a byte to the terminal as if it was typed.
+pub const TIOCSTI: usize = 0x5412;
+
+pub unsafe fn sys_ioctl(fd: usize, command: usize, arg: usize) -> usize {
+    syscall_3(fd, command, arg, SyscallNumbers::Ioctl as usize)
+}
+
+pub unsafe fn sys_tcgetattr(fd: usize, termios: &mut Termios) -> usize {
+    sys_ioctl(fd, TCGETS, termios as *mut Termios as usize)
+}
+
+/// flush drops the input that was not read yet, like TCSAFLUSH.
+pub unsafe fn sys_tcsetattr(fd: usize, termios: &Termios, flush: bool) -> usize {
+    let command = if flush { TCSETSF } else { TCSETS };
+    sys_ioctl(fd, command, termios as *const Termios as usize)
+}
+
+pub unsafe fn sys_lseek(fd: usize, offset: usize, whence: usize) -> usize {
+    syscall_3(fd, offset, whence, SyscallNumbers::LSeek as usize)
+}
+
+/// returns 0 in the child and the pid of the child in the parent.
+pub unsafe fn sys_fork() -> usize {
+    syscall_0(SyscallNumbers::Fork as usize)
+}
+
+pub unsafe fn sys_exit(code: usize) -> usize {
+    syscall_1(code, SyscallNumbers::Exit as usize)
+}
+
+/// returns the old end of the heap.
+pub unsafe fn sys_sbrk(size: usize) -> usize {
+    syscall_1(size, SyscallNumbers::Sbrk as usize)
+}
+
+pub const MADV_NORMAL: usize = 0;
+pub const MADV_DONTNEED: usize = 4;
+
+pub unsafe fn sys_madvise(addr: usize, length: usize, advice: usize) -> usize {
+    syscall_3(addr, length, advice, SyscallNumbers::Madvise as usize)
+}
+
+pub unsafe fn sys_yield() -> usize {
+    syscall_0(SyscallNumbers::Yield as usize)
+}
+
+/// path must be nul terminated.
+pub unsafe fn sys_truncate(path: &[u8], length: usize) -> usize {
+    let addr = path.as_ptr() as usize;
+    syscall_2(addr, length, SyscallNumbers::Truncate as usize)
+}
+
+/// path must be nul terminated, returns only on errors.
+pub unsafe fn sys_execvp(path: &[u8]) -> usize {
+    let addr = path.as_ptr() as usize;
+    syscall_1(addr, SyscallNumbers::Execvp as usize)
+}
+
+pub unsafe fn sys_ftruncate(fd: usize, length: usize) -> usize {
+    syscall_2(fd, length, SyscallNumbers::FTruncate as usize)
+}
+
+pub unsafe fn sys_pread(fd: usize, buffer: &mut [u8], size: usize, offset: usize) -> usize {
+    let addr = buffer.as_ptr() as usize;
+    syscall_4(fd, addr, size, offset, SyscallNumbers::PRead as usize)
+}
+
+pub unsafe fn sys_pwrite(fd: usize, buffer: &[u8], size: usize, offset: usize) -> usize {
+    let addr = buffer.as_ptr() as usize;
+    syscall_4(fd, addr, size, offset, SyscallNumbers::PWrite as usize)
+}
+
 pub unsafe fn sys_uname(uts: &mut UTSName) -> usize {
     let addr = (uts as *const _) as usize;
     syscall_1(addr, SyscallNumbers::Uname as usize)
 } 
 
+pub unsafe fn sys_sysconf(key: usize) -> usize {
+    syscall_1(key, SyscallNumbers::Sysconf as usize)
+}
+
+pub unsafe fn sys_getrlimit(resource: usize, limit: &mut RLimit) -> usize {
+    let addr = (limit as *const _) as usize;
+    syscall_2(resource, addr, SyscallNumbers::GetRLimit as usize)
+}
+
+pub unsafe fn sys_setrlimit(resource: usize, limit: &RLimit) -> usize {
+    let addr = (limit as *const _) as usize;
+    syscall_2(resource, addr, SyscallNumbers::SetRLimit as usize)
+}
+
+pub unsafe fn sys_prctl(option: usize, name: &mut [u8; TASK_NAME_LEN]) -> usize {
+    let addr = (name as *const _) as usize;
+    syscall_2(option, addr, SyscallNumbers::Prctl as usize)
+}
+
+pub unsafe fn sys_cpustat(stat: &mut CPUStat) -> usize {
+    let addr = (stat as *const _) as usize;
+    syscall_1(addr, SyscallNumbers::CPUStat as usize)
+}
+
+pub unsafe fn sys_ifconfig(request: &IfconfigRequest) -> usize {
+    let addr = (request as *const _) as usize;
+    syscall_1(addr, SyscallNumbers::Ifconfig as usize)
+}
+
+/// name must be nul terminated, the IPv4 address is written to `address`.
+pub unsafe fn sys_gethostbyname(name: &[u8], address: &mut [u8; 4]) -> usize {
+    let name_addr = name.as_ptr() as usize;
+    let address_addr = address.as_mut_ptr() as usize;
+    syscall_2(name_addr, address_addr, SyscallNumbers::GetHostByName as usize)
+}
+
+/// only datagram sockets of AF_INET can be created.
+pub unsafe fn sys_socket(domain: usize, sock_type: usize, protocol: usize) -> usize {
+    syscall_3(domain, sock_type, protocol, SyscallNumbers::Socket as usize)
+}
+
+pub unsafe fn sys_bind(fd: usize, addr: &SockAddrIn) -> usize {
+    let addr_ptr = (addr as *const _) as usize;
+    syscall_3(fd, addr_ptr, core::mem::size_of::<SockAddrIn>(), SyscallNumbers::Bind as usize)
+}
+
+/// socket option level and names, same values as linux.
+pub const SOL_IP: usize = 0;
+pub const IP_TOS: usize = 1;
+pub const IP_TTL: usize = 2;
+
+pub unsafe fn sys_setsockopt(fd: usize, level: usize, name: usize, value: &u32) -> usize {
+    let value_addr = (value as *const _) as usize;
+    syscall_5(fd, level, name, value_addr, 4, SyscallNumbers::SetSockOpt as usize)
+}
+
+pub unsafe fn sys_getsockopt(fd: usize, level: usize, name: usize, value: &mut u32) -> usize {
+    let mut length: u32 = 4;
+    let value_addr = (value as *const _) as usize;
+    let length_addr = (&mut length as *const _) as usize;
+    syscall_5(fd, level, name, value_addr, length_addr, SyscallNumbers::GetSockOpt as usize)
+}
+
 pub unsafe fn sys_shutdown() -> usize {
     syscall_0(SyscallNumbers::Shutdown as usize)
 }
@@ -87,4 +339,25 @@ pub unsafe fn sys_lstat(path: &str, stat: &mut FStatInfo) -> usize {
     let stat_addr = (stat as *const _) as usize;
 
     syscall_2(path_addr, stat_addr, SyscallNumbers::LStat as usize)
-}
\ No newline at end of file
+}
+
+/// ends the calling thread, the last one ends the process like sys_exit.
+pub unsafe fn sys_thread_exit(code: usize) -> usize {
+    syscall_1(code, SyscallNumbers::ThreadExit as usize)
+}
+
+/// `stack_end` must be 16 aligned, the 8 bytes below it are the return address
+/// of `entry`. it is 0, so `entry` must end with sys_thread_exit and not return.
+pub unsafe fn sys_thread_create(entry: extern "C" fn(usize), arg: usize, stack_end: usize) -> usize {
+    syscall_3(entry as usize, arg, stack_end, SyscallNumbers::Clone as usize)
+}
+
+pub unsafe fn sys_clock_gettime(clock: usize, timeval: &mut Timeval) -> usize {
+    let addr = (timeval as *const _) as usize;
+    syscall_2(clock, addr, SyscallNumbers::GetTime as usize)
+}
+
+pub unsafe fn sys_clock_getres(clock: usize, timeval: &mut Timeval) -> usize {
+    let addr = (timeval as *const _) as usize;
+    syscall_2(clock, addr, SyscallNumbers::ClockGetRes as usize)
+}
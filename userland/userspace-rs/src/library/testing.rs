@@ -0,0 +1,39 @@
+use crate::library;
+use crate::println;
+
+use core::fmt::{self, Write};
+use library::syscalls;
+
+/// exit codes of the test programs, the selftest runner reports on them.
+pub const EXIT_PASS: usize = 0;
+pub const EXIT_FAIL: usize = 1;
+
+fn exit(code: usize) -> ! {
+    unsafe {
+        syscalls::sys_exit(code);
+    }
+    loop {}
+}
+
+/// prints "<name>: FAIL, <reason>: <value>" and exits with EXIT_FAIL.
+pub fn fail(name: &str, reason: &str, value: usize) -> ! {
+    println!("{}: FAIL, {}: {}", name, reason, value);
+    exit(EXIT_FAIL)
+}
+
+/// like fail, for messages that need more than one value.
+pub fn fail_with(name: &str, message: fmt::Arguments) -> ! {
+    println!("{}: FAIL, {}", name, message);
+    exit(EXIT_FAIL)
+}
+
+/// prints "<name>: PASS" and exits with EXIT_PASS.
+pub fn pass(name: &str) -> ! {
+    println!("{}: PASS", name);
+    exit(EXIT_PASS)
+}
+
+pub fn pass_with(name: &str, message: fmt::Arguments) -> ! {
+    println!("{}: PASS, {}", name, message);
+    exit(EXIT_PASS)
+}
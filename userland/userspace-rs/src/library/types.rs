@@ -7,6 +7,113 @@ pub enum Stdio {
     Stdout = 1
 }
 
+/// keys accepted by the sysconf syscall
+pub enum SysconfKey {
+    ChildMax = 1,
+    OpenMax = 4,
+    PageSize = 30,
+    NProcessorsConf = 83,
+    NProcessorsOnline = 84,
+    PhysPages = 85,
+    AvailablePhysPages = 86,
+}
+
+/// cpu time counters, the utilization is of the last one second window.
+#[derive(Default, Debug)]
+#[repr(C)]
+pub struct CPUStat {
+    pub uptime_ns: u64,
+    pub idle_ns: u64,
+    pub utilization: u64,
+}
+
+/// resources accepted by getrlimit and setrlimit
+pub enum RLimitResource {
+    Data = 2,
+    Stack = 3,
+    NoFile = 7,
+}
+
+/// soft (current) and hard (max) limit of a resource.
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RLimit {
+    pub current: u64,
+    pub max: u64,
+}
+
+/// ^C kills the foreground process, without it ^C is read like the other keys.
+pub const ISIG: u32 = 0o000001;
+/// line editing and line reads, without it reads return whatever was typed.
+pub const ICANON: u32 = 0o000002;
+pub const ECHO: u32 = 0o000010;
+
+/// terminal settings of tcgetattr and tcsetattr, same layout as linux.
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; 19],
+}
+
+/// options accepted by prctl, the process ones are r3 specific.
+pub enum PrctlOption {
+    SetName = 15,
+    GetName = 16,
+    SetProcessName = 1000,
+    GetProcessName = 1001,
+}
+
+/// size of a prctl name buffer, the names are NUL terminated.
+pub const TASK_NAME_LEN: usize = 16;
+
+pub const IFCONFIG_UP: u32 = 1 << 0;
+pub const IFCONFIG_SET_ADDRESS: u32 = 1 << 1;
+/// only `napi_threshold` is used, the interface is left as it is.
+pub const IFCONFIG_SET_NAPI_THRESHOLD: u32 = 1 << 2;
+
+/// interface configuration request, a zero gateway means no default route.
+#[derive(Default, Debug)]
+#[repr(C)]
+pub struct IfconfigRequest {
+    pub address: [u8; 4],
+    pub gateway: [u8; 4],
+    pub prefix: u32,
+    pub flags: u32,
+    /// RX frames per 10ms that switch the device to polling, 0 turns it off.
+    pub napi_threshold: u32,
+}
+
+/// socket domains and types, same values as linux.
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+pub const SOCK_DGRAM: usize = 2;
+
+/// same layout as sockaddr_in, the port and the address are in network byte order.
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SockAddrIn {
+    pub family: u16,
+    pub port: [u8; 2],
+    pub address: [u8; 4],
+    pub padding: [u8; 8],
+}
+
+impl SockAddrIn {
+    pub fn new(address: [u8; 4], port: u16) -> Self {
+        SockAddrIn {
+            family: AF_INET,
+            port: port.to_be_bytes(),
+            address,
+            padding: [0; 8],
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
 pub struct UTSName {
@@ -49,4 +156,16 @@ pub struct FStatInfo {
     pub atime: usize,
     pub mtime: usize,
     pub ctime: usize,
-}
\ No newline at end of file
+}
+
+pub enum ClockType {
+    Realtime = 0,
+    Monotonic = 1,
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
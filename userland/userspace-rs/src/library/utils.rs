@@ -2,7 +2,7 @@ use crate::library;
 
 use core::{fmt, str};
 use library::syscalls;
-use library::types::{Stdio, UTSName, FStatInfo};
+use library::types::{Stdio, SysconfKey, UTSName, FStatInfo, CPUStat, IfconfigRequest};
 
 pub struct SysStdout;
 
@@ -34,6 +34,61 @@ pub fn get_uname() -> Result<UTSName, usize> {
     Err(result)
 }
 
+pub fn sysconf(key: SysconfKey) -> usize {
+    unsafe { syscalls::sys_sysconf(key as usize) }
+}
+
+pub fn cpustat() -> Result<CPUStat, usize> {
+    let mut stat = CPUStat::default();
+    let result = unsafe { syscalls::sys_cpustat(&mut stat) };
+    if result == 0 {
+        return Ok(stat);
+    }
+
+    Err(result)
+}
+
+pub fn ifconfig(request: &IfconfigRequest) -> Result<(), usize> {
+    let result = unsafe { syscalls::sys_ifconfig(request) };
+    if result == 0 {
+        return Ok(());
+    }
+
+    Err(result)
+}
+
+/// replaces the current program, bare names are searched in /sbin and /bin.
+/// returns only if the exec failed.
+pub fn execvp(name: &str) -> Result<(), usize> {
+    let mut path: [u8; 512] = [0; 512];
+    if name.len() >= path.len() {
+        // ENAMETOOLONG, same limit as the kernel
+        return Err(63);
+    }
+
+    path[0..name.len()].copy_from_slice(name.as_bytes());
+    let result = unsafe { syscalls::sys_execvp(&path) };
+    Err(result)
+}
+
+/// parses a dotted IPv4 address like 10.0.2.15
+pub fn parse_ipv4(string: &str) -> Option<[u8; 4]> {
+    let mut octets: [u8; 4] = [0; 4];
+    let mut count = 0;
+    for part in string.split('.') {
+        if count == 4 {
+            return None;
+        }
+        octets[count] = part.parse::<u8>().ok()?;
+        count += 1;
+    }
+
+    if count != 4 {
+        return None;
+    }
+    Some(octets)
+}
+
 pub fn power_off_machine() {
     unsafe {
         syscalls::sys_shutdown();
@@ -0,0 +1,80 @@
+use crate::library::syscalls;
+use crate::library::types::{ClockType, Timeval};
+
+use core::arch::asm;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+/// address at which the kernel maps the time page, same as VDSO_TIME_ADDRESS in the kernel.
+const VDSO_TIME_ADDRESS: usize = 0x600000000000;
+
+/// Layout of the time page, must be kept in sync with the kernel.
+#[repr(C)]
+struct VDSOTimeData {
+    sequence: AtomicU64,
+    base_ns: AtomicU64,
+    base_tsc: AtomicU64,
+    tsc_frequency: AtomicU64,
+    max_age_ns: AtomicU64,
+}
+
+#[inline(always)]
+fn read_tsc() -> u64 {
+    let rax: u64;
+    let rdx: u64;
+    unsafe {
+        asm!("rdtsc", out("rax") rax, out("rdx") rdx, options(nomem, nostack));
+    }
+    (rdx << 32) | rax
+}
+
+/// reads monotonic time in nanoseconds from the time page without a syscall,
+/// returns None if the page is stale or the TSC is not calibrated yet.
+pub fn vdso_monotonic_ns() -> Option<u64> {
+    let data: &VDSOTimeData = unsafe { &*(VDSO_TIME_ADDRESS as *const VDSOTimeData) };
+
+    loop {
+        // reader side of the seqlock, retry if the kernel updated the page in between.
+        let start_seq = data.sequence.load(Ordering::Acquire);
+        if start_seq % 2 != 0 {
+            continue;
+        }
+
+        let base_ns = data.base_ns.load(Ordering::Relaxed);
+        let base_tsc = data.base_tsc.load(Ordering::Relaxed);
+        let frequency = data.tsc_frequency.load(Ordering::Relaxed);
+        let max_age_ns = data.max_age_ns.load(Ordering::Relaxed);
+
+        fence(Ordering::Acquire);
+        if data.sequence.load(Ordering::Relaxed) != start_seq {
+            continue;
+        }
+
+        if frequency == 0 {
+            return None;
+        }
+
+        let delta_tsc = read_tsc().wrapping_sub(base_tsc);
+        let delta_ns = ((delta_tsc as u128 * 1000000000) / frequency as u128) as u64;
+        if delta_ns > max_age_ns {
+            return None;
+        }
+
+        return Some(base_ns + delta_ns);
+    }
+}
+
+/// monotonic time in nanoseconds, uses the syscall if the time page can't be used.
+pub fn monotonic_ns() -> u64 {
+    if let Some(ns) = vdso_monotonic_ns() {
+        return ns;
+    }
+
+    let mut timeval = Timeval::default();
+    unsafe {
+        syscalls::sys_clock_gettime(ClockType::Monotonic as usize, &mut timeval);
+    }
+
+    let tv_sec = timeval.tv_sec as u64;
+    let tv_usec = timeval.tv_usec as u64;
+    tv_sec * 1000000000 + tv_usec * 1000
+}